@@ -0,0 +1,236 @@
+//! Compute unit benchmarks for programs built on apq_core
+//!
+//! [`Bench`] runs instructions against a deployed program in an [`apq_testkit::TestKit`] and
+//! records the compute units of each under a label. The [`Report`] summarizes them per label
+//! and is checked against [`Thresholds`], so changes to the queue backend show up as
+//! regressions locally.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::{Deref, DerefMut},
+    path::Path,
+};
+
+use apq_testkit::TestKit;
+use solana_instruction::Instruction;
+use solana_pubkey::Pubkey;
+
+/// A [`TestKit`] that records compute units
+pub struct Bench {
+    pub kit: TestKit,
+    samples: BTreeMap<String, Vec<u64>>,
+}
+
+impl Bench {
+    /// Loads the program built at `path`, see [`apq_testkit::deploy_path`]
+    pub fn new(program_id: Pubkey, path: &Path) -> Result<Self, String> {
+        Ok(Bench {
+            kit: TestKit::new(program_id, path)?,
+            samples: BTreeMap::new(),
+        })
+    }
+
+    /// Sends `instruction` on its own and records its compute units under `label`
+    pub fn measure(&mut self, label: &str, instruction: Instruction) -> Result<u64, String> {
+        let units = match self.kit.send(&[instruction]) {
+            Ok(meta) => meta.compute_units_consumed,
+            Err(failed) => {
+                return Err(format!("{:?}\n{}", failed.err, failed.meta.logs.join("\n")))
+            }
+        };
+        self.record(label, units);
+        Ok(units)
+    }
+
+    /// Sends `instruction`, which must fail, on its own and records its compute units under
+    /// `label`, e.g. to measure how early invalid data is rejected
+    pub fn measure_failure(
+        &mut self,
+        label: &str,
+        instruction: Instruction,
+    ) -> Result<u64, String> {
+        let units = match self.kit.send(&[instruction]) {
+            Ok(_) => return Err(format!("{label} succeeded")),
+            Err(failed) => failed.meta.compute_units_consumed,
+        };
+        self.record(label, units);
+        Ok(units)
+    }
+
+    /// Records a sample measured some other way, e.g. units per drained entry
+    pub fn record(&mut self, label: &str, units: u64) {
+        self.samples
+            .entry(label.to_owned())
+            .or_default()
+            .push(units);
+    }
+
+    pub fn report(&self) -> Report {
+        Report::new(&self.samples)
+    }
+}
+
+impl Deref for Bench {
+    type Target = TestKit;
+
+    fn deref(&self) -> &TestKit {
+        &self.kit
+    }
+}
+
+impl DerefMut for Bench {
+    fn deref_mut(&mut self) -> &mut TestKit {
+        &mut self.kit
+    }
+}
+
+/// Compute units of one label
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub label: String,
+    pub count: usize,
+    pub min: u64,
+    pub mean: u64,
+    pub max: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Sorted by label
+    pub stats: Vec<Stats>,
+}
+
+impl Report {
+    pub fn new(samples: &BTreeMap<String, Vec<u64>>) -> Self {
+        let stats = samples
+            .iter()
+            .filter(|(_, units)| !units.is_empty())
+            .map(|(label, units)| Stats {
+                label: label.clone(),
+                count: units.len(),
+                min: *units.iter().min().unwrap(),
+                mean: units.iter().sum::<u64>() / units.len() as u64,
+                max: *units.iter().max().unwrap(),
+            })
+            .collect();
+        Report { stats }
+    }
+
+    /// Labels whose worst case exceeds their threshold. Labels without one aren't checked.
+    pub fn regressions(&self, thresholds: &Thresholds) -> Vec<Regression> {
+        self.stats
+            .iter()
+            .filter_map(|stats| {
+                let threshold = *thresholds.0.get(&stats.label)?;
+                (stats.max > threshold).then(|| Regression {
+                    label: stats.label.clone(),
+                    max: stats.max,
+                    threshold,
+                })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:>6} {:>10} {:>10} {:>10}",
+            "label", "count", "min", "mean", "max"
+        )?;
+        for stats in &self.stats {
+            writeln!(
+                f,
+                "{:<24} {:>6} {:>10} {:>10} {:>10}",
+                stats.label, stats.count, stats.min, stats.mean, stats.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Maximum compute units per label, one `label units` pair per line with `#` comments
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Thresholds(pub BTreeMap<String, u64>);
+
+impl Thresholds {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut thresholds = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = line
+                .split_once(char::is_whitespace)
+                .and_then(|(label, units)| Some((label, units.trim().parse().ok()?)));
+            let Some((label, units)) = parsed else {
+                return Err(format!(
+                    "line {}: expected `label units`, got `{line}`",
+                    i + 1
+                ));
+            };
+            thresholds.insert(label.to_owned(), units);
+        }
+        Ok(Thresholds(thresholds))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Regression {
+    pub label: String,
+    pub max: u64,
+    pub threshold: u64,
+}
+
+impl fmt::Display for Regression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} used up to {} CU, over its threshold of {}",
+            self.label, self.max, self.threshold
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regressions() {
+        let samples = BTreeMap::from([
+            ("enqueue@0".to_owned(), vec![900, 1100, 1000]),
+            ("drain@0".to_owned(), vec![5000]),
+            ("view@0".to_owned(), vec![100]),
+        ]);
+        let report = Report::new(&samples);
+        assert_eq!(
+            report.stats[1],
+            Stats {
+                label: "enqueue@0".to_owned(),
+                count: 3,
+                min: 900,
+                mean: 1000,
+                max: 1100,
+            }
+        );
+
+        let thresholds = Thresholds::parse(
+            "# worst case per instruction\nenqueue@0 1000\n\ndrain@0  6000 # plenty\n",
+        )
+        .unwrap();
+        assert_eq!(
+            report.regressions(&thresholds),
+            [Regression {
+                label: "enqueue@0".to_owned(),
+                max: 1100,
+                threshold: 1000,
+            }]
+        );
+
+        assert!(Thresholds::parse("enqueue@0 lots").is_err());
+    }
+}
@@ -0,0 +1,165 @@
+//! Compute units of the counter program's queue under parameterized workloads
+//!
+//! ```text
+//! cargo build-sbf --manifest-path counter/Cargo.toml
+//! cargo run -p apq-bench --release -- --fill 0,1000,8000 --enqueues 16 --drains 4
+//! ```
+//!
+//! For each queue fill, the queue is prefilled with entries that are never due, then each of
+//! the drains processes a batch of freshly queued entries. Initialization, enqueues, drains
+//! and drained entries are reported as `init@fill`, `enqueue@fill`, `drain@fill` and
+//! `entry@fill`, and checked against the thresholds file if there is one. Exits with an error
+//! on any regression.
+//!
+//! `decode@fill` is a sync instruction with an invalid variant, which fails as soon as the
+//! variant is decoded, and `sync@fill` a refill on an initialized state. Together they show
+//! what the dispatch and variant decode cost every instruction, see
+//! `apq_core::pod::decode_variant`.
+
+use std::{path::PathBuf, process::ExitCode, str::FromStr};
+
+use apq_bench::{Bench, Thresholds};
+use apq_core::AsyncState;
+use counter::{client::CounterClient, AsyncIxKey, CounterPayload, CounterState};
+use sokoban::NodeAllocatorMap;
+use solana_pubkey::Pubkey;
+
+const COUNTER_PROGRAM_ID: Pubkey =
+    solana_pubkey::pubkey!("CounterProgram111111111111111111111111111111");
+
+struct Args {
+    program: PathBuf,
+    thresholds: PathBuf,
+    fill: Vec<usize>,
+    enqueues: usize,
+    drains: usize,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            program: apq_testkit::deploy_path("counter"),
+            thresholds: "bench/thresholds.txt".into(),
+            fill: vec![0, 1000, 4000],
+            enqueues: 16,
+            drains: 4,
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(flag) = argv.next() {
+            let value = argv.next().ok_or(format!("{flag} needs a value"))?;
+            match flag.as_str() {
+                "--program" => args.program = value.into(),
+                "--thresholds" => args.thresholds = value.into(),
+                "--fill" => {
+                    args.fill = value
+                        .split(',')
+                        .map(parse_count)
+                        .collect::<Result<_, _>>()?
+                }
+                "--enqueues" => args.enqueues = parse_count(&value)?,
+                "--drains" => args.drains = parse_count(&value)?,
+                _ => return Err(format!("unknown flag {flag}")),
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn parse_count<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{value}` is not a count"))
+}
+
+/// Runs one fill level, recording under `label@fill`
+fn run(bench: &mut Bench, fill: usize, enqueues: usize, drains: usize) -> Result<(), String> {
+    let state = bench.create_state::<CounterState>();
+    let client = CounterClient::new(COUNTER_PROGRAM_ID, state);
+    let user = bench.user(1_000_000_000_000);
+
+    // The first instruction initializes the state
+    bench.measure(&format!("init@{fill}"), client.refill_actions(&user, &[]))?;
+    bench.measure(&format!("sync@{fill}"), client.refill_actions(&user, &[]))?;
+    let invalid = client.sync(&user, &u64::MAX.to_le_bytes(), &[]);
+    bench.measure_failure(&format!("decode@{fill}"), invalid)?;
+    bench.modify_state::<CounterState>(&state, |state| {
+        state
+            .credit_actions(&user.to_bytes(), u64::MAX / 2)
+            .unwrap();
+        for seq in 0..fill as u64 {
+            // Queued at the end of time, so never drained
+            let key = AsyncIxKey {
+                slot: u64::MAX,
+                seq,
+                ..Default::default()
+            };
+            let payload = CounterPayload::new(user.to_bytes(), 1).unwrap();
+            state.async_queue.insert(key, payload);
+        }
+    });
+
+    for _ in 0..drains {
+        for _ in 0..enqueues {
+            bench.measure(
+                &format!("enqueue@{fill}"),
+                client.increment(&user, 1, 0, &Pubkey::default()),
+            )?;
+        }
+        bench.warp_slots(CounterState::ASYNC_DELAY_SLOTS);
+        let units = bench.measure(&format!("drain@{fill}"), client.drain(&user))?;
+        if enqueues > 0 {
+            bench.record(&format!("entry@{fill}"), units / enqueues as u64);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut bench = match Bench::new(COUNTER_PROGRAM_ID, &args.program) {
+        Ok(bench) => bench,
+        Err(err) => {
+            eprintln!("{err}\nbuild the program first with cargo build-sbf");
+            return ExitCode::FAILURE;
+        }
+    };
+    for &fill in &args.fill {
+        if let Err(err) = run(&mut bench, fill, args.enqueues, args.drains) {
+            eprintln!("fill {fill} failed: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let report = bench.report();
+    println!("{report}");
+
+    let thresholds = match std::fs::read_to_string(&args.thresholds) {
+        Ok(text) => match Thresholds::parse(&text) {
+            Ok(thresholds) => thresholds,
+            Err(err) => {
+                eprintln!("{}: {err}", args.thresholds.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(_) => {
+            println!("No thresholds at {}", args.thresholds.display());
+            return ExitCode::SUCCESS;
+        }
+    };
+    let regressions = report.regressions(&thresholds);
+    for regression in &regressions {
+        eprintln!("REGRESSION: {regression}");
+    }
+    if regressions.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
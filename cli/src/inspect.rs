@@ -0,0 +1,69 @@
+//! What the CLI knows about each example program's state
+
+use apq_client::{queue, DecodeError, TryDecode};
+use apq_core::AsyncState;
+use counter::CounterState;
+use orderbook::OrderbookState;
+use serde_json::{json, Value};
+use vesting::VestingState;
+
+/// A state the CLI can show beyond its header
+pub trait Inspect: AsyncState + TryDecode {
+    /// The state's own fields, leaving out the header and the queue
+    fn fields(&self) -> Result<Value, DecodeError>;
+
+    /// The queue in drain order, as `{ "key": .., "value": .. }` objects
+    fn queue(&self) -> Result<Vec<Value>, DecodeError>;
+}
+
+/// `snapshot` as JSON values
+fn to_values<T: serde::Serialize>(snapshot: Vec<T>) -> Vec<Value> {
+    snapshot
+        .iter()
+        .map(|entry| serde_json::to_value(entry).expect("entries serialize to JSON"))
+        .collect()
+}
+
+impl Inspect for CounterState {
+    fn fields(&self) -> Result<Value, DecodeError> {
+        Ok(json!({
+            "seq": self.seq,
+            "counter": self.counter,
+            "deposits": self.deposits,
+            "users": queue::entries(&self.balances).collect::<Result<Vec<_>, _>>()?.len(),
+        }))
+    }
+
+    fn queue(&self) -> Result<Vec<Value>, DecodeError> {
+        queue::snapshot(&self.async_queue).map(to_values)
+    }
+}
+
+impl Inspect for OrderbookState {
+    fn fields(&self) -> Result<Value, DecodeError> {
+        Ok(json!({
+            "seq": self.seq,
+            "last_batch": self.last_batch,
+            "bids": queue::entries(&self.bids).collect::<Result<Vec<_>, _>>()?.len(),
+            "asks": queue::entries(&self.asks).collect::<Result<Vec<_>, _>>()?.len(),
+            "users": queue::entries(&self.balances).collect::<Result<Vec<_>, _>>()?.len(),
+        }))
+    }
+
+    fn queue(&self) -> Result<Vec<Value>, DecodeError> {
+        queue::snapshot(&self.async_queue).map(to_values)
+    }
+}
+
+impl Inspect for VestingState {
+    fn fields(&self) -> Result<Value, DecodeError> {
+        Ok(json!({
+            "seq": self.seq,
+            "grants": queue::entries(&self.grants).collect::<Result<Vec<_>, _>>()?.len(),
+        }))
+    }
+
+    fn queue(&self) -> Result<Vec<Value>, DecodeError> {
+        queue::snapshot(&self.async_queue).map(to_values)
+    }
+}
@@ -0,0 +1,279 @@
+//! Inspecting the state of apq programs from the command line
+//!
+//! Everything read from the header works for any program, while decoding the rest of the
+//! state takes knowing its type, see [`inspect::Inspect`]. Output is JSON, so it can be piped
+//! into `jq` and the like.
+
+use apq_client::{instructions::InstructionBuilder, stats};
+use apq_core::{
+    cursor::DrainArgs,
+    events::{self, DrainedEvent},
+    header::StateHeader,
+};
+use serde_json::{json, Value};
+use solana_message::Message;
+use solana_pubkey::Pubkey;
+use solana_transaction::Transaction;
+
+pub mod inspect;
+pub mod rpc;
+
+use inspect::Inspect;
+use rpc::Rpc;
+
+pub const USAGE: &str = "\
+usage: apq-cli [--rpc <URL>] [--program counter|orderbook|vesting] <COMMAND>
+
+commands:
+    state decode <STATE>     the header and the program's fields
+    queue list <STATE>       queued entries in drain order, one per line
+    queue depth <STATE>      how many entries are queued, for any program
+    drain simulate <STATE> --payer <PUBKEY> [--max-items <N>]
+                             simulates a drain paid by the payer";
+
+/// Which example program's state to decode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Program {
+    Counter,
+    Orderbook,
+    Vesting,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    StateDecode(Pubkey),
+    QueueList(Pubkey),
+    QueueDepth(Pubkey),
+    DrainSimulate {
+        state: Pubkey,
+        payer: Pubkey,
+        max_items: u32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Args {
+    pub rpc: String,
+    pub program: Program,
+    pub command: Command,
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("`{value}` is invalid"))
+}
+
+impl Args {
+    pub fn parse(argv: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let (mut rpc, mut program) = ("http://127.0.0.1:8899".to_owned(), Program::Counter);
+        let (mut payer, mut max_items) = (None, 0);
+        let mut words = Vec::new();
+        let mut argv = argv.into_iter();
+        while let Some(arg) = argv.next() {
+            if !arg.starts_with("--") {
+                words.push(arg);
+                continue;
+            }
+            let value = argv.next().ok_or(format!("{arg} needs a value"))?;
+            match arg.as_str() {
+                "--rpc" => rpc = value,
+                "--program" => {
+                    program = match value.as_str() {
+                        "counter" => Program::Counter,
+                        "orderbook" => Program::Orderbook,
+                        "vesting" => Program::Vesting,
+                        _ => return Err(format!("unknown program {value}")),
+                    }
+                }
+                "--payer" => payer = Some(parse(&value)?),
+                "--max-items" => max_items = parse(&value)?,
+                _ => return Err(format!("unknown flag {arg}")),
+            }
+        }
+
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let command = match words[..] {
+            ["state", "decode", state] => Command::StateDecode(parse(state)?),
+            ["queue", "list", state] => Command::QueueList(parse(state)?),
+            ["queue", "depth", state] => Command::QueueDepth(parse(state)?),
+            ["drain", "simulate", state] => Command::DrainSimulate {
+                state: parse(state)?,
+                payer: payer.ok_or("drain simulate needs --payer")?,
+                max_items,
+            },
+            _ => return Err(USAGE.to_owned()),
+        };
+        Ok(Args {
+            rpc,
+            program,
+            command,
+        })
+    }
+}
+
+/// The header and fields of a state, as `state decode` prints them
+pub fn decode_state<S: Inspect>(data: &[u8]) -> Result<Value, String> {
+    let state = S::try_decode(data).map_err(|err| err.to_string())?;
+    let header = StateHeader::read(data).map_err(|err| format!("{err:?}"))?;
+    Ok(json!({
+        "header": header,
+        "fields": state.fields().map_err(|err| err.to_string())?,
+    }))
+}
+
+/// The queued entries of a state, as `queue list` prints them
+pub fn list_queue<S: Inspect>(data: &[u8]) -> Result<Vec<Value>, String> {
+    let state = S::try_decode(data).map_err(|err| err.to_string())?;
+    state.queue().map_err(|err| err.to_string())
+}
+
+/// Reads a drain simulation: whether it failed, its compute units, the
+/// [`DrainedEvent`] it emitted and its logs
+pub fn simulation_report(simulation: &Value) -> Value {
+    let logs: Vec<&str> = simulation["logs"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    let drained = logs
+        .iter()
+        .filter_map(|line| events::decode_log(line))
+        .find_map(|segments| events::decode::<DrainedEvent>(&segments));
+    json!({
+        "err": simulation["err"],
+        "units": simulation["unitsConsumed"],
+        "drained": drained,
+        "logs": logs,
+    })
+}
+
+/// Runs `command` against the state type `S`, returning what to print
+fn run_as<S: Inspect>(rpc: &Rpc, command: &Command) -> Result<String, String> {
+    match *command {
+        Command::StateDecode(state) => {
+            let decoded = decode_state::<S>(&rpc.account(&state)?.data)?;
+            Ok(serde_json::to_string_pretty(&decoded).expect("JSON values serialize"))
+        }
+        Command::QueueList(state) => {
+            let entries = list_queue::<S>(&rpc.account(&state)?.data)?;
+            let lines: Vec<String> = entries.iter().map(Value::to_string).collect();
+            Ok(lines.join("\n"))
+        }
+        Command::QueueDepth(state) => {
+            let depth = stats::depth(&rpc.account(&state)?.data).map_err(|err| err.to_string())?;
+            Ok(depth.to_string())
+        }
+        Command::DrainSimulate {
+            state,
+            payer,
+            max_items,
+        } => {
+            let account = rpc.account(&state)?;
+            let header = StateHeader::read(&account.data).map_err(|err| format!("{err:?}"))?;
+            let args = DrainArgs {
+                max_items,
+                nonce: Some(header.cursor.nonce),
+            };
+            let drain = InstructionBuilder::new(account.owner, state).drain_with(&payer, args);
+            let transaction = Transaction::new_unsigned(Message::new(&[drain], Some(&payer)));
+            let report = simulation_report(&rpc.simulate(&transaction)?);
+            Ok(serde_json::to_string_pretty(&report).expect("JSON values serialize"))
+        }
+    }
+}
+
+/// Runs the command, returning what to print
+pub fn run(args: &Args) -> Result<String, String> {
+    let rpc = Rpc::new(&args.rpc);
+    match args.program {
+        Program::Counter => run_as::<counter::CounterState>(&rpc, &args.command),
+        Program::Orderbook => run_as::<orderbook::OrderbookState>(&rpc, &args.command),
+        Program::Vesting => run_as::<vesting::VestingState>(&rpc, &args.command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_core::{events::Event, migrate::Migratable, AsyncState};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use counter::{AsyncIxKey, CounterPayload, CounterState};
+    use sokoban::NodeAllocatorMap;
+
+    use super::*;
+
+    fn args(line: &str) -> Result<Args, String> {
+        Args::parse(line.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let state = Pubkey::new_unique();
+        let parsed = args(&format!("--program vesting queue list {state}")).unwrap();
+        assert_eq!(parsed.program, Program::Vesting);
+        assert_eq!(parsed.command, Command::QueueList(state));
+
+        let payer = Pubkey::new_unique();
+        let parsed = args(&format!(
+            "drain simulate {state} --max-items 3 --payer {payer}"
+        ));
+        assert_eq!(
+            parsed.unwrap().command,
+            Command::DrainSimulate {
+                state,
+                payer,
+                max_items: 3
+            }
+        );
+
+        assert!(args(&format!("drain simulate {state}")).is_err());
+        assert_eq!(args("queue"), Err(USAGE.to_owned()));
+        assert!(args("queue depth nope").is_err());
+    }
+
+    #[test]
+    fn test_decode_counter() {
+        let mut state: Box<CounterState> = bytemuck::zeroed_box();
+        state.initialize();
+        state.header = StateHeader::new(CounterState::VERSION);
+        state.header.layout_hash = CounterState::LAYOUT_HASH;
+        state.counter = 7;
+        let key = AsyncIxKey {
+            slot: 3,
+            ..Default::default()
+        };
+        let payload = CounterPayload::new([1; 32], 2).unwrap();
+        state.async_queue.insert(key, payload);
+        let data = bytemuck::bytes_of(&*state);
+
+        let decoded = decode_state::<CounterState>(data).unwrap();
+        assert_eq!(decoded["header"]["version"], CounterState::VERSION);
+        assert_eq!(decoded["fields"]["counter"], 7);
+        let entries = list_queue::<CounterState>(data).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["key"]["slot"], 3);
+
+        assert!(decode_state::<CounterState>(&data[..100]).is_err());
+    }
+
+    #[test]
+    fn test_simulation_report() {
+        let drained = DrainedEvent {
+            cranker: [1; 32],
+            drained: 2,
+            bounty: 10_000,
+        };
+        let data = [
+            &DrainedEvent::DISCRIMINATOR[..],
+            bytemuck::bytes_of(&drained),
+        ];
+        let segments: Vec<String> = data.iter().map(|s| STANDARD.encode(s)).collect();
+        let line = format!("Program data: {}", segments.join(" "));
+        let simulation = json!({ "err": null, "unitsConsumed": 1234, "logs": [line] });
+
+        let report = simulation_report(&simulation);
+        assert_eq!(report["units"], 1234);
+        assert_eq!(report["drained"]["drained"], 2);
+        assert!(report["err"].is_null());
+        assert_eq!(simulation_report(&json!({}))["drained"], Value::Null);
+    }
+}
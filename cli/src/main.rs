@@ -0,0 +1,27 @@
+//! ```text
+//! cargo run -p apq-cli -- --rpc http://127.0.0.1:8899 queue list <STATE>
+//! ```
+
+use std::process::ExitCode;
+
+use apq_cli::{run, Args};
+
+fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(&args) {
+        Ok(output) => {
+            println!("{output}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
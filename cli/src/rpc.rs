@@ -0,0 +1,79 @@
+//! The two JSON-RPC calls the CLI makes
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use solana_pubkey::Pubkey;
+use solana_transaction::Transaction;
+
+pub struct Account {
+    /// The program owning the account
+    pub owner: Pubkey,
+    pub data: Vec<u8>,
+}
+
+pub struct Rpc {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Rpc {
+    pub fn new(url: &str) -> Self {
+        Rpc {
+            url: url.to_owned(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// The `result` of calling `method`, or the error the node answered with
+    fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let mut response: Value = self
+            .agent
+            .post(&self.url)
+            .send_json(request)
+            .map_err(|err| format!("{method}: {err}"))?
+            .into_json()
+            .map_err(|err| format!("{method}: {err}"))?;
+        if let Some(err) = response.get("error") {
+            return Err(format!("{method}: {err}"));
+        }
+        Ok(response["result"].take())
+    }
+
+    pub fn account(&self, address: &Pubkey) -> Result<Account, String> {
+        let result = self.call(
+            "getAccountInfo",
+            json!([address.to_string(), { "encoding": "base64" }]),
+        )?;
+        let account = &result["value"];
+        if account.is_null() {
+            return Err(format!("{address} doesn't exist"));
+        }
+        let owner = account["owner"]
+            .as_str()
+            .and_then(|owner| owner.parse().ok())
+            .ok_or("getAccountInfo: no owner")?;
+        let data = account["data"][0]
+            .as_str()
+            .ok_or("getAccountInfo: no data")?;
+        Ok(Account {
+            owner,
+            data: STANDARD.decode(data).map_err(|err| err.to_string())?,
+        })
+    }
+
+    /// The `value` of simulating `transaction`, which needn't be signed, against the latest
+    /// state
+    pub fn simulate(&self, transaction: &Transaction) -> Result<Value, String> {
+        let bytes = bincode::serialize(transaction).map_err(|err| err.to_string())?;
+        let mut result = self.call(
+            "simulateTransaction",
+            json!([STANDARD.encode(bytes), {
+                "encoding": "base64",
+                "sigVerify": false,
+                "replaceRecentBlockhash": true,
+            }]),
+        )?;
+        Ok(result["value"].take())
+    }
+}
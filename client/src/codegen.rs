@@ -0,0 +1,508 @@
+//! Generating typed clients from a program's IDL
+//!
+//! [`client`] writes the source of a client struct with one method per sync and async
+//! instruction of an IDL built with [`IdlBuilder`](crate::idl::IdlBuilder), so downstream teams
+//! don't hand-maintain wire formats. Discriminators are read off the IDL, which the program
+//! builds from its own instruction enums, so the client can't send a variant the program
+//! doesn't declare. Arguments follow in order, each encoded as its bytes, so defined types
+//! must be [`Pod`](bytemuck::Pod) and match the layout the program parses.
+//!
+//! The accounts a sync instruction declares after the state and user become parameters of its
+//! method, in order and with the writable and signer flags the IDL gives them, followed by
+//! whatever else the caller passes, e.g. the accounts a [`Vault`](apq_core::vault::Vault)
+//! charges through. Async instructions take the accounts
+//! [`queue_async`](crate::instructions::InstructionBuilder::queue_async) adds.
+//!
+//! The client derefs to an [`InstructionBuilder`](crate::instructions::InstructionBuilder) for
+//! the instructions every program shares, like draining and cancelling. Its
+//! `decode_instruction` reads instructions back from the same IDL, see [`crate::decode`], and
+//! its `decode_compact_instruction` reads them in the compact format a builder
+//! [`with_compact_variants`](crate::instructions::InstructionBuilder::with_compact_variants)
+//! sends.
+//!
+//! The source is meant to be committed as a child module of the one defining the IDL's types,
+//! which it glob imports, and kept current with [`assert_generated`] in a test.
+
+use std::fmt::Write;
+
+use apq_core::dispatch::tag;
+use serde_json::Value;
+
+/// An instruction argument, see [`rust_type`]
+struct Arg<'a> {
+    name: &'a str,
+    ty: &'a Value,
+}
+
+impl Arg<'_> {
+    /// Type of the method parameter. Top level pubkeys are taken as a `&Pubkey` like the
+    /// user, nested ones as their bytes.
+    fn param_type(&self) -> Result<String, String> {
+        match self.ty.as_str() {
+            Some("pubkey") => Ok("&Pubkey".to_owned()),
+            _ => rust_type(self.ty),
+        }
+    }
+
+    fn encode(&self) -> String {
+        match self.ty.as_str() {
+            Some("pubkey") => format!("data.extend_from_slice({}.as_ref());", self.name),
+            _ => format!("data.extend_from_slice(bytes_of(&{}));", self.name),
+        }
+    }
+
+    fn decode(&self) -> Result<String, String> {
+        match self.ty.as_str() {
+            Some("pubkey") => Ok("decode::take_pubkey(&mut rest)?".to_owned()),
+            _ => Ok(format!(
+                "decode::take_arg::<{}>(&mut rest)?",
+                rust_type(self.ty)?
+            )),
+        }
+    }
+}
+
+/// An account a sync instruction declares after the state and user
+struct Account<'a> {
+    name: &'a str,
+    writable: bool,
+    signer: bool,
+}
+
+impl Account<'_> {
+    fn meta(&self) -> String {
+        match self.writable {
+            true => format!("AccountMeta::new(*{}, {})", self.name, self.signer),
+            false => format!("AccountMeta::new_readonly(*{}, {})", self.name, self.signer),
+        }
+    }
+}
+
+/// A sync or async instruction of the IDL
+struct Method<'a> {
+    name: &'a str,
+    docs: &'a str,
+    tag: u8,
+    variant: u64,
+    accounts: Vec<Account<'a>>,
+    args: Vec<Arg<'a>>,
+}
+
+impl<'a> Method<'a> {
+    /// `None` for the instructions every program shares
+    fn parse(ix: &'a Value) -> Result<Option<Self>, String> {
+        let name = ix["name"].as_str().ok_or("instruction without a name")?;
+        let discriminator: Vec<u8> = ix["discriminator"]
+            .as_array()
+            .and_then(|bytes| {
+                bytes
+                    .iter()
+                    .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                    .collect()
+            })
+            .ok_or(format!("{name} has no discriminator"))?;
+        let (tag, variant) = match discriminator.split_first() {
+            Some((&tag, variant)) if matches!(tag, tag::SYNC | tag::QUEUE) => {
+                let variant = variant
+                    .try_into()
+                    .map_err(|_| format!("{name} has no u64 variant"))?;
+                (tag, u64::from_le_bytes(variant))
+            }
+            _ => return Ok(None),
+        };
+        check_ident(name)?;
+
+        let accounts = ix["accounts"]
+            .as_array()
+            .ok_or(format!("{name} has no accounts"))?;
+        let (Some("state"), Some("user")) = (
+            accounts.first().and_then(|a| a["name"].as_str()),
+            accounts.get(1).and_then(|a| a["name"].as_str()),
+        ) else {
+            return Err(format!(
+                "{name} doesn't start with the state and user accounts"
+            ));
+        };
+        let extra = &accounts[2..];
+        let accounts = match tag {
+            tag::QUEUE => match extra {
+                [system] if system["name"] == "system_program" => vec![],
+                _ => return Err(format!("{name} takes accounts queue_async doesn't add")),
+            },
+            _ => extra
+                .iter()
+                .map(|account| {
+                    let name = account["name"].as_str().ok_or("account without a name")?;
+                    if account.get("address").is_some() {
+                        return Err(format!("{name} has a fixed address, pass it with accounts"));
+                    }
+                    check_ident(name)?;
+                    Ok(Account {
+                        name,
+                        writable: account["writable"].as_bool().unwrap_or(false),
+                        signer: account["signer"].as_bool().unwrap_or(false),
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+        };
+        let args = ix["args"]
+            .as_array()
+            .map_or(&[][..], Vec::as_slice)
+            .iter()
+            .map(|arg| {
+                let name = arg["name"].as_str().ok_or("argument without a name")?;
+                check_ident(name)?;
+                Ok(Arg {
+                    name,
+                    ty: &arg["type"],
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Some(Method {
+            name,
+            docs: ix["docs"][0].as_str().unwrap_or_default(),
+            tag,
+            variant,
+            accounts,
+            args,
+        }))
+    }
+
+    fn write(&self, out: &mut String) -> Result<(), String> {
+        let mut params = vec!["&self".to_owned(), "user: &Pubkey".to_owned()];
+        params.extend(self.accounts.iter().map(|a| format!("{}: &Pubkey", a.name)));
+        for arg in &self.args {
+            params.push(format!("{}: {}", arg.name, arg.param_type()?));
+        }
+        if self.tag == tag::SYNC {
+            params.push("accounts: &[AccountMeta]".to_owned());
+        }
+
+        if !self.docs.is_empty() {
+            writeln!(out, "    /// {}", self.docs).unwrap();
+        }
+        if params.len() > 7 {
+            writeln!(out, "    #[allow(clippy::too_many_arguments)]").unwrap();
+        }
+        write_signature(out, self.name, &params);
+        let data = match self.args.is_empty() {
+            true => "let data",
+            false => "let mut data",
+        };
+        writeln!(
+            out,
+            "        {data} = {}_u64.to_le_bytes().to_vec();",
+            self.variant
+        )
+        .unwrap();
+        for arg in &self.args {
+            writeln!(out, "        {}", arg.encode()).unwrap();
+        }
+        match (self.tag, &self.accounts[..]) {
+            (tag::QUEUE, _) => writeln!(out, "        self.builder.queue_async(user, &data)"),
+            (_, []) => writeln!(out, "        self.builder.sync(user, &data, accounts)"),
+            (_, extra) => {
+                let metas: Vec<String> = extra.iter().map(Account::meta).collect();
+                writeln!(out, "        let mut metas = vec![{}];", metas.join(", ")).unwrap();
+                writeln!(out, "        metas.extend_from_slice(accounts);").unwrap();
+                writeln!(out, "        self.builder.sync(user, &data, &metas)")
+            }
+        }
+        .unwrap();
+        writeln!(out, "    }}").unwrap();
+        Ok(())
+    }
+
+    fn write_decode_arm(&self, out: &mut String) -> Result<(), String> {
+        let tag = match self.tag {
+            tag::SYNC => "tag::SYNC",
+            _ => "tag::QUEUE",
+        };
+        let pattern = format!("            ({tag}, Some({})) => (", self.variant);
+        if self.args.is_empty() {
+            writeln!(out, "{pattern}\"{}\", vec![]),", self.name).unwrap();
+            return Ok(());
+        }
+        writeln!(out, "{pattern}").unwrap();
+        writeln!(out, "                \"{}\",", self.name).unwrap();
+        writeln!(out, "                vec![").unwrap();
+        for arg in &self.args {
+            writeln!(
+                out,
+                "                    (\"{}\", {}),",
+                arg.name,
+                arg.decode()?
+            )
+            .unwrap();
+        }
+        writeln!(out, "                ],").unwrap();
+        writeln!(out, "            ),").unwrap();
+        Ok(())
+    }
+}
+
+/// Rust type of an IDL type, e.g. `u64`, `[u8; 32]` for a `pubkey` or the name of a defined
+/// type
+fn rust_type(ty: &Value) -> Result<String, String> {
+    if let Some(primitive) = ty.as_str() {
+        return match primitive {
+            "u8" | "u16" | "u32" | "u64" | "u128" | "i8" | "i16" | "i32" | "i64" | "i128" => {
+                Ok(primitive.to_owned())
+            }
+            "pubkey" => Ok("[u8; 32]".to_owned()),
+            // `bool` isn't `Pod`, programs take a `u8`
+            _ => Err(format!("unsupported argument type {primitive}")),
+        };
+    }
+    if let Some([element, len]) = ty["array"].as_array().map(Vec::as_slice) {
+        let len = len
+            .as_u64()
+            .ok_or(format!("array length {len} isn't a number"))?;
+        return Ok(format!("[{}; {len}]", rust_type(element)?));
+    }
+    let name = ty["defined"]["name"]
+        .as_str()
+        .ok_or(format!("unsupported argument type {ty}"))?;
+    check_ident(name)?;
+    Ok(name.to_owned())
+}
+
+fn check_ident(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(format!("{name:?} isn't a Rust identifier")),
+    }
+}
+
+/// Writes `pub fn name(params) -> Instruction {`, one parameter per line if they don't fit on
+/// one, as rustfmt would
+fn write_signature(out: &mut String, name: &str, params: &[String]) {
+    let line = format!("    pub fn {name}({}) -> Instruction {{", params.join(", "));
+    if line.len() <= 100 {
+        writeln!(out, "{line}").unwrap();
+        return;
+    }
+    writeln!(out, "    pub fn {name}(").unwrap();
+    for param in params {
+        writeln!(out, "        {param},").unwrap();
+    }
+    writeln!(out, "    ) -> Instruction {{").unwrap();
+}
+
+/// Source of a client struct named `name` for the program `idl` describes, see the
+/// [module docs](self)
+pub fn client(idl: &Value, name: &str) -> Result<String, String> {
+    check_ident(name)?;
+    let program = idl["metadata"]["name"]
+        .as_str()
+        .ok_or("IDL without a program name")?;
+    let methods: Vec<Method> = idl["instructions"]
+        .as_array()
+        .ok_or("IDL without instructions")?
+        .iter()
+        .filter_map(|ix| Method::parse(ix).transpose())
+        .collect::<Result<_, _>>()?;
+    if methods.is_empty() {
+        return Err(format!("{program} has no sync or async instructions"));
+    }
+    let has_args = methods.iter().any(|method| !method.args.is_empty());
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "//! Client for the `{program}` program, generated from its IDL by `apq_client::codegen`.\n\
+         //! Don't edit it by hand, regenerate it with `APQ_UPDATE_GOLDEN=1`.\n"
+    )
+    .unwrap();
+    writeln!(out, "#[allow(unused_imports)]\nuse super::*;\n").unwrap();
+    let bytes_of = match has_args {
+        true => "bytes_of, ",
+        false => "",
+    };
+    writeln!(
+        out,
+        "use apq_client::__private::{{{bytes_of}tag, AccountMeta, Instruction, Pubkey}};\n\
+         use apq_client::{{decode, decode::DecodedInstruction, instructions::InstructionBuilder, \
+         DecodeError}};\n"
+    )
+    .unwrap();
+
+    writeln!(
+        out,
+        "/// Builds the `{program}` program's instructions, and derefs to the\n\
+         /// [`InstructionBuilder`] for the ones every program shares\n\
+         #[derive(Copy, Clone, Debug)]\n\
+         pub struct {name} {{\n    \
+             pub builder: InstructionBuilder,\n\
+         }}\n\n\
+         impl {name} {{\n    \
+             pub fn new(program_id: Pubkey, state: Pubkey) -> Self {{\n        \
+                 {name} {{\n            \
+                     builder: InstructionBuilder::new(program_id, state),\n        \
+                 }}\n    \
+             }}"
+    )
+    .unwrap();
+    for method in &methods {
+        writeln!(out).unwrap();
+        method.write(&mut out)?;
+    }
+
+    let rest = match has_args {
+        true => "mut rest",
+        false => "rest",
+    };
+    writeln!(
+        out,
+        "\n    /// Describes instruction `data` sent with `accounts`, naming the instructions \
+         and\n    /// arguments of the IDL, see [`apq_client::decode`]\n    \
+         pub fn decode_instruction(\n        \
+             data: &[u8],\n        \
+             accounts: &[AccountMeta],\n    \
+         ) -> Result<DecodedInstruction, DecodeError> {{\n        \
+             let (tag, variant, {rest}) = decode::split(data)?;\n        \
+             let (name, args): (&str, Vec<(&str, String)>) = match (tag, variant) {{"
+    )
+    .unwrap();
+    for method in &methods {
+        method.write_decode_arm(&mut out)?;
+    }
+    writeln!(
+        out,
+        "            _ => return decode::shared(data, accounts),\n        \
+             }};\n        \
+             Ok(DecodedInstruction::new(tag, name, variant, args, rest, accounts))\n    \
+         }}\n\n    \
+         /// [`Self::decode_instruction`] for the compact format, see\n    \
+         /// `apq_core::discriminator::Compact`\n    \
+         pub fn decode_compact_instruction(\n        \
+             data: &[u8],\n        \
+             accounts: &[AccountMeta],\n    \
+         ) -> Result<DecodedInstruction, DecodeError> {{\n        \
+             Self::decode_instruction(&decode::expand_compact(data)?, accounts)\n    \
+         }}\n\
+         }}\n\n\
+         impl std::ops::Deref for {name} {{\n    \
+             type Target = InstructionBuilder;\n\n    \
+             fn deref(&self) -> &Self::Target {{\n        \
+                 &self.builder\n    \
+             }}\n\
+         }}"
+    )
+    .unwrap();
+    Ok(out)
+}
+
+/// Panics unless `source` matches the generated file at `path`, or writes the file instead
+/// when `APQ_UPDATE_GOLDEN` is set
+#[track_caller]
+pub fn assert_generated(source: &str, path: &str) {
+    if std::env::var_os("APQ_UPDATE_GOLDEN").is_some() {
+        std::fs::write(path, source).unwrap_or_else(|err| panic!("writing {path}: {err}"));
+        return;
+    }
+    let generated = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        panic!("reading {path}: {err}, run with APQ_UPDATE_GOLDEN=1 to create it")
+    });
+    if generated != source {
+        panic!(
+            "{path} drifted from the IDL it's generated from. Run with APQ_UPDATE_GOLDEN=1 to \
+             regenerate it.\nexpected:\n{source}\nactual:\n{generated}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use solana_pubkey::Pubkey;
+
+    use super::*;
+    use crate::{idl::IdlBuilder, idl_type};
+
+    fn idl() -> IdlBuilder {
+        let mut idl = IdlBuilder::new(Pubkey::new_unique(), "test", "0.1.0");
+        idl.sync_ix("ping", 0, &[])
+            .sync_ix_with_accounts(
+                "sweep",
+                3,
+                &[IdlBuilder::account("vault", true, false)],
+                &[("amounts", idl_type!([u64; 2]))],
+            )
+            .async_ix(
+                "increment",
+                1,
+                &[("amount", idl_type!(u64)), ("referrer", idl_type!(pubkey))],
+            );
+        idl
+    }
+
+    #[test]
+    fn test_client() {
+        let source = client(&idl().build(), "TestClient").unwrap();
+
+        assert!(source.contains("pub struct TestClient {"));
+        assert!(source.contains(
+            "    pub fn ping(&self, user: &Pubkey, accounts: &[AccountMeta]) -> Instruction \
+             {\n        \
+             let data = 0_u64.to_le_bytes().to_vec();\n        \
+             self.builder.sync(user, &data, accounts)\n"
+        ));
+        // Declared accounts are parameters, with their flags, ahead of the caller's
+        assert!(source.contains(
+            "        let mut data = 3_u64.to_le_bytes().to_vec();\n        \
+             data.extend_from_slice(bytes_of(&amounts));\n        \
+             let mut metas = vec![AccountMeta::new(*vault, false)];\n"
+        ));
+        assert!(source.contains("amounts: [u64; 2],"));
+        assert!(source.contains(
+            "        data.extend_from_slice(referrer.as_ref());\n        \
+             self.builder.queue_async(user, &data)\n"
+        ));
+        assert!(source.contains("(tag::QUEUE, Some(1)) => ("));
+        assert!(source.contains("(\"referrer\", decode::take_pubkey(&mut rest)?),"));
+        // The instructions every program shares go through the builder
+        assert!(!source.contains("fn drain"));
+        assert!(source.lines().all(|line| line.len() <= 100));
+    }
+
+    #[test]
+    fn test_client_rejects() {
+        let mut idl = idl().build();
+        assert_eq!(
+            client(&idl, "test client"),
+            Err("\"test client\" isn't a Rust identifier".to_owned())
+        );
+
+        idl["instructions"][4]["accounts"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "name": "extra", "writable": true, "signer": false }));
+        assert_eq!(
+            client(&idl, "TestClient"),
+            Err("increment takes accounts queue_async doesn't add".to_owned())
+        );
+
+        idl["instructions"][4]["accounts"]
+            .as_array_mut()
+            .unwrap()
+            .pop();
+        idl["instructions"][4]["args"][0]["type"] = json!("bool");
+        assert_eq!(
+            client(&idl, "TestClient"),
+            Err("unsupported argument type bool".to_owned())
+        );
+
+        let shared_only = IdlBuilder::new(Pubkey::new_unique(), "test", "0.1.0").build();
+        assert_eq!(
+            client(&shared_only, "TestClient"),
+            Err("test has no sync or async instructions".to_owned())
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Human-readable descriptions of instructions for the default dispatch
+//!
+//! Explorers and debugging tools only see raw instruction data. Clients generated by
+//! [`codegen`](crate::codegen) describe it with their `decode_instruction`, naming the
+//! instructions and arguments of the IDL they're generated from, so the decoder and the
+//! builders can't drift apart. [`shared`] describes the instructions every program shares by their
+//! [`SHARED`] names. Their `decode_compact_instruction` reads the
+//! [`Compact`] format, see [`expand_compact`].
+
+use std::{borrow::Cow, fmt};
+
+use apq_core::{
+    discriminator::{Compact, Discriminator, SHARED},
+    dispatch::{tag, DispatchTag},
+};
+use bytemuck::Pod;
+use solana_instruction::AccountMeta;
+use solana_pubkey::Pubkey;
+
+use crate::DecodeError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    /// The [`tag`] the dispatch matches on
+    pub tag: u8,
+    /// As in the IDL for sync and async variants, otherwise as in [`SHARED`]
+    pub name: String,
+    /// Variant of sync and async instructions
+    pub variant: Option<u64>,
+    /// Declared arguments as `(name, value)`, the value formatted with `Debug`
+    pub args: Vec<(String, String)>,
+    /// Bytes after the declared arguments, e.g. optional ones, or all of a shared
+    /// instruction's data
+    pub rest: Vec<u8>,
+    /// The account after the state
+    pub user: Option<Pubkey>,
+}
+
+impl DecodedInstruction {
+    pub fn new(
+        tag: u8,
+        name: &str,
+        variant: Option<u64>,
+        args: Vec<(&str, String)>,
+        rest: &[u8],
+        accounts: &[AccountMeta],
+    ) -> Self {
+        DecodedInstruction {
+            tag,
+            name: name.to_owned(),
+            variant,
+            args: args
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect(),
+            rest: rest.to_vec(),
+            user: accounts.get(1).map(|account| account.pubkey),
+        }
+    }
+}
+
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (i, (name, value)) in self.args.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{separator}{name}: {value}")?;
+        }
+        write!(f, ")")?;
+        if !self.rest.is_empty() {
+            write!(f, " + {} bytes", self.rest.len())?;
+        }
+        if let Some(user) = self.user {
+            write!(f, " by {user}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits instruction data into its tag, its variant for sync and async instructions, and
+/// the rest
+pub fn split(data: &[u8]) -> Result<(u8, Option<u64>, &[u8]), DecodeError> {
+    let truncated = |expected| DecodeError::Truncated {
+        expected,
+        actual: data.len(),
+    };
+    let (&tag, rest) = data.split_first().ok_or(truncated(1))?;
+    if !matches!(tag, tag::SYNC | tag::QUEUE) {
+        return Ok((tag, None, rest));
+    }
+    let (variant, rest) = rest.split_first_chunk().ok_or(truncated(9))?;
+    Ok((tag, Some(u64::from_le_bytes(*variant)), rest))
+}
+
+/// Instruction data in the [`Compact`] format, widened to the default one the decoders read
+pub fn expand_compact(data: &[u8]) -> Result<Cow<'_, [u8]>, DecodeError> {
+    match Compact::decode(data) {
+        Ok((_, Cow::Borrowed(_))) => Ok(Cow::Borrowed(data)),
+        Ok((tag, Cow::Owned(rest))) => Ok(Cow::Owned([&[tag], &rest[..]].concat())),
+        Err(_) => Err(DecodeError::Truncated {
+            expected: 2,
+            actual: data.len(),
+        }),
+    }
+}
+
+/// Reads the next argument of type `T` off `data`, formatted with `Debug`
+pub fn take_arg<T: Pod + fmt::Debug>(data: &mut &[u8]) -> Result<String, DecodeError> {
+    let bytes = data.get(..size_of::<T>()).ok_or(DecodeError::Truncated {
+        expected: size_of::<T>(),
+        actual: data.len(),
+    })?;
+    *data = &data[size_of::<T>()..];
+    Ok(format!("{:?}", bytemuck::pod_read_unaligned::<T>(bytes)))
+}
+
+/// Reads the next argument off `data` as a pubkey, formatted in base58
+pub fn take_pubkey(data: &mut &[u8]) -> Result<String, DecodeError> {
+    let (bytes, rest) = data.split_first_chunk().ok_or(DecodeError::Truncated {
+        expected: 32,
+        actual: data.len(),
+    })?;
+    *data = rest;
+    Ok(Pubkey::new_from_array(*bytes).to_string())
+}
+
+/// Describes one of the instructions every program shares, or a program specific one as
+/// `custom`. Sync and async instructions need the program's client to decode.
+pub fn shared(data: &[u8], accounts: &[AccountMeta]) -> Result<DecodedInstruction, DecodeError> {
+    let (tag, variant, rest) = split(data)?;
+    let name = match DispatchTag::parse(tag) {
+        DispatchTag::Custom(_) => Some("custom"),
+        _ => SHARED
+            .iter()
+            .find(|(_, shared)| *shared == tag)
+            .map(|(name, _)| *name),
+    };
+    let name = name.ok_or(DecodeError::UnknownInstruction { tag, variant })?;
+    Ok(DecodedInstruction::new(
+        tag,
+        name,
+        None,
+        vec![],
+        rest,
+        accounts,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared() {
+        let state = AccountMeta::new(Pubkey::new_unique(), false);
+        let user = AccountMeta::new(Pubkey::new_unique(), true);
+        let accounts = [state, user.clone()];
+
+        let decoded = shared(&[tag::DRAIN, 3, 0, 0, 0], &accounts).unwrap();
+        assert_eq!(decoded.name, "drain");
+        assert_eq!(decoded.rest, [3, 0, 0, 0]);
+        assert_eq!(decoded.user, Some(user.pubkey));
+        assert_eq!(
+            decoded.to_string(),
+            format!("drain() + 4 bytes by {}", user.pubkey)
+        );
+        assert_eq!(shared(&[tag::CUSTOM], &[]).unwrap().name, "custom");
+
+        assert_eq!(
+            shared(&[tag::QUEUE, 1, 0, 0, 0, 0, 0, 0, 0], &accounts),
+            Err(DecodeError::UnknownInstruction {
+                tag: tag::QUEUE,
+                variant: Some(1)
+            })
+        );
+        assert_eq!(
+            shared(&[tag::QUEUE, 1], &accounts),
+            Err(DecodeError::Truncated {
+                expected: 9,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            shared(&[tag::LAST + 1], &accounts),
+            Err(DecodeError::UnknownInstruction {
+                tag: tag::LAST + 1,
+                variant: None
+            })
+        );
+
+        assert_eq!(
+            *expand_compact(&[tag::QUEUE, 1, 5]).unwrap(),
+            [tag::QUEUE, 1, 0, 0, 0, 0, 0, 0, 0, 5]
+        );
+        assert_eq!(*expand_compact(&[tag::DRAIN, 3]).unwrap(), [tag::DRAIN, 3]);
+        assert!(expand_compact(&[tag::SYNC]).is_err());
+
+        let mut data = &[5, 0, 0, 0, 0, 0, 0, 0, 1][..];
+        assert_eq!(take_arg::<u64>(&mut data), Ok("5".to_owned()));
+        assert_eq!(data, [1]);
+        assert!(take_arg::<u64>(&mut data).is_err());
+    }
+}
@@ -0,0 +1,485 @@
+//! Anchor-style IDL for programs using the default dispatch
+//!
+//! [`IdlBuilder`] writes an IDL in the Anchor 0.30 / Codama shape, so explorers and generic
+//! clients can decode a program's instructions and accounts. The instructions every program
+//! shares (draining, cancelling, viewing) are added from [`apq_core::dispatch::tag`], and the
+//! program adds its own sync and async variants. Discriminators are the dispatch tag followed
+//! by the variant as a little endian `u64`. [`codegen`](crate::codegen) generates typed clients
+//! from the IDL.
+//!
+//! Types are described with [`idl_struct!`](crate::idl_struct), which fails to compile when
+//! the description misses or misnames a field of the struct it describes. Everything is
+//! zero-copy, so types are marked with `bytemuck` serialization and a C layout.
+
+use apq_core::{dispatch::tag, fees::FeeLedger, instance::Instance, stats::QueueStats};
+use serde_json::{json, Value};
+use solana_pubkey::Pubkey;
+
+/// Anchor IDL spec version the output follows
+pub const SPEC: &str = "0.1.0";
+
+/// IDL type of a field, e.g. `u64`, `pubkey`, `[u8; 32]` or the name of a type described
+/// with [`idl_struct!`](crate::idl_struct)
+#[macro_export]
+macro_rules! idl_type {
+    (u8) => { $crate::__private::serde_json::json!("u8") };
+    (u16) => { $crate::__private::serde_json::json!("u16") };
+    (u32) => { $crate::__private::serde_json::json!("u32") };
+    (u64) => { $crate::__private::serde_json::json!("u64") };
+    (u128) => { $crate::__private::serde_json::json!("u128") };
+    (i8) => { $crate::__private::serde_json::json!("i8") };
+    (i16) => { $crate::__private::serde_json::json!("i16") };
+    (i32) => { $crate::__private::serde_json::json!("i32") };
+    (i64) => { $crate::__private::serde_json::json!("i64") };
+    (i128) => { $crate::__private::serde_json::json!("i128") };
+    (bool) => { $crate::__private::serde_json::json!("bool") };
+    (pubkey) => { $crate::__private::serde_json::json!("pubkey") };
+    ([$ty:tt; $len:expr]) => {
+        $crate::__private::serde_json::json!({ "array": [$crate::idl_type!($ty), ($len)] })
+    };
+    ($defined:ident) => {
+        $crate::__private::serde_json::json!({ "defined": { "name": stringify!($defined) } })
+    };
+}
+
+/// Describes a struct for the IDL, field by field, e.g.
+///
+/// ```ignore
+/// idl_struct!(CounterPayload { user: pubkey, amount: u64 })
+/// ```
+///
+/// Every field of the struct must be listed, by name and in declaration order. Types with
+/// private fields can't be described outside their crate, see [`state_header`] for the
+/// header every state starts with.
+#[macro_export]
+macro_rules! idl_struct {
+    ($name:ident { $($field:ident : $ty:tt),* $(,)? }) => {{
+        // Fails to compile if a field is missing or misnamed
+        let _ = |value: &$name| {
+            let $name { $($field: _),* } = value;
+        };
+        $crate::__private::serde_json::json!({
+            "name": stringify!($name),
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [ $({ "name": stringify!($field), "type": $crate::idl_type!($ty) }),* ],
+            },
+        })
+    }};
+}
+
+/// [`StateHeader`](apq_core::header::StateHeader) and the types it holds
+pub fn state_header() -> [Value; 7] {
+    [
+        json!({
+            "name": "StateHeader",
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [
+                    { "name": "version", "type": "u32" },
+                    { "name": "shard", "type": "u8" },
+                    { "name": "padding", "type": { "array": ["u8", 3] } },
+                    { "name": "layout_hash", "type": "u64" },
+                    { "name": "operators", "type": { "defined": { "name": "OperatorRegistry" } } },
+                    { "name": "admin", "type": { "defined": { "name": "Admin" } } },
+                    { "name": "stats", "type": { "defined": { "name": "QueueStats" } } },
+                    { "name": "cursor", "type": { "defined": { "name": "DrainCursor" } } },
+                    { "name": "fees", "type": { "defined": { "name": "FeeLedger" } } },
+                    { "name": "instance", "type": { "defined": { "name": "Instance" } } },
+                ],
+            },
+        }),
+        json!({
+            "name": "OperatorRegistry",
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [
+                    { "name": "authority", "type": "pubkey" },
+                    {
+                        "name": "operators",
+                        "type": { "array": ["pubkey", apq_core::operators::MAX_OPERATORS] },
+                    },
+                ],
+            },
+        }),
+        json!({
+            "name": "Admin",
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [
+                    { "name": "authority", "type": "pubkey" },
+                    { "name": "pending", "type": "pubkey" },
+                    { "name": "paused", "type": "u8" },
+                    { "name": "padding", "type": { "array": ["u8", 7] } },
+                ],
+            },
+        }),
+        crate::idl_struct!(QueueStats {
+            total_enqueued: u64,
+            total_processed: u64,
+            total_cancelled: u64,
+            max_depth: u64,
+            last_processed_slot: u64,
+        }),
+        json!({
+            "name": "DrainCursor",
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [
+                    { "name": "nonce", "type": "u64" },
+                    { "name": "slot", "type": "u64" },
+                    {
+                        "name": "next_key",
+                        "type": { "array": ["u8", apq_core::cursor::MAX_CURSOR_KEY_LEN] },
+                    },
+                    { "name": "key_len", "type": "u8" },
+                    { "name": "partial", "type": "u8" },
+                    { "name": "padding", "type": { "array": ["u8", 6] } },
+                ],
+            },
+        }),
+        crate::idl_struct!(FeeLedger {
+            accrued: u64,
+            withdrawn: u64,
+            destination: pubkey,
+        }),
+        crate::idl_struct!(Instance {
+            registry: pubkey,
+            id: u64,
+        }),
+    ]
+}
+
+/// Size of IDL type `ty` laid out as C, looking types it names up in `types`. `None` if one
+/// of them is missing.
+pub fn size_of_type(types: &[Value], ty: &Value) -> Option<usize> {
+    layout_of(types, ty).map(|(size, _)| size)
+}
+
+/// Size and alignment of `ty`, see [`size_of_type`]
+fn layout_of(types: &[Value], ty: &Value) -> Option<(usize, usize)> {
+    if let Some(primitive) = ty.as_str() {
+        let size = match primitive {
+            "u8" | "i8" | "bool" => 1,
+            "u16" | "i16" => 2,
+            "u32" | "i32" => 4,
+            "u64" | "i64" => 8,
+            "u128" | "i128" => return Some((16, align_of::<u128>())),
+            "pubkey" => return Some((32, 1)),
+            _ => return None,
+        };
+        return Some((size, size));
+    }
+    if let Some([element, len]) = ty["array"].as_array().map(Vec::as_slice) {
+        let (size, align) = layout_of(types, element)?;
+        return Some((size * len.as_u64()? as usize, align));
+    }
+    let name = ty["defined"]["name"].as_str()?;
+    let defined = types.iter().find(|ty| ty["name"] == name)?;
+    let (mut size, mut align) = (0_usize, 1);
+    for field in defined["type"]["fields"].as_array()? {
+        let (field_size, field_align) = layout_of(types, &field["type"])?;
+        size = size.next_multiple_of(field_align) + field_size;
+        align = align.max(field_align);
+    }
+    Some((size.next_multiple_of(align), align))
+}
+
+/// Name of a type described with [`idl_struct!`](crate::idl_struct)
+fn type_name(ty: &Value) -> &str {
+    ty["name"]
+        .as_str()
+        .expect("types are described with idl_struct!")
+}
+
+fn system_program() -> Value {
+    json!({ "name": "system_program", "address": "11111111111111111111111111111111" })
+}
+
+/// Builds the IDL of one program, see the [module docs](self)
+#[derive(Clone, Debug)]
+pub struct IdlBuilder {
+    address: Pubkey,
+    name: String,
+    version: String,
+    instructions: Vec<Value>,
+    accounts: Vec<Value>,
+    types: Vec<Value>,
+}
+
+impl IdlBuilder {
+    pub fn new(address: Pubkey, name: &str, version: &str) -> Self {
+        let mut builder = IdlBuilder {
+            address,
+            name: name.to_owned(),
+            version: version.to_owned(),
+            instructions: Vec::new(),
+            accounts: Vec::new(),
+            types: state_header().into(),
+        };
+        builder.instruction(
+            "drain",
+            "Processes every due queued instruction, paying the crank bounties to user",
+            vec![tag::DRAIN],
+            Self::account("user", true, true),
+            vec![],
+        );
+        builder.instruction(
+            "view",
+            "Returns up to count queued entries from start, for simulation",
+            vec![tag::VIEW],
+            Self::account("user", false, false),
+            vec![
+                json!({ "name": "start", "type": "u32" }),
+                json!({ "name": "count", "type": "u32" }),
+            ],
+        );
+        builder
+    }
+
+    /// An account an instruction takes, see [`IdlBuilder::sync_ix_with_accounts`]
+    pub fn account(name: &str, writable: bool, signer: bool) -> Value {
+        json!({ "name": name, "writable": writable, "signer": signer })
+    }
+
+    fn instruction(
+        &mut self,
+        name: &str,
+        docs: &str,
+        discriminator: Vec<u8>,
+        user: Value,
+        args: Vec<Value>,
+    ) -> &mut Value {
+        self.instructions.push(json!({
+            "name": name,
+            "docs": [docs],
+            "discriminator": discriminator,
+            "accounts": [Self::account("state", true, false), user],
+            "args": args,
+        }));
+        self.instructions.last_mut().unwrap()
+    }
+
+    fn variant_discriminator(tag: u8, variant: u64) -> Vec<u8> {
+        [&[tag][..], &variant.to_le_bytes()].concat()
+    }
+
+    /// A sync instruction variant with its `(name, type)` args, see [`idl_type!`](crate::idl_type)
+    pub fn sync_ix(&mut self, name: &str, variant: u64, args: &[(&str, Value)]) -> &mut Self {
+        self.sync_ix_with_accounts(name, variant, &[], args)
+    }
+
+    /// [`IdlBuilder::sync_ix`] taking `accounts` after the user, built with
+    /// [`IdlBuilder::account`]
+    pub fn sync_ix_with_accounts(
+        &mut self,
+        name: &str,
+        variant: u64,
+        accounts: &[Value],
+        args: &[(&str, Value)],
+    ) -> &mut Self {
+        let args = args
+            .iter()
+            .map(|(name, ty)| json!({ "name": name, "type": ty }))
+            .collect();
+        let ix = self.instruction(
+            name,
+            "Executed immediately",
+            Self::variant_discriminator(tag::SYNC, variant),
+            Self::account("user", false, true),
+            args,
+        );
+        ix["accounts"]
+            .as_array_mut()
+            .unwrap()
+            .extend_from_slice(accounts);
+        self
+    }
+
+    /// An async instruction variant with its `(name, type)` queue args, see
+    /// [`idl_type!`](crate::idl_type)
+    pub fn async_ix(&mut self, name: &str, variant: u64, args: &[(&str, Value)]) -> &mut Self {
+        let args = args
+            .iter()
+            .map(|(name, ty)| json!({ "name": name, "type": ty }))
+            .collect();
+        let ix = self.instruction(
+            name,
+            "Queued, escrowing the crank bounty and priority bid from user",
+            Self::variant_discriminator(tag::QUEUE, variant),
+            Self::account("user", true, true),
+            args,
+        );
+        ix["accounts"]
+            .as_array_mut()
+            .unwrap()
+            .push(system_program());
+        self
+    }
+
+    /// The state account, described with [`idl_struct!`](crate::idl_struct)
+    pub fn state(&mut self, state: Value) -> &mut Self {
+        // Accounts are told apart by owner and size, not a discriminator
+        self.accounts
+            .push(json!({ "name": type_name(&state), "discriminator": [] }));
+        self.types.push(state);
+        self
+    }
+
+    /// Any other type referenced by name, described with [`idl_struct!`](crate::idl_struct)
+    pub fn ty(&mut self, ty: Value) -> &mut Self {
+        self.types.push(ty);
+        self
+    }
+
+    /// The queue's key and payload, described with [`idl_struct!`](crate::idl_struct)
+    ///
+    /// Adds the `QueueEntry` the queue stores them as, which is also the entry format of the
+    /// view return data, and the cancel instruction that takes a key.
+    pub fn queue(&mut self, key: Value, payload: Value) -> &mut Self {
+        let entry = json!({
+            "name": "QueueEntry",
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [
+                    { "name": "key", "type": { "defined": { "name": type_name(&key) } } },
+                    { "name": "value", "type": { "defined": { "name": type_name(&payload) } } },
+                ],
+            },
+        });
+        let cancel_args = vec![json!({
+            "name": "key",
+            "type": { "defined": { "name": type_name(&key) } },
+        })];
+        self.instruction(
+            "cancel",
+            "Removes user's instruction queued under key, refunding its escrow",
+            vec![tag::CANCEL],
+            Self::account("user", true, true),
+            cancel_args,
+        );
+        self.types.extend([key, payload, entry]);
+        self
+    }
+
+    pub fn build(&self) -> Value {
+        json!({
+            "address": self.address.to_string(),
+            "metadata": {
+                "name": self.name,
+                "version": self.version,
+                "spec": SPEC,
+            },
+            "instructions": self.instructions,
+            "accounts": self.accounts,
+            "types": self.types,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_core::{
+        admin::Admin, cursor::DrainCursor, header::StateHeader, operators::OperatorRegistry,
+    };
+
+    use super::*;
+
+    #[derive(Copy, Clone)]
+    #[allow(dead_code)]
+    struct Key {
+        slot: u64,
+        seq: u64,
+    }
+
+    #[derive(Copy, Clone)]
+    #[allow(dead_code)]
+    struct Payload {
+        user: [u8; 32],
+        amounts: [u64; 2],
+    }
+
+    #[test]
+    fn test_idl() {
+        let program = Pubkey::new_unique();
+        let idl = IdlBuilder::new(program, "test", "0.1.0")
+            .async_ix("increment", 1, &[("amount", idl_type!(u64))])
+            .queue(
+                idl_struct!(Key {
+                    slot: u64,
+                    seq: u64
+                }),
+                idl_struct!(Payload {
+                    user: pubkey,
+                    amounts: [u64; 2]
+                }),
+            )
+            .build();
+
+        assert_eq!(idl["address"], program.to_string());
+        let names: Vec<&str> = idl["instructions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|ix| ix["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["drain", "view", "increment", "cancel"]);
+
+        let increment = &idl["instructions"][2];
+        assert_eq!(
+            increment["discriminator"],
+            json!([tag::QUEUE, 1, 0, 0, 0, 0, 0, 0, 0])
+        );
+        assert_eq!(increment["accounts"][1]["signer"], true);
+        assert_eq!(
+            increment["args"][0],
+            json!({ "name": "amount", "type": "u64" })
+        );
+
+        let cancel = &idl["instructions"][3];
+        assert_eq!(
+            cancel["args"][0]["type"],
+            json!({ "defined": { "name": "Key" } })
+        );
+
+        let payload = idl["types"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|ty| ty["name"] == "Payload")
+            .unwrap();
+        assert_eq!(
+            payload["type"]["fields"][1]["type"],
+            json!({ "array": ["u64", 2] })
+        );
+    }
+
+    #[test]
+    fn test_state_header_sizes() {
+        let types = state_header();
+        let size = |name: &str| size_of_type(&types, &json!({ "defined": { "name": name } }));
+        assert_eq!(size("StateHeader"), Some(size_of::<StateHeader>()));
+        assert_eq!(
+            size("OperatorRegistry"),
+            Some(size_of::<OperatorRegistry>())
+        );
+        assert_eq!(size("Admin"), Some(size_of::<Admin>()));
+        assert_eq!(size("QueueStats"), Some(size_of::<QueueStats>()));
+        assert_eq!(size("DrainCursor"), Some(size_of::<DrainCursor>()));
+        assert_eq!(size("FeeLedger"), Some(size_of::<FeeLedger>()));
+        assert_eq!(size("Instance"), Some(size_of::<Instance>()));
+        assert_eq!(size("Missing"), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Finding the instances of a registry among a program's accounts
+//!
+//! Every instance records its registry in its [`StateHeader`], so fetching the program's
+//! accounts, e.g. with `getProgramAccounts` and a memcmp of the registry at
+//! [`REGISTRY_OFFSET`], and passing them to [`enumerate`] lists a registry's instances. See
+//! [`apq_core::instance`].
+
+use std::mem::offset_of;
+
+use apq_core::header::StateHeader;
+pub use apq_core::instance::{Instance, Registry};
+use solana_pubkey::Pubkey;
+
+use crate::DecodeError;
+
+/// Where state account data holds the registry of the instance
+pub const REGISTRY_OFFSET: usize =
+    offset_of!(StateHeader, instance) + offset_of!(Instance, registry);
+
+/// The instance a state account is, `None` if it isn't one
+pub fn read(data: &[u8]) -> Result<Option<Instance>, DecodeError> {
+    let header = StateHeader::read(data).map_err(|_| DecodeError::Truncated {
+        expected: StateHeader::LEN,
+        actual: data.len(),
+    })?;
+    if header.version == 0 {
+        return Err(DecodeError::Uninitialized);
+    }
+    Ok((header.instance.id != 0).then_some(header.instance))
+}
+
+/// `registry`'s instances among the program's `accounts`, as `(id, state)` in order of id.
+/// Accounts that aren't initialized states are skipped.
+pub fn enumerate<'a>(
+    registry: &Pubkey,
+    accounts: impl IntoIterator<Item = (Pubkey, &'a [u8])>,
+) -> Vec<(u64, Pubkey)> {
+    let mut instances: Vec<(u64, Pubkey)> = accounts
+        .into_iter()
+        .filter_map(|(state, data)| Some((read(data).ok()??, state)))
+        .filter(|(instance, _)| instance.registry == registry.to_bytes())
+        .map(|(instance, state)| (instance.id, state))
+        .collect();
+    instances.sort_unstable();
+    instances
+}
+
+/// The id the next instance created by the registry account gets
+pub fn next_id(registry: &[u8]) -> Result<u64, DecodeError> {
+    let registry: Registry = registry
+        .get(..Registry::LEN)
+        .and_then(|bytes| bytemuck::try_pod_read_unaligned(bytes).ok())
+        .ok_or(DecodeError::Truncated {
+            expected: Registry::LEN,
+            actual: registry.len(),
+        })?;
+    Ok(registry.instances + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(registry: &Pubkey, id: u64) -> Vec<u8> {
+        let mut header = StateHeader::new(1);
+        header.instance = Instance {
+            registry: registry.to_bytes(),
+            id,
+        };
+        let mut data = vec![0; StateHeader::LEN + 8];
+        header.write(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let registry = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let states = [
+            (Pubkey::new_unique(), state(&registry, 2)),
+            (Pubkey::new_unique(), state(&other, 1)),
+            (Pubkey::new_unique(), state(&registry, 1)),
+            (Pubkey::new_unique(), vec![0; StateHeader::LEN]),
+            // Not an instance
+            (Pubkey::new_unique(), state(&Pubkey::default(), 0)),
+        ];
+        let instances = enumerate(
+            &registry,
+            states.iter().map(|(key, data)| (*key, &data[..])),
+        );
+        assert_eq!(instances, [(1, states[2].0), (2, states[0].0)]);
+
+        let data = &states[0].1;
+        assert_eq!(
+            data[REGISTRY_OFFSET..REGISTRY_OFFSET + 32],
+            registry.to_bytes()
+        );
+        assert_eq!(read(&states[4].1), Ok(None));
+        assert_eq!(next_id(&[0; Registry::LEN]), Ok(1));
+    }
+}
@@ -0,0 +1,587 @@
+//! Instruction builders for the default dispatch
+//!
+//! Tags come from [`apq_core::dispatch::tag`] and the account order follows the dispatch's
+//! `[state, user, remaining..]`, so these stay in step with the program.
+
+use std::borrow::Cow;
+
+use apq_core::{
+    config::ConfigParams,
+    cursor::DrainArgs,
+    dispatch::{tag, takes_config},
+    queue::QueueKey,
+    session::SessionParams,
+};
+use solana_instruction::{AccountMeta, Instruction};
+use solana_pubkey::Pubkey;
+
+const SYSTEM_PROGRAM_ID: Pubkey = solana_pubkey::pubkey!("11111111111111111111111111111111");
+const INSTRUCTIONS_SYSVAR_ID: Pubkey =
+    solana_pubkey::pubkey!("Sysvar1nstructions1111111111111111111111111");
+
+/// Builds instructions for one state account of a program using the default dispatch
+#[derive(Copy, Clone, Debug)]
+pub struct InstructionBuilder {
+    pub program_id: Pubkey,
+    pub state: Pubkey,
+    /// Config account of states with one, see [`apq_core::config`]
+    pub config: Option<Pubkey>,
+    /// Whether sync and async variants are sent in one byte, see
+    /// [`InstructionBuilder::with_compact_variants`]
+    pub compact: bool,
+}
+
+impl InstructionBuilder {
+    pub fn new(program_id: Pubkey, state: Pubkey) -> Self {
+        InstructionBuilder {
+            program_id,
+            state,
+            config: None,
+            compact: false,
+        }
+    }
+
+    /// Passes `config` to the instructions that read it, for states with a config account
+    pub fn with_config(mut self, config: Pubkey) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Sends sync and async variants in one byte, for programs dispatching with
+    /// [`Compact`](apq_core::discriminator::Compact). Instructions are built from the same
+    /// `u64` variants, which must fit in a byte.
+    pub fn with_compact_variants(mut self) -> Self {
+        self.compact = true;
+        self
+    }
+
+    /// `data` starting with a `u64` variant, narrowed to one byte if compact
+    fn variant_data<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.compact {
+            return Cow::Borrowed(data);
+        }
+        let (variant, rest) = data
+            .split_first_chunk::<8>()
+            .expect("instruction data starts with its variant");
+        let variant =
+            u8::try_from(u64::from_le_bytes(*variant)).expect("compact variants fit in a byte");
+        Cow::Owned([&[variant], rest].concat())
+    }
+
+    fn instruction(&self, tag: u8, data: &[u8], user: AccountMeta) -> Instruction {
+        let mut accounts = vec![AccountMeta::new(self.state, false), user];
+        if let Some(config) = self.config.filter(|_| takes_config(tag)) {
+            accounts.push(AccountMeta::new(config, false));
+        }
+        Instruction {
+            program_id: self.program_id,
+            accounts,
+            data: [&[tag], data].concat(),
+        }
+    }
+
+    /// `sync_ix` is the encoded sync instruction and `accounts` whatever else it needs. The
+    /// user signs, so the program can act on their behalf.
+    pub fn sync(&self, user: &Pubkey, sync_ix: &[u8], accounts: &[AccountMeta]) -> Instruction {
+        let sync_ix = self.variant_data(sync_ix);
+        let mut ix = self.instruction(tag::SYNC, &sync_ix, AccountMeta::new_readonly(*user, true));
+        ix.accounts.extend_from_slice(accounts);
+        ix
+    }
+
+    /// `async_ix` is the encoded async instruction followed by its queue args. The user
+    /// escrows the crank bounty, priority bid and deposit.
+    pub fn queue_async(&self, user: &Pubkey, async_ix: &[u8]) -> Instruction {
+        let async_ix = self.variant_data(async_ix);
+        let mut ix = self.instruction(tag::QUEUE, &async_ix, AccountMeta::new(*user, true));
+        ix.accounts
+            .push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
+        ix
+    }
+
+    /// [`InstructionBuilder::queue_async`] on behalf of `owner`, who authorized it until
+    /// `expiry` with a signature verified by the ed25519 instruction before it. The relayer
+    /// signs and escrows. See [`crate::relay`].
+    pub fn queue_relayed(
+        &self,
+        relayer: &Pubkey,
+        owner: &Pubkey,
+        expiry: u64,
+        async_ix: &[u8],
+    ) -> Instruction {
+        let data = [&expiry.to_le_bytes()[..], async_ix].concat();
+        let mut ix = self.instruction(tag::QUEUE_RELAYED, &data, AccountMeta::new(*relayer, true));
+        ix.accounts.extend([
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new_readonly(INSTRUCTIONS_SYSVAR_ID, false),
+            AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+        ]);
+        ix
+    }
+
+    /// Delegates queueing to `params.key` in the zeroed `session` account, see
+    /// [`apq_core::session`]
+    pub fn create_session(
+        &self,
+        user: &Pubkey,
+        session: &Pubkey,
+        params: &SessionParams,
+    ) -> Instruction {
+        let mut ix = self.instruction(
+            tag::CREATE_SESSION,
+            bytemuck::bytes_of(params),
+            AccountMeta::new_readonly(*user, true),
+        );
+        ix.accounts.push(AccountMeta::new(*session, false));
+        ix
+    }
+
+    /// Revokes `session`, signed by its user or its key
+    pub fn revoke_session(&self, signer: &Pubkey, session: &Pubkey) -> Instruction {
+        let mut ix = self.instruction(
+            tag::REVOKE_SESSION,
+            &[],
+            AccountMeta::new_readonly(*signer, true),
+        );
+        ix.accounts.push(AccountMeta::new(*session, false));
+        ix
+    }
+
+    /// [`InstructionBuilder::queue_async`] as `owner`, signed by the key of their `session`,
+    /// which escrows
+    pub fn queue_delegated(
+        &self,
+        key: &Pubkey,
+        owner: &Pubkey,
+        session: &Pubkey,
+        async_ix: &[u8],
+    ) -> Instruction {
+        let mut ix = self.instruction(tag::QUEUE_DELEGATED, async_ix, AccountMeta::new(*key, true));
+        ix.accounts.extend([
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new_readonly(*session, false),
+            AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+        ]);
+        ix
+    }
+
+    /// [`InstructionBuilder::queue_async`] into this state and each of the other `instances`
+    /// of its registry, all or none of them. See [`apq_core::instance`].
+    pub fn queue_many(&self, user: &Pubkey, instances: &[Pubkey], async_ix: &[u8]) -> Instruction {
+        assert!(
+            instances.len() <= u8::MAX as usize,
+            "at most 255 other instances"
+        );
+        let data = [&[instances.len() as u8][..], async_ix].concat();
+        let mut ix = self.instruction(tag::QUEUE_MANY, &data, AccountMeta::new(*user, true));
+        ix.accounts.extend(
+            instances
+                .iter()
+                .map(|instance| AccountMeta::new(*instance, false)),
+        );
+        ix.accounts
+            .push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
+        ix
+    }
+
+    /// Replaces the user's pending instruction with sequence number `old_seq` by `async_ix`,
+    /// encoded as for [`InstructionBuilder::queue_async`]
+    pub fn replace(&self, user: &Pubkey, old_seq: u64, async_ix: &[u8]) -> Instruction {
+        let data = [&old_seq.to_le_bytes()[..], async_ix].concat();
+        let mut ix = self.instruction(tag::REPLACE, &data, AccountMeta::new(*user, true));
+        ix.accounts
+            .push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
+        ix
+    }
+
+    /// Cancels the user's instruction queued under `key`, refunding its escrow
+    pub fn cancel<K: QueueKey>(&self, user: &Pubkey, key: &K) -> Instruction {
+        self.instruction(
+            tag::CANCEL,
+            bytemuck::bytes_of(key),
+            AccountMeta::new(*user, true),
+        )
+    }
+
+    /// Cancels up to `max` of the user's queued instructions in drain order, refunding their
+    /// escrow. The return data says whether any are left.
+    pub fn cancel_all(&self, user: &Pubkey, max: u32) -> Instruction {
+        self.instruction(
+            tag::CANCEL_ALL,
+            &max.to_le_bytes(),
+            AccountMeta::new(*user, true),
+        )
+    }
+
+    /// [`InstructionBuilder::cancel_all`] of `owner`'s instructions, signed by the admin, with
+    /// the refund going to `owner`
+    pub fn cancel_all_for(&self, admin: &Pubkey, owner: &Pubkey, max: u32) -> Instruction {
+        let mut ix = self.cancel_all(admin, max);
+        ix.accounts[1].is_writable = false;
+        ix.accounts.push(AccountMeta::new(*owner, false));
+        ix
+    }
+
+    /// The program's own instruction tagged `tag`, from [`tag::CUSTOM`] on, with `accounts`
+    /// after the user. See [`apq_core::dispatch::DispatchTag`].
+    pub fn custom(
+        &self,
+        tag: u8,
+        data: &[u8],
+        user: AccountMeta,
+        accounts: &[AccountMeta],
+    ) -> Instruction {
+        let mut ix = self.instruction(tag, data, user);
+        ix.accounts.extend_from_slice(accounts);
+        ix
+    }
+
+    /// Drains the due part of the queue, paying the crank bounties to `cranker`
+    pub fn drain(&self, cranker: &Pubkey) -> Instruction {
+        self.instruction(tag::DRAIN, &[], AccountMeta::new(*cranker, true))
+    }
+
+    /// [`InstructionBuilder::drain`] up to `args.max_items`, failing if another drain landed
+    /// since the cursor `args.nonce` was read. See [`apq_core::cursor`].
+    pub fn drain_with(&self, cranker: &Pubkey, args: DrainArgs) -> Instruction {
+        self.instruction(tag::DRAIN, &args.encode(), AccountMeta::new(*cranker, true))
+    }
+
+    /// [`InstructionBuilder::drain_with`] only processing entries queued up to `slot`, e.g. to
+    /// settle what was queued by a past slot. See [`apq_core::cursor::replay_due`].
+    pub fn drain_upto(&self, cranker: &Pubkey, slot: u64, args: DrainArgs) -> Instruction {
+        let data = [&slot.to_le_bytes()[..], &args.encode()].concat();
+        self.instruction(tag::DRAIN_UPTO, &data, AccountMeta::new(*cranker, true))
+    }
+
+    /// Initializes the zeroed state account as instance `id` of `registry`, signed by the
+    /// registry authority, who becomes the admin. See [`apq_core::instance`] and
+    /// [`crate::instances::next_id`].
+    pub fn create_instance(&self, authority: &Pubkey, registry: &Pubkey, id: u64) -> Instruction {
+        let mut ix = self.instruction(
+            tag::CREATE_INSTANCE,
+            &id.to_le_bytes(),
+            AccountMeta::new_readonly(*authority, true),
+        );
+        ix.accounts.push(AccountMeta::new(*registry, false));
+        ix
+    }
+
+    /// Removes up to `max` expired entries, paying their crank bounties to `compactor`, and
+    /// rebuilds an empty queue's free list if `rebuild`. The return data says whether expired
+    /// entries are left. See [`apq_core::compact`].
+    pub fn compact(&self, compactor: &Pubkey, max: u32, rebuild: bool) -> Instruction {
+        let data = [&max.to_le_bytes()[..], &[rebuild as u8]].concat();
+        self.instruction(tag::COMPACT, &data, AccountMeta::new(*compactor, true))
+    }
+
+    /// Takes up to `max` entries off the head of the queue without refunding them, signed by
+    /// the admin. The return data says whether entries are left. See [`apq_core::flush`].
+    pub fn flush(&self, admin: &Pubkey, max: u32) -> Instruction {
+        let data = [&max.to_le_bytes()[..], &[0]].concat();
+        self.instruction(tag::FLUSH, &data, AccountMeta::new_readonly(*admin, true))
+    }
+
+    /// [`InstructionBuilder::flush`] cancelling up to `max` entries of the `owners` instead,
+    /// refunding their escrow to them
+    pub fn flush_refunding(&self, admin: &Pubkey, owners: &[Pubkey], max: u32) -> Instruction {
+        let data = [&max.to_le_bytes()[..], &[1]].concat();
+        let mut ix = self.instruction(tag::FLUSH, &data, AccountMeta::new_readonly(*admin, true));
+        ix.accounts
+            .extend(owners.iter().map(|owner| AccountMeta::new(*owner, false)));
+        ix
+    }
+
+    /// Grows the state account by up to 10KB towards `target_len`, the full state length if
+    /// `None`, with `payer` topping up rent. See [`apq_core::grow`].
+    pub fn grow(&self, payer: &Pubkey, target_len: Option<u64>) -> Instruction {
+        let data = target_len.map(u64::to_le_bytes);
+        let mut ix = self.instruction(
+            tag::GROW,
+            data.as_ref().map_or(&[], |data| &data[..]),
+            AccountMeta::new(*payer, true),
+        );
+        ix.accounts
+            .push(AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false));
+        ix
+    }
+
+    /// Closes the settled state, paying its lamports to `destination`, signed by the admin. See
+    /// [`apq_core::close`].
+    pub fn close_state(&self, admin: &Pubkey, destination: &Pubkey) -> Instruction {
+        let mut ix = self.instruction(
+            tag::CLOSE_STATE,
+            &[],
+            AccountMeta::new_readonly(*admin, true),
+        );
+        ix.accounts.push(AccountMeta::new(*destination, false));
+        ix
+    }
+
+    /// Binds the zeroed config account to this state with `params`, signed by the admin. See
+    /// [`apq_core::config`].
+    pub fn init_config(&self, admin: &Pubkey, params: &ConfigParams) -> Instruction {
+        self.instruction(
+            tag::INIT_CONFIG,
+            bytemuck::bytes_of(params),
+            AccountMeta::new_readonly(*admin, true),
+        )
+    }
+
+    /// Replaces the config's params, signed by the admin
+    pub fn update_config(&self, admin: &Pubkey, params: &ConfigParams) -> Instruction {
+        self.instruction(
+            tag::UPDATE_CONFIG,
+            bytemuck::bytes_of(params),
+            AccountMeta::new_readonly(*admin, true),
+        )
+    }
+
+    /// Sets where protocol fees are withdrawn to, signed by the admin. All zeroes is the
+    /// admin. See [`apq_core::fees`].
+    pub fn set_fee_destination(&self, admin: &Pubkey, destination: &Pubkey) -> Instruction {
+        self.instruction(
+            tag::SET_FEE_DESTINATION,
+            destination.as_ref(),
+            AccountMeta::new_readonly(*admin, true),
+        )
+    }
+
+    /// Withdraws `amount` of the accrued protocol fees, all of them if `None`, to the
+    /// destination set, signed by the admin
+    pub fn withdraw_fees(
+        &self,
+        admin: &Pubkey,
+        destination: &Pubkey,
+        amount: Option<u64>,
+    ) -> Instruction {
+        let data = amount.map(u64::to_le_bytes);
+        let mut ix = self.instruction(
+            tag::WITHDRAW_FEES,
+            data.as_ref().map_or(&[][..], |data| &data[..]),
+            AccountMeta::new_readonly(*admin, true),
+        );
+        ix.accounts.push(AccountMeta::new(*destination, false));
+        ix
+    }
+
+    /// Pauses what the [`pause`](apq_core::admin::pause) `flags` name, signed by the admin
+    pub fn pause(&self, admin: &Pubkey, flags: u8) -> Instruction {
+        self.instruction(
+            tag::PAUSE,
+            &[flags],
+            AccountMeta::new_readonly(*admin, true),
+        )
+    }
+
+    /// Empties the dead-letter store, signed by the operators' authority, see
+    /// [`apq_core::dead_letter`]
+    pub fn purge_dead_letters(&self, authority: &Pubkey) -> Instruction {
+        self.instruction(
+            tag::PURGE_DEAD_LETTERS,
+            &[],
+            AccountMeta::new_readonly(*authority, true),
+        )
+    }
+
+    /// Lifts any pause, signed by the admin
+    pub fn unpause(&self, admin: &Pubkey) -> Instruction {
+        self.instruction(tag::UNPAUSE, &[], AccountMeta::new_readonly(*admin, true))
+    }
+
+    /// Proposes `authority` as the new admin, signed by the current one. It takes over once
+    /// it accepts with [`InstructionBuilder::accept_authority`].
+    pub fn set_authority(&self, admin: &Pubkey, authority: &Pubkey) -> Instruction {
+        self.instruction(
+            tag::SET_AUTHORITY,
+            authority.as_ref(),
+            AccountMeta::new_readonly(*admin, true),
+        )
+    }
+
+    /// Accepts the admin role, signed by the proposed authority
+    pub fn accept_authority(&self, authority: &Pubkey) -> Instruction {
+        self.instruction(
+            tag::ACCEPT_AUTHORITY,
+            &[],
+            AccountMeta::new_readonly(*authority, true),
+        )
+    }
+
+    /// Makes this state account shard `index` of a sharded state, signed by the admin. See
+    /// [`crate::shard`].
+    pub fn set_shard(&self, admin: &Pubkey, index: u8) -> Instruction {
+        self.instruction(
+            tag::SET_SHARD,
+            &[index],
+            AccountMeta::new_readonly(*admin, true),
+        )
+    }
+
+    /// Views up to `count` queued entries from `start`, for simulation.
+    /// See [`apq_core::view::decode`] for reading the return data.
+    pub fn view(&self, user: &Pubkey, start: u32, count: u32) -> Instruction {
+        let data = [start.to_le_bytes(), count.to_le_bytes()].concat();
+        self.instruction(tag::VIEW, &data, AccountMeta::new_readonly(*user, false))
+    }
+
+    /// Checks the state's invariants, failing if they don't hold, see
+    /// [`apq_core::paranoid`]
+    pub fn verify_state(&self, user: &Pubkey) -> Instruction {
+        self.instruction(
+            tag::VERIFY_STATE,
+            &[],
+            AccountMeta::new_readonly(*user, false),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_order() {
+        let builder = InstructionBuilder::new(Pubkey::new_unique(), Pubkey::new_unique());
+        let user = Pubkey::new_unique();
+
+        let ix = builder.queue_async(&user, &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.data[0], tag::QUEUE);
+        assert_eq!(ix.data[1..], [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            ix.accounts,
+            [
+                AccountMeta::new(builder.state, false),
+                AccountMeta::new(user, true),
+                AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+            ]
+        );
+
+        let ix = builder.cancel(&user, &7_u64);
+        assert_eq!(ix.data, [tag::CANCEL, 7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts[1], AccountMeta::new(user, true));
+
+        let owner = Pubkey::new_unique();
+        let ix = builder.cancel_all_for(&user, &owner, 3);
+        assert_eq!(ix.data, [tag::CANCEL_ALL, 3, 0, 0, 0]);
+        assert_eq!(ix.accounts[1], AccountMeta::new_readonly(user, true));
+        assert_eq!(ix.accounts[2], AccountMeta::new(owner, false));
+
+        let config = Pubkey::new_unique();
+        let configured = builder.with_config(config);
+        let ix = configured.queue_async(&user, &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts[2], AccountMeta::new(config, false));
+        assert_eq!(
+            ix.accounts[3],
+            AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false)
+        );
+        assert_eq!(configured.cancel(&user, &7u64).accounts.len(), 2);
+        let ix = configured.update_config(&user, &ConfigParams::default());
+        assert_eq!(ix.data.len(), 1 + size_of::<ConfigParams>());
+        assert_eq!(ix.accounts[2], AccountMeta::new(config, false));
+
+        let ix = configured.withdraw_fees(&user, &owner, Some(5));
+        assert_eq!(ix.data, [tag::WITHDRAW_FEES, 5, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts[2], AccountMeta::new(owner, false));
+        assert_eq!(
+            builder.withdraw_fees(&user, &owner, None).data,
+            [tag::WITHDRAW_FEES]
+        );
+
+        let ix = builder.drain_upto(&user, 9, DrainArgs::default());
+        assert_eq!(
+            ix.data,
+            [tag::DRAIN_UPTO, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(ix.accounts[1], AccountMeta::new(user, true));
+
+        let ix = configured.custom(
+            tag::CUSTOM + 1,
+            &[5],
+            AccountMeta::new(user, true),
+            &[AccountMeta::new(owner, false)],
+        );
+        assert_eq!(ix.data, [tag::CUSTOM + 1, 5]);
+        assert_eq!(ix.accounts[2], AccountMeta::new(owner, false));
+
+        let ix = configured.create_instance(&user, &owner, 2);
+        assert_eq!(ix.data, [tag::CREATE_INSTANCE, 2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts[1], AccountMeta::new_readonly(user, true));
+        assert_eq!(ix.accounts[2], AccountMeta::new(owner, false));
+
+        let compact = builder.with_compact_variants();
+        let ix = compact.queue_async(&user, &[1, 0, 0, 0, 0, 0, 0, 0, 5]);
+        assert_eq!(ix.data, [tag::QUEUE, 1, 5]);
+        let ix = compact.sync(&user, &[2, 0, 0, 0, 0, 0, 0, 0], &[]);
+        assert_eq!(ix.data, [tag::SYNC, 2]);
+        let ix = compact.drain(&user);
+        assert_eq!(ix.data, builder.drain(&user).data);
+
+        let ix = builder.queue_relayed(&user, &owner, 9, &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.data[0], tag::QUEUE_RELAYED);
+        assert_eq!(ix.data[1..9], 9u64.to_le_bytes());
+        assert_eq!(ix.accounts[1], AccountMeta::new(user, true));
+        assert_eq!(ix.accounts[2], AccountMeta::new_readonly(owner, false));
+        assert_eq!(ix.accounts.len(), 5);
+
+        let session = Pubkey::new_unique();
+        let params = SessionParams {
+            key: [3; 32],
+            expiry: 10,
+            ixn_mask: 1,
+        };
+        let ix = builder.create_session(&user, &session, &params);
+        assert_eq!(ix.data[1..], *bytemuck::bytes_of(&params));
+        assert_eq!(ix.accounts[2], AccountMeta::new(session, false));
+        let ix = builder.queue_delegated(&user, &owner, &session, &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.data[0], tag::QUEUE_DELEGATED);
+        assert_eq!(ix.accounts[1], AccountMeta::new(user, true));
+        assert_eq!(ix.accounts[3], AccountMeta::new_readonly(session, false));
+
+        let ix = builder.verify_state(&user);
+        assert_eq!(ix.data, [tag::VERIFY_STATE]);
+        assert_eq!(ix.accounts.len(), 2);
+
+        let ix = builder.queue_many(&user, &[owner], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.data, [tag::QUEUE_MANY, 1, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts[2], AccountMeta::new(owner, false));
+        assert_eq!(
+            ix.accounts[3],
+            AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false)
+        );
+
+        let ix = builder.compact(&user, 3, true);
+        assert_eq!(ix.data, [tag::COMPACT, 3, 0, 0, 0, 1]);
+        assert_eq!(ix.accounts[1], AccountMeta::new(user, true));
+
+        let ix = builder.flush(&user, 3);
+        assert_eq!(ix.data, [tag::FLUSH, 3, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts[1], AccountMeta::new_readonly(user, true));
+        let ix = builder.flush_refunding(&user, &[owner], 3);
+        assert_eq!(ix.data, [tag::FLUSH, 3, 0, 0, 0, 1]);
+        assert_eq!(ix.accounts[2..], [AccountMeta::new(owner, false)]);
+
+        let ix = builder.grow(&user, Some(20_480));
+        assert_eq!(ix.data, [tag::GROW, 0, 80, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts[1], AccountMeta::new(user, true));
+        assert_eq!(ix.accounts.len(), 3);
+        assert_eq!(builder.grow(&user, None).data, [tag::GROW]);
+
+        let ix = builder.close_state(&user, &owner);
+        assert_eq!(ix.data, [tag::CLOSE_STATE]);
+        assert_eq!(
+            ix.accounts,
+            [
+                AccountMeta::new(builder.state, false),
+                AccountMeta::new_readonly(user, true),
+                AccountMeta::new(owner, false),
+            ]
+        );
+
+        let authority = Pubkey::new_unique();
+        let ix = builder.set_authority(&user, &authority);
+        assert_eq!(ix.data[0], tag::SET_AUTHORITY);
+        assert_eq!(ix.data[1..], authority.to_bytes());
+        assert_eq!(ix.accounts[1], AccountMeta::new_readonly(user, true));
+    }
+}
@@ -0,0 +1,189 @@
+//! Off-chain decoding of programs built on apq_core
+//!
+//! Account data fetched over RPC is untrusted, so everything here validates before touching it
+//! and reports truncated or corrupt data as a [`DecodeError`] instead of panicking.
+
+use std::fmt;
+
+use apq_core::{header::StateHeader, migrate::Migratable};
+use bytemuck::Pod;
+
+#[cfg(feature = "idl")]
+pub mod codegen;
+pub mod decode;
+#[cfg(feature = "idl")]
+pub mod idl;
+pub mod instances;
+pub mod instructions;
+pub mod logs;
+#[cfg(feature = "serde")]
+pub mod preflight;
+pub mod priority;
+pub mod queue;
+pub mod relay;
+pub mod shard;
+pub mod stats;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use apq_core::dispatch::tag;
+    pub use bytemuck::bytes_of;
+    #[cfg(feature = "idl")]
+    pub use serde_json;
+    pub use sokoban::NodeAllocatorMap;
+    pub use solana_instruction::{AccountMeta, Instruction};
+    pub use solana_pubkey::Pubkey;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The account is shorter than the state
+    Truncated { expected: usize, actual: usize },
+    /// The state was never initialized
+    Uninitialized,
+    /// The state is laid out for another version of the program
+    VersionMismatch { expected: u32, found: u32 },
+    /// The state's layout hash isn't the one this version lays it out with, see
+    /// [`apq_core::layout`]
+    LayoutMismatch { expected: u64, found: u64 },
+    /// The queue tree doesn't hold together, e.g. it has out of bounds links or cycles
+    CorruptQueue,
+    /// Instruction data names no instruction the decoder knows, see [`decode`]
+    UnknownInstruction { tag: u8, variant: Option<u64> },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { expected, actual } => {
+                write!(f, "state is {actual} bytes, expected at least {expected}")
+            }
+            DecodeError::Uninitialized => write!(f, "state is not initialized"),
+            DecodeError::VersionMismatch { expected, found } => {
+                write!(f, "state is v{found}, expected v{expected}")
+            }
+            DecodeError::LayoutMismatch { expected, found } => {
+                write!(f, "state layout hash is {found:#x}, expected {expected:#x}")
+            }
+            DecodeError::CorruptQueue => write!(f, "queue is corrupt"),
+            DecodeError::UnknownInstruction { tag, variant: None } => {
+                write!(f, "unknown instruction tag {tag}")
+            }
+            DecodeError::UnknownInstruction {
+                tag,
+                variant: Some(variant),
+            } => write!(f, "unknown variant {variant} of instruction tag {tag}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Validated decoding of a state account, e.g. `CounterState::try_decode(&account.data)`
+pub trait TryDecode: Pod + Migratable {
+    /// Checks the length, header version and layout hash of `data` and copies the state out
+    /// of it, so the account data doesn't need to be aligned
+    fn try_decode(data: &[u8]) -> Result<Box<Self>, DecodeError> {
+        let expected = size_of::<Self>();
+        let truncated = DecodeError::Truncated {
+            expected,
+            actual: data.len(),
+        };
+        let bytes = data.get(..expected).ok_or(truncated.clone())?;
+
+        let header = StateHeader::read(bytes).map_err(|_| truncated)?;
+        if header.version == 0 {
+            return Err(DecodeError::Uninitialized);
+        }
+        if header.version != Self::VERSION {
+            return Err(DecodeError::VersionMismatch {
+                expected: Self::VERSION,
+                found: header.version,
+            });
+        }
+        if header.layout_hash != Self::LAYOUT_HASH {
+            return Err(DecodeError::LayoutMismatch {
+                expected: Self::LAYOUT_HASH,
+                found: header.layout_hash,
+            });
+        }
+
+        let mut state: Box<Self> = bytemuck::zeroed_box();
+        bytemuck::bytes_of_mut(&mut *state).copy_from_slice(bytes);
+        Ok(state)
+    }
+}
+
+impl<T: Pod + Migratable> TryDecode for T {}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+    use pinocchio::ProgramResult;
+
+    use super::*;
+
+    #[derive(Copy, Clone, Zeroable, Pod)]
+    #[repr(C)]
+    struct State {
+        header: StateHeader,
+        value: u64,
+    }
+
+    impl Migratable for State {
+        const VERSION: u32 = 2;
+        const LAYOUT_HASH: u64 = apq_core::layout_hash!(State {
+            header: StateHeader,
+            value: u64,
+        });
+
+        fn migrate(_from_version: u32, _data: &mut [u8]) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_try_decode() {
+        let mut state = State::zeroed();
+        let expected = size_of::<State>();
+        assert_eq!(
+            State::try_decode(bytemuck::bytes_of(&state)).err(),
+            Some(DecodeError::Uninitialized)
+        );
+
+        state.header = StateHeader::new(1);
+        assert_eq!(
+            State::try_decode(bytemuck::bytes_of(&state)).err(),
+            Some(DecodeError::VersionMismatch {
+                expected: 2,
+                found: 1
+            })
+        );
+
+        state.header = StateHeader::new(2);
+        assert_eq!(
+            State::try_decode(bytemuck::bytes_of(&state)).err(),
+            Some(DecodeError::LayoutMismatch {
+                expected: State::LAYOUT_HASH,
+                found: 0
+            })
+        );
+
+        state.header.layout_hash = State::LAYOUT_HASH;
+        state.value = 7;
+        assert_eq!(
+            State::try_decode(&bytemuck::bytes_of(&state)[..expected - 1]).err(),
+            Some(DecodeError::Truncated {
+                expected,
+                actual: expected - 1
+            })
+        );
+
+        // Misaligned, with trailing bytes as in a grown account
+        let mut data = vec![0; expected + 9];
+        data[1..expected + 1].copy_from_slice(bytemuck::bytes_of(&state));
+        assert_eq!(State::try_decode(&data[1..]).unwrap().value, 7);
+    }
+}
@@ -0,0 +1,167 @@
+//! Typed events from transaction logs
+//!
+//! The logs of a fetched transaction, e.g. its `meta.logMessages`, hold the
+//! [`apq_core::events`] a program emitted as `Program data:` lines. [`parse`] reads back the
+//! ones one program emitted, so callers don't match on free-form log messages, which change
+//! with the log features a program is built with.
+//!
+//! Queue keys are program-defined, so [`parse`] is for one key type, e.g. the counter's
+//! `AsyncIxKey`, which carries the slot and sequence number an instruction was queued at.
+
+use apq_core::{
+    events::{
+        self, CancelledEvent, CompactedEvent, DrainedEvent, FlushedEvent, InitializedEvent,
+        ProcessedEvent, QueuedEvent,
+    },
+    queue::QueueKey,
+};
+use solana_pubkey::Pubkey;
+
+/// An event from a program's logs, with the key of the queued instruction it is about
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProgramEvent<K> {
+    Initialized(InitializedEvent),
+    Queued(QueuedEvent, K),
+    Processed(ProcessedEvent, K),
+    Cancelled(CancelledEvent, K),
+    Drained(DrainedEvent),
+    Compacted(CompactedEvent),
+    Flushed(FlushedEvent),
+}
+
+impl<K: QueueKey> ProgramEvent<K> {
+    /// The event in one `Program data:` log line, if it is one
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let segments = events::decode_log(line)?;
+        let segments = &segments[..];
+        events::decode_keyed(segments)
+            .map(|(event, key)| ProgramEvent::Queued(event, key))
+            .or_else(|| {
+                events::decode_keyed(segments)
+                    .map(|(event, key)| ProgramEvent::Processed(event, key))
+            })
+            .or_else(|| {
+                events::decode_keyed(segments)
+                    .map(|(event, key)| ProgramEvent::Cancelled(event, key))
+            })
+            .or_else(|| events::decode(segments).map(ProgramEvent::Drained))
+            .or_else(|| events::decode(segments).map(ProgramEvent::Compacted))
+            .or_else(|| events::decode(segments).map(ProgramEvent::Flushed))
+            .or_else(|| events::decode(segments).map(ProgramEvent::Initialized))
+    }
+
+    /// Key of the queued instruction the event is about
+    pub fn key(&self) -> Option<&K> {
+        match self {
+            ProgramEvent::Queued(_, key)
+            | ProgramEvent::Processed(_, key)
+            | ProgramEvent::Cancelled(_, key) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// User who queued the instruction the event is about
+    pub fn user(&self) -> Option<Pubkey> {
+        match self {
+            ProgramEvent::Queued(event, _) => Some(event.user),
+            ProgramEvent::Processed(event, _) => Some(event.user),
+            ProgramEvent::Cancelled(event, _) => Some(event.user),
+            _ => None,
+        }
+        .map(Pubkey::from)
+    }
+}
+
+/// The events `program` emitted in a transaction's `logs`. Logs of other programs it invokes,
+/// or that invoke it, are skipped, so their data lines aren't mistaken for events.
+pub fn parse<K: QueueKey>(program: &Pubkey, logs: &[String]) -> Vec<ProgramEvent<K>> {
+    let program = program.to_string();
+    let mut stack: Vec<&str> = Vec::new();
+    let mut parsed = Vec::new();
+    for line in logs {
+        if let Some(rest) = line.strip_prefix("Program ") {
+            let mut words = rest.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some(invoked), Some("invoke")) => stack.push(invoked),
+                (Some(_), Some("success" | "failed:")) => {
+                    stack.pop();
+                }
+                _ => {}
+            }
+        }
+        if stack.last() != Some(&program.as_str()) {
+            continue;
+        }
+        if let Some(event) = ProgramEvent::parse_line(line) {
+            parsed.push(event);
+        }
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_core::{events::Event, ordering::FifoKey};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::*;
+
+    /// What `emit!` logs
+    fn data_line(segments: &[&[u8]]) -> String {
+        let encoded: Vec<String> = segments.iter().map(|s| STANDARD.encode(s)).collect();
+        format!("Program data: {}", encoded.join(" "))
+    }
+
+    fn keyed<E: Event>(event: &E, key: &FifoKey) -> String {
+        data_line(&[
+            &E::DISCRIMINATOR,
+            bytemuck::bytes_of(event),
+            bytemuck::bytes_of(key),
+        ])
+    }
+
+    #[test]
+    fn test_parse() {
+        let program = Pubkey::new_unique();
+        let key = FifoKey { slot: 1, seq: 2 };
+        let queued = QueuedEvent {
+            user: [1; 32],
+            ixn: 0,
+            priority_bid: 5,
+        };
+        let compacted = CompactedEvent {
+            expired: 3,
+            ..Default::default()
+        };
+        let other = Pubkey::new_unique();
+        let logs = [
+            format!("Program {program} invoke [1]"),
+            "Program log: Queueing Asynchronous Instruction".to_owned(),
+            keyed(&queued, &key),
+            // Another program logging the same event through a CPI is skipped
+            format!("Program {other} invoke [2]"),
+            keyed(&queued, &key),
+            format!("Program {other} success"),
+            data_line(&[
+                &CompactedEvent::DISCRIMINATOR,
+                bytemuck::bytes_of(&compacted),
+            ]),
+            format!("Program {program} success"),
+        ];
+
+        let parsed = parse::<FifoKey>(&program, &logs);
+        assert_eq!(
+            parsed,
+            [
+                ProgramEvent::Queued(queued, key),
+                ProgramEvent::Compacted(compacted)
+            ]
+        );
+        assert_eq!(parsed[0].key(), Some(&key));
+        assert_eq!(parsed[0].user(), Some(Pubkey::from([1; 32])));
+        assert_eq!(parsed[1].user(), None);
+        assert_eq!(parse::<FifoKey>(&other, &logs).len(), 1);
+        // Keys of another type don't decode
+        assert_eq!(parse::<u64>(&program, &logs).len(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+//! Previewing an enqueue before paying for it
+//!
+//! Simulate the queue transaction, e.g. with `simulateTransaction` and `sigVerify: false` so
+//! it needn't be signed yet, and pass the simulation's `value` to [`preview`] together with
+//! the queue of the decoded state. A rejected enqueue comes back as a [`Rejection`] and an
+//! accepted one as the key it would be queued under, which holds its sequence number and
+//! priority, and how many entries would be drained before it.
+//!
+//! The position is against the state as decoded, so it shifts with whatever lands first.
+
+use apq_core::{
+    dedupe::DUPLICATE,
+    events::{self, QueuedEvent},
+    queue::{Payload, QueueKey},
+    rate_limit::RATE_LIMITED,
+};
+use serde_json::Value;
+use sokoban::RedBlackTree;
+
+use crate::{queue, DecodeError};
+
+/// Why an enqueue would fail
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The queue has no room left
+    QueueFull,
+    /// See [`apq_core::rate_limit`]
+    RateLimited,
+    /// See [`apq_core::dedupe`]
+    Duplicate,
+    /// The user has no actions left, for programs metering them like the counter example
+    InsufficientActions,
+    /// Anything else, as the simulation reported it
+    Other(Value),
+}
+
+impl Rejection {
+    /// Classifies the `err` of a simulation. `insufficient_actions` is the custom error code
+    /// the program fails with for users out of actions, if it has one.
+    pub fn from_err(err: &Value, insufficient_actions: Option<u32>) -> Self {
+        let Some(error) = err["InstructionError"].get(1) else {
+            return Rejection::Other(err.clone());
+        };
+        if error == "AccountDataTooSmall" {
+            return Rejection::QueueFull;
+        }
+        match error["Custom"].as_u64().map(|code| code as u32) {
+            Some(RATE_LIMITED) => Rejection::RateLimited,
+            Some(DUPLICATE) => Rejection::Duplicate,
+            Some(code) if Some(code) == insufficient_actions => Rejection::InsufficientActions,
+            _ => Rejection::Other(err.clone()),
+        }
+    }
+}
+
+/// What an enqueue would do
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Preview<K> {
+    Queued {
+        /// The key it would be queued under
+        key: K,
+        /// Entries drained before it
+        ahead: usize,
+    },
+    Rejected(Rejection),
+    /// The simulation succeeded without emitting a [`QueuedEvent`], e.g. because the program
+    /// doesn't emit events
+    Unknown,
+}
+
+/// Previews an enqueue from its `simulation` against the current `queue`, see the module docs
+pub fn preview<K: QueueKey, V: Payload, const N: usize>(
+    simulation: &Value,
+    queue: &RedBlackTree<K, V, N>,
+    insufficient_actions: Option<u32>,
+) -> Result<Preview<K>, DecodeError> {
+    let err = &simulation["err"];
+    if !err.is_null() {
+        return Ok(Preview::Rejected(Rejection::from_err(
+            err,
+            insufficient_actions,
+        )));
+    }
+
+    let logs = simulation["logs"].as_array().into_iter().flatten();
+    let queued = logs
+        .filter_map(|line| events::decode_log(line.as_str()?))
+        .find_map(|segments| events::decode_keyed::<QueuedEvent, K>(&segments));
+    let Some((_event, key)) = queued else {
+        return Ok(Preview::Unknown);
+    };
+
+    let mut ahead = 0;
+    for entry in queue::entries(queue) {
+        if *entry?.0 >= key {
+            break;
+        }
+        ahead += 1;
+    }
+    Ok(Preview::Queued { key, ahead })
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_core::{events::Event, ordering::FifoKey};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::json;
+    use sokoban::NodeAllocatorMap;
+
+    use super::*;
+
+    #[test]
+    fn test_preview() {
+        let mut tree = RedBlackTree::<FifoKey, u64, 8>::new();
+        for seq in [1, 2, 4] {
+            tree.insert(FifoKey { slot: 0, seq }, 0);
+        }
+
+        let key = FifoKey { slot: 0, seq: 3 };
+        let segments = [
+            STANDARD.encode(QueuedEvent::DISCRIMINATOR),
+            STANDARD.encode(bytemuck::bytes_of(&QueuedEvent::default())),
+            STANDARD.encode(bytemuck::bytes_of(&key)),
+        ];
+        let logs = [
+            "Program log: Queueing".to_owned(),
+            format!("Program data: {}", segments.join(" ")),
+        ];
+        let simulation = json!({ "err": null, "logs": logs });
+        assert_eq!(
+            preview(&simulation, &tree, None),
+            Ok(Preview::Queued { key, ahead: 2 })
+        );
+
+        let simulation = json!({ "err": null, "logs": [] });
+        assert_eq!(preview(&simulation, &tree, None), Ok(Preview::Unknown));
+    }
+
+    #[test]
+    fn test_rejections() {
+        let err = |error: Value| json!({ "InstructionError": [0, error] });
+        let cases = [
+            (err(json!("AccountDataTooSmall")), Rejection::QueueFull),
+            (
+                err(json!({ "Custom": RATE_LIMITED })),
+                Rejection::RateLimited,
+            ),
+            (err(json!({ "Custom": DUPLICATE })), Rejection::Duplicate),
+            (err(json!({ "Custom": 0 })), Rejection::InsufficientActions),
+            (
+                err(json!({ "Custom": 1 })),
+                Rejection::Other(err(json!({ "Custom": 1 }))),
+            ),
+            (
+                json!("AccountNotFound"),
+                Rejection::Other(json!("AccountNotFound")),
+            ),
+        ];
+        let tree = RedBlackTree::<FifoKey, u64, 8>::new();
+        for (err, rejection) in cases {
+            let simulation = json!({ "err": err, "logs": [] });
+            assert_eq!(
+                preview(&simulation, &tree, Some(0)),
+                Ok(Preview::Rejected(rejection))
+            );
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Compute budgets for enqueue and drain transactions
+//!
+//! Without a budget a transaction reserves 200k compute units per instruction and bids no
+//! priority fee, which overpays for the reservation and lands late under load. Simulate the
+//! instructions first, e.g. with `simulateTransaction` and `replaceRecentBlockhash`, fetch
+//! `getRecentPrioritizationFees` for the state account, and let [`Priority::enqueue`] or
+//! [`Priority::drain`] prepend a unit limit sized to the simulation and a unit price picked by
+//! a [`FeeStrategy`].
+//!
+//! Enqueues and drains bid separately, since a drain landing late delays every entry due,
+//! e.g. fills in a market, while an enqueue only delays its own.
+
+use solana_compute_budget_interface::ComputeBudgetInstruction;
+use solana_instruction::Instruction;
+
+/// Most compute units a transaction can request
+pub const MAX_COMPUTE_UNITS: u32 = 1_400_000;
+
+/// How the unit price is picked from recent prioritization fees
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FeeStrategy {
+    /// This many micro-lamports per compute unit regardless of recent fees
+    Fixed(u64),
+    /// The `percentile` of recent fees, from 0 to 100, within `min..=max`
+    Percentile { percentile: u8, min: u64, max: u64 },
+}
+
+impl FeeStrategy {
+    /// The unit price in micro-lamports given `recent` prioritization fees, `min` if there are
+    /// none
+    pub fn price(&self, recent: &[u64]) -> u64 {
+        match *self {
+            FeeStrategy::Fixed(price) => price,
+            FeeStrategy::Percentile {
+                percentile,
+                min,
+                max,
+            } => {
+                let mut recent = recent.to_vec();
+                recent.sort_unstable();
+                let rank = recent.len().saturating_sub(1) * percentile.min(100) as usize / 100;
+                recent
+                    .get(rank)
+                    .map_or(min, |fee| (*fee).clamp(min, max.max(min)))
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Priority {
+    pub enqueue: FeeStrategy,
+    pub drain: FeeStrategy,
+    /// Compute units requested on top of what the simulation consumed, in percent, since the
+    /// state may have changed by the time the transaction lands
+    pub margin_percent: u32,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority {
+            enqueue: FeeStrategy::Percentile {
+                percentile: 50,
+                min: 0,
+                max: 1_000_000,
+            },
+            drain: FeeStrategy::Percentile {
+                percentile: 75,
+                min: 1,
+                max: 10_000_000,
+            },
+            margin_percent: 10,
+        }
+    }
+}
+
+impl Priority {
+    /// The unit limit for a transaction whose simulation `consumed` as many units
+    pub fn compute_units(&self, consumed: u64) -> u32 {
+        let units = consumed.saturating_mul(100 + self.margin_percent as u64) / 100;
+        units.clamp(1, MAX_COMPUTE_UNITS as u64) as u32
+    }
+
+    /// `instructions` of an enqueue with a budget bid by [`Priority::enqueue`]
+    pub fn enqueue(
+        &self,
+        instructions: &[Instruction],
+        consumed: u64,
+        recent: &[u64],
+    ) -> Vec<Instruction> {
+        self.with_budget(&self.enqueue, instructions, consumed, recent)
+    }
+
+    /// `instructions` of a drain with a budget bid by [`Priority::drain`]
+    pub fn drain(
+        &self,
+        instructions: &[Instruction],
+        consumed: u64,
+        recent: &[u64],
+    ) -> Vec<Instruction> {
+        self.with_budget(&self.drain, instructions, consumed, recent)
+    }
+
+    /// The budget instructions followed by `instructions`, less any budget they already set
+    fn with_budget(
+        &self,
+        strategy: &FeeStrategy,
+        instructions: &[Instruction],
+        consumed: u64,
+        recent: &[u64],
+    ) -> Vec<Instruction> {
+        [
+            ComputeBudgetInstruction::set_compute_unit_limit(self.compute_units(consumed)),
+            ComputeBudgetInstruction::set_compute_unit_price(strategy.price(recent)),
+        ]
+        .into_iter()
+        .chain(
+            instructions
+                .iter()
+                .filter(|ix| ix.program_id != solana_compute_budget_interface::id())
+                .cloned(),
+        )
+        .collect()
+    }
+}
+
+/// The units a `simulateTransaction` result's `value` consumed
+#[cfg(feature = "serde")]
+pub fn units_consumed(simulation: &serde_json::Value) -> Option<u64> {
+    simulation["unitsConsumed"].as_u64()
+}
+
+/// The fees of a `getRecentPrioritizationFees` result
+#[cfg(feature = "serde")]
+pub fn recent_fees(result: &serde_json::Value) -> Vec<u64> {
+    result
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|sample| sample["prioritizationFee"].as_u64())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_pubkey::Pubkey;
+
+    use super::*;
+    use crate::instructions::InstructionBuilder;
+
+    #[test]
+    fn test_priority() {
+        let recent = [40, 10, 30, 20, 50];
+        assert_eq!(FeeStrategy::Fixed(7).price(&recent), 7);
+        let median = FeeStrategy::Percentile {
+            percentile: 50,
+            min: 0,
+            max: 100,
+        };
+        assert_eq!(median.price(&recent), 30);
+        assert_eq!(median.price(&[]), 0);
+        let capped = FeeStrategy::Percentile {
+            percentile: 100,
+            min: 15,
+            max: 45,
+        };
+        assert_eq!(capped.price(&recent), 45);
+        assert_eq!(capped.price(&[1]), 15);
+
+        let priority = Priority::default();
+        assert_eq!(priority.compute_units(10_000), 11_000);
+        assert_eq!(priority.compute_units(u64::MAX), MAX_COMPUTE_UNITS);
+
+        let builder = InstructionBuilder::new(Pubkey::new_unique(), Pubkey::new_unique());
+        let user = Pubkey::new_unique();
+        let drain = builder.drain(&user);
+        // A budget already set is replaced
+        let stale = ComputeBudgetInstruction::set_compute_unit_limit(1);
+        let instructions = priority.drain(&[stale, drain.clone()], 20_000, &recent);
+        assert_eq!(
+            instructions,
+            [
+                ComputeBudgetInstruction::set_compute_unit_limit(22_000),
+                ComputeBudgetInstruction::set_compute_unit_price(40),
+                drain,
+            ]
+        );
+        let queue = builder.queue_async(&user, &[]);
+        assert_eq!(
+            priority.enqueue(std::slice::from_ref(&queue), 5_000, &recent)[1..],
+            [ComputeBudgetInstruction::set_compute_unit_price(30), queue]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_simulation() {
+        let simulation = serde_json::json!({ "err": null, "unitsConsumed": 1234 });
+        assert_eq!(units_consumed(&simulation), Some(1234));
+        let fees = serde_json::json!([
+            { "slot": 1, "prioritizationFee": 0 },
+            { "slot": 2, "prioritizationFee": 500 },
+        ]);
+        assert_eq!(recent_fees(&fees), [0, 500]);
+        assert!(recent_fees(&serde_json::Value::Null).is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+//! Iterating over the queue of a decoded state
+//!
+//! Walking a sokoban tree with its own iterator panics or loops forever on corrupt links. This
+//! walks it with every link bounds checked, at most one visit per node and keys checked to be
+//! strictly increasing, yielding entries in drain order.
+//!
+//! With the `serde` feature, [`snapshot`] collects the whole queue for JSON export.
+
+use apq_core::queue::{Payload, QueueKey};
+use sokoban::{RedBlackTree, SENTINEL};
+
+use crate::DecodeError;
+
+/// One entry of a [`snapshot`]
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotEntry<K, V> {
+    pub key: K,
+    pub value: V,
+}
+
+/// In-order iterator over a queue tree, see [`entries`]
+pub struct Entries<'a, K: QueueKey, V: Payload, const N: usize> {
+    tree: &'a RedBlackTree<K, V, N>,
+    stack: Vec<u32>,
+    next: u32,
+    visited: usize,
+    last_key: Option<K>,
+    failed: bool,
+}
+
+/// Entries of `tree` in drain order, stopping at the first sign of corruption
+pub fn entries<K: QueueKey, V: Payload, const N: usize>(
+    tree: &RedBlackTree<K, V, N>,
+) -> Entries<'_, K, V, N> {
+    Entries {
+        tree,
+        stack: Vec::new(),
+        next: tree.root,
+        visited: 0,
+        last_key: None,
+        failed: false,
+    }
+}
+
+impl<'a, K: QueueKey, V: Payload, const N: usize> Entries<'a, K, V, N> {
+    /// Node addresses run from 1 to the capacity, 0 being the sentinel
+    fn check_addr(addr: u32) -> Result<u32, DecodeError> {
+        if addr as usize > N {
+            return Err(DecodeError::CorruptQueue);
+        }
+        Ok(addr)
+    }
+
+    fn step(&mut self) -> Result<Option<(&'a K, &'a V)>, DecodeError> {
+        while self.next != SENTINEL {
+            if self.stack.len() >= N {
+                return Err(DecodeError::CorruptQueue);
+            }
+            let addr = Self::check_addr(self.next)?;
+            self.stack.push(addr);
+            self.next = self.tree.get_left(addr);
+        }
+
+        let Some(addr) = self.stack.pop() else {
+            return Ok(None);
+        };
+        self.visited += 1;
+        if self.visited > N {
+            return Err(DecodeError::CorruptQueue);
+        }
+        self.next = self.tree.get_right(addr);
+
+        let node = self.tree.get_node(addr);
+        if self.last_key.is_some_and(|last| last >= node.key) {
+            return Err(DecodeError::CorruptQueue);
+        }
+        self.last_key = Some(node.key);
+        Ok(Some((&node.key, &node.value)))
+    }
+}
+
+impl<'a, K: QueueKey, V: Payload, const N: usize> Iterator for Entries<'a, K, V, N> {
+    type Item = Result<(&'a K, &'a V), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let item = self.step().transpose();
+        self.failed = matches!(item, Some(Err(_)));
+        item
+    }
+}
+
+/// Every entry of `tree` in drain order, failing on corruption rather than truncating
+#[cfg(feature = "serde")]
+pub fn snapshot<K: QueueKey, V: Payload, const N: usize>(
+    tree: &RedBlackTree<K, V, N>,
+) -> Result<Vec<SnapshotEntry<K, V>>, DecodeError> {
+    entries(tree)
+        .map(|entry| {
+            entry.map(|(key, value)| SnapshotEntry {
+                key: *key,
+                value: *value,
+            })
+        })
+        .collect()
+}
+
+/// [`snapshot`] as a JSON array of `{ "key": .., "value": .. }` objects
+#[cfg(feature = "serde")]
+pub fn json_snapshot<K, V, const N: usize>(
+    tree: &RedBlackTree<K, V, N>,
+) -> Result<String, DecodeError>
+where
+    K: QueueKey + serde::Serialize,
+    V: Payload + serde::Serialize,
+{
+    let snapshot = snapshot(tree)?;
+    Ok(serde_json::to_string(&snapshot).expect("keys and payloads serialize to JSON"))
+}
+
+#[cfg(test)]
+mod tests {
+    use sokoban::NodeAllocatorMap;
+
+    use super::*;
+
+    #[test]
+    fn test_entries_in_order() {
+        let mut tree = RedBlackTree::<u64, u64, 16>::new();
+        for key in [5, 1, 9, 3, 7] {
+            tree.insert(key, key * 10);
+        }
+
+        let keys: Vec<u64> = entries(&tree).map(|entry| *entry.unwrap().0).collect();
+        assert_eq!(keys, [1, 3, 5, 7, 9]);
+    }
+
+    #[test]
+    fn test_corrupt_tree() {
+        let mut tree = RedBlackTree::<u64, u64, 16>::new();
+        for key in [5, 1, 9] {
+            tree.insert(key, 0);
+        }
+
+        let mut out_of_bounds = tree;
+        out_of_bounds.root = 17;
+        let items: Vec<_> = entries(&out_of_bounds).collect();
+        assert_eq!(items, [Err(DecodeError::CorruptQueue)]);
+
+        // Keys out of order, yielding what came before
+        let mut unordered = tree;
+        let right = unordered.get_right(unordered.root);
+        unordered.get_node_mut(right).key = 0;
+        let items: Vec<_> = entries(&unordered).collect();
+        assert_eq!(
+            items,
+            [Ok((&1, &0)), Ok((&5, &0)), Err(DecodeError::CorruptQueue)]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_snapshot() {
+        use apq_core::ordering::FifoKey;
+
+        let mut tree = RedBlackTree::<FifoKey, u64, 16>::new();
+        tree.insert(FifoKey { slot: 2, seq: 1 }, 20);
+        tree.insert(FifoKey { slot: 1, seq: 2 }, 10);
+
+        let json = json_snapshot(&tree).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"key":{"slot":1,"seq":2},"value":10},{"key":{"slot":2,"seq":1},"value":20}]"#
+        );
+        let parsed: Vec<SnapshotEntry<FifoKey, u64>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, snapshot(&tree).unwrap());
+
+        let mut corrupt = tree;
+        corrupt.root = 17;
+        assert_eq!(json_snapshot(&corrupt), Err(DecodeError::CorruptQueue));
+    }
+}
@@ -0,0 +1,105 @@
+//! Building relayed enqueues, see [`apq_core::relay`]
+//!
+//! The user signs [`message`] with their wallet, e.g. through its sign message request, and
+//! sends the signature to the relayer, who puts the two instructions from [`relayed`] in a
+//! transaction it signs and pays for. The ed25519 instruction has to come before the queue
+//! instruction, which looks for it in the instructions sysvar.
+
+use apq_core::relay::ED25519_PROGRAM_ID;
+use solana_instruction::Instruction;
+use solana_pubkey::Pubkey;
+
+use crate::instructions::InstructionBuilder;
+
+/// Signature, public key and message offsets of one signature, after the signature count and
+/// a padding byte
+const OFFSETS_END: usize = 2 + 14;
+
+/// What `owner` signs to authorize queueing `async_ix` into `builder`'s state until `expiry`,
+/// on the state's [`Schedule`](apq_core::ordering::Schedule)
+pub fn message(
+    builder: &InstructionBuilder,
+    owner: &Pubkey,
+    expiry: u64,
+    async_ix: &[u8],
+) -> Vec<u8> {
+    apq_core::relay::message(
+        &builder.program_id.to_bytes(),
+        &builder.state.to_bytes(),
+        &owner.to_bytes(),
+        expiry,
+        async_ix,
+    )
+}
+
+/// The ed25519 program instruction verifying `signature` by `signer` over `message`, with all
+/// three in its own data
+pub fn ed25519_instruction(signer: &Pubkey, signature: &[u8; 64], message: &[u8]) -> Instruction {
+    let key = OFFSETS_END;
+    let signature_at = key + 32;
+    let message_at = signature_at + 64;
+    // The ed25519 program's index for its own instruction
+    let own = u16::MAX;
+    let offsets = [
+        signature_at as u16,
+        own,
+        key as u16,
+        own,
+        message_at as u16,
+        u16::try_from(message.len()).expect("message fits an instruction"),
+        own,
+    ];
+    let mut data = Vec::with_capacity(message_at + message.len());
+    data.extend([1, 0]);
+    data.extend(offsets.iter().flat_map(|offset| offset.to_le_bytes()));
+    data.extend(signer.to_bytes());
+    data.extend(signature);
+    data.extend(message);
+    Instruction {
+        program_id: Pubkey::new_from_array(ED25519_PROGRAM_ID),
+        accounts: vec![],
+        data,
+    }
+}
+
+/// The ed25519 instruction and the relayed queue instruction, in the order the transaction
+/// takes them, for `owner`'s `signature` over [`message`]
+pub fn relayed(
+    builder: &InstructionBuilder,
+    relayer: &Pubkey,
+    owner: &Pubkey,
+    expiry: u64,
+    async_ix: &[u8],
+    signature: &[u8; 64],
+) -> [Instruction; 2] {
+    let message = message(builder, owner, expiry, async_ix);
+    [
+        ed25519_instruction(owner, signature, &message),
+        builder.queue_relayed(relayer, owner, expiry, async_ix),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_core::{dispatch::tag, relay::verifies};
+
+    use super::*;
+
+    #[test]
+    fn test_relayed() {
+        let builder = InstructionBuilder::new(Pubkey::new_unique(), Pubkey::new_unique());
+        let relayer = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let async_ix = [1, 0, 0, 0, 0, 0, 0, 0];
+
+        let [verify, queue] = relayed(&builder, &relayer, &owner, 50, &async_ix, &[7; 64]);
+        let message = message(&builder, &owner, 50, &async_ix);
+        assert_eq!(verify.program_id.to_bytes(), ED25519_PROGRAM_ID);
+        assert!(verifies(&verify.data, &owner.to_bytes(), &message));
+        assert!(!verifies(&verify.data, &relayer.to_bytes(), &message));
+        assert_eq!(verify.data[OFFSETS_END + 32..OFFSETS_END + 96], [7; 64]);
+
+        assert_eq!(queue.data[0], tag::QUEUE_RELAYED);
+        assert_eq!(queue.data[9..], async_ix);
+    }
+}
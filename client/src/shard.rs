@@ -0,0 +1,91 @@
+//! Routing for queues sharded across several state accounts
+//!
+//! Users enqueue into the shard [`route`] picks for them, which the program checks, and
+//! cranks drain every shard together. See [`apq_core::shard`].
+
+pub use apq_core::shard::route;
+use solana_instruction::{AccountMeta, Instruction};
+use solana_pubkey::Pubkey;
+
+use crate::instructions::InstructionBuilder;
+
+/// The state accounts of a sharded state, in shard order
+#[derive(Clone, Debug)]
+pub struct Shards {
+    pub program_id: Pubkey,
+    pub accounts: Vec<Pubkey>,
+}
+
+impl Shards {
+    pub fn new(program_id: Pubkey, accounts: Vec<Pubkey>) -> Self {
+        assert!(
+            (1..=u8::MAX as usize).contains(&accounts.len()),
+            "between 1 and 255 shards"
+        );
+        Shards {
+            program_id,
+            accounts,
+        }
+    }
+
+    /// The shard account `user` enqueues into
+    pub fn for_user(&self, user: &Pubkey) -> &Pubkey {
+        &self.accounts[route(user.as_array(), self.accounts.len() as u8) as usize]
+    }
+
+    /// Builds `user`'s instructions against their shard, e.g. queueing or cancelling
+    pub fn builder(&self, user: &Pubkey) -> InstructionBuilder {
+        InstructionBuilder::new(self.program_id, *self.for_user(user))
+    }
+
+    /// Drains every shard, paying the crank bounties to `cranker`
+    pub fn drain(&self, cranker: &Pubkey) -> Instruction {
+        let mut ix = InstructionBuilder::new(self.program_id, self.accounts[0]).drain(cranker);
+        ix.accounts.extend(
+            self.accounts[1..]
+                .iter()
+                .map(|shard| AccountMeta::new(*shard, false)),
+        );
+        ix
+    }
+
+    /// Sets up each account as its shard, signed by the admin
+    pub fn set_shards(&self, admin: &Pubkey) -> Vec<Instruction> {
+        (0..)
+            .zip(&self.accounts)
+            .map(|(index, shard)| {
+                InstructionBuilder::new(self.program_id, *shard).set_shard(admin, index)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing() {
+        let shards = Shards::new(
+            Pubkey::new_unique(),
+            (0..4).map(|_| Pubkey::new_unique()).collect(),
+        );
+        let user = Pubkey::new_unique();
+        let shard = route(user.as_array(), 4) as usize;
+        assert_eq!(shards.builder(&user).state, shards.accounts[shard]);
+
+        let ix = shards.drain(&user);
+        assert_eq!(ix.accounts[0], AccountMeta::new(shards.accounts[0], false));
+        assert_eq!(
+            ix.accounts[2..],
+            shards.accounts[1..]
+                .iter()
+                .map(|shard| AccountMeta::new(*shard, false))
+                .collect::<Vec<_>>()
+        );
+
+        let ix = &shards.set_shards(&user)[3];
+        assert_eq!(ix.accounts[0].pubkey, shards.accounts[3]);
+        assert_eq!(ix.data[1..], [3]);
+    }
+}
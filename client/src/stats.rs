@@ -0,0 +1,57 @@
+//! Queue monitoring from account data alone
+//!
+//! Every state starts with a [`StateHeader`], so the [`QueueStats`] can be read without knowing
+//! the state type or its layout version, e.g. to chart throughput and backlog over time.
+
+use apq_core::header::StateHeader;
+pub use apq_core::stats::QueueStats;
+
+use crate::DecodeError;
+
+/// The queue stats of a state account
+pub fn read(data: &[u8]) -> Result<QueueStats, DecodeError> {
+    let header = StateHeader::read(data).map_err(|_| DecodeError::Truncated {
+        expected: StateHeader::LEN,
+        actual: data.len(),
+    })?;
+    if header.version == 0 {
+        return Err(DecodeError::Uninitialized);
+    }
+    Ok(header.stats)
+}
+
+/// Entries queued right now
+pub fn depth(data: &[u8]) -> Result<u64, DecodeError> {
+    read(data).map(|stats| stats.depth())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let mut header = StateHeader::new(3);
+        header.stats.record_enqueued();
+        header.stats.record_enqueued();
+        header.stats.record_processed(1, 9);
+        let mut data = vec![0; StateHeader::LEN + 16];
+        header.write(&mut data).unwrap();
+
+        let stats = read(&data).unwrap();
+        assert_eq!((stats.max_depth, stats.last_processed_slot), (2, 9));
+        assert_eq!(depth(&data), Ok(1));
+
+        assert_eq!(
+            read(&data[..8]),
+            Err(DecodeError::Truncated {
+                expected: StateHeader::LEN,
+                actual: 8
+            })
+        );
+        assert_eq!(
+            read(&vec![0; StateHeader::LEN]),
+            Err(DecodeError::Uninitialized)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Decoding in the browser
+//!
+//! The client builds for `wasm32-unknown-unknown`, and with the `wasm` feature
+//! [`wasm_state!`](crate::wasm_state) wraps a program's state in a `wasm-bindgen` class, so
+//! web UIs can decode account data fetched over RPC and render the queue without a server in
+//! between. Decoded values cross into JavaScript as JSON strings for `JSON.parse`, with
+//! integers beyond `Number.MAX_SAFE_INTEGER`, e.g. bid ranks, as decimal strings so they keep
+//! their precision.
+
+use apq_core::{
+    header::StateHeader,
+    queue::{Payload, QueueKey},
+};
+use serde::Serialize;
+use serde_json::Value;
+use sokoban::RedBlackTree;
+
+use crate::{queue, DecodeError};
+
+/// Largest integer JavaScript numbers hold exactly
+pub const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// `value` with every integer JavaScript can't hold exactly turned into a decimal string
+pub fn js_safe(value: Value) -> Value {
+    match value {
+        Value::Number(number) => match number.as_u64() {
+            Some(n) if n > MAX_SAFE_INTEGER => Value::String(n.to_string()),
+            _ => Value::Number(number),
+        },
+        Value::Array(values) => Value::Array(values.into_iter().map(js_safe).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, js_safe(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// `value` as JSON safe for `JSON.parse`
+pub fn to_json<T: Serialize>(value: &T) -> String {
+    let value = serde_json::to_value(value).expect("decoded values serialize to JSON");
+    js_safe(value).to_string()
+}
+
+/// The header of state account data as JSON
+pub fn header_json(data: &[u8]) -> Result<String, DecodeError> {
+    let header = StateHeader::read(data).map_err(|_| DecodeError::Truncated {
+        expected: StateHeader::LEN,
+        actual: data.len(),
+    })?;
+    Ok(to_json(&header))
+}
+
+/// Up to `count` queue entries from `start` in drain order, as a JSON array of
+/// `{ "key": .., "value": .. }` objects
+pub fn entries_json<K, V, const N: usize>(
+    tree: &RedBlackTree<K, V, N>,
+    start: usize,
+    count: usize,
+) -> Result<String, DecodeError>
+where
+    K: QueueKey + Serialize,
+    V: Payload + Serialize,
+{
+    let entries = queue::entries(tree)
+        .skip(start)
+        .take(count)
+        .map(|entry| entry.map(|(key, value)| queue::SnapshotEntry { key, value }))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(to_json(&entries))
+}
+
+/// Declares a `wasm-bindgen` class decoding a program's state, for the program's web bindings
+///
+/// The calling crate depends on `wasm-bindgen` itself, as its attribute only expands right
+/// where the crate is in scope.
+///
+/// ```ignore
+/// apq_client::wasm_state! {
+///     /// The counter's state, decoded from account data
+///     pub struct Counter(CounterState) { queue: async_queue }
+/// }
+/// ```
+///
+/// In JavaScript, `new Counter(data)` throws on data that doesn't decode, then `header()` and
+/// `entries(start, count)` return JSON and `depth()` the queue length.
+#[macro_export]
+macro_rules! wasm_state {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident($state:ty) { queue: $queue:ident }
+    ) => {
+        $(#[$attr])*
+        #[::wasm_bindgen::prelude::wasm_bindgen]
+        $vis struct $name {
+            data: ::std::vec::Vec<u8>,
+            state: ::std::boxed::Box<$state>,
+        }
+
+        #[::wasm_bindgen::prelude::wasm_bindgen]
+        impl $name {
+            #[wasm_bindgen(constructor)]
+            pub fn new(
+                data: &[u8],
+            ) -> ::std::result::Result<$name, ::wasm_bindgen::JsError> {
+                let state = <$state as $crate::TryDecode>::try_decode(data)?;
+                Ok($name {
+                    data: data.to_vec(),
+                    state,
+                })
+            }
+
+            /// The state header as JSON
+            pub fn header(&self) -> ::std::string::String {
+                $crate::wasm::header_json(&self.data).expect("decoded state has a header")
+            }
+
+            /// Entries queued right now
+            pub fn depth(&self) -> usize {
+                use $crate::__private::NodeAllocatorMap;
+                self.state.$queue.len()
+            }
+
+            /// Up to `count` queue entries from `start` in drain order as JSON
+            pub fn entries(
+                &self,
+                start: usize,
+                count: usize,
+            ) -> ::std::result::Result<
+                ::std::string::String,
+                ::wasm_bindgen::JsError,
+            > {
+                Ok($crate::wasm::entries_json(&self.state.$queue, start, count)?)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_core::ordering::FifoKey;
+    use serde_json::json;
+    use sokoban::NodeAllocatorMap;
+
+    use super::*;
+
+    #[test]
+    fn test_js_safe() {
+        let value = json!({ "small": 7, "big": [u64::MAX], "negative": -1, "name": "x" });
+        assert_eq!(
+            js_safe(value),
+            json!({ "small": 7, "big": [u64::MAX.to_string()], "negative": -1, "name": "x" })
+        );
+        assert_eq!(js_safe(json!(MAX_SAFE_INTEGER)), json!(MAX_SAFE_INTEGER));
+    }
+
+    #[test]
+    fn test_entries_json() {
+        let mut tree = RedBlackTree::<FifoKey, u64, 8>::new();
+        for seq in [3, 1, 2] {
+            tree.insert(FifoKey { slot: 0, seq }, seq * 10);
+        }
+        let json: Value = serde_json::from_str(&entries_json(&tree, 1, 5).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            json!([
+                { "key": { "slot": 0, "seq": 2 }, "value": 20 },
+                { "key": { "slot": 0, "seq": 3 }, "value": 30 },
+            ])
+        );
+
+        assert!(header_json(&[0; 4]).is_err());
+        let header = StateHeader::new(2);
+        let json: Value =
+            serde_json::from_str(&header_json(bytemuck::bytes_of(&header)).unwrap()).unwrap();
+        assert_eq!(json["version"], 2);
+    }
+}
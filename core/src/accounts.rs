@@ -0,0 +1,75 @@
+//! Accounts referenced by queued instructions
+//!
+//! An instruction that CPIs when it is drained, e.g. to transfer tokens on settlement, needs
+//! accounts the drain transaction is built with long after it was queued. It records their
+//! keys as [`AccountRefs`] in its payload when queued, and the cranker passes them as the
+//! drain's remaining accounts in any order. The state's
+//! [`AsyncState::process_next_async_with`](crate::AsyncState::process_next_async_with) then
+//! [resolves](AccountRefs::resolve) them and hands them to
+//! [`AsyncIx::process_with_accounts`](crate::AsyncIx::process_with_accounts).
+//!
+//! Referencing by key rather than by position keeps entries independent of how the cranker
+//! orders the accounts, and of which other entries are drained in the same transaction.
+//! Entries that keep referencing the same accounts can reference them by index into a registry
+//! instead, see [`crate::registry`].
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
+
+/// Most accounts one queued instruction can reference
+pub const MAX_ACCOUNT_REFS: usize = 4;
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct AccountRefs {
+    keys: [Pubkey; MAX_ACCOUNT_REFS],
+    len: u64,
+}
+
+impl AccountRefs {
+    pub fn new(keys: &[Pubkey]) -> Result<Self, ProgramError> {
+        let mut refs = AccountRefs::default();
+        refs.keys
+            .get_mut(..keys.len())
+            .ok_or(ProgramError::InvalidArgument)?
+            .copy_from_slice(keys);
+        refs.len = keys.len() as u64;
+        Ok(refs)
+    }
+
+    pub fn keys(&self) -> &[Pubkey] {
+        &self.keys[..self.len as usize]
+    }
+
+    /// Finds each referenced account among `accounts`, in the order they were referenced
+    pub fn resolve<'a>(
+        &self,
+        accounts: &'a [AccountInfo],
+    ) -> Result<Vec<&'a AccountInfo>, ProgramError> {
+        self.keys()
+            .iter()
+            .map(|key| {
+                accounts
+                    .iter()
+                    .find(|account| account.key() == key)
+                    .ok_or(ProgramError::NotEnoughAccountKeys)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_refs() {
+        let refs = AccountRefs::new(&[[1; 32], [2; 32]]).unwrap();
+        assert_eq!(refs.keys(), [[1; 32], [2; 32]]);
+        assert!(AccountRefs::default().keys().is_empty());
+        assert_eq!(
+            AccountRefs::new(&[[1; 32]; MAX_ACCOUNT_REFS + 1]),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+}
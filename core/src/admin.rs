@@ -0,0 +1,131 @@
+//! Admin authority and pausing for incident response
+//!
+//! The admin is whoever initialized the state and lives in the
+//! [`StateHeader`](crate::header::StateHeader). It can pause new enqueues, and optionally
+//! draining, until it unpauses. Cancelling is never paused, so users can always get their
+//! escrow back. All zeroes means there is no admin, e.g. for states initialized before there
+//! was one, and the queue can't be paused.
+//!
+//! The admin is handed over in two steps, so a mistyped key can't lock it out: the admin
+//! proposes a new authority, which takes over once it accepts by signing.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+
+/// What a pause blocks, as bit flags
+pub mod pause {
+    /// Queueing, committing and revealing
+    pub const ENQUEUE: u8 = 1;
+    pub const DRAIN: u8 = 2;
+    pub const ALL: u8 = ENQUEUE | DRAIN;
+}
+
+/// No admin
+const NO_ADMIN: Pubkey = [0; 32];
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Admin {
+    pub authority: Pubkey,
+    /// Proposed new authority that hasn't accepted yet, all zeroes if none
+    pub pending: Pubkey,
+    /// [`pause`] flags, zero when running normally
+    pub paused: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    _padding: [u8; 7],
+}
+
+crate::impl_layout_hash!(Admin {
+    authority: Pubkey,
+    pending: Pubkey,
+    paused: u8,
+    _padding: [u8; 7]
+});
+
+impl Admin {
+    pub fn new(authority: Pubkey) -> Self {
+        Admin {
+            authority,
+            ..Default::default()
+        }
+    }
+
+    /// Checks that `admin` is the signing admin, and that there is one
+    pub fn check_authority(&self, admin: &AccountInfo) -> ProgramResult {
+        if self.authority == NO_ADMIN || !admin.is_signer() || *admin.key() != self.authority {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        Ok(())
+    }
+
+    /// Fails with [`ProgramError::Immutable`] if any of the `flags` are paused
+    pub fn check_running(&self, flags: u8) -> ProgramResult {
+        if self.paused & flags != 0 {
+            return Err(ProgramError::Immutable);
+        }
+        Ok(())
+    }
+
+    /// Handles the set authority instruction, data is the proposed authority. Proposing all
+    /// zeroes withdraws a pending proposal.
+    pub fn process_set_authority(&mut self, admin: &AccountInfo, data: &[u8]) -> ProgramResult {
+        self.check_authority(admin)?;
+
+        self.pending = data
+            .try_into()
+            .map_err(|_| ProgramError::InvalidInstructionData)?;
+        Ok(())
+    }
+
+    /// Handles the accept authority instruction, signed by the proposed authority
+    pub fn process_accept_authority(&mut self, pending: &AccountInfo) -> ProgramResult {
+        if self.pending == NO_ADMIN || !pending.is_signer() || *pending.key() != self.pending {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        self.authority = self.pending;
+        self.pending = NO_ADMIN;
+        Ok(())
+    }
+
+    /// Handles the pause instruction, data is `[flags: u8]`. Pausing again replaces the flags.
+    pub fn process_pause(&mut self, admin: &AccountInfo, data: &[u8]) -> ProgramResult {
+        self.check_authority(admin)?;
+
+        let &[flags] = data else {
+            return Err(ProgramError::InvalidInstructionData);
+        };
+        if flags == 0 || flags & !pause::ALL != 0 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        self.paused = flags;
+        Ok(())
+    }
+
+    /// Handles the unpause instruction
+    pub fn process_unpause(&mut self, admin: &AccountInfo) -> ProgramResult {
+        self.check_authority(admin)?;
+        self.paused = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_running() {
+        let mut admin = Admin::new([1; 32]);
+        assert_eq!(admin.check_running(pause::ALL), Ok(()));
+
+        admin.paused = pause::ENQUEUE;
+        assert_eq!(
+            admin.check_running(pause::ENQUEUE),
+            Err(ProgramError::Immutable)
+        );
+        assert_eq!(admin.check_running(pause::DRAIN), Ok(()));
+    }
+}
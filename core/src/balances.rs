@@ -0,0 +1,139 @@
+//! Per-user balances in account data
+//!
+//! Most programs on this pattern keep something per user, e.g. the counter's actions or the
+//! orderbook's funds. [`Balances`] is a tree from user to an [`Amount`], and the functions here
+//! credit and debit it checked. Users whose balance drops to zero are removed, so the capacity
+//! of the tree bounds how many users can hold something at once rather than how many ever did.
+
+use bytemuck::Pod;
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey};
+use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+/// Balances of up to `N` users
+pub type Balances<V, const N: usize> = RedBlackTree<Pubkey, V, N>;
+
+/// What a balance holds, zero being [`Default`]
+pub trait Amount: Pod + Default + PartialEq {
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    fn is_zero(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Amount for u64 {
+    fn checked_add(self, other: Self) -> Option<Self> {
+        u64::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        u64::checked_sub(self, other)
+    }
+}
+
+/// What `user` holds, zero for users without a balance
+pub fn balance<V: Amount, const N: usize>(balances: &Balances<V, N>, user: &Pubkey) -> V {
+    balances.get(user).copied().unwrap_or_default()
+}
+
+/// Adds `amount` to `user`'s balance, returning the new balance. Fails with
+/// `AccountDataTooSmall` for a new user when every slot is taken.
+pub fn credit<V: Amount, const N: usize>(
+    balances: &mut Balances<V, N>,
+    user: &Pubkey,
+    amount: V,
+) -> Result<V, ProgramError> {
+    let balance = balance(balances, user)
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    if balance.is_zero() {
+        return Ok(balance);
+    }
+    balances
+        .insert(*user, balance)
+        .ok_or(ProgramError::AccountDataTooSmall)?;
+    Ok(balance)
+}
+
+/// Takes `amount` from `user`'s balance, returning what is left. Fails with `insufficient` if
+/// the user doesn't hold that much.
+pub fn debit<V: Amount, const N: usize>(
+    balances: &mut Balances<V, N>,
+    user: &Pubkey,
+    amount: V,
+    insufficient: ProgramError,
+) -> Result<V, ProgramError> {
+    let Some(held) = balances.get_mut(user) else {
+        return match amount.is_zero() {
+            true => Ok(amount),
+            false => Err(insufficient),
+        };
+    };
+    *held = held.checked_sub(amount).ok_or(insufficient)?;
+    let left = *held;
+    if left.is_zero() {
+        balances.remove(user);
+    }
+    Ok(left)
+}
+
+/// How many more users can hold a balance
+pub fn remaining<V: Amount, const N: usize>(balances: &Balances<V, N>) -> usize {
+    N - balances.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: Pubkey = [1; 32];
+    const BOB: Pubkey = [2; 32];
+
+    #[test]
+    fn test_credit_debit() {
+        let mut balances = Balances::<u64, 2>::new();
+        assert_eq!(credit(&mut balances, &ALICE, 5), Ok(5));
+        assert_eq!(credit(&mut balances, &ALICE, 2), Ok(7));
+        assert_eq!(balance(&balances, &ALICE), 7);
+        assert_eq!(balance(&balances, &BOB), 0);
+        assert_eq!(
+            credit(&mut balances, &ALICE, u64::MAX),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+
+        let insufficient = ProgramError::InsufficientFunds;
+        assert_eq!(
+            debit(&mut balances, &ALICE, 8, insufficient.clone()),
+            Err(ProgramError::InsufficientFunds)
+        );
+        assert_eq!(debit(&mut balances, &BOB, 0, insufficient.clone()), Ok(0));
+        assert_eq!(
+            debit(&mut balances, &BOB, 1, insufficient.clone()),
+            Err(ProgramError::InsufficientFunds)
+        );
+        assert_eq!(debit(&mut balances, &ALICE, 3, insufficient.clone()), Ok(4));
+        assert_eq!(balance(&balances, &ALICE), 4);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut balances = Balances::<u64, 2>::new();
+        assert_eq!(remaining(&balances), 2);
+        credit(&mut balances, &ALICE, 1).unwrap();
+        credit(&mut balances, &BOB, 1).unwrap();
+        assert_eq!(remaining(&balances), 0);
+        assert_eq!(
+            credit(&mut balances, &[3; 32], 1),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        // Crediting nothing needs no slot
+        assert_eq!(credit(&mut balances, &[3; 32], 0), Ok(0));
+
+        // Spent balances make room
+        debit(&mut balances, &ALICE, 1, ProgramError::InsufficientFunds).unwrap();
+        assert_eq!(remaining(&balances), 1);
+        assert_eq!(credit(&mut balances, &[3; 32], 1), Ok(1));
+    }
+}
@@ -0,0 +1,57 @@
+//! Rejecting enqueues while the crank is stalled
+//!
+//! Nothing bounds how long an entry waits if nobody drains the queue, and in a market every
+//! order queued behind a stalled crank fills later at a staler price. States with
+//! [`AsyncState::MAX_QUEUE_AGE_SLOTS`](crate::AsyncState::MAX_QUEUE_AGE_SLOTS) set trip a
+//! circuit breaker once the head of a queue has waited that long: queueing, committing and
+//! replacing fail with [`STALLED`] until drains catch up, after which they are accepted again
+//! without anyone resetting the breaker.
+//!
+//! The head is what a crank drains next, so its age is how far the crank has fallen behind.
+//! Entries further back in a queue ordered by priority can wait longer without the crank
+//! having stalled, e.g. under a low bid, and don't trip the breaker. Reveals of earlier
+//! commitments are still accepted, as their users committed before the crank stalled and
+//! would otherwise lose their commitment's priority.
+
+use pinocchio::{program_error::ProgramError, ProgramResult};
+
+use crate::AsyncState;
+
+/// Custom program error for an enqueue while the crank is stalled, spelling `APQ` followed
+/// by 8
+pub const STALLED: u32 = 0x4150_5108;
+
+/// Whether the head of a queue, queued at `head`, has waited more than `max_age` at `now`.
+/// Zero never stalls.
+pub fn is_stalled(head: u64, now: u64, max_age: u64) -> bool {
+    max_age != 0 && head.saturating_add(max_age) < now
+}
+
+/// Fails with [`STALLED`] if the head of any of `state`'s queues has waited more than
+/// [`AsyncState::MAX_QUEUE_AGE_SLOTS`] at `now`
+pub fn check<S: AsyncState>(state: &S, now: u64) -> ProgramResult {
+    if S::MAX_QUEUE_AGE_SLOTS == 0 {
+        return Ok(());
+    }
+    let stalled = (0..S::QUEUES.len())
+        .filter_map(|queue| state.queue_head(queue))
+        .any(|head| is_stalled(head, now, S::MAX_QUEUE_AGE_SLOTS));
+    match stalled {
+        true => Err(ProgramError::Custom(STALLED)),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_stalled() {
+        assert!(!is_stalled(10, 1_000, 0));
+        assert!(!is_stalled(10, 15, 5));
+        assert!(is_stalled(10, 16, 5));
+        // Scheduled for later
+        assert!(!is_stalled(100, 16, 5));
+    }
+}
@@ -0,0 +1,386 @@
+//! Async instructions queueing follow-ups
+//!
+//! Some steps can only run once an earlier one has, e.g. a matched trade settling in the slot
+//! after it filled. Instead of a keeper or the user queueing the next step, states with
+//! [`AsyncState::CHAINED`] process entries with [`AsyncState::process_chained_entry`], which
+//! can queue follow-ups on the [`Chain`] it is handed, e.g. through
+//! [`AsyncIx::process_chained`](crate::AsyncIx::process_chained). The dispatch drains them
+//! with [`drain`], one entry at a time, failures aborting the drain.
+//!
+//! Follow-ups are only buffered while the instruction runs, since the state is borrowed by it,
+//! and [`drain`] queues them with [`AsyncState::queue_follow_up`] once it succeeded. They go
+//! through the same checks as any enqueue, [`AsyncState::validate_enqueue`] without accounts,
+//! the [`breaker`](crate::breaker) and the rate limit of the user who queued the chain, and pay
+//! the protocol fee. A follow-up failing them, or not fitting in the queue, is dropped rather
+//! than failing a drain that would then fail again on the same entry. It doesn't count against
+//! the rate limit, and what its parent's budget paid for it accrues as protocol fees.
+//! Follow-ups are queued at the time of the drain processing their parent, which never
+//! processes anything dated the slot it runs in, see
+//! [`drain_cutoff`](crate::ordering::drain_cutoff), so a chain advances at most one step per
+//! slot. Scheduled keys date them no earlier than that either, see
+//! [`TimestampKey`](crate::ordering::TimestampKey).
+//!
+//! Follow-ups escrow nothing: a chain is paid for by the [`AsyncState::DEPOSIT`] of the entry
+//! a user queued at its root. Every entry carries a [`Link`] with its depth and what is left
+//! of that budget, which the program stores with it, away from anything users queue. Each
+//! follow-up costs its crank bounty and fee out of its parent's budget, and they split what
+//! is left of it between them. Every instruction can queue at most [`MAX_FOLLOW_UPS`], and
+//! chains are at most [`MAX_CHAIN_DEPTH`] steps deep, so one entry can only ever lead to a
+//! bounded number of others, all of them paid for.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey, ProgramResult};
+
+use crate::AsyncState;
+
+/// Custom program error for an instruction queueing more follow-ups than allowed, deeper, or
+/// than its budget pays for, spelling `APQ` followed by 10
+pub const CHAIN_LIMIT: u32 = 0x4150_5110;
+
+/// Follow-ups one instruction can queue
+pub const MAX_FOLLOW_UPS: usize = 4;
+
+/// Steps a chain can take after the instruction queued by a user, which is at depth zero
+pub const MAX_CHAIN_DEPTH: u8 = 8;
+
+/// Where an entry is in its chain, stored by the program with the entry
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Link {
+    /// Lamports left to pay for follow-ups, out of the root's deposit
+    pub budget: u64,
+    /// Steps after the root
+    pub depth: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    _padding: [u8; 7],
+}
+
+crate::impl_layout_hash!(Link {
+    budget: u64,
+    depth: u8,
+    _padding: [u8; 7]
+});
+
+impl Link {
+    /// The link of an entry a user queued, whose deposit pays for its chain
+    pub fn root<S: AsyncState>() -> Self {
+        Link {
+            budget: S::DEPOSIT,
+            ..Default::default()
+        }
+    }
+}
+
+/// Follow-ups an instruction being processed queues, see
+/// [`AsyncState::process_chained_entry`]
+pub struct Chain<Ix, Args> {
+    /// Of the instruction being processed
+    link: Link,
+    /// Lamports each follow-up costs, its crank bounty and fee
+    cost: u64,
+    follow_ups: Vec<(Ix, Args)>,
+}
+
+impl<Ix, Args> Chain<Ix, Args> {
+    /// The follow-ups of an instruction at `link`, costing `cost` lamports each
+    pub fn new(link: Link, cost: u64) -> Self {
+        Chain {
+            link,
+            cost,
+            follow_ups: Vec::new(),
+        }
+    }
+
+    /// Queues `ix` with `args` once the instruction being processed succeeds. Fails with
+    /// [`CHAIN_LIMIT`] past [`MAX_FOLLOW_UPS`] or [`MAX_CHAIN_DEPTH`], or past what the
+    /// instruction's budget pays for.
+    pub fn enqueue(&mut self, ix: Ix, args: Args) -> ProgramResult {
+        let count = self.follow_ups.len() + 1;
+        let spent = self.cost.saturating_mul(count as u64);
+        if self.link.depth >= MAX_CHAIN_DEPTH || count > MAX_FOLLOW_UPS || spent > self.link.budget
+        {
+            return Err(ProgramError::Custom(CHAIN_LIMIT));
+        }
+        self.follow_ups.push((ix, args));
+        Ok(())
+    }
+
+    /// Follow-ups in the order they were queued
+    pub fn follow_ups(&self) -> &[(Ix, Args)] {
+        &self.follow_ups
+    }
+
+    /// The link of each follow-up, one step deeper, with an even share of what they left of
+    /// the budget
+    pub fn follow_up_link(&self) -> Link {
+        let count = self.follow_ups.len() as u64;
+        let left = self.link.budget - self.cost * count;
+        Link {
+            budget: left / count.max(1),
+            depth: self.link.depth + 1,
+            ..Default::default()
+        }
+    }
+}
+
+/// Processes due entries at `due` with [`AsyncState::process_chained_entry`] until `is_done`,
+/// follow-ups costing `cost` lamports each. Each one's follow-ups are queued with `queue`,
+/// which checks and charges them like any enqueue by the user given, and dropped if it fails.
+/// Returns the number of entries processed.
+pub fn drain<S: AsyncState>(
+    state: &mut S,
+    due: u64,
+    cost: u64,
+    is_done: impl Fn(u64) -> bool,
+    mut queue: impl FnMut(&mut S, &Pubkey, &S::AsyncIx, &S::QueueArgs, Link) -> ProgramResult,
+) -> Result<u64, ProgramError> {
+    let mut processed = 0;
+    while !is_done(processed) && state.has_pending_async(due) {
+        let Some(entry) = state.pop_entry() else {
+            break;
+        };
+        let (user, link) = state.chain_link(&entry)?;
+        let mut chain = Chain::new(link, cost);
+        state.process_chained_entry(&entry, &mut chain)?;
+        state.on_item_processed(&entry)?;
+        processed += 1;
+
+        let link = chain.follow_up_link();
+        for (ix, args) in &chain.follow_ups {
+            if queue(state, &user, ix, args, link).is_err() {
+                crate::log_error!("Dropping follow-up at depth {}", link.depth);
+            }
+        }
+    }
+    Ok(processed)
+}
+
+#[cfg(test)]
+mod tests {
+    use pinocchio::account_info::AccountInfo;
+    use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+    use super::*;
+    use crate::{
+        ordering::FifoKey,
+        queue::{peek_min, pop_min, Entry},
+        AsyncIx, FromBytes, SyncIx,
+    };
+
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    struct Step;
+
+    impl FromBytes for Step {
+        type Target<'a> = Step;
+        type TargetMut<'a> = Step;
+        fn from_bytes(_bytes: &[u8]) -> Result<Step, ProgramError> {
+            Ok(Step)
+        }
+        fn from_bytes_mut(_bytes: &mut [u8]) -> Result<Step, ProgramError> {
+            Ok(Step)
+        }
+    }
+
+    impl SyncIx for Step {
+        fn process<S: AsyncState>(&self, _: &[u8], _: &[AccountInfo], _: &mut S) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    impl AsyncIx for Step {
+        type Args = ();
+        fn process<S: AsyncState>(&self, _args: &(), _state: &mut S) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    type Tree = RedBlackTree<FifoKey, Link, 16>;
+
+    /// Stores the link of each entry, whose processing queues `follow_ups`
+    struct Mock {
+        queue: Box<Tree>,
+        seq: u64,
+        follow_ups: usize,
+    }
+
+    impl Mock {
+        fn new(follow_ups: usize) -> Self {
+            let mut queue: Box<Tree> = bytemuck::zeroed_box();
+            queue.initialize();
+            Mock {
+                queue,
+                seq: 0,
+                follow_ups,
+            }
+        }
+
+        fn insert(&mut self, link: Link, now: u64) -> ProgramResult {
+            let key = FifoKey {
+                slot: now,
+                seq: self.seq,
+            };
+            self.seq += 1;
+            self.queue
+                .insert(key, link)
+                .ok_or(ProgramError::AccountDataTooSmall)?;
+            Ok(())
+        }
+
+        fn links(&self) -> Vec<(u64, Link)> {
+            self.queue
+                .iter()
+                .map(|(key, link)| (key.slot, *link))
+                .collect()
+        }
+    }
+
+    impl FromBytes for Mock {
+        type Target<'a> = &'a Mock;
+        type TargetMut<'a> = &'a mut Mock;
+        fn from_bytes(_bytes: &[u8]) -> Result<&Mock, ProgramError> {
+            Err(ProgramError::InvalidAccountData)
+        }
+        fn from_bytes_mut(_bytes: &mut [u8]) -> Result<&mut Mock, ProgramError> {
+            Err(ProgramError::InvalidAccountData)
+        }
+    }
+
+    impl AsyncState for Mock {
+        type SyncIx = Step;
+        type AsyncIx = Step;
+        type Payload = Link;
+        type QueueArgs = ();
+        type Key = FifoKey;
+
+        const CRANK_BOUNTY: u64 = 10;
+        const DEPOSIT: u64 = 30;
+        const CHAINED: bool = true;
+
+        fn initialize(&mut self) {}
+
+        fn queue_args(_user: &AccountInfo, _data: &[u8]) -> Result<(), ProgramError> {
+            Ok(())
+        }
+
+        fn queue_async(&mut self, _ix: &Step, _args: &(), now: u64) -> ProgramResult {
+            self.insert(Link::root::<Self>(), now)
+        }
+
+        fn queue_follow_up(
+            &mut self,
+            _ix: &Step,
+            _args: &(),
+            link: Link,
+            now: u64,
+        ) -> ProgramResult {
+            self.insert(link, now)
+        }
+
+        fn chain_link(&self, entry: &Entry<FifoKey, Link>) -> Result<(Pubkey, Link), ProgramError> {
+            Ok(([1; 32], entry.value))
+        }
+
+        fn process_chained_entry(
+            &mut self,
+            _entry: &Entry<FifoKey, Link>,
+            chain: &mut Chain<Step, ()>,
+        ) -> ProgramResult {
+            for _ in 0..self.follow_ups {
+                chain.enqueue(Step, ())?;
+            }
+            Ok(())
+        }
+
+        fn process_next_async(&mut self) -> ProgramResult {
+            Ok(())
+        }
+
+        fn has_pending_async(&self, now: u64) -> bool {
+            self.peek_entry().is_some_and(|entry| entry.key.slot <= now)
+        }
+
+        fn entries(&self) -> impl Iterator<Item = (&FifoKey, &Link)> {
+            self.queue.iter()
+        }
+
+        fn peek_entry(&self) -> Option<&Entry<FifoKey, Link>> {
+            peek_min(&self.queue).map(|(_addr, node)| node)
+        }
+
+        fn pop_entry(&mut self) -> Option<Entry<FifoKey, Link>> {
+            pop_min(&mut self.queue)
+        }
+
+        fn process_entry(&mut self, _entry: &Entry<FifoKey, Link>) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    fn link(budget: u64, depth: u8) -> Link {
+        Link {
+            budget,
+            depth,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_enqueue() {
+        let limit = Err(ProgramError::Custom(CHAIN_LIMIT));
+
+        // The budget pays for three
+        let mut chain = Chain::new(link(30, 0), 10);
+        for _ in 0..3 {
+            chain.enqueue(Step, ()).unwrap();
+        }
+        assert_eq!(chain.enqueue(Step, ()), limit);
+        assert_eq!(chain.follow_up_link(), link(0, 1));
+
+        let mut chain = Chain::new(link(u64::MAX, 0), 0);
+        for _ in 0..MAX_FOLLOW_UPS {
+            chain.enqueue(Step, ()).unwrap();
+        }
+        assert_eq!(chain.enqueue(Step, ()), limit);
+
+        let mut chain = Chain::new(link(u64::MAX, MAX_CHAIN_DEPTH), 0);
+        assert_eq!(chain.enqueue(Step, ()), limit);
+        let mut chain = Chain::new(link(25, MAX_CHAIN_DEPTH - 1), 10);
+        chain.enqueue(Step, ()).unwrap();
+        assert_eq!(chain.follow_up_link(), link(15, MAX_CHAIN_DEPTH));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut state = Mock::new(2);
+        state.queue_async(&Step, &(), 0).unwrap();
+        let queue = |state: &mut Mock, _: &Pubkey, ix: &Step, args: &(), link| {
+            state.queue_follow_up(ix, args, link, 1)
+        };
+
+        // The follow-ups split what they left of the root's deposit
+        assert_eq!(drain(&mut state, 0, 10, |_| false, queue), Ok(1));
+        assert_eq!(state.links(), [(1, link(5, 1)); 2]);
+
+        state.follow_ups = 0;
+        assert_eq!(
+            drain(&mut state, 1, 10, |processed| processed == 1, queue),
+            Ok(1)
+        );
+        assert_eq!(state.queue.len(), 1);
+        // Which can't pay for more
+        state.follow_ups = 1;
+        assert_eq!(
+            drain(&mut state, 1, 10, |_| false, queue),
+            Err(ProgramError::Custom(CHAIN_LIMIT))
+        );
+
+        // Follow-ups failing the enqueue checks are dropped
+        state.queue = Mock::new(0).queue;
+        state.queue_async(&Step, &(), 1).unwrap();
+        let reject =
+            |_: &mut Mock, _: &Pubkey, _: &Step, _: &(), _| Err(ProgramError::InvalidArgument);
+        assert_eq!(drain(&mut state, 1, 10, |_| false, reject), Ok(1));
+        assert!(state.queue.is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+//! Closing a state account to reclaim its rent
+//!
+//! A state that is no longer used holds its rent, and whatever escrow and fees are left in it,
+//! until it is closed. The close state instruction, signed by the admin, pays every lamport
+//! of the state account to a destination and zeroes its data, but only once nothing in it is
+//! owed to anyone, see [`check_settled`]: the commitments are empty, the fees are withdrawn
+//! and the state settled its queue and whatever else it keeps for its users, e.g. balances.
+//! States are never settled unless they say so, see [`AsyncState::is_settled`].
+//!
+//! An account left without lamports is removed at the end of the transaction, but until then,
+//! or for good if the same transaction funds it again, its zeroed data would look like a state
+//! to initialize to whoever sent the next instruction. So instead of all zeroes, a closed
+//! state's header carries the [`CLOSED`] version, which dispatch refuses to initialize, load or
+//! migrate.
+
+use pinocchio::{account_info::AccountInfo, program_error::ProgramError, ProgramResult};
+use sokoban::NodeAllocatorMap;
+
+use crate::{escrow, header::StateHeader, AsyncState};
+
+/// Custom program error for closing a state that still owes lamports, spelling `APQ`
+/// followed by E
+pub const NOT_SETTLED: u32 = 0x4150_510E;
+
+/// Header version of a closed state
+pub const CLOSED: u32 = u32::MAX;
+
+/// Fails with [`NOT_SETTLED`] unless the queue, the commitments and the fees of `state` with
+/// `header` are settled
+pub fn check_settled<S: AsyncState>(state: &mut S, header: &StateHeader) -> ProgramResult {
+    let commitments = state.commitments().map_or(0, |store| store.len());
+    if !state.is_settled() || commitments != 0 || header.fees.accrued != 0 {
+        return Err(ProgramError::Custom(NOT_SETTLED));
+    }
+    Ok(())
+}
+
+/// Zeroes the state `data` but for the [`CLOSED`] version
+pub fn mark_closed(data: &mut [u8]) -> ProgramResult {
+    data.fill(0);
+    StateHeader::new(CLOSED).write(data)
+}
+
+/// Marks the `account` holding `data` closed and pays all its lamports to `destination`
+pub fn close(data: &mut [u8], account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+    if destination.key() == account.key() {
+        return Err(ProgramError::InvalidArgument);
+    }
+    mark_closed(data)?;
+    escrow::pay(account, destination, account.lamports())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrate::{migrate_state, Migratable};
+
+    struct V3;
+
+    impl Migratable for V3 {
+        const VERSION: u32 = 3;
+        fn migrate(_from_version: u32, _data: &mut [u8]) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mark_closed() {
+        let mut data = vec![7; StateHeader::LEN + 16];
+        mark_closed(&mut data).unwrap();
+        assert_eq!(StateHeader::read(&data).unwrap().version, CLOSED);
+        assert!(data[4..].iter().all(|byte| *byte == 0));
+        // Not an older layout to migrate either
+        assert_eq!(
+            migrate_state::<V3>(&mut data),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! Commit-reveal for sealed async instructions
+//!
+//! Queued instructions are public as soon as they land, so anyone watching can trade ahead of
+//! them before they execute. Instead, a user can commit to [`commitment_hash`] of their async
+//! instruction data and reveal the salt and data later. The revealed instruction is queued
+//! with the time and sequence number of its commitment, so it keeps its place in line.
+//!
+//! The crank bounty is escrowed on commit, so spamming the store costs the same as spamming
+//! the queue. Commitments not revealed within
+//! [`AsyncState::COMMIT_EXPIRY_SLOTS`](crate::AsyncState::COMMIT_EXPIRY_SLOTS) can no longer
+//! be revealed and are purged, bounty and all, to make room for new ones.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey, ProgramResult};
+use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+pub const MAX_COMMITMENTS: usize = 256;
+
+pub type Hash = [u8; 32];
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Commitment {
+    /// Only this user can reveal, the hash is bound to them as well
+    pub user: Pubkey,
+    /// Time of the commit, on the state's [`Schedule`](crate::ordering::Schedule)
+    pub slot: u64,
+    /// Sequence number reserved for the revealed instruction
+    pub seq: u64,
+}
+
+crate::impl_layout_hash!(Commitment {
+    user: Pubkey,
+    slot: u64,
+    seq: u64
+});
+
+impl Commitment {
+    pub fn is_expired(&self, now: u64, expiry: u64) -> bool {
+        self.slot.saturating_add(expiry) < now
+    }
+}
+
+pub type Commitments = RedBlackTree<Hash, Commitment, MAX_COMMITMENTS>;
+
+/// What a user commits to: their key, a random salt and the async instruction data
+/// exactly as it would be sent to the queue tag
+pub fn commitment_hash(user: &Pubkey, salt: &[u8; 32], data: &[u8]) -> Hash {
+    solana_sha256_hasher::hashv(&[user, salt, data]).to_bytes()
+}
+
+/// Stores a new commitment, purging expired ones first
+pub fn commit(
+    store: &mut Commitments,
+    hash: Hash,
+    commitment: Commitment,
+    expiry: u64,
+) -> ProgramResult {
+    purge_expired(store, commitment.slot, expiry);
+
+    if store.get(&hash).is_some() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    store
+        .insert(hash, commitment)
+        .ok_or(ProgramError::AccountDataTooSmall)?;
+    Ok(())
+}
+
+/// Removes and returns `user`'s unexpired commitment to `hash`
+pub fn reveal(
+    store: &mut Commitments,
+    hash: &Hash,
+    user: &Pubkey,
+    now: u64,
+    expiry: u64,
+) -> Result<Commitment, ProgramError> {
+    let commitment = *store.get(hash).ok_or(ProgramError::InvalidArgument)?;
+    if commitment.user != *user {
+        return Err(ProgramError::IncorrectAuthority);
+    }
+    if commitment.is_expired(now, expiry) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    crate::ordering::check_slot(commitment.slot, now)?;
+
+    store.remove(hash);
+    Ok(commitment)
+}
+
+/// Drops every commitment that can no longer be revealed. Returns how many were purged.
+pub fn purge_expired(store: &mut Commitments, now: u64, expiry: u64) -> usize {
+    let expired: Vec<Hash> = store
+        .iter()
+        .filter(|(_, commitment)| commitment.is_expired(now, expiry))
+        .map(|(hash, _)| *hash)
+        .collect();
+    for hash in &expired {
+        store.remove(hash);
+    }
+    expired.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Box<Commitments> {
+        let mut store: Box<Commitments> = bytemuck::zeroed_box();
+        store.initialize();
+        store
+    }
+
+    #[test]
+    fn test_commit_reveal() {
+        let mut store = store();
+        let user = [1; 32];
+        let hash = commitment_hash(&user, &[9; 32], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        // Bound to the user and salt
+        assert_ne!(
+            hash,
+            commitment_hash(&[2; 32], &[9; 32], &[1, 0, 0, 0, 0, 0, 0, 0])
+        );
+        assert_ne!(
+            hash,
+            commitment_hash(&user, &[8; 32], &[1, 0, 0, 0, 0, 0, 0, 0])
+        );
+
+        let commitment = Commitment {
+            user,
+            slot: 10,
+            seq: 4,
+        };
+        commit(&mut store, hash, commitment, 5).unwrap();
+        assert_eq!(
+            commit(&mut store, hash, commitment, 5),
+            Err(ProgramError::AccountAlreadyInitialized)
+        );
+
+        assert_eq!(
+            reveal(&mut store, &hash, &[2; 32], 12, 5),
+            Err(ProgramError::IncorrectAuthority)
+        );
+        assert_eq!(
+            reveal(&mut store, &hash, &user, 16, 5),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(reveal(&mut store, &hash, &user, 15, 5), Ok(commitment));
+
+        // Revealed only once
+        assert!(store.is_empty());
+        assert_eq!(
+            reveal(&mut store, &hash, &user, 15, 5),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let mut store = store();
+        for slot in 0..4 {
+            let commitment = Commitment {
+                user: [1; 32],
+                slot,
+                seq: slot,
+            };
+            commit(&mut store, [slot as u8; 32], commitment, 10).unwrap();
+        }
+
+        // Committing at slot 12 purges the ones from slots 0 and 1
+        let late = Commitment {
+            user: [1; 32],
+            slot: 12,
+            seq: 4,
+        };
+        commit(&mut store, [4; 32], late, 10).unwrap();
+        assert_eq!(store.len(), 3);
+        assert!(store.get(&[1; 32]).is_none());
+        assert!(store.get(&[2; 32]).is_some());
+
+        assert_eq!(purge_expired(&mut store, 100, 10), 3);
+        assert!(store.is_empty());
+    }
+}
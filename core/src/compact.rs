@@ -0,0 +1,135 @@
+//! Garbage collection of the queue and its side stores
+//!
+//! Entries nobody drains pile up, e.g. behind a stalled crank or under a bid too low to ever
+//! reach the head, and so do expired commitments and stale rate-limit records. The compact
+//! instruction is permissionless and removes them:
+//!
+//! - entries queued longer than [`AsyncState::ENTRY_TTL_SLOTS`] ago, through
+//!   [`AsyncState::expire_entry`], oldest key first and at most `max` per instruction so it
+//!   fits in a transaction's compute. Their escrow is forfeited but for the crank bounty,
+//!   which pays the compactor like a drain would
+//! - commitments past [`AsyncState::COMMIT_EXPIRY_SLOTS`], see [`commit::purge_expired`]
+//! - rate-limit records from earlier slots, see [`rate_limit::purge_stale`]
+//!
+//! Once the queue is empty it can also rebuild its free list, see [`reset_if_empty`], so new
+//! entries are allocated from the front of the account again instead of wherever churn left
+//! free nodes. [`CompactedEvent`](crate::events::CompactedEvent) reports what was reclaimed,
+//! and [`Compacted::more`] tells the compactor to send another instruction.
+
+use pinocchio::program_error::ProgramError;
+use sokoban::{NodeAllocatorMap, RedBlackTree, SENTINEL};
+
+use crate::{
+    commit,
+    ordering::OrderingKey,
+    queue::{Payload, QueueKey},
+    rate_limit, AsyncState,
+};
+
+/// What a compaction reclaimed, reported as [`CompactedEvent`](crate::events::CompactedEvent)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Compacted {
+    /// Expired queue entries removed
+    pub expired: u32,
+    /// Whether expired entries are left, to remove in another instruction
+    pub more: bool,
+    /// Expired commitments purged
+    pub commitments: u32,
+    /// Stale rate-limit records purged
+    pub rate_limits: u32,
+    /// Whether an empty queue's free list was rebuilt
+    pub rebuilt: bool,
+}
+
+/// Whether an entry queued in `slot` has waited longer than `ttl` at `now`, zero never
+/// expiring
+pub fn is_expired(slot: u64, now: u64, ttl: u64) -> bool {
+    ttl != 0 && slot.saturating_add(ttl) < now
+}
+
+/// Removes up to `max` expired entries and purges the side stores, rebuilding the free list
+/// too if asked and nothing expired is left
+pub fn compact<S: AsyncState>(
+    state: &mut S,
+    now: u64,
+    max: u32,
+    rebuild: bool,
+) -> Result<Compacted, ProgramError> {
+    let max = max as usize;
+    let expired: Vec<S::Key> = state
+        .entries()
+        .map(|(key, _)| *key)
+        .filter(|key| is_expired(key.slot(), now, S::ENTRY_TTL_SLOTS))
+        .take(max.saturating_add(1))
+        .collect();
+
+    let mut compacted = Compacted {
+        more: expired.len() > max,
+        ..Default::default()
+    };
+    for key in expired.iter().take(max) {
+        state.expire_entry(key)?;
+        compacted.expired += 1;
+    }
+
+    if S::COMMIT_EXPIRY_SLOTS > 0 {
+        if let Some(store) = state.commitments() {
+            compacted.commitments =
+                commit::purge_expired(store, now, S::COMMIT_EXPIRY_SLOTS) as u32;
+        }
+    }
+    if let Some(store) = state.rate_limits() {
+        compacted.rate_limits = rate_limit::purge_stale(store, now) as u32;
+    }
+    compacted.rebuilt = rebuild && !compacted.more && state.rebuild_free_list();
+    Ok(compacted)
+}
+
+/// Resets an empty tree so its nodes are allocated from the start again. Returns whether it
+/// was empty.
+///
+/// Sokoban hands out the most recently freed node first, so after churn a tree's nodes are
+/// scattered over the whole account even when it holds few entries. Rebuilding a tree that
+/// isn't empty would mean copying its entries out, which doesn't fit on the heap for a full
+/// queue, so only empty ones are reset.
+pub fn reset_if_empty<K: QueueKey, V: Payload, const N: usize>(
+    tree: &mut RedBlackTree<K, V, N>,
+) -> bool {
+    if tree.len() != 0 || tree.root != SENTINEL {
+        return false;
+    }
+    bytemuck::write_zeroes(tree);
+    tree.initialize();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_expired() {
+        assert!(!is_expired(10, 1_000, 0));
+        assert!(!is_expired(10, 15, 5));
+        assert!(is_expired(10, 16, 5));
+        assert!(!is_expired(u64::MAX, u64::MAX, 5));
+    }
+
+    #[test]
+    fn test_reset_if_empty() {
+        let mut tree = RedBlackTree::<u64, u64, 8>::new();
+        for key in 0..8 {
+            tree.insert(key, key);
+        }
+        assert!(!reset_if_empty(&mut tree));
+        for key in [0, 1, 2, 3, 4, 5, 6, 7] {
+            tree.remove(&key);
+        }
+
+        // The last freed node would be handed out first
+        assert!(reset_if_empty(&mut tree));
+        assert_eq!(tree.insert(9, 9), Some(1));
+        assert_eq!(tree.insert(8, 8), Some(2));
+        assert_eq!(tree.len(), 2);
+    }
+}
@@ -0,0 +1,251 @@
+//! Operational parameters in a separate config account
+//!
+//! The [`AsyncState`](crate::AsyncState) consts are fixed at build time, so changing a fee or a
+//! rate limit means redeploying the program. States with
+//! [`AsyncState::CONFIG_ACCOUNT`](crate::AsyncState::CONFIG_ACCOUNT) set keep the parameters
+//! operators tune in a [`Config`] account instead, which the admin initializes and updates:
+//!
+//! - extra slots entries wait before drains see them due, on top of
+//!   [`AsyncState::ASYNC_DELAY_SLOTS`](crate::AsyncState::ASYNC_DELAY_SLOTS)
+//! - the protocol fee every queued, committed or replaced action pays, instead of
+//!   [`AsyncState::PROTOCOL_FEE`](crate::AsyncState::PROTOCOL_FEE), see [`crate::fees`]
+//! - a per-user rate limit overriding
+//!   [`AsyncState::MAX_ENQUEUES_PER_SLOT`](crate::AsyncState::MAX_ENQUEUES_PER_SLOT), see
+//!   [`crate::rate_limit`]
+//! - an operator allowlist, which permissions the crank to its operators instead of the
+//!   header's [`OperatorRegistry`](crate::operators::OperatorRegistry) while it isn't empty
+//! - [`pause`](crate::admin::pause) flags, on top of the admin's
+//! - a drain window after which undrained entries expire, see [`crate::window`]
+//!
+//! The config account is created by the client like the state account, zeroed, owned by the
+//! program and [`Config::LEN`] long. It is bound to one state account when initialized, and
+//! the admin initializes one config per state: dispatch takes any config initialized for the
+//! state it runs on.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+
+use crate::{admin::Admin, operators::MAX_OPERATORS, window, AsyncState};
+
+/// Empty operator slot
+const NO_OPERATOR: Pubkey = [0; 32];
+
+/// What the admin sets, the data of the init and update config instructions
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct ConfigParams {
+    /// [`pause`](crate::admin::pause) flags, zero when running normally
+    pub paused: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    _padding: [u8; 3],
+    /// Slots the entries of each slot can be drained in once due, zero for as long as they
+    /// are queued. See [`crate::window`].
+    pub drain_window: u32,
+    /// Slots entries wait on top of the state's delay, on its
+    /// [`Schedule`](crate::ordering::Schedule)
+    pub delay_slots: u64,
+    /// Protocol fee lamports each queued, committed or replaced action pays
+    pub fee: u64,
+    /// Instructions each user can queue or commit per slot, zero for the state's own limit
+    pub max_enqueues_per_slot: u64,
+    /// Keepers allowed to drain, empty slots all zeroes
+    pub operators: [Pubkey; MAX_OPERATORS],
+}
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Config {
+    /// Layout version, zero until initialized
+    pub version: u32,
+    /// [`Config::DISCRIMINATOR`] once initialized, telling configs apart from the program's
+    /// other accounts of the same version
+    pub discriminator: [u8; 4],
+    /// State account configured
+    pub state: Pubkey,
+    pub params: ConfigParams,
+}
+
+impl Config {
+    pub const LEN: usize = size_of::<Config>();
+
+    pub const VERSION: u32 = 1;
+
+    pub const DISCRIMINATOR: [u8; 4] = *b"cnfg";
+
+    /// Reads the config of `state` from `account`, checking it is initialized for it
+    pub fn load(
+        program_id: &Pubkey,
+        state: &Pubkey,
+        account: &AccountInfo,
+    ) -> Result<Self, ProgramError> {
+        if !account.is_owned_by(program_id) {
+            return Err(ProgramError::IllegalOwner);
+        }
+        Self::read(&account.try_borrow_data()?, state)
+    }
+
+    /// Reads the config of `state` from an account's `data`, e.g. fetched off-chain
+    pub fn read(data: &[u8], state: &Pubkey) -> Result<Self, ProgramError> {
+        let config: Config = data
+            .get(..Self::LEN)
+            .and_then(|bytes| bytemuck::try_pod_read_unaligned(bytes).ok())
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        if config.version != Self::VERSION {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if config.discriminator != Self::DISCRIMINATOR || config.state != *state {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(config)
+    }
+
+    pub fn write(&self, account: &AccountInfo) -> ProgramResult {
+        account
+            .try_borrow_mut_data()?
+            .get_mut(..Self::LEN)
+            .ok_or(ProgramError::AccountDataTooSmall)?
+            .copy_from_slice(bytemuck::bytes_of(self));
+        Ok(())
+    }
+
+    /// Fails with [`ProgramError::Immutable`] if any of the `flags` are paused
+    pub fn check_running(&self, flags: u8) -> ProgramResult {
+        if self.params.paused & flags != 0 {
+            return Err(ProgramError::Immutable);
+        }
+        Ok(())
+    }
+
+    /// Whether the allowlist has any operator, in which case only they can drain
+    pub fn is_permissioned(&self) -> bool {
+        self.params.operators.iter().any(|key| *key != NO_OPERATOR)
+    }
+
+    /// Checks that `operator` is a signing operator on the allowlist
+    pub fn check_operator(&self, operator: &AccountInfo) -> ProgramResult {
+        if !operator.is_signer()
+            || *operator.key() == NO_OPERATOR
+            || !self.params.operators.contains(operator.key())
+        {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        Ok(())
+    }
+
+    /// The per-user rate limit, `default` unless the config sets one
+    pub fn max_enqueues_per_slot(&self, default: u64) -> u64 {
+        match self.params.max_enqueues_per_slot {
+            0 => default,
+            limit => limit,
+        }
+    }
+}
+
+fn read_params(data: &[u8]) -> Result<ConfigParams, ProgramError> {
+    bytemuck::try_pod_read_unaligned(data).map_err(|_| ProgramError::InvalidInstructionData)
+}
+
+/// Handles the init config instruction, signed by the `state`'s admin. Data is the
+/// [`ConfigParams`], with a drain window only for states that support it.
+pub fn process_init<S: AsyncState>(
+    program_id: &Pubkey,
+    state: &Pubkey,
+    admin: &Admin,
+    authority: &AccountInfo,
+    account: &AccountInfo,
+    data: &[u8],
+) -> ProgramResult {
+    admin.check_authority(authority)?;
+    if !account.is_owned_by(program_id) {
+        return Err(ProgramError::IllegalOwner);
+    }
+    if account.data_len() != Config::LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let version = u32::from_le_bytes(account.try_borrow_data()?[..4].try_into().unwrap());
+    if version != 0 {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let params = read_params(data)?;
+    window::check_window::<S>(params.drain_window)?;
+    let config = Config {
+        version: Config::VERSION,
+        discriminator: Config::DISCRIMINATOR,
+        state: *state,
+        params,
+    };
+    config.write(account)
+}
+
+/// Handles the update config instruction, signed by the state's admin. Data is the new
+/// [`ConfigParams`], as for [`process_init`].
+pub fn process_update<S: AsyncState>(
+    config: &mut Config,
+    admin: &Admin,
+    authority: &AccountInfo,
+    data: &[u8],
+) -> ProgramResult {
+    admin.check_authority(authority)?;
+    let params = read_params(data)?;
+    window::check_window::<S>(params.drain_window)?;
+    config.params = params;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+
+    #[test]
+    fn test_read_needs_discriminator() {
+        let state = [1; 32];
+        let config = Config {
+            version: Config::VERSION,
+            discriminator: Config::DISCRIMINATOR,
+            state,
+            params: ConfigParams::default(),
+        };
+        assert_eq!(
+            Config::read(bytemuck::bytes_of(&config), &state),
+            Ok(config)
+        );
+
+        // A session of the same version naming the state at the same offset isn't a config
+        let session = Session::new(state, [2; 32], Default::default());
+        let mut data = vec![0; Config::LEN];
+        data[..Session::LEN].copy_from_slice(bytemuck::bytes_of(&session));
+        assert_eq!(
+            Config::read(&data, &state),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_params() {
+        let mut config = Config::default();
+        assert!(!config.is_permissioned());
+        assert_eq!(config.max_enqueues_per_slot(3), 3);
+        assert_eq!(config.check_running(crate::admin::pause::ALL), Ok(()));
+
+        config.params.operators[2] = [7; 32];
+        config.params.max_enqueues_per_slot = 1;
+        config.params.paused = crate::admin::pause::DRAIN;
+        assert!(config.is_permissioned());
+        assert_eq!(config.max_enqueues_per_slot(3), 1);
+        assert_eq!(
+            config.check_running(crate::admin::pause::DRAIN),
+            Err(ProgramError::Immutable)
+        );
+        assert_eq!(config.check_running(crate::admin::pause::ENQUEUE), Ok(()));
+        assert_eq!(
+            read_params(&[0; 3]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Named, checked accounts for an instruction
+//!
+//! Rather than destructuring `&[AccountInfo]` by hand and checking each account where it is
+//! used, an instruction declares the accounts it takes with
+//! [`derive_accounts!`](crate::derive_accounts) and parses them in one go,
+//!
+//! ```ignore
+//! apq_core::derive_accounts! {
+//!     /// Accounts of a deposit
+//!     pub struct DepositAccounts {
+//!         state: writable,
+//!         user: signer,
+//!         vault: key(&VAULT),
+//!         ..rem
+//!     }
+//! }
+//!
+//! let DepositAccounts { state, user, vault, rem } = DepositAccounts::parse(accounts)?;
+//! ```
+//!
+//! Each field is checked by the function in this module it names, with any arguments given
+//! after the account: [`account`], [`signer`], [`writable`], [`owner`] or [`key`]. The optional
+//! `..rest` field takes whatever accounts are left over, which are otherwise ignored.
+//!
+//! Accounts that only some instructions take, like the config account the dispatch passes
+//! when a state has one, stay in the rest and are taken from there.
+
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+
+/// Accounts parsed and checked from the front of an instruction's accounts
+pub trait AccountsCtx<'a>: Sized {
+    /// How many accounts are named, and so the fewest that parse
+    const LEN: usize;
+
+    /// Fails with [`ProgramError::NotEnoughAccountKeys`] if there are fewer than [`Self::LEN`]
+    /// accounts, or with the error of the first check that fails
+    fn parse(accounts: &'a [AccountInfo]) -> Result<Self, ProgramError>;
+}
+
+/// Any account
+pub fn account(_account: &AccountInfo) -> ProgramResult {
+    Ok(())
+}
+
+/// Fails with [`ProgramError::MissingRequiredSignature`] unless `account` signed
+pub fn signer(account: &AccountInfo) -> ProgramResult {
+    match account.is_signer() {
+        true => Ok(()),
+        false => Err(ProgramError::MissingRequiredSignature),
+    }
+}
+
+/// Fails with [`ProgramError::InvalidAccountData`] unless `account` is writable
+pub fn writable(account: &AccountInfo) -> ProgramResult {
+    match account.is_writable() {
+        true => Ok(()),
+        false => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+/// Fails with [`ProgramError::IncorrectProgramId`] unless `account` is owned by `program_id`
+pub fn owner(account: &AccountInfo, program_id: &Pubkey) -> ProgramResult {
+    match account.is_owned_by(program_id) {
+        true => Ok(()),
+        false => Err(ProgramError::IncorrectProgramId),
+    }
+}
+
+/// Fails with [`ProgramError::InvalidArgument`] unless `account` is at `address`
+pub fn key(account: &AccountInfo, address: &Pubkey) -> ProgramResult {
+    match account.key() == address {
+        true => Ok(()),
+        false => Err(ProgramError::InvalidArgument),
+    }
+}
+
+/// Declares a struct of named accounts and its [`AccountsCtx`](crate::context::AccountsCtx)
+/// implementation, which takes the fields in order and checks each with the function of
+/// [`context`](crate::context) it names
+///
+/// ```ignore
+/// derive_accounts! {
+///     pub struct SetVaultAccounts {
+///         state: account,
+///         admin: signer,
+///         vault: owner(&pinocchio_token::ID),
+///         ..rem
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! derive_accounts {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field:ident: $check:ident $(($($arg:expr),*))?,)*
+            $(..$rest:ident $(,)?)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name<'a> {
+            $($(#[$field_meta])* pub $field: &'a ::pinocchio::account_info::AccountInfo,)*
+            $(pub $rest: &'a [::pinocchio::account_info::AccountInfo],)?
+        }
+
+        impl<'a> $crate::context::AccountsCtx<'a> for $name<'a> {
+            const LEN: usize = 0 $(+ { let _ = stringify!($field); 1 })*;
+
+            fn parse(
+                accounts: &'a [::pinocchio::account_info::AccountInfo],
+            ) -> Result<Self, ::pinocchio::program_error::ProgramError> {
+                if accounts.len() < Self::LEN {
+                    return Err(::pinocchio::program_error::ProgramError::NotEnoughAccountKeys);
+                }
+                let _rest = accounts;
+                $(
+                    let ($field, _rest) = _rest
+                        .split_first()
+                        .ok_or(::pinocchio::program_error::ProgramError::NotEnoughAccountKeys)?;
+                    $crate::context::$check($field $($(, $arg)*)?)?;
+                )*
+                Ok($name {
+                    $($field,)*
+                    $($rest: _rest,)?
+                })
+            }
+        }
+    };
+}
+
+derive_accounts! {
+    /// The state and the user every core instruction starts with, as
+    /// [`process_with`](crate::dispatch::process_with) takes them
+    pub struct StateAccounts {
+        state: account,
+        user: account,
+        ..rem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    derive_accounts! {
+        // Only ever fails to parse here
+        #[allow(dead_code)]
+        struct Checked {
+            state: writable,
+            user: signer,
+            vault: key(&[1; 32]),
+        }
+    }
+
+    #[test]
+    fn test_accounts() {
+        assert_eq!(StateAccounts::LEN, 2);
+        assert_eq!(Checked::LEN, 3);
+        assert!(matches!(
+            StateAccounts::parse(&[]),
+            Err(ProgramError::NotEnoughAccountKeys)
+        ));
+        assert!(matches!(
+            Checked::parse(&[]),
+            Err(ProgramError::NotEnoughAccountKeys)
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+//! Calling a program built on apq_core from another program
+//!
+//! Builders for the default dispatch's queue, drain and cancel instructions, in the style of
+//! `pinocchio_system`: the accounts are named fields, and `invoke` builds the account metas in
+//! the dispatch's `[state, user, remaining..]` order. A calling program acting through a PDA
+//! signs for it with `invoke_signed`, e.g. with seeds built by `pinocchio::signer!`.
+
+use pinocchio::{
+    account_info::AccountInfo,
+    cpi::{invoke_signed, slice_invoke_signed},
+    instruction::{AccountMeta, Instruction, Signer},
+    pubkey::Pubkey,
+    ProgramResult,
+};
+
+use crate::{dispatch::tag, queue::QueueKey};
+
+/// Queues an async instruction. See [`tag::QUEUE`].
+pub struct QueueAsync<'a> {
+    /// The apq program
+    pub program_id: &'a Pubkey,
+    pub state: &'a AccountInfo,
+    /// Escrows the crank bounty and priority bid, so signs
+    pub user: &'a AccountInfo,
+    pub system_program: &'a AccountInfo,
+    /// The encoded async instruction followed by its queue args
+    pub async_ix: &'a [u8],
+}
+
+impl QueueAsync<'_> {
+    #[inline(always)]
+    pub fn invoke(&self) -> ProgramResult {
+        self.invoke_signed(&[])
+    }
+
+    pub fn invoke_signed(&self, signers: &[Signer]) -> ProgramResult {
+        let accounts = [
+            AccountMeta::writable(self.state.key()),
+            AccountMeta::writable_signer(self.user.key()),
+            AccountMeta::readonly(self.system_program.key()),
+        ];
+        let data = data(tag::QUEUE, self.async_ix);
+        let instruction = Instruction {
+            program_id: self.program_id,
+            accounts: &accounts,
+            data: &data,
+        };
+        invoke_signed(
+            &instruction,
+            &[self.state, self.user, self.system_program],
+            signers,
+        )
+    }
+}
+
+/// Processes the due part of the queue. See [`tag::DRAIN`].
+pub struct Drain<'a> {
+    /// The apq program
+    pub program_id: &'a Pubkey,
+    pub state: &'a AccountInfo,
+    /// Paid the crank bounties, and signs in case the crank is permissioned
+    pub cranker: &'a AccountInfo,
+    /// The slot hashes sysvar for shuffled states, the other shards for sharded ones, or the
+    /// accounts the drained instructions reference
+    pub remaining: &'a [&'a AccountInfo],
+}
+
+impl Drain<'_> {
+    #[inline(always)]
+    pub fn invoke(&self) -> ProgramResult {
+        self.invoke_signed(&[])
+    }
+
+    pub fn invoke_signed(&self, signers: &[Signer]) -> ProgramResult {
+        let mut accounts = vec![
+            AccountMeta::writable(self.state.key()),
+            AccountMeta::writable_signer(self.cranker.key()),
+        ];
+        accounts.extend(
+            self.remaining
+                .iter()
+                .map(|account| AccountMeta::from(*account)),
+        );
+        let mut account_infos = vec![self.state, self.cranker];
+        account_infos.extend_from_slice(self.remaining);
+
+        let instruction = Instruction {
+            program_id: self.program_id,
+            accounts: &accounts,
+            data: &[tag::DRAIN],
+        };
+        slice_invoke_signed(&instruction, &account_infos, signers)
+    }
+}
+
+/// Cancels `user`'s instruction queued under `key`, refunding its escrow. See [`tag::CANCEL`].
+pub struct Cancel<'a, K: QueueKey> {
+    /// The apq program
+    pub program_id: &'a Pubkey,
+    pub state: &'a AccountInfo,
+    /// Who queued the instruction, so signs
+    pub user: &'a AccountInfo,
+    pub key: &'a K,
+}
+
+impl<K: QueueKey> Cancel<'_, K> {
+    #[inline(always)]
+    pub fn invoke(&self) -> ProgramResult {
+        self.invoke_signed(&[])
+    }
+
+    pub fn invoke_signed(&self, signers: &[Signer]) -> ProgramResult {
+        let accounts = [
+            AccountMeta::writable(self.state.key()),
+            AccountMeta::writable_signer(self.user.key()),
+        ];
+        let data = data(tag::CANCEL, bytemuck::bytes_of(self.key));
+        let instruction = Instruction {
+            program_id: self.program_id,
+            accounts: &accounts,
+            data: &data,
+        };
+        invoke_signed(&instruction, &[self.state, self.user], signers)
+    }
+}
+
+/// Instruction data for the default [`Tag`](crate::discriminator::Tag) discriminator
+fn data(tag: u8, data: &[u8]) -> Vec<u8> {
+    [&[tag], data].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data() {
+        assert_eq!(
+            data(tag::CANCEL, bytemuck::bytes_of(&7_u64)),
+            [tag::CANCEL, 7, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(data(tag::DRAIN, &[]), [tag::DRAIN]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Draining a large backlog across transactions
+//!
+//! A drain processes everything due unless the cranker caps it at `max_items`, e.g. to stay
+//! within the compute budget. Processed entries leave the queue, so the next drain resumes
+//! exactly at the first one left, and the [`DrainCursor`] in the
+//! [`StateHeader`](crate::header::StateHeader) records where that is.
+//!
+//! Two capped drains built from the same view of the queue, e.g. by competing keepers, would
+//! each process a batch where one was meant. A drain can name the cursor `nonce` it expects,
+//! and fails with [`STALE_CURSOR`] if another drain landed first, even in the same slot.
+//!
+//! The drain up to variant only processes entries queued up to a given slot, see
+//! [`replay_due`], so operators can settle what was queued by a past slot, e.g. an auction
+//! that closed then, and tests can drive processing one slot at a time.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{program_error::ProgramError, ProgramResult};
+
+use crate::queue::QueueKey;
+
+/// Custom program error for a drain expecting another cursor nonce, spelling `APQ` followed
+/// by 7
+pub const STALE_CURSOR: u32 = 0x4150_5107;
+
+/// Longest key the cursor records
+pub const MAX_CURSOR_KEY_LEN: usize = 32;
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct DrainCursor {
+    /// Drains so far
+    pub nonce: u64,
+    /// When the last drain ran, on the state's
+    /// [`AsyncState::SCHEDULE`](crate::AsyncState::SCHEDULE)
+    pub slot: u64,
+    /// Bytes of the key the next drain resumes at, see [`DrainCursor::next_key`]
+    pub next_key: [u8; MAX_CURSOR_KEY_LEN],
+    /// Length of `next_key`, zero if the queue was empty or the key too long to record
+    pub key_len: u8,
+    /// Whether the last drain stopped with entries still due
+    pub partial: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    _padding: [u8; 6],
+}
+
+crate::impl_layout_hash!(DrainCursor {
+    nonce: u64,
+    slot: u64,
+    next_key: [u8; MAX_CURSOR_KEY_LEN],
+    key_len: u8,
+    partial: u8,
+    _padding: [u8; 6]
+});
+
+impl DrainCursor {
+    /// Fails with [`STALE_CURSOR`] unless the nonce is `expected`, if the drain expects one
+    pub fn check_nonce(&self, expected: Option<u64>) -> ProgramResult {
+        match expected {
+            Some(nonce) if nonce != self.nonce => Err(ProgramError::Custom(STALE_CURSOR)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a drain at `now` that left `next` at the head of the queue
+    pub fn advance<K: QueueKey>(&mut self, now: u64, next: Option<&K>, partial: bool) {
+        let key = next
+            .map(bytemuck::bytes_of)
+            .filter(|key| key.len() <= MAX_CURSOR_KEY_LEN)
+            .unwrap_or_default();
+        *self = DrainCursor {
+            nonce: self.nonce + 1,
+            slot: now,
+            key_len: key.len() as u8,
+            partial: partial as u8,
+            ..Default::default()
+        };
+        self.next_key[..key.len()].copy_from_slice(key);
+    }
+
+    /// The key the next drain resumes at, if recorded
+    pub fn next_key<K: QueueKey>(&self) -> Option<K> {
+        let key = &self.next_key[..self.key_len as usize];
+        (key.len() == size_of::<K>()).then(|| bytemuck::pod_read_unaligned(key))
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.partial != 0
+    }
+}
+
+/// Drain instruction data, `[max_items: u32, nonce: u64]` with either left off from the end
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct DrainArgs {
+    /// Most entries to process, zero for no limit
+    pub max_items: u32,
+    /// The cursor nonce the drain expects
+    pub nonce: Option<u64>,
+}
+
+impl DrainArgs {
+    /// Bytes of the longest encoding
+    pub const LEN: usize = 12;
+
+    pub fn parse(data: &[u8]) -> Result<Self, ProgramError> {
+        let (max_items, nonce) = match data.len() {
+            0 => (0, None),
+            4 | Self::LEN => {
+                let (max_items, nonce) = data.split_at(4);
+                (
+                    u32::from_le_bytes(max_items.try_into().unwrap()),
+                    nonce.try_into().ok().map(u64::from_le_bytes),
+                )
+            }
+            _ => return Err(ProgramError::InvalidInstructionData),
+        };
+        Ok(DrainArgs { max_items, nonce })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = self.max_items.to_le_bytes().to_vec();
+        if let Some(nonce) = self.nonce {
+            data.extend_from_slice(&nonce.to_le_bytes());
+        }
+        data
+    }
+
+    /// Whether `processed` entries reach the limit
+    pub fn is_done(&self, processed: u64) -> bool {
+        self.max_items != 0 && processed >= u64::from(self.max_items)
+    }
+}
+
+/// The time a drain at `due` processes entries by, when it only processes entries queued up
+/// to `upto`. Entries queued up to `upto` are due `delay_slots` later, and the drain still
+/// can't process anything the clock hasn't made due.
+pub fn replay_due(due: u64, upto: Option<u64>, delay_slots: u64) -> u64 {
+    upto.map_or(due, |slot| due.min(slot.saturating_add(delay_slots)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ordering::{FifoKey, OrderingKey};
+
+    #[test]
+    fn test_cursor() {
+        let mut cursor = DrainCursor::default();
+        assert_eq!(cursor.check_nonce(Some(0)), Ok(()));
+
+        let key = FifoKey { slot: 3, seq: 9 };
+        cursor.advance(5, Some(&key), true);
+        assert_eq!(cursor.next_key::<FifoKey>(), Some(key));
+        assert!(cursor.is_partial());
+        assert_eq!(cursor.check_nonce(None), Ok(()));
+        assert_eq!(
+            cursor.check_nonce(Some(0)),
+            Err(ProgramError::Custom(STALE_CURSOR))
+        );
+
+        // Too long to record
+        cursor.advance(6, Some(&[0_u64; 5]), false);
+        assert_eq!((cursor.nonce, cursor.key_len), (2, 0));
+        assert_eq!(cursor.next_key::<FifoKey>(), None);
+    }
+
+    #[test]
+    fn test_drain_args() {
+        for args in [
+            DrainArgs::default(),
+            DrainArgs {
+                max_items: 5,
+                nonce: Some(7),
+            },
+        ] {
+            assert_eq!(DrainArgs::parse(&args.encode()), Ok(args));
+        }
+        assert_eq!(DrainArgs::parse(&[]), Ok(DrainArgs::default()));
+        assert_eq!(
+            DrainArgs::parse(&[0; 5]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert!(DrainArgs {
+            max_items: 2,
+            nonce: None
+        }
+        .is_done(2));
+        assert!(!DrainArgs::default().is_done(u64::MAX));
+    }
+
+    #[test]
+    fn test_replay_due() {
+        assert_eq!(replay_due(10, None, 2), 10);
+        assert_eq!(replay_due(10, Some(5), 2), 7);
+        // Bounded by the clock
+        assert_eq!(replay_due(10, Some(9), 2), 10);
+        assert_eq!(replay_due(10, Some(u64::MAX), 2), 10);
+
+        let key = FifoKey { slot: 5, seq: 0 };
+        let due = replay_due(10, Some(5), 2);
+        assert!(OrderingKey::<(), ()>::is_due(&key, due, 2));
+        let key = FifoKey { slot: 6, seq: 0 };
+        assert!(!OrderingKey::<(), ()>::is_due(&key, due, 2));
+    }
+}
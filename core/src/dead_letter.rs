@@ -0,0 +1,320 @@
+//! Handling async instructions that fail to process
+//!
+//! By default a failing instruction fails the whole drain, and since it stays at the front of
+//! the queue, every drain after it too. Programs that can't rule out failures pick another
+//! [`FailurePolicy`] and drain through [`drain`] instead, which pops entries with
+//! [`AsyncState::pop_entry`] and runs them with [`AsyncState::process_entry`].
+//!
+//! Failed entries are not rolled back, so `process_entry` must leave the state untouched when
+//! it errors. Dead-lettered entries are kept by the program through
+//! [`AsyncState::dead_letter`] for inspection until the registry authority purges them.
+
+use pinocchio::program_error::ProgramError;
+
+use crate::{ordering::OrderingKey, AsyncState};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FailurePolicy {
+    /// Fail the drain
+    Abort,
+    /// Drop failed entries
+    Skip,
+    /// Retry failed entries up to this many times once everything else due has been
+    /// processed, then dead-letter them
+    Retry(u8),
+    /// Move failed entries to the dead-letter store
+    DeadLetter,
+}
+
+/// Takes entries dated after `now` off the head of the queue, which could only get there
+/// through a skewed clock or a corrupt state. With a key not ordered by time first, such an
+/// entry would otherwise hold up everything behind it until its date. They are dead-lettered
+/// like failures under `policy`, or dropped under `Abort` and `Skip`. Returns how many were
+/// taken off.
+///
+/// Does nothing for [`OrderingKey::SCHEDULED`] keys, whose entries wait at the head until
+/// their date as intended.
+pub fn skip_future<S: AsyncState>(
+    state: &mut S,
+    now: u64,
+    policy: FailurePolicy,
+) -> Result<u64, ProgramError> {
+    if <S::Key as OrderingKey<S::AsyncIx, S::QueueArgs>>::SCHEDULED {
+        return Ok(0);
+    }
+
+    let mut skipped = 0;
+    while state
+        .peek_entry()
+        .is_some_and(|entry| entry.key.slot() > now)
+    {
+        let Some(entry) = state.pop_entry() else {
+            break;
+        };
+        skipped += 1;
+        crate::log_error!("Skipping async instruction dated {}", entry.key.slot());
+        if matches!(policy, FailurePolicy::Retry(_) | FailurePolicy::DeadLetter) {
+            state.dead_letter(&entry)?;
+        }
+    }
+    Ok(skipped)
+}
+
+/// Processes due entries until `is_done`, handling failures according to `policy`.
+/// Returns the number of entries drained, failed ones included.
+pub fn drain<S: AsyncState>(
+    state: &mut S,
+    now: u64,
+    policy: FailurePolicy,
+    is_done: impl Fn(u64) -> bool,
+) -> Result<u64, ProgramError> {
+    let mut drained = 0;
+    let mut failed = Vec::new();
+    while !is_done(drained) && state.has_pending_async(now) {
+        let Some(entry) = state.pop_entry() else {
+            break;
+        };
+        drained += 1;
+
+        match state.process_entry(&entry) {
+            Ok(()) => state.on_item_processed(&entry)?,
+            Err(err) if policy == FailurePolicy::Abort => return Err(err),
+            Err(_) => failed.push(entry),
+        }
+    }
+
+    // Later entries may have made the failed ones processable
+    if let FailurePolicy::Retry(retries) = policy {
+        for _ in 0..retries {
+            if failed.is_empty() {
+                break;
+            }
+            let mut still_failed = Vec::with_capacity(failed.len());
+            for entry in failed {
+                match state.process_entry(&entry) {
+                    Ok(()) => state.on_item_processed(&entry)?,
+                    Err(_) => still_failed.push(entry),
+                }
+            }
+            failed = still_failed;
+        }
+    }
+
+    if !failed.is_empty() {
+        crate::log_error!("{} async instructions failed", failed.len());
+        if policy != FailurePolicy::Skip {
+            for entry in &failed {
+                state.dead_letter(entry)?;
+            }
+        }
+    }
+
+    Ok(drained)
+}
+
+#[cfg(test)]
+mod tests {
+    use pinocchio::{account_info::AccountInfo, ProgramResult};
+    use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+    use super::*;
+    use crate::{
+        ordering::{FifoKey, OrderingKey},
+        queue::{peek_min, pop_min, Entry},
+        AsyncIx, FromBytes, SyncIx,
+    };
+
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    struct Noop;
+
+    impl FromBytes for Noop {
+        type Target<'a> = Noop;
+        type TargetMut<'a> = Noop;
+        fn from_bytes(_bytes: &[u8]) -> Result<Noop, ProgramError> {
+            Ok(Noop)
+        }
+        fn from_bytes_mut(_bytes: &mut [u8]) -> Result<Noop, ProgramError> {
+            Ok(Noop)
+        }
+    }
+
+    impl SyncIx for Noop {
+        fn process<S: AsyncState>(&self, _: &[u8], _: &[AccountInfo], _: &mut S) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    impl AsyncIx for Noop {
+        type Args = ();
+        fn process<S: AsyncState>(&self, _args: &(), _state: &mut S) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    type Tree = RedBlackTree<FifoKey, u64, 8>;
+
+    /// An entry holding `n` only succeeds once `n` others have
+    struct Mock {
+        queue: Box<Tree>,
+        dead: Box<Tree>,
+        processed: Vec<u64>,
+        hooked: Vec<u64>,
+    }
+
+    impl FromBytes for Mock {
+        type Target<'a> = &'a Mock;
+        type TargetMut<'a> = &'a mut Mock;
+        fn from_bytes(_bytes: &[u8]) -> Result<&Mock, ProgramError> {
+            Err(ProgramError::InvalidAccountData)
+        }
+        fn from_bytes_mut(_bytes: &mut [u8]) -> Result<&mut Mock, ProgramError> {
+            Err(ProgramError::InvalidAccountData)
+        }
+    }
+
+    impl AsyncState for Mock {
+        type SyncIx = Noop;
+        type AsyncIx = Noop;
+        type Payload = u64;
+        type QueueArgs = ();
+        type Key = FifoKey;
+
+        fn initialize(&mut self) {}
+
+        fn queue_args(_user: &AccountInfo, _data: &[u8]) -> Result<(), ProgramError> {
+            Ok(())
+        }
+
+        fn queue_async(&mut self, _ix: &Noop, _args: &(), _now: u64) -> ProgramResult {
+            Ok(())
+        }
+
+        fn process_next_async(&mut self) -> ProgramResult {
+            Ok(())
+        }
+
+        fn has_pending_async(&self, now: u64) -> bool {
+            self.peek_entry()
+                .is_some_and(|entry| OrderingKey::<Noop, ()>::is_due(&entry.key, now, 1))
+        }
+
+        fn entries(&self) -> impl Iterator<Item = (&FifoKey, &u64)> {
+            self.queue.iter()
+        }
+
+        fn peek_entry(&self) -> Option<&Entry<FifoKey, u64>> {
+            peek_min(&self.queue).map(|(_addr, node)| node)
+        }
+
+        fn pop_entry(&mut self) -> Option<Entry<FifoKey, u64>> {
+            pop_min(&mut self.queue)
+        }
+
+        fn process_entry(&mut self, entry: &Entry<FifoKey, u64>) -> ProgramResult {
+            if (self.processed.len() as u64) < entry.value {
+                return Err(ProgramError::Custom(0));
+            }
+            self.processed.push(entry.key.seq);
+            Ok(())
+        }
+
+        fn on_item_processed(&mut self, entry: &Entry<FifoKey, u64>) -> ProgramResult {
+            self.hooked.push(entry.key.seq);
+            Ok(())
+        }
+
+        fn dead_letter(&mut self, entry: &Entry<FifoKey, u64>) -> ProgramResult {
+            self.dead
+                .insert(entry.key, entry.value)
+                .ok_or(ProgramError::AccountDataTooSmall)?;
+            Ok(())
+        }
+    }
+
+    /// Seq 0 needs two successes first and seq 3 can never succeed
+    fn mock() -> Mock {
+        let mut queue: Box<Tree> = bytemuck::zeroed_box();
+        queue.initialize();
+        for (seq, value) in [2, 0, 0, 9].into_iter().enumerate() {
+            queue.insert(
+                FifoKey {
+                    slot: 0,
+                    seq: seq as u64,
+                },
+                value,
+            );
+        }
+        let mut dead: Box<Tree> = bytemuck::zeroed_box();
+        dead.initialize();
+        Mock {
+            queue,
+            dead,
+            processed: Vec::new(),
+            hooked: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_skip_future() {
+        let mut state = mock();
+        assert_eq!(skip_future(&mut state, 0, FailurePolicy::DeadLetter), Ok(0));
+
+        let future = FifoKey { slot: 9, seq: 4 };
+        while state.pop_entry().is_some() {}
+        state.queue.insert(future, 0);
+        assert_eq!(skip_future(&mut state, 1, FailurePolicy::DeadLetter), Ok(1));
+        assert!(state.queue.is_empty());
+        assert_eq!(state.dead.get(&future), Some(&0));
+
+        state.queue.insert(future, 0);
+        assert_eq!(skip_future(&mut state, 1, FailurePolicy::Skip), Ok(1));
+        assert_eq!(state.dead.len(), 1);
+    }
+
+    #[test]
+    fn test_failure_policies() {
+        let mut state = mock();
+        assert_eq!(
+            drain(&mut state, 1, FailurePolicy::Abort, |_| false),
+            Err(ProgramError::Custom(0))
+        );
+
+        let mut state = mock();
+        assert_eq!(drain(&mut state, 1, FailurePolicy::Skip, |_| false), Ok(4));
+        assert_eq!(state.processed, [1, 2]);
+        assert!(state.dead.is_empty() && state.queue.is_empty());
+
+        let mut state = mock();
+        assert_eq!(
+            drain(&mut state, 1, FailurePolicy::DeadLetter, |_| false),
+            Ok(4)
+        );
+        assert_eq!(state.processed, [1, 2]);
+        let dead: Vec<u64> = state.dead.iter().map(|(key, _)| key.seq).collect();
+        assert_eq!(dead, [0, 3]);
+
+        let mut state = mock();
+        assert_eq!(
+            drain(&mut state, 1, FailurePolicy::Retry(2), |_| false),
+            Ok(4)
+        );
+        assert_eq!(state.processed, [1, 2, 0]);
+        assert_eq!(state.hooked, state.processed);
+        let dead: Vec<u64> = state.dead.iter().map(|(key, _)| key.seq).collect();
+        assert_eq!(dead, [3]);
+    }
+
+    #[test]
+    fn test_drain_stops_when_done() {
+        let mut state = mock();
+        let two = |drained| drained == 2;
+        assert_eq!(drain(&mut state, 1, FailurePolicy::DeadLetter, two), Ok(2));
+        assert_eq!(state.processed, [1]);
+        assert_eq!(state.queue.len(), 2);
+
+        // The next drain resumes at the first entry left
+        assert_eq!(drain(&mut state, 1, FailurePolicy::DeadLetter, two), Ok(2));
+        assert_eq!(state.processed, [1, 2]);
+        assert!(state.queue.is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+//! Helpers for states deduplicating their pending entries
+//!
+//! Some programs only want one pending copy of an identical instruction, e.g. a user resending
+//! the same order after a timeout. The dispatch knows nothing about it: states that want it
+//! keep a [`DedupeIndex`] next to their queue, keyed by the [`fingerprint`] of the user,
+//! instruction and payload, and maintain it themselves:
+//!
+//! - in [`AsyncState::queue_async`](crate::AsyncState::queue_async), [`claim`] the new entry's
+//!   fingerprint before inserting it, and remove the entry it replaces if there is one
+//! - whenever an entry leaves the queue, drained or cancelled, [`release`] its fingerprint
+//!
+//! `queue_async` has no accounts to refund from, so [`Dedupe::Replace`] only suits states whose
+//! entries escrow nothing. Both are lookups in the index tree, so deduplication stays
+//! `O(log n)`.
+
+use bytemuck::Pod;
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey};
+use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+/// Custom program error for a rejected duplicate, spelling `APQ` followed by 2
+pub const DUPLICATE: u32 = 0x4150_5102;
+
+pub type Fingerprint = [u8; 32];
+
+/// Fingerprints of pending entries and the queue keys they are under
+pub type DedupeIndex<K, const N: usize> = RedBlackTree<Fingerprint, K, N>;
+
+/// What to do when an identical entry is already pending
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Dedupe {
+    /// Drop the pending entry for the new one, which gets the new one's place in line
+    Replace,
+    /// Fail with [`DUPLICATE`], keeping the pending entry
+    Reject,
+}
+
+/// Identifies an entry by what it does rather than where it is queued
+pub fn fingerprint(user: &Pubkey, ixn: u64, payload: &[u8]) -> Fingerprint {
+    solana_sha256_hasher::hashv(&[user, &ixn.to_le_bytes(), payload]).to_bytes()
+}
+
+/// Indexes an entry about to be queued under `key`. Returns the key of the pending duplicate
+/// it replaces, which the caller removes from the queue.
+pub fn claim<K, const N: usize>(
+    index: &mut DedupeIndex<K, N>,
+    fingerprint: Fingerprint,
+    key: K,
+    policy: Dedupe,
+) -> Result<Option<K>, ProgramError>
+where
+    K: Pod + Default,
+{
+    if let Some(pending) = index.get_mut(&fingerprint) {
+        if policy == Dedupe::Reject {
+            return Err(ProgramError::Custom(DUPLICATE));
+        }
+        return Ok(Some(std::mem::replace(pending, key)));
+    }
+    index
+        .insert(fingerprint, key)
+        .ok_or(ProgramError::AccountDataTooSmall)?;
+    Ok(None)
+}
+
+/// Forgets an entry that left the queue
+pub fn release<K, const N: usize>(index: &mut DedupeIndex<K, N>, fingerprint: &Fingerprint)
+where
+    K: Pod + Default,
+{
+    index.remove(fingerprint);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_release() {
+        let mut index: Box<DedupeIndex<u64, 16>> = bytemuck::zeroed_box();
+        index.initialize();
+        let order = fingerprint(&[1; 32], 0, &[5; 8]);
+        assert_ne!(order, fingerprint(&[2; 32], 0, &[5; 8]));
+        assert_ne!(order, fingerprint(&[1; 32], 1, &[5; 8]));
+
+        assert_eq!(claim(&mut index, order, 10, Dedupe::Reject), Ok(None));
+        assert_eq!(
+            claim(&mut index, order, 11, Dedupe::Reject),
+            Err(ProgramError::Custom(DUPLICATE))
+        );
+        assert_eq!(claim(&mut index, order, 12, Dedupe::Replace), Ok(Some(10)));
+        assert_eq!(index.get(&order), Some(&12));
+
+        // Once it's drained the same entry can be queued again
+        release(&mut index, &order);
+        assert_eq!(claim(&mut index, order, 13, Dedupe::Reject), Ok(None));
+    }
+}
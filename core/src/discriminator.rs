@@ -0,0 +1,211 @@
+//! Instruction discriminators
+//!
+//! The default dispatch expects a one byte [`tag`](crate::dispatch::tag), with sync and async
+//! variants as a `u64` at the start of the instruction's own data. Programs called from Anchor
+//! clients can use [`Sighash`] instead, which takes Anchor's 8 byte discriminators and turns
+//! them back into the tag and variant. Programs with fewer than 256 variants of each can use
+//! [`Compact`] to save seven bytes per instruction. See
+//! [`dispatch::process_with`](crate::dispatch::process_with).
+
+use std::{borrow::Cow, marker::PhantomData};
+
+use pinocchio::program_error::ProgramError;
+
+use crate::{dispatch::tag, strict::DataError};
+
+/// How instruction data tells the dispatch which instruction it is
+pub trait Discriminator {
+    /// Splits instruction data into its tag and the data the default dispatch expects for it,
+    /// i.e. starting with the variant for sync and async instructions
+    fn decode(data: &[u8]) -> Result<(u8, Cow<'_, [u8]>), ProgramError>;
+}
+
+/// The default one byte tag
+pub struct Tag;
+
+impl Discriminator for Tag {
+    fn decode(data: &[u8]) -> Result<(u8, Cow<'_, [u8]>), ProgramError> {
+        let (&tag, data) = data.split_first().ok_or(DataError::Empty)?;
+        Ok((tag, Cow::Borrowed(data)))
+    }
+}
+
+/// The default tag with sync and async variants in one byte instead of eight
+///
+/// The variant is widened back into a copy of the data. Only sync and queue instructions are
+/// compact, the others that carry an async instruction, like replace, keep its `u64` variant.
+pub struct Compact;
+
+impl Discriminator for Compact {
+    fn decode(data: &[u8]) -> Result<(u8, Cow<'_, [u8]>), ProgramError> {
+        let (&tag, data) = data.split_first().ok_or(DataError::Empty)?;
+        if !matches!(tag, tag::SYNC | tag::QUEUE) {
+            return Ok((tag, Cow::Borrowed(data)));
+        }
+        let (&variant, data) = data
+            .split_first()
+            .ok_or(ProgramError::InvalidInstructionData)?;
+        let data = [&(variant as u64).to_le_bytes(), data].concat();
+        Ok((tag, Cow::Owned(data)))
+    }
+}
+
+/// Anchor's discriminator of instruction `name`, the start of `sha256("global:<name>")`
+pub fn sighash(name: &str) -> [u8; 8] {
+    let hash = solana_sha256_hasher::hashv(&[b"global:", name.as_bytes()]).to_bytes();
+    let mut discriminator = [0; 8];
+    discriminator.copy_from_slice(&hash[..8]);
+    discriminator
+}
+
+/// Instruction names to derive [`Sighash`] discriminators from, in snake case like Anchor
+pub trait InstructionNames {
+    /// Sync instruction names, indexed by variant
+    const SYNC: &'static [&'static str];
+    /// Async instruction names, indexed by variant
+    const ASYNC: &'static [&'static str];
+    /// The program's own instruction names, indexed by tag from
+    /// [`tag::CUSTOM`](crate::dispatch::tag::CUSTOM)
+    const CUSTOM: &'static [&'static str] = &[];
+}
+
+/// Names of the instructions every program shares
+pub const SHARED: [(&str, u8); 31] = [
+    ("drain", tag::DRAIN),
+    ("grow", tag::GROW),
+    ("migrate", tag::MIGRATE),
+    ("set_operator", tag::SET_OPERATOR),
+    ("commit", tag::COMMIT),
+    ("reveal", tag::REVEAL),
+    ("purge_dead_letters", tag::PURGE_DEAD_LETTERS),
+    ("view", tag::VIEW),
+    ("cancel", tag::CANCEL),
+    ("pause", tag::PAUSE),
+    ("unpause", tag::UNPAUSE),
+    ("set_authority", tag::SET_AUTHORITY),
+    ("accept_authority", tag::ACCEPT_AUTHORITY),
+    ("replace", tag::REPLACE),
+    ("set_shard", tag::SET_SHARD),
+    ("cancel_all", tag::CANCEL_ALL),
+    ("compact", tag::COMPACT),
+    ("init_config", tag::INIT_CONFIG),
+    ("update_config", tag::UPDATE_CONFIG),
+    ("set_fee_destination", tag::SET_FEE_DESTINATION),
+    ("withdraw_fees", tag::WITHDRAW_FEES),
+    ("drain_upto", tag::DRAIN_UPTO),
+    ("create_instance", tag::CREATE_INSTANCE),
+    ("queue_many", tag::QUEUE_MANY),
+    ("verify_state", tag::VERIFY_STATE),
+    ("queue_relayed", tag::QUEUE_RELAYED),
+    ("create_session", tag::CREATE_SESSION),
+    ("revoke_session", tag::REVOKE_SESSION),
+    ("queue_delegated", tag::QUEUE_DELEGATED),
+    ("flush", tag::FLUSH),
+    ("close_state", tag::CLOSE_STATE),
+];
+
+/// Anchor-style 8 byte discriminators named by `N`
+///
+/// Each instruction is hashed until one matches, so instructions late in the lists cost a
+/// few more sha256 syscalls. Sync and async data is copied to put the variant in front.
+pub struct Sighash<N>(PhantomData<N>);
+
+impl<N: InstructionNames> Discriminator for Sighash<N> {
+    fn decode(data: &[u8]) -> Result<(u8, Cow<'_, [u8]>), ProgramError> {
+        if data.is_empty() {
+            return Err(DataError::Empty.into());
+        }
+        let (discriminator, data) = data
+            .split_first_chunk::<8>()
+            .ok_or(ProgramError::InvalidInstructionData)?;
+
+        if let Some((_, tag)) = SHARED
+            .iter()
+            .find(|(name, _)| sighash(name) == *discriminator)
+        {
+            return Ok((*tag, Cow::Borrowed(data)));
+        }
+
+        if let Some(index) = N::CUSTOM
+            .iter()
+            .position(|name| sighash(name) == *discriminator)
+        {
+            let tag = u8::try_from(index)
+                .ok()
+                .and_then(|index| tag::CUSTOM.checked_add(index))
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            return Ok((tag, Cow::Borrowed(data)));
+        }
+
+        for (tag, names) in [(tag::SYNC, N::SYNC), (tag::QUEUE, N::ASYNC)] {
+            if let Some(variant) = names
+                .iter()
+                .position(|name| sighash(name) == *discriminator)
+            {
+                let data = [&(variant as u64).to_le_bytes(), data].concat();
+                return Ok((tag, Cow::Owned(data)));
+            }
+        }
+        Err(ProgramError::InvalidInstructionData)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Names;
+
+    impl InstructionNames for Names {
+        const SYNC: &'static [&'static str] = &["refill_actions"];
+        const ASYNC: &'static [&'static str] = &["decrement", "increment"];
+        const CUSTOM: &'static [&'static str] = &["settle"];
+    }
+
+    #[test]
+    fn test_sighash() {
+        // Anchor's discriminator for `initialize`
+        assert_eq!(
+            sighash("initialize"),
+            [175, 175, 109, 31, 13, 152, 155, 237]
+        );
+
+        let data = [&sighash("increment")[..], &5u64.to_le_bytes()].concat();
+        let (tag, data) = Sighash::<Names>::decode(&data).unwrap();
+        assert_eq!(tag, tag::QUEUE);
+        assert_eq!(*data, [1u64.to_le_bytes(), 5u64.to_le_bytes()].concat());
+
+        let drain = sighash("drain");
+        let (tag, data) = Sighash::<Names>::decode(&drain).unwrap();
+        assert_eq!((tag, &*data), (tag::DRAIN, &[][..]));
+
+        let data = [&sighash("settle")[..], &[7]].concat();
+        let (tag, data) = Sighash::<Names>::decode(&data).unwrap();
+        assert_eq!((tag, &*data), (tag::CUSTOM, &[7][..]));
+
+        assert_eq!(
+            Sighash::<Names>::decode(&sighash("transfer")),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert_eq!(
+            Tag::decode(&[tag::SYNC, 0]),
+            Ok((tag::SYNC, Cow::Borrowed(&[0][..])))
+        );
+    }
+
+    #[test]
+    fn test_compact() {
+        let (tag, data) = Compact::decode(&[tag::QUEUE, 1, 5]).unwrap();
+        assert_eq!(tag, tag::QUEUE);
+        assert_eq!(*data, [1, 0, 0, 0, 0, 0, 0, 0, 5]);
+        assert_eq!(
+            Compact::decode(&[tag::DRAIN, 3]),
+            Ok((tag::DRAIN, Cow::Borrowed(&[3][..])))
+        );
+        assert_eq!(
+            Compact::decode(&[tag::SYNC]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert!(Compact::decode(&[]).is_err());
+    }
+}
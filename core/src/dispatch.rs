@@ -0,0 +1,1600 @@
+//! Default instruction dispatch
+//!
+//! Instruction data is a one byte tag followed by that tag's data, and the accounts are
+//! always `[state, user, remaining..]`:
+//!
+//! | tag | instruction        | data                     | remaining accounts |
+//! |-----|--------------------|--------------------------|--------------------|
+//! | 0   | sync               | sync ix                  | ix specific        |
+//! | 1   | queue async        | async ix + queue args    | system program     |
+//! | 2   | drain              | max items: u32, nonce: u64 [^2] | see [^1]    |
+//! | 3   | grow state         | target len: u64 (optional) | system program   |
+//! | 4   | migrate            |                          |                    |
+//! | 5   | set operator       | index: u8, operator      |                    |
+//! | 6   | commit             | commitment hash          | system program     |
+//! | 7   | reveal             | salt, async ix + args    | system program     |
+//! | 8   | purge dead letters |                          |                    |
+//! | 9   | view queue         | start: u32, count: u32   |                    |
+//! | 10  | cancel             | queue key                |                    |
+//! | 11  | pause              | flags: u8                |                    |
+//! | 12  | unpause            |                          |                    |
+//! | 13  | set authority      | proposed admin           |                    |
+//! | 14  | accept authority   |                          |                    |
+//! | 15  | replace            | old seq: u64, async ix + queue args | system program |
+//! | 16  | set shard          | index: u8                |                    |
+//! | 17  | cancel all         | max items: u32 [^3]      | owner [^3]         |
+//! | 18  | compact            | max items: u32, rebuild: u8 [^4] |            |
+//! | 19  | init config        | config params            | see [^5]           |
+//! | 20  | update config      | config params            | see [^5]           |
+//! | 21  | set fee destination | destination             |                    |
+//! | 22  | withdraw fees      | amount: u64 (optional)   | destination [^6]   |
+//! | 23  | drain up to        | slot: u64, drain args [^7] | see [^1]         |
+//! | 24  | create instance    | instance id: u64         | registry [^9]      |
+//! | 25  | queue into instances | count: u8, async ix + queue args | instances [^10], system program |
+//! | 26  | verify state [^11] |                          |                    |
+//! | 27  | queue relayed [^12] | expiry: u64, async ix + queue args | owner, instructions sysvar, system program |
+//! | 28  | create session [^13] | session key, expiry: u64, ixn mask: u64 | session |
+//! | 29  | revoke session [^13] |                        | session            |
+//! | 30  | queue delegated [^13] | async ix + queue args | owner, session, system program |
+//! | 31  | flush              | max items: u32, refund: u8 [^14] | owners [^14] |
+//! | 32  | close state        |                          | destination [^15]  |
+//! | 128.. | program specific | see [^8]                 | see [^8]           |
+//!
+//! [^1]: The slot hashes sysvar with [`AsyncState::SHUFFLE_SLOT`](crate::AsyncState::SHUFFLE_SLOT),
+//! the other shards with [`AsyncState::SHARDS`](crate::AsyncState::SHARDS), and otherwise the
+//! accounts the drained instructions reference, see [`crate::accounts`]
+//!
+//! [^2]: Both optional, see [`DrainArgs`]. Drains settling a slot's entries together stop at
+//! the first batch that reaches `max_items`.
+//!
+//! [^3]: Both optional. Without the owner, the user cancels their own entries. With it, the
+//! user is the admin cancelling the owner's, e.g. to liquidate them. `max items` defaults to
+//! all of them, and the return data is one byte, 1 if the owner has entries left.
+//!
+//! [^4]: Both optional. `max items` defaults to all expired entries, and the return data is
+//! one byte, 1 if expired entries are left. `rebuild` is 1 to rebuild the free list of an
+//! empty queue.
+//!
+//! [^5]: States with [`AsyncState::CONFIG_ACCOUNT`](crate::AsyncState::CONFIG_ACCOUNT) take
+//! the config account first, before any other remaining accounts, when queueing, draining,
+//! committing, revealing, replacing, compacting and initializing or updating the config.
+//!
+//! [^6]: The destination the admin set, see [`crate::fees`]
+//!
+//! [^7]: A drain that only processes entries queued up to `slot`, followed by the optional
+//! [`DrainArgs`], see [`cursor::replay_due`]
+//!
+//! [^8]: Tags from [`tag::CUSTOM`] on are the program's own, passed with all the accounts to
+//! [`Program::process_custom`]. The tags in between are reserved for core and fail, see
+//! [`DispatchTag`].
+//!
+//! [^9]: Only for states with [`AsyncState::INSTANCES`](crate::AsyncState::INSTANCES), which
+//! it initializes, see [`crate::instance`]
+//!
+//! [^10]: `count` other instances of the same registry as the state, which the instruction is
+//! queued into too, all or none of them. Not for states with a config account.
+//!
+//! [^11]: Anyone can check the state with
+//! [`AsyncState::check_invariants`](crate::AsyncState::check_invariants), which the result is
+//! logged from, failing if it doesn't hold. See [`crate::paranoid`].
+//!
+//! [^12]: Only for states with [`AsyncState::RELAYED`](crate::AsyncState::RELAYED). Queued as
+//! the owner's, who authorized it until `expiry` with a signature an earlier ed25519 program
+//! instruction verifies, while the user relays it and pays. See [`crate::relay`].
+//!
+//! [^13]: Only for states with [`AsyncState::SESSIONS`](crate::AsyncState::SESSIONS). The user
+//! creating a session delegates to the session key, which signs delegated queues as the user
+//! and queues them as the owner's. See [`crate::session`].
+//!
+//! [^14]: Both optional, like compacting's. With `refund` 1 the owners' entries are cancelled
+//! and refunded, otherwise entries are taken off the head and their escrow kept. The return
+//! data is one byte, 1 if entries are left. See [`crate::flush`].
+//!
+//! [^15]: Where all the state account's lamports go, once the queue, commitments, fees and the
+//! state's own balances are settled. See [`crate::close`].
+//!
+//! Anchor clients can call [`process_with`] a [`Sighash`](crate::discriminator::Sighash)
+//! discriminator instead of the tag, see [`crate::discriminator`]. [`process_with`] also takes
+//! the [`SlotSource`] to read the time from, so dispatch can run in unit tests without the clock
+//! sysvar.
+//!
+//! Queueing escrows the crank bounty, priority bid and deposit from `user`, who must sign. Draining
+//! pays the bounty of every processed item to `user`, who must be writable, and must be a
+//! signing operator if the crank is permissioned. Setting an operator is signed by the
+//! registry authority, as is purging dead letters. Pausing, unpausing and proposing a new
+//! admin are signed by the admin, and accepting by the proposed admin, see [`crate::admin`].
+//!
+//! Committing escrows the crank bounty and deposit, and revealing the priority bid, see
+//! [`crate::commit`]. Queueing and committing count against the user's rate limit, see
+//! [`crate::rate_limit`], and fail while the crank is stalled, see [`crate::breaker`].
+//! Every way of queueing, revealing and replacing lets the state reject the instruction first,
+//! see [`AsyncState::validate_enqueue`], as do follow-ups queued while draining, see
+//! [`crate::chain`]. Viewing doesn't write to any account, see [`crate::view`]. Cancelling is
+//! signed by the user who queued the instruction and refunds its escrow to them. Replacing is
+//! a cancel and a queue in one, refunding the old entry's escrow and escrowing the new one's.
+//! Cancelling all of an owner's entries refunds their escrow to the owner, see
+//! [`crate::queue::cancel_all`]. Compacting is permissionless and pays the crank bounty of
+//! every expired entry to `user`, see [`crate::compact`]. Initializing and updating the config
+//! are signed by the admin, see [`crate::config`]. Creating an instance is signed by the
+//! registry authority, who becomes the admin of the new state. Queueing into instances
+//! escrows into each of them like queueing into one, and logs which instance failed if any
+//! does. Relayed queueing escrows from the relaying user, and counts against the owner's rate
+//! limit like queueing, as does delegated queueing, escrowed by the session key. Creating a
+//! session is signed by the user, and revoking it by its user or key. Flushing is signed by
+//! the admin and works while paused, as does closing the state, after which it can't be
+//! initialized again. Queueing, committing and replacing escrow the protocol
+//! fee too, which the admin withdraws, see [`crate::fees`].
+//!
+//! States whose header holds another layout hash than the program's fail to load with
+//! [`layout::LAYOUT_MISMATCH`] until migrated, see [`crate::layout`].
+//!
+//! Empty instruction data fails with [`strict::EMPTY_DATA`], and bytes after what an
+//! instruction reads are rejected for states that are strict about it, see [`crate::strict`].
+//!
+//! Draining first takes entries dated in the future off the head of the queue, see
+//! [`dead_letter::skip_future`], and never processes entries queued in the slot it runs in,
+//! see [`ordering::drain_cutoff`]. With a config's drain window, it expires the entries of
+//! slots whose window closed before anything else, see [`crate::window`]. States with several
+//! queues are drained across them by their drain policy, see [`crate::queues`]. Sharded states
+//! only let users enqueue into their own shard, and drain every shard together, see
+//! [`crate::shard`]. Setting a shard's index is signed by the admin. States with overflow
+//! pages take them among the remaining accounts when queueing and draining, and a drain
+//! processes what is in the primary page, refilling it from the next page once it empties, see
+//! [`crate::paged_queue`].
+//!
+//! Queueing, revealing, draining and cancelling are counted in the header's
+//! [`QueueStats`](crate::stats::QueueStats).
+
+use std::{
+    borrow::Cow,
+    ops::{Deref, DerefMut},
+};
+
+use pinocchio::{
+    account_info::AccountInfo, cpi::set_return_data, program_error::ProgramError, pubkey::Pubkey,
+    ProgramResult,
+};
+
+use crate::{
+    admin::{pause, Admin},
+    breaker, chain, close,
+    commit::{self, Commitment},
+    compact,
+    config::{self, Config},
+    context::{AccountsCtx, StateAccounts},
+    cursor::{self, DrainArgs},
+    dead_letter::{self, FailurePolicy},
+    deser_containers::IntoOwned,
+    discriminator::{Discriminator, Tag},
+    emit, escrow,
+    events::{CompactedEvent, DrainedEvent, FlushedEvent, InitializedEvent},
+    flush,
+    grow::{self, GrowState},
+    header::StateHeader,
+    instance, layout, log_debug, log_error, log_info,
+    migrate::migrate_state,
+    migrate::Migratable,
+    ordering::{self, SlotSource, SysvarClock},
+    paged_queue::Pages,
+    paranoid, queues, relay,
+    session::{self, Session, SessionParams},
+    shard, shuffle, strict, view, window, AsyncState, FromBytes, Persist, Program, SyncIx,
+};
+
+/// Instruction tags, shared with clients building instructions
+pub mod tag {
+    pub const SYNC: u8 = 0;
+    pub const QUEUE: u8 = 1;
+    pub const DRAIN: u8 = 2;
+    pub const GROW: u8 = 3;
+    pub const MIGRATE: u8 = 4;
+    pub const SET_OPERATOR: u8 = 5;
+    pub const COMMIT: u8 = 6;
+    pub const REVEAL: u8 = 7;
+    pub const PURGE_DEAD_LETTERS: u8 = 8;
+    pub const VIEW: u8 = 9;
+    pub const CANCEL: u8 = 10;
+    pub const PAUSE: u8 = 11;
+    pub const UNPAUSE: u8 = 12;
+    pub const SET_AUTHORITY: u8 = 13;
+    pub const ACCEPT_AUTHORITY: u8 = 14;
+    pub const REPLACE: u8 = 15;
+    pub const SET_SHARD: u8 = 16;
+    pub const CANCEL_ALL: u8 = 17;
+    pub const COMPACT: u8 = 18;
+    pub const INIT_CONFIG: u8 = 19;
+    pub const UPDATE_CONFIG: u8 = 20;
+    pub const SET_FEE_DESTINATION: u8 = 21;
+    pub const WITHDRAW_FEES: u8 = 22;
+    pub const DRAIN_UPTO: u8 = 23;
+    pub const CREATE_INSTANCE: u8 = 24;
+    pub const QUEUE_MANY: u8 = 25;
+    pub const VERIFY_STATE: u8 = 26;
+    pub const QUEUE_RELAYED: u8 = 27;
+    pub const CREATE_SESSION: u8 = 28;
+    pub const REVOKE_SESSION: u8 = 29;
+    pub const QUEUE_DELEGATED: u8 = 30;
+    pub const FLUSH: u8 = 31;
+    pub const CLOSE_STATE: u8 = 32;
+
+    /// The last tag core dispatches, see [`DispatchTag`](super::DispatchTag)
+    pub const LAST: u8 = CLOSE_STATE;
+    /// The first tag left to the program, see [`DispatchTag`](super::DispatchTag)
+    pub const CUSTOM: u8 = 0x80;
+}
+
+/// Which range an instruction tag is in, so programs can add their own instructions without
+/// colliding with core's, present or future
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DispatchTag {
+    /// One of the [`tag`]s core dispatches
+    Core(u8),
+    /// Kept for core instructions to come, failing until then
+    Reserved(u8),
+    /// From [`tag::CUSTOM`] on, handled by [`Program::process_custom`]
+    Custom(u8),
+}
+
+impl DispatchTag {
+    pub fn parse(tag: u8) -> Self {
+        match tag {
+            0..=tag::LAST => DispatchTag::Core(tag),
+            tag::CUSTOM..=u8::MAX => DispatchTag::Custom(tag),
+            _ => DispatchTag::Reserved(tag),
+        }
+    }
+}
+
+pub fn process<P: Program>(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult
+where
+    P::State: Migratable + Persist,
+    for<'a> <P::Sync as FromBytes>::Target<'a>: Deref<Target = P::Sync>,
+    for<'a> <P::Async as FromBytes>::Target<'a>: Deref<Target = P::Async>,
+    for<'a> <P::State as FromBytes>::Target<'a>: Deref<Target = P::State>,
+    for<'a> <P::State as FromBytes>::TargetMut<'a>:
+        DerefMut<Target = P::State> + IntoOwned<P::State>,
+{
+    process_with::<P, Tag>(program_id, accounts, instruction_data, &SysvarClock)
+}
+
+/// [`process`] with instructions told apart by `D`, e.g. Anchor-style
+/// [`Sighash`](crate::discriminator::Sighash) discriminators, and the time read from `clock`
+pub fn process_with<P: Program, D: Discriminator>(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+    clock: &impl SlotSource,
+) -> ProgramResult
+where
+    P::State: Migratable + Persist,
+    for<'a> <P::Sync as FromBytes>::Target<'a>: Deref<Target = P::Sync>,
+    for<'a> <P::Async as FromBytes>::Target<'a>: Deref<Target = P::Async>,
+    for<'a> <P::State as FromBytes>::Target<'a>: Deref<Target = P::State>,
+    for<'a> <P::State as FromBytes>::TargetMut<'a>:
+        DerefMut<Target = P::State> + IntoOwned<P::State>,
+{
+    // Parse instruction
+    let (ix_type, ix_data) = D::decode(instruction_data)?;
+    let ix_data = &ix_data[..];
+    match DispatchTag::parse(ix_type) {
+        DispatchTag::Core(_) => {}
+        DispatchTag::Reserved(_) => return Err(ProgramError::InvalidInstructionData),
+        DispatchTag::Custom(tag) => return P::process_custom(program_id, accounts, tag, ix_data),
+    }
+
+    let StateAccounts {
+        state: state_account,
+        user,
+        rem,
+    } = StateAccounts::parse(accounts)?;
+    // The rest are read exactly, or by the state
+    let used = match ix_type {
+        tag::MIGRATE
+        | tag::PURGE_DEAD_LETTERS
+        | tag::UNPAUSE
+        | tag::ACCEPT_AUTHORITY
+        | tag::VERIFY_STATE
+        | tag::REVOKE_SESSION
+        | tag::CLOSE_STATE => Some(0),
+        tag::VIEW => Some(8),
+        tag::CREATE_SESSION => Some(size_of::<SessionParams>()),
+        _ => None,
+    };
+    if let Some(used) = used {
+        strict::check_consumed(ix_data, used, P::State::STRICTNESS)?;
+    }
+
+    // States with a config account take it after the user in the instructions that read it
+    let (config_account, rem) = match (P::State::CONFIG_ACCOUNT, takes_config(ix_type)) {
+        (true, true) => {
+            let (config, rem) = rem
+                .split_first()
+                .ok_or(ProgramError::NotEnoughAccountKeys)?;
+            (Some(config), rem)
+        }
+        (false, _) if matches!(ix_type, tag::INIT_CONFIG | tag::UPDATE_CONFIG) => {
+            return Err(ProgramError::InvalidInstructionData)
+        }
+        _ => (None, rem),
+    };
+    if ix_type == tag::CREATE_INSTANCE && !P::State::INSTANCES {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    // The state can't be loaded until it has been grown to full size
+    if ix_type == tag::GROW {
+        log_info!("Growing State");
+
+        let grow = GrowState {
+            target_len: grow::target_len(ix_data, P::State::LEN)?,
+        };
+        if grow.process(program_id, state_account, user)? {
+            log_info!("State grown to {} bytes", grow.target_len);
+        }
+        return Ok(());
+    }
+
+    // Old layouts can't be loaded as the current one, so migrate the raw bytes
+    if ix_type == tag::MIGRATE {
+        log_info!("Migrating State");
+
+        let mut state_data = state_account.try_borrow_mut_data()?;
+        return migrate_state::<P::State>(&mut state_data);
+    }
+
+    // Viewing only needs a read-only state, e.g. in a simulation
+    if ix_type == tag::VIEW {
+        let state_data = state_account.try_borrow_data()?;
+        let header = StateHeader::read(&state_data)?;
+        if header.version != P::State::VERSION {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        layout::check_hash(header.layout_hash, P::State::LAYOUT_HASH)?;
+        let state = P::State::from_bytes(&state_data)?;
+        return view::process(state.deref(), ix_data);
+    }
+    if ix_type == tag::VERIFY_STATE {
+        let state_data = state_account.try_borrow_data()?;
+        let header = StateHeader::read(&state_data)?;
+        if header.version != P::State::VERSION {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        layout::check_hash(header.layout_hash, P::State::LAYOUT_HASH)?;
+        let state = P::State::from_bytes(&state_data)?;
+        return match state.check_invariants() {
+            Ok(()) => {
+                log_info!("State verified");
+                Ok(())
+            }
+            Err(err) => {
+                log_error!("State invalid");
+                Err(err)
+            }
+        };
+    }
+
+    // Sessions are kept in their own accounts
+    if matches!(ix_type, tag::CREATE_SESSION | tag::REVOKE_SESSION) {
+        if !P::State::SESSIONS {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let account = rem.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+        if ix_type == tag::CREATE_SESSION {
+            log_info!("Creating Session");
+
+            // Only for states of this program, which sessions are checked against when used
+            if !state_account.is_owned_by(program_id) {
+                return Err(ProgramError::IllegalOwner);
+            }
+            let header = StateHeader::read(&state_account.try_borrow_data()?)?;
+            if header.version != P::State::VERSION {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            layout::check_hash(header.layout_hash, P::State::LAYOUT_HASH)?;
+            return session::process_create(
+                program_id,
+                state_account.key(),
+                user,
+                account,
+                ix_data,
+            );
+        }
+        log_info!("Revoking Session");
+        return session::process_revoke(program_id, state_account.key(), user, account);
+    }
+
+    if ix_type == tag::CLOSE_STATE {
+        log_info!("Closing State");
+
+        let destination = rem.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+        let mut state_data = state_account.try_borrow_mut_data()?;
+        let header = StateHeader::read(&state_data)?;
+        if header.version != P::State::VERSION {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        layout::check_hash(header.layout_hash, P::State::LAYOUT_HASH)?;
+        header.admin.check_authority(user)?;
+        let mut state = P::State::from_bytes_mut(&mut state_data[..])?;
+        close::check_settled(&mut *state, &header)?;
+        drop(state);
+        return close::close(&mut state_data, state_account, destination);
+    }
+
+    // Check if this is an initialization
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut header = StateHeader::read(&state_data)?;
+    // Zeroed but for the version, which keeps it from looking fresh
+    if header.version == close::CLOSED {
+        log_error!("State is closed");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let fresh = header.version == 0;
+    // Instances are only initialized by their registry
+    if P::State::INSTANCES && fresh != (ix_type == tag::CREATE_INSTANCE) {
+        return Err(match fresh {
+            true => ProgramError::UninitializedAccount,
+            false => ProgramError::AccountAlreadyInitialized,
+        });
+    }
+    if fresh {
+        layout::check_len(state_data.len(), P::State::LEN)?;
+        header = StateHeader::new(P::State::VERSION);
+        header.layout_hash = P::State::LAYOUT_HASH;
+        header.operators.authority = *user.key();
+        header.admin = Admin::new(*user.key());
+        if ix_type == tag::CREATE_INSTANCE {
+            let registry = rem.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+            header.instance = instance::process_create(program_id, registry, user, ix_data)?;
+        }
+        header.write(&mut state_data)?;
+    } else if header.version != P::State::VERSION {
+        log_error!(
+            "State is v{}, migrate to v{} first",
+            header.version,
+            P::State::VERSION
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+    layout::check_hash(header.layout_hash, P::State::LAYOUT_HASH)?;
+    let mut header_dirty = false;
+
+    // Load state with zero-copy
+    let mut state = P::State::from_bytes_mut(&mut state_data[..])?;
+    if fresh {
+        log_info!("Initializing state");
+        state.initialize();
+        emit!(InitializedEvent {
+            authority: header.operators.authority,
+            version: header.version as u64,
+        });
+    }
+
+    let mut config = match config_account {
+        Some(account) if ix_type != tag::INIT_CONFIG => {
+            Some(Config::load(program_id, state_account.key(), account)?)
+        }
+        _ => None,
+    };
+    let mut config_dirty = false;
+
+    let mut escrowed = 0;
+    // Escrowed by other instances, see [`enqueue_instances`]
+    let mut other_escrows = Vec::new();
+    match ix_type {
+        tag::SYNC => {
+            log_info!("Executing Synchronous Instruction");
+
+            // Sync instruction
+            let ix_data = sync_data::<P::Sync>(ix_data)?;
+            let sync_ix = P::Sync::from_bytes(&ix_data)?;
+            sync_ix.process(&ix_data, accounts, state.deref_mut())?;
+        }
+        tag::QUEUE => {
+            log_info!("Queueing Asynchronous Instruction");
+
+            // Async instruction - queue it
+            check_running(&header, config.as_ref(), pause::ENQUEUE)?;
+            let async_ix = P::Async::from_bytes(ix_data)?;
+            let args = P::State::queue_args(user, ix_data)?;
+            let now = clock.now(P::State::SCHEDULE)?;
+            escrowed = enqueue(
+                &mut header,
+                &mut *state,
+                user,
+                user,
+                async_ix.deref(),
+                &args,
+                rem,
+                &Pages::new(program_id, state_account.key(), rem),
+                now,
+                config.as_ref(),
+            )?;
+            header_dirty = true;
+        }
+        tag::QUEUE_RELAYED => {
+            log_info!("Queueing Relayed Asynchronous Instruction");
+
+            if !P::State::RELAYED {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            let [owner, instructions, rem @ ..] = rem else {
+                return Err(ProgramError::NotEnoughAccountKeys);
+            };
+            let (expiry, ix_data) = ix_data
+                .split_first_chunk::<8>()
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            let expiry = u64::from_le_bytes(*expiry);
+            let now = clock.now(P::State::SCHEDULE)?;
+            relay::check_expiry(expiry, now)?;
+            let message = relay::message(
+                program_id,
+                state_account.key(),
+                owner.key(),
+                expiry,
+                ix_data,
+            );
+            relay::verify_ed25519(instructions, owner.key(), &message)?;
+            let relayed = state
+                .relayed()
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            relay::consume(relayed, &message, expiry, owner.key(), user.key(), now)?;
+
+            let async_ix = P::Async::from_bytes(ix_data)?;
+            let args = P::State::queue_args(owner, ix_data)?;
+            escrowed = enqueue(
+                &mut header,
+                &mut *state,
+                user,
+                owner,
+                async_ix.deref(),
+                &args,
+                rem,
+                &Pages::new(program_id, state_account.key(), rem),
+                now,
+                config.as_ref(),
+            )?;
+            header_dirty = true;
+        }
+        tag::QUEUE_DELEGATED => {
+            log_info!("Queueing Delegated Asynchronous Instruction");
+
+            if !P::State::SESSIONS {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            let [owner, session, rem @ ..] = rem else {
+                return Err(ProgramError::NotEnoughAccountKeys);
+            };
+            let ixn = ix_data
+                .first_chunk::<8>()
+                .map(|ixn| u64::from_le_bytes(*ixn))
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            let now = clock.now(P::State::SCHEDULE)?;
+            Session::load(program_id, state_account.key(), session)?.check(
+                owner.key(),
+                user,
+                ixn,
+                now,
+            )?;
+
+            let async_ix = P::Async::from_bytes(ix_data)?;
+            let args = P::State::queue_args(owner, ix_data)?;
+            escrowed = enqueue(
+                &mut header,
+                &mut *state,
+                user,
+                owner,
+                async_ix.deref(),
+                &args,
+                rem,
+                &Pages::new(program_id, state_account.key(), rem),
+                now,
+                config.as_ref(),
+            )?;
+            header_dirty = true;
+        }
+        tag::QUEUE_MANY => {
+            log_info!("Queueing Asynchronous Instruction Into Instances");
+
+            // Each instance would take its own config
+            if !P::State::INSTANCES || P::State::CONFIG_ACCOUNT {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            let (&count, ix_data) = ix_data
+                .split_first()
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            if rem.len() < count as usize {
+                return Err(ProgramError::NotEnoughAccountKeys);
+            }
+            let (instances, rem) = rem.split_at(count as usize);
+            let async_ix = P::Async::from_bytes(ix_data)?;
+            let args = P::State::queue_args(user, ix_data)?;
+            let now = clock.now(P::State::SCHEDULE)?;
+            escrowed = enqueue(
+                &mut header,
+                &mut *state,
+                user,
+                user,
+                async_ix.deref(),
+                &args,
+                rem,
+                &Pages::new(program_id, state_account.key(), rem),
+                now,
+                None,
+            )
+            .inspect_err(|_| log_error!("Instance {} failed", header.instance.id))?;
+            header_dirty = true;
+            other_escrows = enqueue_instances::<P>(
+                program_id,
+                &header,
+                instances,
+                user,
+                async_ix.deref(),
+                &args,
+                rem,
+                now,
+            )?;
+        }
+        tag::DRAIN | tag::DRAIN_UPTO => {
+            log_info!("Executing Asynchronous Instruction");
+
+            check_running(&header, config.as_ref(), pause::DRAIN)?;
+            match &config {
+                Some(config) if config.is_permissioned() => config.check_operator(user)?,
+                _ if P::State::PERMISSIONED_CRANK => header.operators.check_operator(user)?,
+                _ => {}
+            }
+            let (upto, ix_data) = match ix_type {
+                tag::DRAIN_UPTO => {
+                    let (slot, ix_data) = ix_data
+                        .split_first_chunk()
+                        .ok_or(ProgramError::InvalidInstructionData)?;
+                    (Some(u64::from_le_bytes(*slot)), ix_data)
+                }
+                _ => (None, ix_data),
+            };
+            let drain = DrainArgs::parse(ix_data)?;
+            header.cursor.check_nonce(drain.nonce)?;
+
+            // Process next async instruction
+            let now = clock.now(P::State::SCHEDULE)?;
+            // Entries are due the config's extra delay later
+            let due = now.saturating_sub(config.map_or(0, |config| config.params.delay_slots));
+            // Windows close on time, whatever the drain replays up to
+            let closed_at = due;
+            let due = cursor::replay_due(due, upto, P::State::ASYNC_DELAY_SLOTS);
+            // Never anything queued in this slot
+            let due = ordering::drain_cutoff(
+                due,
+                now,
+                P::State::ASYNC_DELAY_SLOTS,
+                P::State::MIN_DRAIN_AGE_SLOTS,
+            );
+            state.on_drain_start(now)?;
+            // The head is always in the primary page, refilled once drained
+            let pages = Pages::new(program_id, state_account.key(), rem);
+            if P::State::OVERFLOW_PAGES {
+                state.refill_from_pages(&pages)?;
+            }
+            let window = config.map_or(0, |config| config.params.drain_window as u64);
+            let max = window::MAX_EXPIRED_PER_DRAIN;
+            let expired = window::expire_closed(&mut *state, closed_at, window, max)?;
+            if expired > 0 {
+                log_info!("Expired {}", expired);
+            }
+            for _ in 0..expired {
+                header.stats.record_cancelled();
+            }
+            dead_letter::skip_future(&mut *state, now, P::State::FAILURE_POLICY)?;
+            let mut processed = 0;
+            // Processed from the other shards, which pay their own bounties
+            let mut other_shards = 0;
+            if window::is_head_closed(&*state, closed_at, window) {
+                log_info!("Closed entries left to expire");
+            } else if P::State::SHARDS > 1 {
+                if header.shard != 0 {
+                    return Err(ProgramError::InvalidArgument);
+                }
+                let shards = rem
+                    .get(..P::State::SHARDS as usize - 1)
+                    .ok_or(ProgramError::NotEnoughAccountKeys)?;
+                let is_done = |processed| drain.is_done(processed);
+                (processed, other_shards) =
+                    drain_shards::<P>(program_id, &mut state, shards, user, due, is_done)?;
+            } else if P::State::BATCH_MODE {
+                // Batches aren't split, so the last one can take the drain past its cap
+                while !drain.is_done(processed) {
+                    match state.process_next_batch(due)? {
+                        0 => break,
+                        batch => processed += batch as u64,
+                    }
+                }
+            } else if P::State::SHUFFLE_SLOT {
+                let slot_hashes = rem.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+                let slot_hash = shuffle::recent_slot_hash(slot_hashes)?;
+                while !drain.is_done(processed) {
+                    match state.process_next_shuffled(due, &slot_hash)? {
+                        0 => break,
+                        batch => processed += batch as u64,
+                    }
+                }
+            } else if P::State::CHAINED {
+                let cost = P::State::CRANK_BOUNTY + fee::<P::State>(config.as_ref());
+                let is_done = |processed| drain.is_done(processed);
+                processed = chain::drain(
+                    &mut *state,
+                    due,
+                    cost,
+                    is_done,
+                    |state, user, ix, args, link| {
+                        enqueue_follow_up(
+                            &mut header,
+                            state,
+                            user,
+                            ix,
+                            args,
+                            link,
+                            now,
+                            config.as_ref(),
+                        )
+                    },
+                )?;
+            } else if P::State::FAILURE_POLICY != FailurePolicy::Abort {
+                let is_done = |processed| drain.is_done(processed);
+                processed =
+                    dead_letter::drain(&mut *state, due, P::State::FAILURE_POLICY, is_done)?;
+            } else if P::State::QUEUES.len() > 1 {
+                processed = queues::drain(&mut *state, due, |processed| drain.is_done(processed))?;
+            } else {
+                while !drain.is_done(processed) && state.has_pending_async(due) {
+                    let entry = state.peek_entry().copied();
+                    state.process_next_async_with(rem)?;
+                    if let Some(entry) = entry {
+                        state.on_item_processed(&entry)?;
+                    }
+                    processed += 1;
+                }
+            }
+
+            if P::State::OVERFLOW_PAGES {
+                state.refill_from_pages(&pages)?;
+            }
+            let partial = state.has_pending_async(due);
+            if !partial {
+                log_debug!("No pending async instructions");
+            }
+            state.on_drain_end(now, processed + other_shards)?;
+            let next = state.peek_entry().map(|entry| entry.key);
+            header.cursor.advance(now, next.as_ref(), partial);
+            header.stats.record_processed(processed, now);
+            header_dirty = true;
+
+            // Lamports don't share a borrow with the data, so the state can stay loaded
+            let bounty = (processed + expired) * P::State::CRANK_BOUNTY;
+            escrow::pay(state_account, user, bounty)?;
+            emit!(DrainedEvent {
+                cranker: *user.key(),
+                drained: processed + other_shards,
+                bounty: bounty + other_shards * P::State::CRANK_BOUNTY,
+            });
+        }
+        tag::SET_OPERATOR => {
+            log_info!("Setting Operator");
+
+            header.operators.process_set(user, ix_data)?;
+            header_dirty = true;
+        }
+        tag::COMMIT => {
+            log_info!("Committing Asynchronous Instruction");
+
+            check_running(&header, config.as_ref(), pause::ENQUEUE)?;
+            if !user.is_signer() {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            let hash = ix_data
+                .try_into()
+                .map_err(|_| ProgramError::InvalidInstructionData)?;
+            check_shard::<P::State>(&header, user)?;
+            let now = clock.now(P::State::SCHEDULE)?;
+            breaker::check(&*state, now)?;
+            rate_limit::<P::State>(&mut state, user.key(), now, config.as_ref())?;
+            let commitment = Commitment {
+                user: *user.key(),
+                slot: now,
+                seq: state.next_seq()?,
+            };
+            let store = commitments::<P::State>(&mut state)?;
+            commit::commit(store, hash, commitment, P::State::COMMIT_EXPIRY_SLOTS)?;
+            escrowed = P::State::CRANK_BOUNTY + P::State::DEPOSIT;
+            escrowed += charge_fee::<P::State>(&mut header, config.as_ref())?;
+            header_dirty = true;
+        }
+        tag::REVEAL => {
+            log_info!("Revealing Asynchronous Instruction");
+
+            check_running(&header, config.as_ref(), pause::ENQUEUE)?;
+            if !user.is_signer() {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            let (salt, ix_data) = ix_data
+                .split_first_chunk()
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            let hash = commit::commitment_hash(user.key(), salt, ix_data);
+            let now = clock.now(P::State::SCHEDULE)?;
+            let store = commitments::<P::State>(&mut state)?;
+            let commitment =
+                commit::reveal(store, &hash, user.key(), now, P::State::COMMIT_EXPIRY_SLOTS)?;
+
+            let async_ix = P::Async::from_bytes(ix_data)?;
+            let args = P::State::queue_args(user, ix_data)?;
+            state.validate_enqueue(async_ix.deref(), &args, rem)?;
+            state.queue_async_at(async_ix.deref(), &args, commitment.slot, commitment.seq)?;
+            header.stats.record_enqueued();
+            header_dirty = true;
+            escrowed = P::State::priority_bid(&args);
+        }
+        tag::PURGE_DEAD_LETTERS => {
+            log_info!("Purging Dead Letters");
+
+            header.operators.check_authority(user)?;
+            let purged = state.purge_dead_letters();
+            log_info!("Purged {} dead letters", purged);
+        }
+        tag::CANCEL => {
+            log_info!("Cancelling Asynchronous Instruction");
+
+            if !user.is_signer() {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            let key = bytemuck::try_pod_read_unaligned(ix_data)
+                .map_err(|_| ProgramError::InvalidInstructionData)?;
+            let refund = state.cancel_async(user.key(), &key)?;
+            header.stats.record_cancelled();
+            header_dirty = true;
+            escrow::pay(state_account, user, refund)?;
+        }
+        tag::CANCEL_ALL => {
+            log_info!("Cancelling All Asynchronous Instructions");
+
+            if !user.is_signer() {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            let owner = match rem.first() {
+                Some(owner) => {
+                    header.admin.check_authority(user)?;
+                    owner
+                }
+                None => user,
+            };
+            let max = match ix_data {
+                [] => u32::MAX,
+                data => u32::from_le_bytes(
+                    data.try_into()
+                        .map_err(|_| ProgramError::InvalidInstructionData)?,
+                ),
+            };
+            let cancel = state.cancel_all_async(owner.key(), max)?;
+            log_info!("Cancelled {}", cancel.cancelled);
+            for _ in 0..cancel.cancelled {
+                header.stats.record_cancelled();
+            }
+            header_dirty = true;
+            escrow::pay(state_account, owner, cancel.refund)?;
+            set_return_data(&[cancel.more as u8]);
+        }
+        tag::COMPACT => {
+            log_info!("Compacting Queue");
+
+            check_running(&header, config.as_ref(), pause::DRAIN)?;
+            let (max, rebuild) = match *ix_data {
+                [] => (u32::MAX, false),
+                [a, b, c, d] => (u32::from_le_bytes([a, b, c, d]), false),
+                [a, b, c, d, rebuild] => (u32::from_le_bytes([a, b, c, d]), rebuild != 0),
+                _ => return Err(ProgramError::InvalidInstructionData),
+            };
+            let now = clock.now(P::State::SCHEDULE)?;
+            let compacted = compact::compact(&mut *state, now, max, rebuild)?;
+            log_info!("Expired {}", compacted.expired);
+            for _ in 0..compacted.expired {
+                header.stats.record_cancelled();
+            }
+            header_dirty = true;
+
+            let bounty = compacted.expired as u64 * P::State::CRANK_BOUNTY;
+            escrow::pay(state_account, user, bounty)?;
+            emit!(CompactedEvent {
+                compactor: *user.key(),
+                expired: compacted.expired as u64,
+                commitments: compacted.commitments as u64,
+                rate_limits: compacted.rate_limits as u64,
+                rebuilt: compacted.rebuilt as u64,
+                bounty,
+            });
+            set_return_data(&[compacted.more as u8]);
+        }
+        tag::FLUSH => {
+            log_info!("Flushing Queue");
+
+            header.admin.check_authority(user)?;
+            let (max, refund) = match *ix_data {
+                [] => (u32::MAX, false),
+                [a, b, c, d] => (u32::from_le_bytes([a, b, c, d]), false),
+                [a, b, c, d, refund] => (u32::from_le_bytes([a, b, c, d]), refund != 0),
+                _ => return Err(ProgramError::InvalidInstructionData),
+            };
+            let flushed = match refund {
+                true => flush::flush_refunding(&mut *state, state_account, rem, max)?,
+                false => flush::flush(&mut *state, max)?,
+            };
+            log_info!("Flushed {}", flushed.flushed);
+            for _ in 0..flushed.flushed {
+                header.stats.record_cancelled();
+            }
+            header_dirty = true;
+            emit!(FlushedEvent {
+                admin: *user.key(),
+                flushed: flushed.flushed as u64,
+                refunded: flushed.refunded,
+                more: flushed.more as u64,
+            });
+            set_return_data(&[flushed.more as u8]);
+        }
+        tag::REPLACE => {
+            log_info!("Replacing Asynchronous Instruction");
+
+            check_running(&header, config.as_ref(), pause::ENQUEUE)?;
+            if !user.is_signer() {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            let (old_seq, ix_data) = ix_data
+                .split_first_chunk()
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            let async_ix = P::Async::from_bytes(ix_data)?;
+            let args = P::State::queue_args(user, ix_data)?;
+            check_shard::<P::State>(&header, user)?;
+            let now = clock.now(P::State::SCHEDULE)?;
+            breaker::check(&*state, now)?;
+            state.validate_enqueue(async_ix.deref(), &args, rem)?;
+            rate_limit::<P::State>(&mut state, user.key(), now, config.as_ref())?;
+            let refund = state.replace_async(
+                user.key(),
+                u64::from_le_bytes(*old_seq),
+                async_ix.deref(),
+                &args,
+                now,
+            )?;
+            escrow::pay(state_account, user, refund)?;
+            header.stats.record_cancelled();
+            header.stats.record_enqueued();
+            header_dirty = true;
+            escrowed = P::State::CRANK_BOUNTY + P::State::DEPOSIT + P::State::priority_bid(&args);
+            escrowed += charge_fee::<P::State>(&mut header, config.as_ref())?;
+        }
+        tag::SET_SHARD => {
+            log_info!("Setting Shard");
+
+            header.admin.check_authority(user)?;
+            let &[shard] = ix_data else {
+                return Err(ProgramError::InvalidInstructionData);
+            };
+            if shard >= P::State::SHARDS {
+                return Err(ProgramError::InvalidArgument);
+            }
+            // Pending entries were routed to the old index
+            if state.peek_entry().is_some() {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            header.shard = shard;
+            header_dirty = true;
+        }
+        tag::INIT_CONFIG => {
+            log_info!("Initializing Config");
+
+            let account = config_account.ok_or(ProgramError::NotEnoughAccountKeys)?;
+            config::process_init::<P::State>(
+                program_id,
+                state_account.key(),
+                &header.admin,
+                user,
+                account,
+                ix_data,
+            )?;
+        }
+        tag::UPDATE_CONFIG => {
+            log_info!("Updating Config");
+
+            let config = config.as_mut().ok_or(ProgramError::NotEnoughAccountKeys)?;
+            config::process_update::<P::State>(config, &header.admin, user, ix_data)?;
+            config_dirty = true;
+        }
+        tag::SET_FEE_DESTINATION => {
+            log_info!("Setting Fee Destination");
+
+            header
+                .fees
+                .process_set_destination(&header.admin, user, ix_data)?;
+            header_dirty = true;
+        }
+        tag::WITHDRAW_FEES => {
+            log_info!("Withdrawing Fees");
+
+            let destination = rem.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+            // Lamports don't share a borrow with the data, so the state can stay loaded
+            let withdrawn = header.fees.process_withdraw(
+                &header.admin,
+                user,
+                state_account,
+                destination,
+                ix_data,
+            )?;
+            log_info!("Withdrew {} lamports", withdrawn);
+            header_dirty = true;
+        }
+        tag::PAUSE => {
+            log_info!("Pausing");
+
+            header.admin.process_pause(user, ix_data)?;
+            header_dirty = true;
+        }
+        tag::UNPAUSE => {
+            log_info!("Unpausing");
+
+            header.admin.process_unpause(user)?;
+            header_dirty = true;
+        }
+        tag::SET_AUTHORITY => {
+            log_info!("Proposing Admin");
+
+            header.admin.process_set_authority(user, ix_data)?;
+            header_dirty = true;
+        }
+        tag::CREATE_INSTANCE => {
+            // Initialized above
+            log_info!("Created Instance {}", header.instance.id);
+        }
+        tag::ACCEPT_AUTHORITY => {
+            log_info!("Accepting Admin");
+
+            header.admin.process_accept_authority(user)?;
+            header_dirty = true;
+        }
+        _ => return Err(ProgramError::InvalidInstructionData),
+    }
+
+    if paranoid::ENABLED {
+        state.check_invariants()?;
+    }
+
+    // Owned states were decoded from a copy, so write their mutations back. Either way the
+    // typed state is released, as the header is written through the raw bytes.
+    if let Some(state) = state.into_owned() {
+        state.save(&mut state_data)?;
+    }
+    if header_dirty {
+        header.write(&mut state_data)?;
+    }
+    if let (Some(config), Some(account), true) = (config, config_account, config_dirty) {
+        config.write(account)?;
+    }
+
+    // The escrow transfer is a CPI into the state account, so release the state first
+    drop(state_data);
+    escrow::deposit(user, state_account, escrowed)?;
+    for (account, lamports) in other_escrows {
+        escrow::deposit(user, account, lamports)?;
+    }
+    Ok(())
+}
+
+/// The data a sync instruction is processed with: a copy normalized by
+/// [`FromBytes::from_bytes_mut`] for [`SyncIx::MUTABLE`] instructions, otherwise `data` as
+/// sent. For example, an instruction that caps its amount:
+///
+/// ```ignore
+/// impl FromBytes for CappedIx {
+///     fn from_bytes_mut<'a>(bytes: &'a mut [u8]) -> Result<Self::TargetMut<'a>, ProgramError> {
+///         let ix = CappedIx::decode_owned(bytes)?;
+///         let amount = read_pod::<u64>(&bytes[8..])?.min(MAX_AMOUNT);
+///         bytes[8..16].copy_from_slice(&amount.to_le_bytes());
+///         Ok(OwnedOrBorrowedMut::Owned(ix))
+///     }
+///     ..
+/// }
+///
+/// impl SyncIx for CappedIx {
+///     const MUTABLE: bool = true;
+///     ..
+/// }
+/// ```
+pub fn sync_data<I: SyncIx>(data: &[u8]) -> Result<Cow<'_, [u8]>, ProgramError> {
+    if !I::MUTABLE {
+        return Ok(Cow::Borrowed(data));
+    }
+    let mut data = data.to_vec();
+    I::from_bytes_mut(&mut data)?;
+    Ok(Cow::Owned(data))
+}
+
+/// Whether instructions tagged `tag` take the config account of states with
+/// [`AsyncState::CONFIG_ACCOUNT`], right after the user
+pub fn takes_config(tag: u8) -> bool {
+    matches!(
+        tag,
+        tag::QUEUE
+            | tag::QUEUE_RELAYED
+            | tag::QUEUE_DELEGATED
+            | tag::DRAIN
+            | tag::DRAIN_UPTO
+            | tag::COMMIT
+            | tag::REVEAL
+            | tag::REPLACE
+            | tag::COMPACT
+            | tag::INIT_CONFIG
+            | tag::UPDATE_CONFIG
+    )
+}
+
+/// Checks that neither the admin nor the config paused any of the `flags`
+fn check_running(header: &StateHeader, config: Option<&Config>, flags: u8) -> ProgramResult {
+    header.admin.check_running(flags)?;
+    config.map_or(Ok(()), |config| config.check_running(flags))
+}
+
+/// The protocol fee, the config's if there is one
+fn fee<S: AsyncState>(config: Option<&Config>) -> u64 {
+    config.map_or(S::PROTOCOL_FEE, |config| config.params.fee)
+}
+
+/// Accrues the protocol fee, the config's if there is one, returning it to deposit with the
+/// escrow
+fn charge_fee<S: AsyncState>(
+    header: &mut StateHeader,
+    config: Option<&Config>,
+) -> Result<u64, ProgramError> {
+    let fee = fee::<S>(config);
+    header.fees.accrue(fee)?;
+    Ok(fee)
+}
+
+/// Checks that `user` enqueues into the shard [`shard::route`] picks for them
+fn check_shard<S: AsyncState>(header: &StateHeader, user: &AccountInfo) -> ProgramResult {
+    if S::SHARDS > 1 && header.shard != shard::route(user.key(), S::SHARDS) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Drains `state`, shard 0, merged with the other `shards` in order until `is_done`, which are
+/// saved and pay `cranker` their bounties. Returns how many were processed from shard 0 and
+/// from the others.
+fn drain_shards<P: Program>(
+    program_id: &Pubkey,
+    state: &mut P::State,
+    shards: &[AccountInfo],
+    cranker: &AccountInfo,
+    now: u64,
+    is_done: impl Fn(u64) -> bool,
+) -> Result<(u64, u64), ProgramError>
+where
+    P::State: Migratable + Persist,
+    for<'a> <P::State as FromBytes>::TargetMut<'a>:
+        DerefMut<Target = P::State> + IntoOwned<P::State>,
+{
+    let mut loaded = Vec::with_capacity(shards.len());
+    for (i, account) in shards.iter().enumerate() {
+        if !account.is_owned_by(program_id) {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let data = account.try_borrow_mut_data()?;
+        let header = StateHeader::read(&data)?;
+        if header.version != P::State::VERSION || header.shard as usize != i + 1 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        layout::check_hash(header.layout_hash, P::State::LAYOUT_HASH)?;
+        loaded.push((header, data));
+    }
+
+    let mut others = loaded
+        .iter_mut()
+        .map(|(_, data)| P::State::from_bytes_mut(&mut data[..]))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut processed = vec![0; shards.len() + 1];
+    let mut states: Vec<&mut P::State> = std::iter::once(state)
+        .chain(others.iter_mut().map(|other| &mut **other))
+        .collect();
+    shard::drain(&mut states, now, &mut processed, is_done)?;
+    if paranoid::ENABLED {
+        for state in &states {
+            state.check_invariants()?;
+        }
+    }
+    drop(states);
+
+    let others: Vec<_> = others.into_iter().map(IntoOwned::into_owned).collect();
+    for ((account, (header, data)), (other, &count)) in shards
+        .iter()
+        .zip(&mut loaded)
+        .zip(others.into_iter().zip(&processed[1..]))
+    {
+        if let Some(other) = other {
+            other.save(data)?;
+        }
+        header.stats.record_processed(count, now);
+        header.write(data)?;
+        escrow::pay(account, cranker, count * P::State::CRANK_BOUNTY)?;
+    }
+    Ok((processed[0], processed[1..].iter().sum()))
+}
+
+/// Queues `user`'s `async_ix` into `state` once [`AsyncState::validate_enqueue`] accepts it
+/// with `accounts`, counting it in its `header`, and returns the lamports to escrow. Paged
+/// states spill into the overflow `pages` once their queue is full. The
+/// `signer` authorizing it must sign, whether or not anything is escrowed: the user, or the
+/// relayer or session key queueing for them.
+#[allow(clippy::too_many_arguments)]
+fn enqueue<S: AsyncState>(
+    header: &mut StateHeader,
+    state: &mut S,
+    signer: &AccountInfo,
+    user: &AccountInfo,
+    async_ix: &S::AsyncIx,
+    args: &S::QueueArgs,
+    accounts: &[AccountInfo],
+    pages: &Pages,
+    now: u64,
+    config: Option<&Config>,
+) -> Result<u64, ProgramError> {
+    check_running(header, config, pause::ENQUEUE)?;
+    if !signer.is_signer() {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    check_shard::<S>(header, user)?;
+    breaker::check(state, now)?;
+    state.validate_enqueue(async_ix, args, accounts)?;
+    rate_limit::<S>(state, user.key(), now, config)?;
+    if S::OVERFLOW_PAGES {
+        state.queue_async_paged(async_ix, args, now, pages)?;
+    } else {
+        state.queue_async(async_ix, args, now)?;
+    }
+    header.stats.record_enqueued();
+    let escrowed = S::CRANK_BOUNTY + S::DEPOSIT + S::priority_bid(args);
+    Ok(escrowed + charge_fee::<S>(header, config)?)
+}
+
+/// Queues `user`'s `async_ix` into each of the `instances` of the same registry as the state
+/// with `header`, logging which instance failed if one does. Returns the lamports to escrow in
+/// each, deposited once their data is released.
+#[allow(clippy::too_many_arguments)]
+fn enqueue_instances<'a, P: Program>(
+    program_id: &Pubkey,
+    header: &StateHeader,
+    instances: &'a [AccountInfo],
+    user: &AccountInfo,
+    async_ix: &P::Async,
+    args: &<P::State as AsyncState>::QueueArgs,
+    accounts: &[AccountInfo],
+    now: u64,
+) -> Result<Vec<(&'a AccountInfo, u64)>, ProgramError>
+where
+    P::State: Migratable + Persist,
+    for<'b> <P::State as FromBytes>::TargetMut<'b>:
+        DerefMut<Target = P::State> + IntoOwned<P::State>,
+{
+    let mut escrows = Vec::with_capacity(instances.len());
+    for (i, account) in instances.iter().enumerate() {
+        if instances[..i]
+            .iter()
+            .any(|other| other.key() == account.key())
+        {
+            return Err(ProgramError::InvalidArgument);
+        }
+        if !account.is_owned_by(program_id) {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let mut data = account.try_borrow_mut_data()?;
+        let mut instance = StateHeader::read(&data)?;
+        if instance.version != P::State::VERSION
+            || instance.instance.id == 0
+            || instance.instance.registry != header.instance.registry
+        {
+            log_error!("Instance account {} isn't an instance", i);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        layout::check_hash(instance.layout_hash, P::State::LAYOUT_HASH)?;
+
+        let mut state = P::State::from_bytes_mut(&mut data[..])?;
+        let escrowed = enqueue(
+            &mut instance,
+            &mut *state,
+            user,
+            user,
+            async_ix,
+            args,
+            accounts,
+            &Pages::new(program_id, account.key(), accounts),
+            now,
+            None,
+        )
+        .inspect_err(|_| log_error!("Instance {} failed", instance.instance.id))?;
+        if paranoid::ENABLED {
+            state.check_invariants()?;
+        }
+        if let Some(state) = state.into_owned() {
+            state.save(&mut data)?;
+        }
+        instance.write(&mut data)?;
+        escrows.push((account, escrowed));
+    }
+    Ok(escrows)
+}
+
+/// Queues the follow-up `async_ix` at `link` into `state` with the checks of [`enqueue`],
+/// counting against the rate limit of the `user` who queued its chain, see [`chain::drain`].
+/// A follow-up that fails them leaves the rate limit as it was, and what it was paid out of
+/// its parent's budget, its cost and share, accrues as fees since nothing else accounts for it.
+#[allow(clippy::too_many_arguments)]
+fn enqueue_follow_up<S: AsyncState>(
+    header: &mut StateHeader,
+    state: &mut S,
+    user: &Pubkey,
+    async_ix: &S::AsyncIx,
+    args: &S::QueueArgs,
+    link: chain::Link,
+    now: u64,
+    config: Option<&Config>,
+) -> ProgramResult {
+    let queued = breaker::check(state, now)
+        .and_then(|()| state.validate_enqueue(async_ix, args, &[]))
+        .and_then(|()| rate_limit::<S>(state, user, now, config))
+        .and_then(|()| {
+            state
+                .queue_follow_up(async_ix, args, link, now)
+                .inspect_err(|_| unrecord_rate_limit::<S>(state, user, now, config))
+        });
+    let fee = fee::<S>(config);
+    if let Err(err) = queued {
+        let paid = (S::CRANK_BOUNTY + fee).saturating_add(link.budget);
+        header.fees.accrue(paid)?;
+        return Err(err);
+    }
+    header.stats.record_enqueued();
+    // Paid out of the parent's budget
+    header.fees.accrue(fee)
+}
+
+/// [`AsyncState::MAX_ENQUEUES_PER_SLOT`], or the config's if there is one
+fn enqueue_limit<S: AsyncState>(config: Option<&Config>) -> u64 {
+    config.map_or(S::MAX_ENQUEUES_PER_SLOT, |config| {
+        config.max_enqueues_per_slot(S::MAX_ENQUEUES_PER_SLOT)
+    })
+}
+
+/// Counts an enqueue by `user` against [`AsyncState::MAX_ENQUEUES_PER_SLOT`]
+fn rate_limit<S: AsyncState>(
+    state: &mut S,
+    user: &Pubkey,
+    now: u64,
+    config: Option<&Config>,
+) -> ProgramResult {
+    let limit = enqueue_limit::<S>(config);
+    if limit == 0 {
+        return Ok(());
+    }
+    let store = state
+        .rate_limits()
+        .ok_or(ProgramError::InvalidAccountData)?;
+    Ok(crate::rate_limit::record(store, user, now, limit)?)
+}
+
+/// Takes back an enqueue [`rate_limit`] counted that didn't go through
+fn unrecord_rate_limit<S: AsyncState>(
+    state: &mut S,
+    user: &Pubkey,
+    now: u64,
+    config: Option<&Config>,
+) {
+    if enqueue_limit::<S>(config) == 0 {
+        return;
+    }
+    if let Some(store) = state.rate_limits() {
+        crate::rate_limit::unrecord(store, user, now);
+    }
+}
+
+fn commitments<S: AsyncState>(state: &mut S) -> Result<&mut commit::Commitments, ProgramError> {
+    if S::COMMIT_EXPIRY_SLOTS == 0 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    state
+        .commitments()
+        .ok_or(ProgramError::InvalidInstructionData)
+}
+
+#[cfg(test)]
+mod tests {
+    use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+    use super::*;
+    use crate::{ordering::FifoKey, queue::Entry, rate_limit::RateLimits, AsyncIx};
+
+    #[test]
+    fn test_dispatch_tag() {
+        assert_eq!(DispatchTag::parse(tag::SYNC), DispatchTag::Core(tag::SYNC));
+        assert_eq!(DispatchTag::parse(tag::LAST), DispatchTag::Core(tag::LAST));
+        assert_eq!(
+            DispatchTag::parse(tag::LAST + 1),
+            DispatchTag::Reserved(tag::LAST + 1)
+        );
+        assert_eq!(
+            DispatchTag::parse(tag::CUSTOM),
+            DispatchTag::Custom(tag::CUSTOM)
+        );
+        assert_eq!(DispatchTag::parse(u8::MAX), DispatchTag::Custom(u8::MAX));
+    }
+
+    /// Caps its amount at 100 before processing
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    struct CappedIx;
+
+    impl FromBytes for CappedIx {
+        type Target<'a> = CappedIx;
+        type TargetMut<'a> = CappedIx;
+
+        fn from_bytes(_bytes: &[u8]) -> Result<CappedIx, ProgramError> {
+            Ok(CappedIx)
+        }
+
+        fn from_bytes_mut(bytes: &mut [u8]) -> Result<CappedIx, ProgramError> {
+            let amount = crate::pod::read_pod::<u64>(bytes.get(8..).unwrap_or_default())?;
+            bytes[8..16].copy_from_slice(&amount.min(100).to_le_bytes());
+            Ok(CappedIx)
+        }
+    }
+
+    impl SyncIx for CappedIx {
+        const MUTABLE: bool = true;
+
+        fn process<S: AsyncState>(
+            &self,
+            _data: &[u8],
+            _accounts: &[AccountInfo],
+            _state: &mut S,
+        ) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sync_data() {
+        let data = [[0; 8], 250_u64.to_le_bytes()].concat();
+        let normalized = sync_data::<CappedIx>(&data).unwrap();
+        assert_eq!(normalized[8..], 100_u64.to_le_bytes());
+        assert_eq!(
+            sync_data::<CappedIx>(&data[..12]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    impl AsyncIx for CappedIx {
+        type Args = ();
+        fn process<S: AsyncState>(&self, _args: &(), _state: &mut S) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    /// Chains with room for a single entry, each user queueing up to two per slot
+    struct OneSlot {
+        queue: Box<RedBlackTree<FifoKey, chain::Link, 1>>,
+        rate_limits: Box<RateLimits>,
+    }
+
+    impl FromBytes for OneSlot {
+        type Target<'a> = &'a OneSlot;
+        type TargetMut<'a> = &'a mut OneSlot;
+        fn from_bytes(_bytes: &[u8]) -> Result<&OneSlot, ProgramError> {
+            Err(ProgramError::InvalidAccountData)
+        }
+        fn from_bytes_mut(_bytes: &mut [u8]) -> Result<&mut OneSlot, ProgramError> {
+            Err(ProgramError::InvalidAccountData)
+        }
+    }
+
+    impl AsyncState for OneSlot {
+        type SyncIx = CappedIx;
+        type AsyncIx = CappedIx;
+        type Payload = chain::Link;
+        type QueueArgs = ();
+        type Key = FifoKey;
+
+        const CRANK_BOUNTY: u64 = 10;
+        const PROTOCOL_FEE: u64 = 1;
+        const DEPOSIT: u64 = 30;
+        const MAX_ENQUEUES_PER_SLOT: u64 = 2;
+        const CHAINED: bool = true;
+
+        fn initialize(&mut self) {}
+
+        fn queue_args(_user: &AccountInfo, _data: &[u8]) -> Result<(), ProgramError> {
+            Ok(())
+        }
+
+        fn queue_async(&mut self, _ix: &CappedIx, _args: &(), _now: u64) -> ProgramResult {
+            Err(ProgramError::InvalidInstructionData)
+        }
+
+        fn queue_follow_up(
+            &mut self,
+            _ix: &CappedIx,
+            _args: &(),
+            link: chain::Link,
+            now: u64,
+        ) -> ProgramResult {
+            let key = FifoKey {
+                slot: now,
+                seq: self.queue.len() as u64,
+            };
+            self.queue
+                .insert(key, link)
+                .ok_or(ProgramError::AccountDataTooSmall)?;
+            Ok(())
+        }
+
+        fn rate_limits(&mut self) -> Option<&mut RateLimits> {
+            Some(&mut self.rate_limits)
+        }
+
+        fn process_next_async(&mut self) -> ProgramResult {
+            Ok(())
+        }
+
+        fn has_pending_async(&self, _now: u64) -> bool {
+            false
+        }
+
+        fn entries(&self) -> impl Iterator<Item = (&FifoKey, &chain::Link)> {
+            self.queue.iter()
+        }
+
+        fn peek_entry(&self) -> Option<&Entry<FifoKey, chain::Link>> {
+            None
+        }
+
+        fn pop_entry(&mut self) -> Option<Entry<FifoKey, chain::Link>> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_dropped_follow_up() {
+        let mut state = OneSlot {
+            queue: bytemuck::zeroed_box(),
+            rate_limits: bytemuck::zeroed_box(),
+        };
+        state.queue.initialize();
+        state.rate_limits.initialize();
+        let mut header: StateHeader = bytemuck::Zeroable::zeroed();
+        let user = [1; 32];
+        let link = chain::Link::root::<OneSlot>();
+
+        let follow_up = |header: &mut StateHeader, state: &mut OneSlot| {
+            enqueue_follow_up(header, state, &user, &CappedIx, &(), link, 5, None)
+        };
+        follow_up(&mut header, &mut state).unwrap();
+        assert_eq!(header.fees.accrued, 1);
+
+        // The queue is full, so the next one is dropped without counting against the rate
+        // limit, its cost and share of the budget accruing as fees
+        assert_eq!(
+            follow_up(&mut header, &mut state),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        assert_eq!(state.queue.len(), 1);
+        assert_eq!(state.rate_limits.get(&user).unwrap().count, 1);
+        assert_eq!(header.stats.total_enqueued, 1);
+        assert_eq!(header.fees.accrued, 1 + 10 + 1 + 30);
+    }
+}
@@ -0,0 +1,21 @@
+//! Errors of core's that aren't about any one feature
+
+use pinocchio::program_error::ProgramError;
+
+/// Custom program error for [`ApqError::Unsupported`], spelling `APQ` followed by B
+pub const UNSUPPORTED: u32 = 0x4150_510B;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ApqError {
+    /// The type leaves out an optional part of a trait, e.g. mutable decoding in
+    /// [`FromBytes::from_bytes_mut`](crate::FromBytes::from_bytes_mut)
+    Unsupported,
+}
+
+impl From<ApqError> for ProgramError {
+    fn from(err: ApqError) -> Self {
+        match err {
+            ApqError::Unsupported => ProgramError::Custom(UNSUPPORTED),
+        }
+    }
+}
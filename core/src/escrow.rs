@@ -0,0 +1,41 @@
+//! Lamports held by the state account on behalf of queued instructions
+
+use pinocchio::{account_info::AccountInfo, program_error::ProgramError, ProgramResult};
+use pinocchio_system::instructions::Transfer;
+
+/// Moves `lamports` from `payer` into the program-owned `escrow` account.
+///
+/// Goes through the system program, which must be present in the transaction, so `escrow`'s
+/// data must not be borrowed when calling this.
+pub fn deposit(payer: &AccountInfo, escrow: &AccountInfo, lamports: u64) -> ProgramResult {
+    if lamports == 0 {
+        return Ok(());
+    }
+    if !payer.is_signer() {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    Transfer {
+        from: payer,
+        to: escrow,
+        lamports,
+    }
+    .invoke()
+}
+
+/// Pays `lamports` out of the program-owned `escrow` account to `recipient`
+pub fn pay(escrow: &AccountInfo, recipient: &AccountInfo, lamports: u64) -> ProgramResult {
+    if lamports == 0 {
+        return Ok(());
+    }
+
+    let mut from = escrow.try_borrow_mut_lamports()?;
+    let mut to = recipient.try_borrow_mut_lamports()?;
+    *from = from
+        .checked_sub(lamports)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    *to = to
+        .checked_add(lamports)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    Ok(())
+}
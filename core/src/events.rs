@@ -0,0 +1,274 @@
+//! Structured events for indexers
+//!
+//! Events are emitted with [`emit!`](crate::emit), which logs them with `sol_log_data`, so each
+//! shows up as a `Program data:` log line of base64 segments: the event's discriminator, the
+//! event itself and, for events about a queued instruction, its queue key. Off-chain, split
+//! the line with [`decode_log`] and read it back with [`decode`] or [`decode_keyed`] instead
+//! of parsing the free-form log messages.
+//!
+//! The default dispatch emits [`InitializedEvent`], [`DrainedEvent`], [`CompactedEvent`] and
+//! [`FlushedEvent`].
+//! Queue keys and payloads are program-defined, so [`QueuedEvent`], [`ProcessedEvent`] and
+//! [`CancelledEvent`] are emitted by the program where it inserts, processes and removes
+//! entries, expired ones included.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey};
+
+use crate::queue::QueueKey;
+
+pub trait Event: Pod {
+    /// First segment of the log line, telling events apart
+    const DISCRIMINATOR: [u8; 8];
+}
+
+/// Logs `$event`, followed by the queue key `$key` of the instruction it is about if given
+#[macro_export]
+macro_rules! emit {
+    ($event:expr) => {
+        $crate::events::emit(&$event)
+    };
+    ($event:expr, $key:expr) => {
+        $crate::events::emit_keyed(&$event, &$key)
+    };
+}
+
+pub fn emit<E: Event>(event: &E) {
+    pinocchio::log::sol_log_data(&[&E::DISCRIMINATOR, bytemuck::bytes_of(event)]);
+}
+
+pub fn emit_keyed<E: Event, K: QueueKey>(event: &E, key: &K) {
+    pinocchio::log::sol_log_data(&[
+        &E::DISCRIMINATOR,
+        bytemuck::bytes_of(event),
+        bytemuck::bytes_of(key),
+    ]);
+}
+
+/// Reads an event from decoded log segments, `None` if they hold something else
+pub fn decode<E: Event>(segments: &[impl AsRef<[u8]>]) -> Option<E> {
+    let [discriminator, event] = segments else {
+        return None;
+    };
+    if discriminator.as_ref() != E::DISCRIMINATOR {
+        return None;
+    }
+    bytemuck::try_pod_read_unaligned(event.as_ref()).ok()
+}
+
+/// Reads an event and the queue key it carries from decoded log segments
+pub fn decode_keyed<E: Event, K: QueueKey>(segments: &[impl AsRef<[u8]>]) -> Option<(E, K)> {
+    let [discriminator, event, key] = segments else {
+        return None;
+    };
+    if discriminator.as_ref() != E::DISCRIMINATOR {
+        return None;
+    }
+    Some((
+        bytemuck::try_pod_read_unaligned(event.as_ref()).ok()?,
+        bytemuck::try_pod_read_unaligned(key.as_ref()).ok()?,
+    ))
+}
+
+/// Decodes the segments of a `Program data: ...` log line, `None` for any other line
+#[cfg(not(target_os = "solana"))]
+pub fn decode_log(line: &str) -> Option<Vec<Vec<u8>>> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    line.strip_prefix("Program data: ")?
+        .split_whitespace()
+        .map(|segment| STANDARD.decode(segment).ok())
+        .collect()
+}
+
+/// The state was initialized
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct InitializedEvent {
+    /// Operator registry authority, see [`crate::operators`]
+    pub authority: Pubkey,
+    /// Layout version of the state
+    pub version: u64,
+}
+
+impl Event for InitializedEvent {
+    const DISCRIMINATOR: [u8; 8] = *b"apqinitd";
+}
+
+/// An async instruction was queued, emitted with its key
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct QueuedEvent {
+    pub user: Pubkey,
+    /// Async instruction variant
+    pub ixn: u64,
+    /// Lamports bid for queue position
+    pub priority_bid: u64,
+}
+
+impl Event for QueuedEvent {
+    const DISCRIMINATOR: [u8; 8] = *b"apqqueud";
+}
+
+/// A queued async instruction was removed before being processed, emitted with its key
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct CancelledEvent {
+    pub user: Pubkey,
+    /// Async instruction variant
+    pub ixn: u64,
+    /// Lamports returned from escrow
+    pub refund: u64,
+}
+
+impl Event for CancelledEvent {
+    const DISCRIMINATOR: [u8; 8] = *b"apqcancd";
+}
+
+/// An async instruction was processed, emitted with its key
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct ProcessedEvent {
+    /// Who queued the instruction
+    pub user: Pubkey,
+    /// Async instruction variant
+    pub ixn: u64,
+    /// Zero on success, otherwise the program error code
+    pub result: u64,
+    /// Frontend the instruction was queued through, for attribution. All zeroes if none.
+    pub referrer: Pubkey,
+}
+
+impl ProcessedEvent {
+    pub fn new(user: Pubkey, ixn: u64, result: &Result<(), ProgramError>) -> Self {
+        ProcessedEvent {
+            user,
+            ixn,
+            result: match result {
+                Ok(()) => 0,
+                Err(err) => u64::from(err.clone()),
+            },
+            referrer: [0; 32],
+        }
+    }
+
+    /// The event attributed to `referrer`
+    pub fn referred_by(self, referrer: Pubkey) -> Self {
+        ProcessedEvent { referrer, ..self }
+    }
+}
+
+impl Event for ProcessedEvent {
+    const DISCRIMINATOR: [u8; 8] = *b"apqprocd";
+}
+
+/// A crank drained the queue
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct DrainedEvent {
+    pub cranker: Pubkey,
+    /// Entries drained, failed ones included
+    pub drained: u64,
+    /// Lamports paid to the cranker
+    pub bounty: u64,
+}
+
+impl Event for DrainedEvent {
+    const DISCRIMINATOR: [u8; 8] = *b"apqdrain";
+}
+
+/// A compaction ran
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct CompactedEvent {
+    pub compactor: Pubkey,
+    /// Expired queue entries removed
+    pub expired: u64,
+    /// Expired commitments purged
+    pub commitments: u64,
+    /// Stale rate-limit records purged
+    pub rate_limits: u64,
+    /// 1 if the queue's free list was rebuilt
+    pub rebuilt: u64,
+    /// Lamports paid to the compactor
+    pub bounty: u64,
+}
+
+impl Event for CompactedEvent {
+    const DISCRIMINATOR: [u8; 8] = *b"apqcompd";
+}
+
+/// The admin flushed the queue
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct FlushedEvent {
+    pub admin: Pubkey,
+    /// Queue entries removed, refunded ones included
+    pub flushed: u64,
+    /// Lamports refunded to the entries' owners
+    pub refunded: u64,
+    /// 1 if entries are left to flush
+    pub more: u64,
+}
+
+impl Event for FlushedEvent {
+    const DISCRIMINATOR: [u8; 8] = *b"apqflush";
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::*;
+    use crate::ordering::FifoKey;
+
+    /// What `sol_log_data` logs for `segments`
+    fn log_line(segments: &[&[u8]]) -> String {
+        let encoded: Vec<String> = segments.iter().map(|s| STANDARD.encode(s)).collect();
+        format!("Program data: {}", encoded.join(" "))
+    }
+
+    #[test]
+    fn test_decode_keyed() {
+        let key = FifoKey { slot: 4, seq: 2 };
+        let event = ProcessedEvent::new([3; 32], 1, &Err(ProgramError::InvalidArgument));
+        assert_ne!(event.result, 0);
+
+        let line = log_line(&[
+            &ProcessedEvent::DISCRIMINATOR,
+            bytemuck::bytes_of(&event),
+            bytemuck::bytes_of(&key),
+        ]);
+        let segments = decode_log(&line).unwrap();
+        assert_eq!(decode_keyed(&segments), Some((event, key)));
+
+        // Wrong key type or some other event
+        assert_eq!(decode_keyed::<ProcessedEvent, u64>(&segments), None);
+        assert_eq!(decode_keyed::<CancelledEvent, FifoKey>(&segments), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        let event = DrainedEvent {
+            cranker: [1; 32],
+            drained: 3,
+            bounty: 15_000,
+        };
+        let line = log_line(&[&DrainedEvent::DISCRIMINATOR, bytemuck::bytes_of(&event)]);
+        let segments = decode_log(&line).unwrap();
+        assert_eq!(decode(&segments), Some(event));
+        assert_eq!(decode::<InitializedEvent>(&segments), None);
+
+        assert_eq!(
+            decode_log("Program log: Queueing Aynchronous Instruction"),
+            None
+        );
+    }
+}
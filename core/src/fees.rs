@@ -0,0 +1,128 @@
+//! Protocol fees held by the state account until the admin withdraws them
+//!
+//! Queueing, committing and replacing charge [`AsyncState::PROTOCOL_FEE`](crate::AsyncState)
+//! lamports on top of the escrow, or the [`crate::config`] fee for states with a config
+//! account. The fee goes into the state account with the escrow, and the [`FeeLedger`] in the
+//! [`StateHeader`](crate::header::StateHeader) keeps it apart from the escrowed lamports, so
+//! withdrawing can never pay out what a queued instruction's bounty or refund needs.
+//!
+//! The admin withdraws fees to the destination it set beforehand, all zeroes meaning the admin
+//! itself, so a compromised withdrawal can only pay where the admin already decided fees go.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+
+use crate::{admin::Admin, escrow};
+
+/// Fees go to the admin
+const NO_DESTINATION: Pubkey = [0; 32];
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct FeeLedger {
+    /// Lamports charged and not withdrawn yet
+    pub accrued: u64,
+    /// Lamports withdrawn so far
+    pub withdrawn: u64,
+    /// Where fees are withdrawn to, all zeroes for the admin
+    pub destination: Pubkey,
+}
+
+crate::impl_layout_hash!(FeeLedger {
+    accrued: u64,
+    withdrawn: u64,
+    destination: Pubkey
+});
+
+impl FeeLedger {
+    /// Records `fee` lamports charged
+    pub fn accrue(&mut self, fee: u64) -> ProgramResult {
+        self.accrued = self
+            .accrued
+            .checked_add(fee)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    /// Whether fees can be withdrawn to `destination` while `admin` is the admin
+    pub fn is_destination(&self, admin: &Admin, destination: &Pubkey) -> bool {
+        match self.destination {
+            NO_DESTINATION => *destination == admin.authority,
+            expected => *destination == expected,
+        }
+    }
+
+    /// Handles the set fee destination instruction, signed by the admin. Data is the
+    /// destination, all zeroes for the admin.
+    pub fn process_set_destination(
+        &mut self,
+        admin: &Admin,
+        authority: &AccountInfo,
+        data: &[u8],
+    ) -> ProgramResult {
+        admin.check_authority(authority)?;
+        self.destination = data
+            .try_into()
+            .map_err(|_| ProgramError::InvalidInstructionData)?;
+        Ok(())
+    }
+
+    /// Handles the withdraw fees instruction, signed by the admin, paying `state` account
+    /// lamports to `destination`. Data is the optional amount, all accrued fees by default.
+    /// Returns the lamports withdrawn.
+    pub fn process_withdraw(
+        &mut self,
+        admin: &Admin,
+        authority: &AccountInfo,
+        state: &AccountInfo,
+        destination: &AccountInfo,
+        data: &[u8],
+    ) -> Result<u64, ProgramError> {
+        admin.check_authority(authority)?;
+        if !self.is_destination(admin, destination.key()) || destination.key() == state.key() {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let amount = match data {
+            [] => self.accrued,
+            data => u64::from_le_bytes(
+                data.try_into()
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            ),
+        };
+        self.accrued = self
+            .accrued
+            .checked_sub(amount)
+            .ok_or(ProgramError::InsufficientFunds)?;
+        self.withdrawn = self.withdrawn.saturating_add(amount);
+        escrow::pay(state, destination, amount)?;
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination() {
+        let admin = Admin::new([1; 32]);
+        let mut ledger = FeeLedger::default();
+        assert!(ledger.is_destination(&admin, &[1; 32]));
+        assert!(!ledger.is_destination(&admin, &[2; 32]));
+
+        ledger.destination = [2; 32];
+        assert!(!ledger.is_destination(&admin, &[1; 32]));
+        assert!(ledger.is_destination(&admin, &[2; 32]));
+
+        ledger.accrue(5).unwrap();
+        ledger.accrue(7).unwrap();
+        assert_eq!(ledger.accrued, 12);
+        assert_eq!(
+            ledger.accrue(u64::MAX),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+    }
+}
@@ -0,0 +1,74 @@
+//! Emergency flush of the queue by the admin
+//!
+//! A queue in a bad state, e.g. holding entries that fail every time after an upgrade, would
+//! otherwise take a redeploy to recover from. The flush instruction, signed by the state's
+//! admin, clears it at most `max` entries at a time so it fits in a transaction's compute, and
+//! its return data tells the admin to send another while entries are left:
+//!
+//! - with refunds, the entries of the owners passed as remaining accounts are cancelled with
+//!   [`AsyncState::cancel_all_async`], refunding their escrow to the owner as if they had
+//!   cancelled them. Anyone else's entries stay queued, so list the owners with a view first.
+//! - without, entries are taken off the head with [`AsyncState::flush_entry`], and their
+//!   escrow stays in the state account. States that hold more for an entry, e.g. funds a take
+//!   locked, give it back there, and states that can't account for the escrow refuse.
+//!
+//! Once the queue is empty its free list is rebuilt, see [`AsyncState::rebuild_free_list`].
+//! Only queue 0 of [`AsyncState::QUEUES`] is flushed, and commitments and dead letters are
+//! left as they are.
+
+use pinocchio::{account_info::AccountInfo, program_error::ProgramError};
+
+use crate::{escrow, AsyncState};
+
+/// What a flush removed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flushed {
+    /// Entries removed, refunded ones included
+    pub flushed: u32,
+    /// Lamports refunded from escrow
+    pub refunded: u64,
+    /// Whether entries are left, of the owners passed when refunding, to flush in another
+    /// instruction
+    pub more: bool,
+}
+
+/// Removes up to `max` entries from the head of the queue without refunding them
+pub fn flush<S: AsyncState>(state: &mut S, max: u32) -> Result<Flushed, ProgramError> {
+    let mut flushed = Flushed::default();
+    while flushed.flushed < max && state.flush_entry()? {
+        flushed.flushed += 1;
+    }
+    flushed.more = state.peek_entry().is_some();
+    if !flushed.more {
+        state.rebuild_free_list();
+    }
+    Ok(flushed)
+}
+
+/// Cancels up to `max` entries of the `owners`, in order, refunding each owner's escrow to
+/// them from the `escrow` state account
+pub fn flush_refunding<S: AsyncState>(
+    state: &mut S,
+    escrow: &AccountInfo,
+    owners: &[AccountInfo],
+    max: u32,
+) -> Result<Flushed, ProgramError> {
+    let mut flushed = Flushed::default();
+    for owner in owners {
+        let cancel = state.cancel_all_async(owner.key(), max - flushed.flushed)?;
+        escrow::pay(escrow, owner, cancel.refund)?;
+        flushed.flushed += cancel.cancelled;
+        flushed.refunded = flushed
+            .refunded
+            .checked_add(cancel.refund)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        if cancel.more {
+            flushed.more = true;
+            break;
+        }
+    }
+    if state.peek_entry().is_none() {
+        state.rebuild_free_list();
+    }
+    Ok(flushed)
+}
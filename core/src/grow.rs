@@ -0,0 +1,123 @@
+//! Growing the state account in place
+//!
+//! Accounts created through CPI are capped at 10KB and one instruction can only grow an
+//! account by another 10KB, so a program that wants its state at a PDA has to create it small
+//! and realloc it up to the full state size over several instructions.
+//!
+//! The grow instruction takes the length to grow towards, so the state can be grown in
+//! increments as it is needed, e.g. the payer topping up rent a step at a time, rather than
+//! all at once. It defaults to the full state length, beyond which nothing grows, since the
+//! state is only loaded once the account is exactly that long.
+
+use pinocchio::{
+    account_info::{AccountInfo, MAX_PERMITTED_DATA_INCREASE},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvars::{rent::Rent, Sysvar},
+};
+use pinocchio_system::instructions::Transfer;
+
+pub struct GrowState {
+    /// Length the state account should end up at
+    pub target_len: u64,
+}
+
+impl GrowState {
+    /// Grows `state` by up to `MAX_PERMITTED_DATA_INCREASE` towards `target_len`, with `payer`
+    /// topping up rent exemption. Returns whether the target has been reached.
+    ///
+    /// The enlarged region is zeroed, so the queue it holds is picked up by the program's
+    /// usual zero-check initialization once the account is fully grown. The system program
+    /// must be present in the transaction for the rent transfer.
+    pub fn process(
+        &self,
+        program_id: &Pubkey,
+        state: &AccountInfo,
+        payer: &AccountInfo,
+    ) -> Result<bool, ProgramError> {
+        if !state.is_owned_by(program_id) {
+            return Err(ProgramError::IllegalOwner);
+        }
+        if !payer.is_signer() {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let target_len = self.target_len as usize;
+        let new_len = next_len(state.data_len(), target_len)?;
+        let shortfall = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(state.lamports());
+        if shortfall > 0 {
+            Transfer {
+                from: payer,
+                to: state,
+                lamports: shortfall,
+            }
+            .invoke()?;
+        }
+
+        // Zero the new region in case this instruction shrank the account earlier
+        state.realloc(new_len, true)?;
+
+        Ok(new_len == target_len)
+    }
+}
+
+/// The length an account of `current_len` grows to in one instruction towards `target_len`
+pub fn next_len(current_len: usize, target_len: usize) -> Result<usize, ProgramError> {
+    if target_len < current_len {
+        return Err(ProgramError::InvalidRealloc);
+    }
+    Ok(target_len.min(current_len + MAX_PERMITTED_DATA_INCREASE))
+}
+
+/// The length the grow instruction `data` targets, the optional `u64` after the tag, at most
+/// `len`, which it defaults to
+pub fn target_len(data: &[u8], len: usize) -> Result<u64, ProgramError> {
+    let target_len = match data {
+        [] => return Ok(len as u64),
+        data => u64::from_le_bytes(
+            data.try_into()
+                .map_err(|_| ProgramError::InvalidInstructionData)?,
+        ),
+    };
+    if target_len > len as u64 {
+        return Err(ProgramError::InvalidRealloc);
+    }
+    Ok(target_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grow_in_increments() {
+        let len = 3 * MAX_PERMITTED_DATA_INCREASE + 100;
+        assert_eq!(target_len(&[], len), Ok(len as u64));
+        let half = (len / 2) as u64;
+        assert_eq!(target_len(&half.to_le_bytes(), len), Ok(half));
+        assert_eq!(
+            target_len(&(len as u64 + 1).to_le_bytes(), len),
+            Err(ProgramError::InvalidRealloc)
+        );
+        assert_eq!(
+            target_len(&[1, 2, 3], len),
+            Err(ProgramError::InvalidInstructionData)
+        );
+
+        // Grown to half first, then the rest of the way
+        let mut current = 0;
+        let mut steps = Vec::new();
+        for target in [len / 2, len] {
+            while current < target {
+                current = next_len(current, target).unwrap();
+                steps.push(current);
+            }
+        }
+        let step = MAX_PERMITTED_DATA_INCREASE;
+        assert_eq!(steps, [step, len / 2, len / 2 + step, len]);
+        assert_eq!(next_len(len, len), Ok(len));
+        assert_eq!(next_len(len, len / 2), Err(ProgramError::InvalidRealloc));
+    }
+}
@@ -0,0 +1,82 @@
+use bytemuck::{Pod, Zeroable};
+use pinocchio::program_error::ProgramError;
+
+use crate::{
+    admin::Admin, cursor::DrainCursor, fees::FeeLedger, instance::Instance,
+    operators::OperatorRegistry, stats::QueueStats,
+};
+
+/// Fixed prefix of every state account
+///
+/// `version` comes first so that the layout of the rest of the account can always be
+/// determined, even across program upgrades. See [`crate::migrate::Migratable`].
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct StateHeader {
+    /// Layout version of the state. Zero until initialized
+    pub version: u32,
+    /// Index of this account among the state's shards, see [`crate::shard`]
+    pub shard: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    _padding: [u8; 3],
+    /// [`Migratable::LAYOUT_HASH`](crate::migrate::Migratable::LAYOUT_HASH) of the layout the
+    /// state was initialized or migrated to, see [`crate::layout`]
+    pub layout_hash: u64,
+
+    /// Keepers allowed to drain a permissioned queue
+    pub operators: OperatorRegistry,
+
+    /// Can pause the queue, see [`crate::admin`]
+    pub admin: Admin,
+
+    /// Kept up to date by dispatch
+    pub stats: QueueStats,
+
+    /// Where the last drain stopped, see [`crate::cursor`]
+    pub cursor: DrainCursor,
+
+    /// Protocol fees held by the state account, see [`crate::fees`]
+    pub fees: FeeLedger,
+
+    /// Registry the state was created by, see [`crate::instance`]
+    pub instance: Instance,
+}
+
+crate::impl_layout_hash!(StateHeader {
+    version: u32,
+    shard: u8,
+    _padding: [u8; 3],
+    layout_hash: u64,
+    operators: OperatorRegistry,
+    admin: Admin,
+    stats: QueueStats,
+    cursor: DrainCursor,
+    fees: FeeLedger,
+    instance: Instance
+});
+
+impl StateHeader {
+    pub const LEN: usize = size_of::<StateHeader>();
+
+    pub fn new(version: u32) -> Self {
+        StateHeader {
+            version,
+            ..Default::default()
+        }
+    }
+
+    /// Reads the header of raw state account data, which may be in any layout version
+    pub fn read(data: &[u8]) -> Result<Self, ProgramError> {
+        data.get(..Self::LEN)
+            .and_then(|bytes| bytemuck::try_pod_read_unaligned(bytes).ok())
+            .ok_or(ProgramError::AccountDataTooSmall)
+    }
+
+    pub fn write(&self, data: &mut [u8]) -> Result<(), ProgramError> {
+        data.get_mut(..Self::LEN)
+            .ok_or(ProgramError::AccountDataTooSmall)?
+            .copy_from_slice(bytemuck::bytes_of(self));
+        Ok(())
+    }
+}
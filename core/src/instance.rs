@@ -0,0 +1,145 @@
+//! Several independent queues, e.g. one per market, run by one program
+//!
+//! Any zeroed state account the program owns becomes a state on its first instruction, so one
+//! deployment can already run many queues, but nothing tells clients which of them belong
+//! together or stops anyone from starting one. States with
+//! [`AsyncState::INSTANCES`](crate::AsyncState::INSTANCES) set are only initialized by the
+//! create instance instruction instead, signed by the authority of a [`Registry`] account,
+//! which numbers them from 1. The [`Instance`] in the
+//! [`StateHeader`](crate::header::StateHeader) records the registry and number, so clients
+//! can enumerate a registry's instances from the program's accounts alone.
+//!
+//! The registry account is created by the client like the state account, zeroed, owned by the
+//! program and [`Registry::LEN`] long. The first create instance instruction naming it makes
+//! its signer the authority.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+
+/// Custom program error for creating an instance under a number the registry already gave
+/// out, or skipping ahead, spelling `APQ` followed by 9
+pub const INSTANCE_TAKEN: u32 = 0x4150_5109;
+
+/// Which registry a state is an instance of, all zeroes for states that aren't
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Instance {
+    /// Registry account that created the state
+    pub registry: Pubkey,
+    /// Number of the instance in its registry, from 1
+    pub id: u64,
+}
+
+crate::impl_layout_hash!(Instance {
+    registry: Pubkey,
+    id: u64
+});
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Registry {
+    /// Layout version, zero until the first instance is created
+    pub version: u32,
+    /// [`Registry::DISCRIMINATOR`] once created, telling registries apart from the program's
+    /// other accounts of the same version
+    pub discriminator: [u8; 4],
+    /// Signs the creation of instances
+    pub authority: Pubkey,
+    /// Instances created so far, the number of the last one
+    pub instances: u64,
+}
+
+impl Registry {
+    pub const LEN: usize = size_of::<Registry>();
+
+    pub const VERSION: u32 = 1;
+
+    pub const DISCRIMINATOR: [u8; 4] = *b"rgst";
+
+    /// Numbers the next instance, failing with [`INSTANCE_TAKEN`] unless it is `expected`
+    pub fn next(&mut self, expected: u64) -> Result<u64, ProgramError> {
+        let id = self
+            .instances
+            .checked_add(1)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        if id != expected {
+            return Err(ProgramError::Custom(INSTANCE_TAKEN));
+        }
+        self.instances = id;
+        Ok(id)
+    }
+
+    fn check_authority(&self, authority: &AccountInfo) -> ProgramResult {
+        if !authority.is_signer() || *authority.key() != self.authority {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        Ok(())
+    }
+}
+
+/// Handles the create instance instruction, signed by the `registry`'s `authority`, or by
+/// whoever creates the first instance of a zeroed registry. Data is the number the instance
+/// is expected to get, so two creations racing for the same one can't both succeed. Returns
+/// the [`Instance`] to record in the new state's header.
+pub fn process_create(
+    program_id: &Pubkey,
+    registry: &AccountInfo,
+    authority: &AccountInfo,
+    data: &[u8],
+) -> Result<Instance, ProgramError> {
+    if !registry.is_owned_by(program_id) {
+        return Err(ProgramError::IllegalOwner);
+    }
+    if registry.data_len() != Registry::LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let expected = u64::from_le_bytes(
+        data.try_into()
+            .map_err(|_| ProgramError::InvalidInstructionData)?,
+    );
+
+    let mut data = registry.try_borrow_mut_data()?;
+    let mut state: Registry = bytemuck::pod_read_unaligned(&data[..]);
+    match state.version {
+        0 => {
+            state = Registry {
+                version: Registry::VERSION,
+                discriminator: Registry::DISCRIMINATOR,
+                authority: *authority.key(),
+                ..Default::default()
+            }
+        }
+        Registry::VERSION if state.discriminator == Registry::DISCRIMINATOR => {}
+        _ => return Err(ProgramError::InvalidAccountData),
+    }
+    state.check_authority(authority)?;
+    let id = state.next(expected)?;
+    data.copy_from_slice(bytemuck::bytes_of(&state));
+
+    Ok(Instance {
+        registry: *registry.key(),
+        id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next() {
+        let mut registry = Registry::default();
+        assert_eq!(registry.next(1), Ok(1));
+        assert_eq!(registry.next(1), Err(ProgramError::Custom(INSTANCE_TAKEN)));
+        assert_eq!(registry.next(3), Err(ProgramError::Custom(INSTANCE_TAKEN)));
+        assert_eq!(registry.next(2), Ok(2));
+        assert_eq!(registry.instances, 2);
+
+        registry.instances = u64::MAX;
+        assert_eq!(registry.next(0), Err(ProgramError::ArithmeticOverflow));
+    }
+}
@@ -0,0 +1,385 @@
+//! Catching state layout mismatches early
+//!
+//! A state's layout is shared by the program, its clients and every account already created,
+//! so a field added in one place and not the others corrupts data silently. Programs pin their
+//! layout at compile time with [`const_assert_state_layout!`](crate::const_assert_state_layout)
+//! next to the state struct, and the dispatch checks the account length with [`check_len`]
+//! when initializing.
+//!
+//! The size alone misses fields swapped or resized against each other, so tests also freeze
+//! where each field sits: [`state_layout!`](crate::state_layout) reads every field's offset
+//! and size, and [`assert_golden`] compares them with a file committed next to the program,
+//!
+//! ```ignore
+//! #[test]
+//! fn test_layout() {
+//!     let layout = apq_core::state_layout!(MyState { header, seq, async_queue });
+//!     apq_core::layout::assert_golden(&layout, concat!(env!("CARGO_MANIFEST_DIR"), "/golden/MyState.txt"));
+//! }
+//! ```
+//!
+//! Run with `APQ_UPDATE_GOLDEN=1` to write the file after a deliberate change, together with a
+//! new version, see [`Migratable`](crate::migrate::Migratable).
+//!
+//! Tests only guard the program they run on, while an upgrade deployed without them would read
+//! every existing account in the new layout. So states also set
+//! [`Migratable::LAYOUT_HASH`](crate::migrate::Migratable::LAYOUT_HASH) to a
+//! [`layout_hash!`](crate::layout_hash) of their fields' names, types and offsets, which the
+//! dispatch writes into the header when initializing or migrating and checks with
+//! [`check_hash`] on every load. An upgrade changing the state without a migration then fails
+//! with [`LAYOUT_MISMATCH`] instead of reinterpreting the bytes.
+//!
+//! Field types are hashed by their own [`LayoutHash`], so a change nested anywhere in a field,
+//! e.g. in the [`StateHeader`](crate::header::StateHeader), changes the state's hash too.
+//! Structs held by a state implement it with [`impl_layout_hash!`](crate::impl_layout_hash)
+//! next to their definition, and primitives, arrays and queue trees have it already.
+//!
+//! ```ignore
+//! impl Migratable for MyState {
+//!     const VERSION: u32 = 1;
+//!     const LAYOUT_HASH: u64 =
+//!         apq_core::layout_hash!(MyState { header: StateHeader, seq: u64, async_queue: Queue });
+//!     ..
+//! }
+//! ```
+
+use pinocchio::{program_error::ProgramError, ProgramResult};
+use sokoban::RedBlackTree;
+
+/// Custom program error for a state whose layout hash isn't the program's, spelling `APQ`
+/// followed by F
+pub const LAYOUT_MISMATCH: u32 = 0x4150_510F;
+
+/// Where [`fnv1a`] starts
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Compiles only for types without implicit padding, which bytemuck's `Pod` derive rejects
+pub const fn assert_pod<T: bytemuck::Pod>() {}
+
+/// Fails to compile unless the state has the given size and alignment and no implicit
+/// padding, e.g.
+///
+/// ```ignore
+/// const_assert_state_layout!(MyState, size = 4_096, align = 8);
+/// ```
+#[macro_export]
+macro_rules! const_assert_state_layout {
+    ($state:ty, size = $size:expr, align = $align:expr $(,)?) => {
+        const _: () = {
+            assert!(
+                ::core::mem::size_of::<$state>() == $size,
+                concat!("size of ", stringify!($state), " changed")
+            );
+            assert!(
+                ::core::mem::align_of::<$state>() == $align,
+                concat!("alignment of ", stringify!($state), " changed")
+            );
+            $crate::layout::assert_pod::<$state>();
+        };
+    };
+}
+
+/// Where a field sits in a state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldLayout {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// Every field of a state in declaration order, see [`state_layout!`](crate::state_layout)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateLayout {
+    pub name: &'static str,
+    pub size: usize,
+    pub align: usize,
+    pub fields: Vec<FieldLayout>,
+}
+
+impl StateLayout {
+    /// The layout as the golden file holds it, one line per field
+    pub fn render(&self) -> String {
+        let mut rendered = format!("{} size {} align {}\n", self.name, self.size, self.align);
+        for field in &self.fields {
+            rendered += &format!(
+                "{} offset {} size {}\n",
+                field.name, field.offset, field.size
+            );
+        }
+        rendered
+    }
+}
+
+/// The size of the field `field` points to, for [`state_layout!`](crate::state_layout)
+#[doc(hidden)]
+pub fn field_size<S, T>(_field: fn(&S) -> &T) -> usize {
+    core::mem::size_of::<T>()
+}
+
+/// The [`StateLayout`] of a state. Fails to compile unless every field is listed, in any
+/// order, and lists them in the order they were given.
+///
+/// ```ignore
+/// let layout = state_layout!(MyState { header, seq, async_queue });
+/// ```
+#[macro_export]
+macro_rules! state_layout {
+    ($state:ident { $($field:ident),* $(,)? }) => {{
+        // Destructuring without `..` is what rejects a field left out
+        let _exhaustive = |state: &$state| {
+            let $state { $($field: _),* } = state;
+        };
+        $crate::layout::StateLayout {
+            name: stringify!($state),
+            size: ::core::mem::size_of::<$state>(),
+            align: ::core::mem::align_of::<$state>(),
+            fields: vec![$($crate::layout::FieldLayout {
+                name: stringify!($field),
+                offset: ::core::mem::offset_of!($state, $field),
+                size: $crate::layout::field_size(|state: &$state| &state.$field),
+            }),*],
+        }
+    }};
+}
+
+/// Panics unless `layout` matches the golden file at `path`, or writes the file instead when
+/// `APQ_UPDATE_GOLDEN` is set
+#[cfg(not(target_os = "solana"))]
+#[track_caller]
+pub fn assert_golden(layout: &StateLayout, path: &str) {
+    let rendered = layout.render();
+    if std::env::var_os("APQ_UPDATE_GOLDEN").is_some() {
+        std::fs::write(path, &rendered).unwrap_or_else(|err| panic!("writing {path}: {err}"));
+        return;
+    }
+    let golden = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        panic!("reading {path}: {err}, run with APQ_UPDATE_GOLDEN=1 to create it")
+    });
+    if golden != rendered {
+        panic!(
+            "layout of {} drifted from {path}, which breaks existing accounts unless it comes \
+             with a new version. Run with APQ_UPDATE_GOLDEN=1 if it's deliberate.\n\
+             expected:\n{golden}\nactual:\n{rendered}",
+            layout.name
+        );
+    }
+}
+
+/// `hash` followed by the 64-bit FNV-1a of `bytes`, for [`layout_hash!`](crate::layout_hash)
+pub const fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+/// The layout of a type held by a state, hashed into the state's
+/// [`layout_hash!`](crate::layout_hash)
+pub trait LayoutHash {
+    const HASH: u64;
+}
+
+macro_rules! primitive_layout_hash {
+    ($($ty:ty),*) => {
+        $(impl LayoutHash for $ty {
+            const HASH: u64 = fnv1a(FNV_OFFSET, stringify!($ty).as_bytes());
+        })*
+    };
+}
+
+primitive_layout_hash!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, bool);
+
+impl<T: LayoutHash, const N: usize> LayoutHash for [T; N] {
+    const HASH: u64 = fnv1a(
+        fnv1a(fnv1a(FNV_OFFSET, b"array"), &T::HASH.to_le_bytes()),
+        &(N as u64).to_le_bytes(),
+    );
+}
+
+impl<K, V, const N: usize> LayoutHash for RedBlackTree<K, V, N>
+where
+    K: LayoutHash + Ord + Copy + Default + bytemuck::Pod,
+    V: LayoutHash + Copy + Default + bytemuck::Pod,
+{
+    const HASH: u64 = {
+        let hash = fnv1a(FNV_OFFSET, b"RedBlackTree");
+        let hash = fnv1a(hash, &K::HASH.to_le_bytes());
+        let hash = fnv1a(hash, &V::HASH.to_le_bytes());
+        let hash = fnv1a(hash, &(N as u64).to_le_bytes());
+        fnv1a(hash, &(size_of::<Self>() as u64).to_le_bytes())
+    };
+}
+
+/// Hash of a state's layout for
+/// [`Migratable::LAYOUT_HASH`](crate::migrate::Migratable::LAYOUT_HASH): its name and size, and
+/// every field's name, offset, size and type's [`LayoutHash`] in the order given. Fails to
+/// compile unless every field is listed with its type.
+///
+/// ```ignore
+/// const LAYOUT_HASH: u64 = layout_hash!(MyState { header: StateHeader, seq: u64 });
+/// ```
+#[macro_export]
+macro_rules! layout_hash {
+    ($state:ident { $($field:ident: $ty:ty),* $(,)? }) => {{
+        // Destructuring without `..` rejects a field left out, and the bindings a wrong type
+        const _: fn(&$state) = |state| {
+            let $state { $($field),* } = state;
+            $(let _: &$ty = $field;)*
+        };
+        let hash = $crate::layout::fnv1a($crate::layout::FNV_OFFSET, stringify!($state).as_bytes());
+        let hash = $crate::layout::fnv1a(
+            hash,
+            &(::core::mem::size_of::<$state>() as u64).to_le_bytes(),
+        );
+        $(
+            let hash = $crate::layout::fnv1a(hash, stringify!($field).as_bytes());
+            let hash = $crate::layout::fnv1a(
+                hash,
+                &<$ty as $crate::layout::LayoutHash>::HASH.to_le_bytes(),
+            );
+            let hash = $crate::layout::fnv1a(
+                hash,
+                &(::core::mem::offset_of!($state, $field) as u64).to_le_bytes(),
+            );
+            let hash = $crate::layout::fnv1a(
+                hash,
+                &(::core::mem::size_of::<$ty>() as u64).to_le_bytes(),
+            );
+        )*
+        hash
+    }};
+}
+
+/// Implements [`LayoutHash`] for a struct held by a state, from its
+/// [`layout_hash!`](crate::layout_hash). Private fields are only listed from the struct's own
+/// module.
+///
+/// ```ignore
+/// impl_layout_hash!(Vault { address: Pubkey, mint: Pubkey, price: u64 });
+/// ```
+#[macro_export]
+macro_rules! impl_layout_hash {
+    ($state:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+        impl $crate::layout::LayoutHash for $state {
+            const HASH: u64 = $crate::layout_hash!($state { $($field: $ty),* });
+        }
+    };
+}
+
+/// Fails with [`LAYOUT_MISMATCH`] unless the layout hash `stored` in a state's header is the
+/// program's `expected` one
+pub fn check_hash(stored: u64, expected: u64) -> ProgramResult {
+    if stored != expected {
+        crate::log_error!(
+            "State layout hash is {}, the program's {}, migrate it first",
+            stored,
+            expected
+        );
+        return Err(ProgramError::Custom(LAYOUT_MISMATCH));
+    }
+    Ok(())
+}
+
+/// Checks that an account about to be initialized is exactly the state's length, `len`
+pub fn check_len(data_len: usize, len: usize) -> Result<(), ProgramError> {
+    if data_len != len {
+        crate::log_error!("State account is {} bytes, expected {}", data_len, len);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::StateHeader;
+
+    const_assert_state_layout!(StateHeader, size = 560, align = 8);
+
+    #[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
+    #[repr(C)]
+    struct Tiny {
+        header: StateHeader,
+        value: u64,
+        flags: [u8; 8],
+    }
+
+    #[test]
+    fn test_layout_hash() {
+        const HASH: u64 = layout_hash!(Tiny {
+            header: StateHeader,
+            value: u64,
+            flags: [u8; 8],
+        });
+        assert_ne!(HASH, 0);
+        // Fields listed in another order, or a type spelled differently
+        let reordered = layout_hash!(Tiny {
+            header: StateHeader,
+            flags: [u8; 8],
+            value: u64,
+        });
+        // Only the layout counts, not how it's spelled
+        let respelled = layout_hash!(Tiny {
+            header: crate::header::StateHeader,
+            value: u64,
+            flags: [u8; 4 + 4],
+        });
+        assert_ne!(reordered, HASH);
+        assert_eq!(respelled, HASH);
+
+        // Nested layouts count too
+        assert_ne!(<[u8; 8]>::HASH, <[u16; 4]>::HASH);
+        assert_ne!(<[u64; 2]>::HASH, <[[u64; 1]; 2]>::HASH);
+        assert_ne!(
+            <RedBlackTree<u64, u64, 8>>::HASH,
+            <RedBlackTree<u64, i64, 8>>::HASH
+        );
+
+        assert_eq!(check_hash(HASH, HASH), Ok(()));
+        assert_eq!(check_hash(0, 0), Ok(()));
+        assert_eq!(
+            check_hash(0, HASH),
+            Err(ProgramError::Custom(LAYOUT_MISMATCH))
+        );
+    }
+
+    #[test]
+    fn test_check_len() {
+        assert_eq!(check_len(64, 64), Ok(()));
+        assert_eq!(check_len(63, 64), Err(ProgramError::InvalidAccountData));
+        assert_eq!(check_len(65, 64), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn test_state_layout() {
+        let layout = state_layout!(Tiny {
+            header,
+            flags,
+            value
+        });
+        assert_eq!(layout.size, 576);
+        assert_eq!(
+            layout.render(),
+            "Tiny size 576 align 8\n\
+             header offset 0 size 560\n\
+             flags offset 568 size 8\n\
+             value offset 560 size 8\n"
+        );
+
+        let path = std::env::temp_dir().join("apq-layout-tiny.txt");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, layout.render()).unwrap();
+        assert_golden(&layout, path);
+
+        let mut drifted = layout.clone();
+        drifted.fields[1].offset = 512;
+        let result = std::panic::catch_unwind(|| assert_golden(&drifted, path));
+        std::fs::remove_file(path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -2,6 +2,59 @@ use pinocchio::{
     account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
 };
 
+use crate::{ordering::OrderingKey, queue::Entry};
+
+pub mod accounts;
+pub mod admin;
+pub mod balances;
+#[cfg(feature = "borsh")]
+pub mod borsh;
+pub mod breaker;
+pub mod chain;
+pub mod close;
+pub mod commit;
+pub mod compact;
+pub mod config;
+pub mod context;
+pub mod cpi;
+pub mod cursor;
+pub mod dead_letter;
+pub mod dedupe;
+pub mod discriminator;
+pub mod dispatch;
+pub mod error;
+pub mod escrow;
+pub mod events;
+pub mod fees;
+pub mod flush;
+pub mod grow;
+pub mod header;
+pub mod instance;
+pub mod layout;
+pub mod log;
+pub mod migrate;
+pub mod operators;
+pub mod ordering;
+pub mod paged_queue;
+pub mod paranoid;
+pub mod pod;
+pub mod queue;
+pub mod queues;
+pub mod rate_limit;
+pub mod registry;
+pub mod relay;
+pub mod session;
+pub mod shard;
+pub mod shuffle;
+pub mod stats;
+pub mod strict;
+pub mod user_index;
+pub mod vault;
+#[cfg(any(kani, test))]
+mod verification;
+pub mod view;
+pub mod window;
+
 // This was pretty midcurve tbh
 pub mod deser_containers {
     use std::ops::{Deref, DerefMut};
@@ -45,6 +98,27 @@ pub mod deser_containers {
             }
         }
     }
+
+    /// Gives back an owned state so its mutations can be [saved](crate::Persist)
+    pub trait IntoOwned<T> {
+        /// `None` if the state was borrowed from the account, and mutated in place
+        fn into_owned(self) -> Option<T>;
+    }
+
+    impl<T> IntoOwned<T> for &mut T {
+        fn into_owned(self) -> Option<T> {
+            None
+        }
+    }
+
+    impl<'a, T> IntoOwned<T> for OwnedOrBorrowedMut<'a, T> {
+        fn into_owned(self) -> Option<T> {
+            match self {
+                OwnedOrBorrowedMut::Owned(t) => Some(t),
+                OwnedOrBorrowedMut::BorrowedMut(_) => None,
+            }
+        }
+    }
 }
 
 /// This is a trait that allows for flexibility between nonzc/zc methods
@@ -53,13 +127,42 @@ pub trait FromBytes: Sized {
     type Target<'a>;
     type TargetMut<'a>;
     fn from_bytes<'a>(bytes: &'a [u8]) -> Result<Self::Target<'a>, ProgramError>;
-    fn from_bytes_mut<'a>(bytes: &'a mut [u8]) -> Result<Self::TargetMut<'a>, ProgramError>;
+    /// Decodes `bytes` for changing them in place. The dispatch calls it on the state
+    /// account's data, and on a copy of the instruction data of [`SyncIx::MUTABLE`] sync
+    /// instructions, which may rewrite it. Types never decoded mutably can leave the default,
+    /// which fails with [`ApqError::Unsupported`](error::ApqError::Unsupported).
+    fn from_bytes_mut<'a>(_bytes: &'a mut [u8]) -> Result<Self::TargetMut<'a>, ProgramError> {
+        Err(error::ApqError::Unsupported.into())
+    }
+}
+
+/// Writes a state decoded into an owned value back to the account, e.g. a borsh state.
+/// Called by the dispatch once the instruction succeeds.
+pub trait Persist {
+    fn save(&self, bytes: &mut [u8]) -> ProgramResult;
+}
+
+/// Plain old data is saved as its bytes
+impl<T: bytemuck::Pod> Persist for T {
+    fn save(&self, bytes: &mut [u8]) -> ProgramResult {
+        bytes
+            .get_mut(..size_of::<T>())
+            .ok_or(ProgramError::AccountDataTooSmall)?
+            .copy_from_slice(bytemuck::bytes_of(self));
+        Ok(())
+    }
 }
 
 // Core traits for the async/sync program pattern.
 // fairly flexible but specific use cases may need more
 
 pub trait SyncIx: FromBytes {
+    /// Whether the instruction normalizes its own data before processing, e.g. clamping an
+    /// argument. The dispatch then decodes a copy of the data with
+    /// [`FromBytes::from_bytes_mut`], which may rewrite it, and passes the rewritten data to
+    /// [`SyncIx::process`]. See [`dispatch::sync_data`].
+    const MUTABLE: bool = false;
+
     fn process<S: AsyncState>(
         &self,
         data: &[u8],
@@ -71,20 +174,496 @@ pub trait SyncIx: FromBytes {
 pub trait AsyncIx: FromBytes + Ord {
     type Args;
     fn process<S: AsyncState>(&self, args: &Self::Args, state: &mut S) -> ProgramResult;
+
+    /// Encodes `args` into the [`ArgsSlot`](queue::ArgsSlot) stored with the entry, so they
+    /// survive the queue. Plain old data args can use [`queue::encode_pod_args`].
+    fn encode_args(_args: &Self::Args) -> Result<queue::ArgsSlot, ProgramError> {
+        Err(ProgramError::InvalidArgument)
+    }
+
+    /// Decodes the args stored by [`AsyncIx::encode_args`]
+    fn decode_args(_slot: &queue::ArgsSlot) -> Result<Self::Args, ProgramError> {
+        Err(ProgramError::InvalidAccountData)
+    }
+
+    /// [`AsyncIx::process`] with a [`chain::Chain`] to queue follow-ups on, for states
+    /// processing entries with [`AsyncState::process_chained_entry`]
+    fn process_chained<S: AsyncState<AsyncIx = Self>>(
+        &self,
+        args: &Self::Args,
+        state: &mut S,
+        _chain: &mut chain::Chain<Self, S::QueueArgs>,
+    ) -> ProgramResult
+    where
+        Self: Sized,
+    {
+        self.process(args, state)
+    }
+
+    /// [`AsyncIx::process`] with the accounts the entry references, for instructions that CPI
+    /// when drained. See [`accounts`].
+    fn process_with_accounts<S: AsyncState>(
+        &self,
+        args: &Self::Args,
+        state: &mut S,
+        _accounts: &[&AccountInfo],
+    ) -> ProgramResult {
+        self.process(args, state)
+    }
 }
 
 pub trait AsyncState: FromBytes {
     type SyncIx: SyncIx;
     type AsyncIx: AsyncIx;
+    /// Stored in the queue with each instruction, built from `QueueArgs` on enqueue
+    /// and turned into the `AsyncIx::Args` it is processed with
+    type Payload: queue::Payload;
     type QueueArgs;
+    /// Orders the queue, see [`ordering`]
+    type Key: ordering::OrderingKey<Self::AsyncIx, Self::QueueArgs>;
+
+    /// Account length the state is grown to, see [`grow`]
+    const LEN: usize = size_of::<Self>();
+
+    /// Lamports escrowed with every queued instruction and paid to whoever drains it
+    const CRANK_BOUNTY: u64 = 0;
+
+    /// Lamports escrowed with every queued instruction on top of the crank bounty and priority
+    /// bid, which the state keeps once the instruction is processed, e.g. as a fee.
+    /// [`AsyncState::cancel_async`] refunds it with the rest of the escrow.
+    const DEPOSIT: u64 = 0;
+
+    /// Lamports every queued, committed or replaced instruction pays on top of its escrow,
+    /// kept by the state until the admin withdraws them. See [`fees`].
+    const PROTOCOL_FEE: u64 = 0;
+
+    /// Only let allowlisted operators drain the queue, see [`operators`]
+    const PERMISSIONED_CRANK: bool = false;
+
+    /// Read operational parameters from a config account, see [`config`]
+    const CONFIG_ACCOUNT: bool = false;
+
+    /// Only initialize states as numbered instances of a registry, see [`instance`]
+    const INSTANCES: bool = false;
+
+    /// Let relayers queue on behalf of users who signed an authorization, see [`relay`].
+    /// States setting it must implement [`AsyncState::relayed`], so authorizations are only
+    /// relayed once.
+    const RELAYED: bool = false;
 
+    /// Let users delegate queueing to session keys, see [`session`]
+    const SESSIONS: bool = false;
+
+    /// Slots an instruction waits in the queue before it can be processed, i.e. the length
+    /// of the auction window. Zero allows processing in the next slot, see
+    /// [`AsyncState::MIN_DRAIN_AGE_SLOTS`].
+    ///
+    /// Counted in seconds for programs on [`ordering::Schedule::UnixTimestamp`].
+    const ASYNC_DELAY_SLOTS: u64 = 1;
+
+    /// Slots an instruction waits before any drain processes it, whatever
+    /// [`AsyncState::ASYNC_DELAY_SLOTS`] is, see [`ordering::drain_cutoff`]. Core never drains
+    /// an entry in the slot it was queued, so at least one: a drain and an enqueue landing in
+    /// the same slot leave the entry queued whichever comes first.
+    ///
+    /// Counted in seconds for programs on [`ordering::Schedule::UnixTimestamp`].
+    const MIN_DRAIN_AGE_SLOTS: u64 = 1;
+
+    /// Clock the queue keys and [`AsyncState::has_pending_async`] are on
+    const SCHEDULE: ordering::Schedule = ordering::Schedule::Slot;
+
+    /// Names of the state's queues. With more than one, the default drain picks between them
+    /// by [`AsyncState::DRAIN_POLICY`], see [`queues`].
+    const QUEUES: &'static [&'static str] = &queues::SINGLE;
+
+    /// Order the queues are drained in, if there are several
+    const DRAIN_POLICY: queues::DrainPolicy = queues::DrainPolicy::Ordered;
+
+    /// State accounts the queue is sharded across, see [`shard`]. Sharded queues drain one
+    /// instruction at a time in key order, with a single queue per shard.
+    const SHARDS: u8 = 1;
+
+    /// Whether instruction data may have bytes left over after what is read, see [`strict`]
+    const STRICTNESS: strict::Strictness = strict::Strictness::Lenient;
+
+    /// Drain with [`AsyncState::process_next_batch`] instead of one instruction at a time
+    const BATCH_MODE: bool = false;
+
+    /// Drain with [`AsyncState::process_next_shuffled`], running each slot's entries in a
+    /// random order instead of key order. See [`shuffle`].
+    const SHUFFLE_SLOT: bool = false;
+
+    /// What happens when an instruction fails while draining, see [`dead_letter`]. Only
+    /// applies to the default one-at-a-time drain.
+    const FAILURE_POLICY: dead_letter::FailurePolicy = dead_letter::FailurePolicy::Abort;
+
+    /// How long a commitment has to be revealed, on [`AsyncState::SCHEDULE`]. Zero disables
+    /// commit-reveal, otherwise [`AsyncState::commitments`], [`AsyncState::next_seq`] and
+    /// [`AsyncState::queue_async_at`] must be implemented. See [`commit`].
+    const COMMIT_EXPIRY_SLOTS: u64 = 0;
+
+    /// Instructions each user can queue or commit per slot, on [`AsyncState::SCHEDULE`].
+    /// Zero is unlimited, otherwise [`AsyncState::rate_limits`] must be implemented. See
+    /// [`rate_limit`].
+    const MAX_ENQUEUES_PER_SLOT: u64 = 0;
+
+    /// How long the head of a queue can wait, on [`AsyncState::SCHEDULE`], before enqueues are
+    /// rejected until the crank catches up. Zero never rejects them. See [`breaker`].
+    const MAX_QUEUE_AGE_SLOTS: u64 = 0;
+
+    /// Whether [`AsyncState::replace_async`] keeps the replaced entry's time priority. If not,
+    /// the replacement goes to the back of the line like a new entry.
+    const REPLACE_KEEPS_PRIORITY: bool = false;
+
+    /// Slots an entry can wait past the slot it was queued in before anyone can remove it
+    /// with the compact instruction, on [`AsyncState::SCHEDULE`]. Zero keeps entries until
+    /// they are drained, otherwise [`AsyncState::expire_entry`] must be implemented. See
+    /// [`compact`].
+    const ENTRY_TTL_SLOTS: u64 = 0;
+
+    /// Whether a config can set a drain window after which each slot's entries expire, in
+    /// which case [`AsyncState::expire_entry`] must be implemented. Windows expire entries off
+    /// the head of a single queue, so its keys must sort by slot first. See [`window`].
+    const DRAIN_WINDOW: bool = false;
+
+    /// Let processed instructions queue follow-ups, see [`chain`]. States setting it implement
+    /// [`AsyncState::chain_link`], [`AsyncState::process_chained_entry`] and
+    /// [`AsyncState::queue_follow_up`], and drain one entry at a time whatever their
+    /// [`AsyncState::FAILURE_POLICY`].
+    const CHAINED: bool = false;
+
+    /// Whether queueing spills into overflow page accounts once the queue is full instead of
+    /// failing, see [`paged_queue`]. States setting it keep their queue in a
+    /// [`paged_queue::QueuePage`], implement [`AsyncState::queue_async_paged`] and
+    /// [`AsyncState::refill_from_pages`], and take their pages among the remaining accounts
+    /// when queueing and draining. Not for sharded states.
+    const OVERFLOW_PAGES: bool = false;
+
+    /// Called on a zeroed account the first time it is loaded
+    fn initialize(&mut self);
+
+    /// Parses the args to queue with, from the async instruction data (variant included)
+    fn queue_args(user: &AccountInfo, data: &[u8]) -> Result<Self::QueueArgs, ProgramError>;
+
+    /// Lamports escrowed on top of the crank bounty to bid for queue position
+    fn priority_bid(_args: &Self::QueueArgs) -> u64 {
+        0
+    }
+
+    /// Called by the default dispatch before queueing, revealing or replacing `ix` with
+    /// `args`, to reject it on the program's own rules, e.g. a market being closed or a price
+    /// outside its band. `accounts` are the remaining accounts after those the instruction
+    /// itself takes.
+    fn validate_enqueue(
+        &self,
+        _ix: &Self::AsyncIx,
+        _args: &Self::QueueArgs,
+        _accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        Ok(())
+    }
+
+    /// Queues `ix` at `now`, a time on [`AsyncState::SCHEDULE`]
     fn queue_async(
         &mut self,
         ix: &Self::AsyncIx,
         args: &Self::QueueArgs,
+        now: u64,
     ) -> Result<(), ProgramError>;
+
+    /// Queues the follow-up `ix` at `now` like [`AsyncState::queue_async`], storing its `link`
+    /// for [`AsyncState::chain_link`]. Entries from `queue_async` are at
+    /// [`chain::Link::root`].
+    fn queue_follow_up(
+        &mut self,
+        _ix: &Self::AsyncIx,
+        _args: &Self::QueueArgs,
+        _link: chain::Link,
+        _now: u64,
+    ) -> ProgramResult {
+        Err(error::ApqError::Unsupported.into())
+    }
+
+    /// [`AsyncState::queue_async`] into the tail of a paged queue, linking one of the spare
+    /// `pages` once it is full, for [`AsyncState::OVERFLOW_PAGES`]
+    fn queue_async_paged(
+        &mut self,
+        _ix: &Self::AsyncIx,
+        _args: &Self::QueueArgs,
+        _now: u64,
+        _pages: &paged_queue::Pages,
+    ) -> ProgramResult {
+        Err(error::ApqError::Unsupported.into())
+    }
+
+    /// Moves the next overflow page's entries into the primary page once it is empty, e.g.
+    /// with [`paged_queue::Pages::refill`], for [`AsyncState::OVERFLOW_PAGES`]. Returns
+    /// whether it did.
+    fn refill_from_pages(&mut self, _pages: &paged_queue::Pages) -> Result<bool, ProgramError> {
+        Err(error::ApqError::Unsupported.into())
+    }
+
+    /// Removes `user`'s queued instruction under `key`, returning the lamports to refund
+    /// from escrow
+    fn cancel_async(&mut self, _user: &Pubkey, _key: &Self::Key) -> Result<u64, ProgramError> {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// Cancels up to `max` of `user`'s queued entries, e.g. with [`queue::cancel_all`]
+    fn cancel_all_async(
+        &mut self,
+        _user: &Pubkey,
+        _max: u32,
+    ) -> Result<queue::CancelAll, ProgramError> {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// Removes the expired entry under `key` without processing it, for [`compact`]. Its
+    /// escrow is forfeited but for the crank bounty.
+    fn expire_entry(&mut self, _key: &Self::Key) -> ProgramResult {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// Removes the next entry without processing or refunding it, for [`flush`]. Returns
+    /// whether there was one. States override it to emit an event or update their own indexes.
+    fn flush_entry(&mut self) -> Result<bool, ProgramError> {
+        Ok(self.pop_entry().is_some())
+    }
+
+    /// Replaces `user`'s pending entry with sequence number `old_seq` by `ix`, queued at `now`
+    /// unless [`AsyncState::REPLACE_KEEPS_PRIORITY`]. Returns the lamports to refund for the
+    /// replaced entry, like [`AsyncState::cancel_async`].
+    fn replace_async(
+        &mut self,
+        _user: &Pubkey,
+        _old_seq: u64,
+        _ix: &Self::AsyncIx,
+        _args: &Self::QueueArgs,
+        _now: u64,
+    ) -> Result<u64, ProgramError> {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// Queues `ix` with the time priority of an earlier `slot` and reserved `seq`
+    fn queue_async_at(
+        &mut self,
+        _ix: &Self::AsyncIx,
+        _args: &Self::QueueArgs,
+        _slot: u64,
+        _seq: u64,
+    ) -> ProgramResult {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// Reserves the next sequence number, e.g. for a commitment, with [`ordering::next_seq`]
+    fn next_seq(&mut self) -> Result<u64, ProgramError> {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// Pending commit-reveal commitments
+    fn commitments(&mut self) -> Option<&mut commit::Commitments> {
+        None
+    }
+
+    /// Who queued how much this slot, for [`AsyncState::MAX_ENQUEUES_PER_SLOT`]
+    fn rate_limits(&mut self) -> Option<&mut rate_limit::RateLimits> {
+        None
+    }
+
+    /// Authorizations relayed until they expire, for [`AsyncState::RELAYED`]
+    fn relayed(&mut self) -> Option<&mut relay::RelayedStore> {
+        None
+    }
     fn process_next_async(&mut self) -> ProgramResult;
-    fn has_pending_async(&self, slot: u64) -> bool;
+
+    /// [`AsyncState::process_next_async`] with the drain's remaining accounts, which states
+    /// whose instructions CPI resolve their entry's accounts from. See [`accounts`].
+    fn process_next_async_with(&mut self, _accounts: &[AccountInfo]) -> ProgramResult {
+        self.process_next_async()
+    }
+
+    /// Whether the next instruction is due at `now`, a time on [`AsyncState::SCHEDULE`].
+    /// Drains expire entries whose [`window`] closed before asking.
+    fn has_pending_async(&self, now: u64) -> bool;
+
+    /// When the head of queue `queue` of [`AsyncState::QUEUES`] was queued, if it isn't empty
+    fn queue_head(&self, queue: usize) -> Option<u64> {
+        match queue {
+            0 => self
+                .peek_entry()
+                .map(|entry| ordering::OrderingKey::slot(&entry.key)),
+            _ => None,
+        }
+    }
+
+    /// Pops and processes the head of queue `queue` of [`AsyncState::QUEUES`]
+    fn process_next_in(&mut self, queue: usize) -> ProgramResult {
+        match queue {
+            0 => self.process_next_async(),
+            _ => Err(ProgramError::InvalidArgument),
+        }
+    }
+
+    /// Called before a drain processes anything, at `now` on [`AsyncState::SCHEDULE`]
+    fn on_drain_start(&mut self, _now: u64) -> ProgramResult {
+        Ok(())
+    }
+
+    /// Called after each entry is processed successfully, e.g. to add up a batch's volume.
+    /// Entries of queues other than queue 0 of [`AsyncState::QUEUES`] aren't reported.
+    fn on_item_processed(&mut self, _entry: &Entry<Self::Key, Self::Payload>) -> ProgramResult {
+        Ok(())
+    }
+
+    /// Called once a drain has processed everything due, `processed` entries in all, e.g. to
+    /// compute a clearing price for the slot
+    fn on_drain_end(&mut self, _now: u64, _processed: u64) -> ProgramResult {
+        Ok(())
+    }
+
+    /// Every queued entry in drain order, see [`view`]
+    fn entries(&self) -> impl Iterator<Item = (&Self::Key, &Self::Payload)>;
+
+    /// Queued entries due at `now` on [`AsyncState::SCHEDULE`], in drain order. Entries not
+    /// due yet are skipped rather than ending the iteration, since keys ranked by priority
+    /// can put them ahead of due ones.
+    fn iter_pending(&self, now: u64) -> impl Iterator<Item = (&Self::Key, &Self::Payload)> {
+        self.entries()
+            .filter(move |(key, _)| key.is_due(now, Self::ASYNC_DELAY_SLOTS))
+    }
+
+    /// `user`'s queued entries in drain order
+    fn iter_by_user<'a>(
+        &'a self,
+        user: &'a Pubkey,
+    ) -> impl Iterator<Item = (&'a Self::Key, &'a Self::Payload)>
+    where
+        Self::Payload: queue::UserPayload,
+    {
+        self.entries()
+            .filter(move |(_, payload)| queue::UserPayload::user(*payload) == user)
+    }
+
+    /// The next entry to be drained
+    fn peek_entry(&self) -> Option<&Entry<Self::Key, Self::Payload>>;
+    /// Removes the next entry without processing it
+    fn pop_entry(&mut self) -> Option<Entry<Self::Key, Self::Payload>>;
+
+    /// Settles every entry queued in one slot at once, e.g. as a uniform-price batch auction.
+    /// Only called in [`AsyncState::BATCH_MODE`], which must override it.
+    fn process_batch(&mut self, _batch: &[Entry<Self::Key, Self::Payload>]) -> ProgramResult {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// Processes an entry that has already been popped, needed for
+    /// [`AsyncState::SHUFFLE_SLOT`] and any [`AsyncState::FAILURE_POLICY`] but `Abort`
+    fn process_entry(&mut self, _entry: &Entry<Self::Key, Self::Payload>) -> ProgramResult {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// The user who queued the chain `entry` is in, and its [`chain::Link`], for
+    /// [`AsyncState::CHAINED`]
+    fn chain_link(
+        &self,
+        _entry: &Entry<Self::Key, Self::Payload>,
+    ) -> Result<(Pubkey, chain::Link), ProgramError> {
+        Err(error::ApqError::Unsupported.into())
+    }
+
+    /// [`AsyncState::process_entry`] queueing follow-ups on `chain`, for
+    /// [`AsyncState::CHAINED`]
+    fn process_chained_entry(
+        &mut self,
+        entry: &Entry<Self::Key, Self::Payload>,
+        _chain: &mut chain::Chain<Self::AsyncIx, Self::QueueArgs>,
+    ) -> ProgramResult {
+        self.process_entry(entry)
+    }
+
+    /// Keeps an entry that failed to process for later inspection
+    fn dead_letter(&mut self, _entry: &Entry<Self::Key, Self::Payload>) -> ProgramResult {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    /// Empties the dead-letter store. Returns how many entries were purged.
+    fn purge_dead_letters(&mut self) -> usize {
+        0
+    }
+
+    /// Checks the state, after every instruction in builds with the `paranoid` feature and
+    /// on demand with the verify state instruction, e.g. the queue with
+    /// [`paranoid::check_tree`]
+    fn check_invariants(&self) -> ProgramResult {
+        Ok(())
+    }
+
+    /// Whether nothing the state account holds lamports for is left, so it can be closed.
+    /// Only the state knows everything it keeps for its users, e.g. balances, resting orders
+    /// or dead letters besides its queue, so states can't be closed unless they override it,
+    /// see [`close`].
+    fn is_settled(&self) -> bool {
+        false
+    }
+
+    /// Rebuilds the queue's free list if the queue is empty, e.g. with
+    /// [`compact::reset_if_empty`]. Returns whether it was rebuilt.
+    fn rebuild_free_list(&mut self) -> bool {
+        false
+    }
+
+    /// Pops the next due entry together with everything else queued in its slot,
+    /// empty if nothing is due
+    fn pop_batch(&mut self, now: u64) -> Vec<Entry<Self::Key, Self::Payload>> {
+        if !self.has_pending_async(now) {
+            return Vec::new();
+        }
+        let Some(first) = self.pop_entry() else {
+            return Vec::new();
+        };
+
+        let slot = first.key.slot();
+        let mut batch = vec![first];
+        while self
+            .peek_entry()
+            .is_some_and(|entry| entry.key.slot() == slot)
+        {
+            batch.extend(self.pop_entry());
+        }
+        batch
+    }
+
+    /// Hands the next due slot's entries to [`AsyncState::process_batch`].
+    /// Returns the batch size, zero if nothing is due.
+    fn process_next_batch(&mut self, now: u64) -> Result<usize, ProgramError> {
+        let batch = self.pop_batch(now);
+        if !batch.is_empty() {
+            self.process_batch(&batch)?;
+        }
+        for entry in &batch {
+            self.on_item_processed(entry)?;
+        }
+        Ok(batch.len())
+    }
+
+    /// Processes the next due slot's entries in an order shuffled by `slot_hash`.
+    /// Returns the batch size, zero if nothing is due.
+    fn process_next_shuffled(
+        &mut self,
+        now: u64,
+        slot_hash: &[u8; 32],
+    ) -> Result<usize, ProgramError> {
+        let mut batch = self.pop_batch(now);
+        let Some(slot) = batch.first().map(|entry| entry.key.slot()) else {
+            return Ok(0);
+        };
+
+        shuffle::shuffle(&mut batch, shuffle::seed(slot_hash, slot));
+        for entry in &batch {
+            self.process_entry(entry)?;
+            self.on_item_processed(entry)?;
+        }
+        Ok(batch.len())
+    }
 }
 
 pub trait Program {
@@ -97,4 +676,16 @@ pub trait Program {
         accounts: &[AccountInfo],
         instruction_data: &[u8],
     ) -> ProgramResult;
+
+    /// Handles the program's own instructions, tagged from
+    /// [`tag::CUSTOM`](dispatch::tag::CUSTOM) on, which the default dispatch passes here with
+    /// all the accounts and the data after the tag. None by default.
+    fn process_custom(
+        _program_id: &Pubkey,
+        _accounts: &[AccountInfo],
+        _tag: u8,
+        _data: &[u8],
+    ) -> ProgramResult {
+        Err(ProgramError::InvalidInstructionData)
+    }
 }
@@ -0,0 +1,70 @@
+//! Leveled logging, compiled out below the enabled level
+//!
+//! [`log_error!`](crate::log_error), [`log_info!`](crate::log_info) and
+//! [`log_debug!`](crate::log_debug) take the same arguments as `pinocchio_log::log!`, which
+//! formats into a stack buffer instead of allocating. Callers need `pinocchio-log` as a
+//! dependency.
+//!
+//! The level is picked with the `log-error`, `log-info` (default) and `log-debug` features of
+//! apq-core, each including the levels before it. Without any, nothing is logged. Disabled
+//! calls still type-check their arguments but cost nothing.
+
+/// Something went wrong, logged with the `log-error` feature
+#[cfg(feature = "log-error")]
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {{
+        pinocchio_log::log!($($arg)*);
+    }};
+}
+
+/// Something went wrong, logged with the `log-error` feature
+#[cfg(not(feature = "log-error"))]
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        if false {
+            pinocchio_log::log!($($arg)*);
+        }
+    };
+}
+
+/// Which instruction is running and what it did, logged with the `log-info` feature
+#[cfg(feature = "log-info")]
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {{
+        pinocchio_log::log!($($arg)*);
+    }};
+}
+
+/// Which instruction is running and what it did, logged with the `log-info` feature
+#[cfg(not(feature = "log-info"))]
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if false {
+            pinocchio_log::log!($($arg)*);
+        }
+    };
+}
+
+/// Per queue entry detail, logged with the `log-debug` feature
+#[cfg(feature = "log-debug")]
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {{
+        pinocchio_log::log!($($arg)*);
+    }};
+}
+
+/// Per queue entry detail, logged with the `log-debug` feature
+#[cfg(not(feature = "log-debug"))]
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if false {
+            pinocchio_log::log!($($arg)*);
+        }
+    };
+}
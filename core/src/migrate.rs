@@ -0,0 +1,148 @@
+//! State layout migrations
+//!
+//! Layout changes to a live state type bump its [`Migratable::VERSION`] and teach
+//! [`Migratable::migrate`] how to rewrite the previous layouts. Accounts are then upgraded in
+//! place through the program's migrate tag. If the new layout is larger, grow the account
+//! first with [`crate::grow::GrowState`].
+
+use std::cmp::Ordering;
+
+use pinocchio::{program_error::ProgramError, ProgramResult};
+
+use crate::header::StateHeader;
+
+pub trait Migratable {
+    /// Current layout version, written into the [`StateHeader`] on initialization
+    const VERSION: u32;
+
+    /// Hash of the current layout, from [`layout_hash!`](crate::layout_hash), written into the
+    /// [`StateHeader`] with the version. Zero doesn't check it. Setting it on a state with
+    /// accounts already created takes a new version too, whose migration writes it. See
+    /// [`crate::layout`].
+    const LAYOUT_HASH: u64 = 0;
+
+    /// Rewrites `data`, laid out as `from_version`, into the current layout.
+    ///
+    /// `data` is the whole account, header included, at its current length.
+    /// The header version is updated by the caller once this returns.
+    fn migrate(from_version: u32, data: &mut [u8]) -> ProgramResult;
+}
+
+/// Opens `len` zeroed bytes at `at` in a state laid out over `data[..old_len]`, shifting what
+/// follows up, for migrations inserting a field. The account must have been grown by `len`
+/// first.
+pub fn insert_zeroed(data: &mut [u8], at: usize, len: usize, old_len: usize) -> ProgramResult {
+    if at > old_len || data.len() < old_len + len {
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    data.copy_within(at..old_len, at + len);
+    data[at..at + len].fill(0);
+    Ok(())
+}
+
+/// Migrates a state laid out over `data[..old_len]` with a header from before
+/// [`StateHeader::layout_hash`], opening the field for [`migrate_state`] to write
+pub fn insert_layout_hash(data: &mut [u8], old_len: usize) -> ProgramResult {
+    insert_zeroed(
+        data,
+        std::mem::offset_of!(StateHeader, layout_hash),
+        size_of::<u64>(),
+        old_len,
+    )
+}
+
+/// Upgrades raw state account data to `S::VERSION`. Up-to-date accounts are left untouched,
+/// so this is safe to call permissionlessly.
+pub fn migrate_state<S: Migratable>(data: &mut [u8]) -> ProgramResult {
+    let header = StateHeader::read(data)?;
+    match header.version.cmp(&S::VERSION) {
+        Ordering::Equal => Ok(()),
+        // Written by a newer program, we can't go back
+        Ordering::Greater => Err(ProgramError::InvalidAccountData),
+        Ordering::Less if header.version == 0 => Err(ProgramError::UninitializedAccount),
+        Ordering::Less => {
+            crate::log_info!("Migrating state v{} -> v{}", header.version, S::VERSION);
+            S::migrate(header.version, data)?;
+
+            // Keep whatever else the migrated header carries, e.g. the operators
+            let mut header = StateHeader::read(data)?;
+            header.version = S::VERSION;
+            header.layout_hash = S::LAYOUT_HASH;
+            header.write(data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// v1 was `[header, a]`, v2 adds `b = 2 * a`
+    struct Doubled;
+
+    const A: std::ops::Range<usize> = StateHeader::LEN..StateHeader::LEN + 8;
+    const B: std::ops::Range<usize> = StateHeader::LEN + 8..StateHeader::LEN + 16;
+
+    impl Migratable for Doubled {
+        const VERSION: u32 = 2;
+
+        fn migrate(from_version: u32, data: &mut [u8]) -> ProgramResult {
+            match from_version {
+                1 => {
+                    let a = u64::from_le_bytes(data[A].try_into().unwrap());
+                    data[B].copy_from_slice(&(2 * a).to_le_bytes());
+                    Ok(())
+                }
+                _ => Err(ProgramError::InvalidAccountData),
+            }
+        }
+    }
+
+    #[test]
+    fn test_migrate_state() {
+        let mut data = [0; StateHeader::LEN + 16];
+        assert_eq!(
+            migrate_state::<Doubled>(&mut data),
+            Err(ProgramError::UninitializedAccount)
+        );
+
+        StateHeader::new(1).write(&mut data).unwrap();
+        data[A].copy_from_slice(&21_u64.to_le_bytes());
+        migrate_state::<Doubled>(&mut data).unwrap();
+        assert_eq!(StateHeader::read(&data).unwrap().version, 2);
+        assert_eq!(data[B], 42_u64.to_le_bytes());
+
+        // Already up to date
+        data[B].fill(0);
+        migrate_state::<Doubled>(&mut data).unwrap();
+        assert_eq!(data[B], [0; 8]);
+
+        StateHeader::new(3).write(&mut data).unwrap();
+        assert_eq!(
+            migrate_state::<Doubled>(&mut data),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_insert_zeroed() {
+        let mut data = [1, 2, 3, 4, 0, 0];
+        assert_eq!(
+            insert_zeroed(&mut data, 1, 3, 4),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        insert_zeroed(&mut data, 1, 2, 4).unwrap();
+        assert_eq!(data, [1, 0, 0, 2, 3, 4]);
+
+        // A header from before the layout hash keeps its fields
+        let mut header = StateHeader::new(1);
+        header.shard = 3;
+        header.stats.total_enqueued = 9;
+        let mut data = vec![0; StateHeader::LEN];
+        let bytes = bytemuck::bytes_of(&header);
+        data[..8].copy_from_slice(&bytes[..8]);
+        data[8..StateHeader::LEN - 8].copy_from_slice(&bytes[16..]);
+        insert_layout_hash(&mut data, StateHeader::LEN - 8).unwrap();
+        assert_eq!(StateHeader::read(&data).unwrap(), header);
+    }
+}
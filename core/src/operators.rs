@@ -0,0 +1,97 @@
+//! Allowlist of keepers permitted to drain the queue
+//!
+//! Only enforced for states with [`AsyncState::PERMISSIONED_CRANK`](crate::AsyncState)
+//! set. The registry lives in the [`StateHeader`](crate::header::StateHeader).
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+
+pub const MAX_OPERATORS: usize = 8;
+
+/// Empty operator slot
+const NO_OPERATOR: Pubkey = [0; 32];
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct OperatorRegistry {
+    /// Can add and remove operators, set to whoever initialized the state
+    pub authority: Pubkey,
+    /// Empty slots are all zeroes
+    pub operators: [Pubkey; MAX_OPERATORS],
+}
+
+crate::impl_layout_hash!(OperatorRegistry {
+    authority: Pubkey,
+    operators: [Pubkey; MAX_OPERATORS]
+});
+
+impl OperatorRegistry {
+    pub fn is_operator(&self, key: &Pubkey) -> bool {
+        *key != NO_OPERATOR && self.operators.contains(key)
+    }
+
+    /// Sets slot `index` to `operator`, all zeroes clears it
+    pub fn set(&mut self, index: usize, operator: Pubkey) -> ProgramResult {
+        *self
+            .operators
+            .get_mut(index)
+            .ok_or(ProgramError::InvalidArgument)? = operator;
+        Ok(())
+    }
+
+    /// Checks that `authority` is the signing registry authority
+    pub fn check_authority(&self, authority: &AccountInfo) -> ProgramResult {
+        if !authority.is_signer() || *authority.key() != self.authority {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        Ok(())
+    }
+
+    /// Handles the set operator instruction, data is `[index: u8, operator: Pubkey]`
+    pub fn process_set(&mut self, authority: &AccountInfo, data: &[u8]) -> ProgramResult {
+        self.check_authority(authority)?;
+
+        let (&index, operator) = data
+            .split_first()
+            .ok_or(ProgramError::InvalidInstructionData)?;
+        let operator = operator
+            .try_into()
+            .map_err(|_| ProgramError::InvalidInstructionData)?;
+        self.set(index as usize, operator)
+    }
+
+    /// Checks that `cranker` is a signing operator
+    pub fn check_operator(&self, cranker: &AccountInfo) -> ProgramResult {
+        if !cranker.is_signer() || !self.is_operator(cranker.key()) {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = OperatorRegistry::default();
+        assert!(!registry.is_operator(&[1; 32]));
+        // Empty slots never match
+        assert!(!registry.is_operator(&NO_OPERATOR));
+
+        registry.set(3, [1; 32]).unwrap();
+        assert!(registry.is_operator(&[1; 32]));
+
+        registry.set(3, NO_OPERATOR).unwrap();
+        assert!(!registry.is_operator(&[1; 32]));
+
+        assert_eq!(
+            registry.set(MAX_OPERATORS, [1; 32]),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,501 @@
+//! Drain order of the async queue
+//!
+//! The queue is a tree sorted by key, so the key type alone decides which instruction runs
+//! next. Programs pick the policy (time priority, price-time, fee priority, ...) by choosing
+//! their [`AsyncState::Key`](crate::AsyncState::Key).
+
+use std::cmp::Ordering;
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    program_error::ProgramError,
+    sysvars::{clock::Clock, Sysvar},
+};
+
+use crate::queue::QueueKey;
+
+/// Clock the queue is scheduled on, see [`AsyncState::SCHEDULE`](crate::AsyncState::SCHEDULE)
+///
+/// Keys store and compare times in the schedule's unit, so `slot` in [`OrderingKey`] is a
+/// unix timestamp for programs scheduled on [`Schedule::UnixTimestamp`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Schedule {
+    Slot,
+    /// Wall-clock seconds, for vesting, timed settlement and the like
+    UnixTimestamp,
+}
+
+impl Schedule {
+    /// Current time on this schedule, from the clock sysvar
+    pub fn now(&self) -> Result<u64, ProgramError> {
+        let clock = Clock::get()?;
+        Ok(match self {
+            Schedule::Slot => clock.slot,
+            // Timestamps before the epoch don't happen on a live cluster
+            Schedule::UnixTimestamp => clock.unix_timestamp.max(0) as u64,
+        })
+    }
+}
+
+/// Custom program error for running out of sequence numbers, spelling `APQ` followed by 3
+pub const SEQ_EXHAUSTED: u32 = 0x4150_5103;
+
+/// Takes the next sequence number from the state's counter `seq`
+///
+/// Fails with [`SEQ_EXHAUSTED`] instead of wrapping around, as a wrapped sequence number
+/// would sort ahead of everything queued in the same slot before it.
+pub fn next_seq(seq: &mut u64) -> Result<u64, ProgramError> {
+    let next = seq
+        .checked_add(1)
+        .ok_or(ProgramError::Custom(SEQ_EXHAUSTED))?;
+    Ok(std::mem::replace(seq, next))
+}
+
+/// Custom program error for an entry dated after the current time, spelling `APQ` followed
+/// by 4
+pub const SLOT_SKEW: u32 = 0x4150_5104;
+
+/// Checks that an entry about to be queued in `slot` isn't dated after `now`, where it would
+/// either wait at the head of the queue or jump ahead of entries actually queued earlier
+pub fn check_slot(slot: u64, now: u64) -> Result<(), ProgramError> {
+    if slot > now {
+        return Err(ProgramError::Custom(SLOT_SKEW));
+    }
+    Ok(())
+}
+
+/// Where [`dispatch`](crate::dispatch) gets the current time from
+///
+/// On-chain this is always [`SysvarClock`]. The clock sysvar isn't available in plain unit
+/// tests, which use a [`FixedClock`] instead.
+pub trait SlotSource {
+    /// Current time on `schedule`
+    fn now(&self, schedule: Schedule) -> Result<u64, ProgramError>;
+}
+
+/// The clock sysvar
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SysvarClock;
+
+impl SlotSource for SysvarClock {
+    fn now(&self, schedule: Schedule) -> Result<u64, ProgramError> {
+        schedule.now()
+    }
+}
+
+/// The same time on every schedule, for tests
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct FixedClock(pub u64);
+
+impl SlotSource for FixedClock {
+    fn now(&self, _schedule: Schedule) -> Result<u64, ProgramError> {
+        Ok(self.0)
+    }
+}
+
+pub trait OrderingKey<Ix, Args>: QueueKey {
+    /// Whether keys can be dated after the time they were queued, i.e. scheduled for later
+    /// like [`TimestampKey`]. Drains only take future-dated entries off the head of the queue
+    /// as skewed for keys that can't, see
+    /// [`dead_letter::skip_future`](crate::dead_letter::skip_future).
+    const SCHEDULED: bool = false;
+
+    /// Builds the key for `ixn` queued in `slot` with sequence number `seq`
+    fn key(slot: u64, seq: u64, ixn: &Ix, args: &Args) -> Self;
+
+    /// Slot the instruction was queued in, which decides when it can be processed
+    fn slot(&self) -> u64;
+
+    /// Whether the instruction can be processed in `slot`, `delay_slots` after it was queued.
+    /// See [`AsyncState::ASYNC_DELAY_SLOTS`](crate::AsyncState::ASYNC_DELAY_SLOTS).
+    fn is_due(&self, slot: u64, delay_slots: u64) -> bool {
+        self.slot().saturating_add(delay_slots) <= slot
+    }
+}
+
+/// The time a drain at `now` processes entries by, when they are due at `due`, so that nothing
+/// dated less than `min_age` before `now` is processed, and never anything dated `now` itself
+///
+/// A drain and an enqueue landing in the same slot could otherwise see the entry drained or
+/// not depending on which comes first in the slot. See
+/// [`AsyncState::MIN_DRAIN_AGE_SLOTS`](crate::AsyncState::MIN_DRAIN_AGE_SLOTS).
+pub fn drain_cutoff(due: u64, now: u64, delay_slots: u64, min_age: u64) -> u64 {
+    due.min(
+        now.saturating_add(delay_slots)
+            .saturating_sub(min_age.max(1)),
+    )
+}
+
+/// Pure first in, first out regardless of instruction type
+#[derive(Copy, Clone, Zeroable, Pod, PartialEq, PartialOrd, Eq, Ord, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct FifoKey {
+    pub slot: u64,
+    pub seq: u64,
+}
+
+crate::impl_layout_hash!(FifoKey {
+    slot: u64,
+    seq: u64
+});
+
+impl<Ix, Args> OrderingKey<Ix, Args> for FifoKey {
+    fn key(slot: u64, seq: u64, _ixn: &Ix, _args: &Args) -> Self {
+        FifoKey { slot, seq }
+    }
+
+    fn slot(&self) -> u64 {
+        self.slot
+    }
+}
+
+/// Implemented by queue args that carry a priority bid, escrowed in lamports on enqueue
+pub trait PriorityBid {
+    fn priority_bid(&self) -> u64;
+}
+
+/// Turns a bid into a key field where higher bids sort first
+pub const fn bid_rank(bid: u64) -> u64 {
+    u64::MAX - bid
+}
+
+/// Fee priority: within a slot, higher bids execute first, ties broken by time priority
+#[derive(Copy, Clone, Zeroable, Pod, PartialEq, PartialOrd, Eq, Ord, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct FeePriorityKey {
+    pub slot: u64,
+    /// See [`bid_rank`]
+    pub bid_rank: u64,
+    pub seq: u64,
+}
+
+crate::impl_layout_hash!(FeePriorityKey {
+    slot: u64,
+    bid_rank: u64,
+    seq: u64
+});
+
+impl FeePriorityKey {
+    pub fn priority_bid(&self) -> u64 {
+        u64::MAX - self.bid_rank
+    }
+}
+
+impl<Ix, Args: PriorityBid> OrderingKey<Ix, Args> for FeePriorityKey {
+    fn key(slot: u64, seq: u64, _ixn: &Ix, args: &Args) -> Self {
+        FeePriorityKey {
+            slot,
+            bid_rank: bid_rank(args.priority_bid()),
+            seq,
+        }
+    }
+
+    fn slot(&self) -> u64 {
+        self.slot
+    }
+}
+
+/// Implemented by queue args that ask to run no earlier than a given unix timestamp
+pub trait ExecuteAt {
+    fn execute_at(&self) -> u64;
+}
+
+/// Wall-clock scheduling for [`Schedule::UnixTimestamp`] programs
+///
+/// Instructions run in order of the timestamp they were scheduled for, which is the later of
+/// the time they were queued and [`ExecuteAt::execute_at`], then by time priority. Keys are
+/// never dated before the time they were queued, so [`drain_cutoff`] holds for them too: an
+/// instruction scheduled for the past is drained no earlier than the second after it was
+/// queued, and one scheduled for the future no earlier than the second after that time, never
+/// by a drain in the same second.
+#[derive(Copy, Clone, Zeroable, Pod, PartialEq, PartialOrd, Eq, Ord, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct TimestampKey {
+    pub timestamp: u64,
+    pub seq: u64,
+}
+
+crate::impl_layout_hash!(TimestampKey {
+    timestamp: u64,
+    seq: u64
+});
+
+impl<Ix, Args: ExecuteAt> OrderingKey<Ix, Args> for TimestampKey {
+    const SCHEDULED: bool = true;
+
+    fn key(now: u64, seq: u64, _ixn: &Ix, args: &Args) -> Self {
+        TimestampKey {
+            timestamp: now.max(args.execute_at()),
+            seq,
+        }
+    }
+
+    fn slot(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum Side {
+    Bid = 0,
+    Ask = 1,
+}
+
+/// Implemented by queue args of market-style programs to key them with [`PriceTimeKey`]
+pub trait PriceTimeArgs {
+    fn price(&self) -> u64;
+    fn side(&self) -> Side;
+}
+
+/// Price-time priority for order-book-style programs
+///
+/// Entries are ordered by auction (slot), then bids before asks, then best price first
+/// (highest bid, lowest ask), then time priority (seq).
+#[derive(Copy, Clone, Zeroable, Pod, PartialEq, Eq, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct PriceTimeKey {
+    pub price: u64,
+    /// A [`Side`] as its `u8` value
+    pub side: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    _padding: [u8; 7],
+    pub slot: u64,
+    pub seq: u64,
+}
+
+crate::impl_layout_hash!(PriceTimeKey {
+    price: u64,
+    side: u8,
+    _padding: [u8; 7],
+    slot: u64,
+    seq: u64
+});
+
+impl PriceTimeKey {
+    pub fn new(price: u64, side: Side, slot: u64, seq: u64) -> Self {
+        PriceTimeKey {
+            price,
+            side: side as u8,
+            _padding: [0; 7],
+            slot,
+            seq,
+        }
+    }
+
+    pub fn side(&self) -> Side {
+        if self.side == Side::Bid as u8 {
+            Side::Bid
+        } else {
+            Side::Ask
+        }
+    }
+}
+
+impl Ord for PriceTimeKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.slot
+            .cmp(&other.slot)
+            .then(self.side.cmp(&other.side))
+            .then(match self.side() {
+                Side::Bid => other.price.cmp(&self.price),
+                Side::Ask => self.price.cmp(&other.price),
+            })
+            .then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for PriceTimeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Ix, Args: PriceTimeArgs> OrderingKey<Ix, Args> for PriceTimeKey {
+    fn key(slot: u64, seq: u64, _ixn: &Ix, args: &Args) -> Self {
+        PriceTimeKey::new(args.price(), args.side(), slot, seq)
+    }
+
+    fn slot(&self) -> u64 {
+        self.slot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+    use super::*;
+    use crate::queue::pop_min;
+
+    struct Order(u64, Side);
+
+    impl PriceTimeArgs for Order {
+        fn price(&self) -> u64 {
+            self.0
+        }
+
+        fn side(&self) -> Side {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_check_slot() {
+        assert_eq!(check_slot(5, 5), Ok(()));
+        assert_eq!(check_slot(4, 5), Ok(()));
+        assert_eq!(check_slot(6, 5), Err(ProgramError::Custom(SLOT_SKEW)));
+    }
+
+    #[test]
+    fn test_next_seq() {
+        let mut seq = u64::MAX - 1;
+        assert_eq!(next_seq(&mut seq), Ok(u64::MAX - 1));
+        assert_eq!(next_seq(&mut seq), Err(ProgramError::Custom(SEQ_EXHAUSTED)));
+        assert_eq!(seq, u64::MAX);
+    }
+
+    #[test]
+    fn test_price_time_ordering() {
+        let orders = [
+            (1, Order(100, Side::Ask)),
+            (1, Order(101, Side::Bid)),
+            (1, Order(99, Side::Ask)),
+            (1, Order(103, Side::Bid)),
+            (1, Order(101, Side::Bid)),
+            (0, Order(1, Side::Ask)),
+            (1, Order(99, Side::Ask)),
+        ];
+
+        let mut tree = RedBlackTree::<PriceTimeKey, u64, 16>::new();
+        for (seq, (slot, order)) in orders.iter().enumerate() {
+            let key = PriceTimeKey::key(*slot, seq as u64, &(), order);
+            tree.insert(key, seq as u64);
+        }
+
+        let drained: Vec<(u64, Side, u64)> = std::iter::from_fn(|| pop_min(&mut tree))
+            .map(|node| (node.key.price, node.key.side(), node.key.seq))
+            .collect();
+        assert_eq!(
+            drained,
+            [
+                // Earlier auction first regardless of price
+                (1, Side::Ask, 5),
+                // Best bid first, then time priority
+                (103, Side::Bid, 3),
+                (101, Side::Bid, 1),
+                (101, Side::Bid, 4),
+                // Best ask first, then time priority
+                (99, Side::Ask, 2),
+                (99, Side::Ask, 6),
+                (100, Side::Ask, 0),
+            ]
+        );
+    }
+
+    impl PriorityBid for u64 {
+        fn priority_bid(&self) -> u64 {
+            *self
+        }
+    }
+
+    #[test]
+    fn test_fee_priority_ordering() {
+        let key = |slot, seq, bid: u64| FeePriorityKey::key(slot, seq, &(), &bid);
+        let mut keys = [key(1, 0, 5), key(1, 1, 7), key(0, 2, 0), key(1, 3, 7)];
+        keys.sort();
+        let order: Vec<(u64, u64)> = keys
+            .iter()
+            .map(|key| (key.seq, key.priority_bid()))
+            .collect();
+        assert_eq!(order, [(2, 0), (1, 7), (3, 7), (0, 5)]);
+    }
+
+    #[test]
+    fn test_fifo_ignores_instruction() {
+        let a = <FifoKey as OrderingKey<u64, ()>>::key(3, 1, &1, &());
+        let b = <FifoKey as OrderingKey<u64, ()>>::key(3, 2, &0, &());
+        let c = <FifoKey as OrderingKey<u64, ()>>::key(2, 9, &0, &());
+        let mut keys = [a, b, c];
+        keys.sort();
+        assert_eq!(keys, [c, a, b]);
+    }
+
+    struct Vesting(u64);
+
+    impl ExecuteAt for Vesting {
+        fn execute_at(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_timestamp_schedule() {
+        let now = 1_700_000_000;
+        let key = |seq, execute_at| TimestampKey::key(now, seq, &(), &Vesting(execute_at));
+        let mut keys = [key(0, now + 60), key(1, 0), key(2, now + 30), key(3, now)];
+        keys.sort();
+        let order: Vec<u64> = keys.iter().map(|key| key.seq).collect();
+        assert_eq!(order, [1, 3, 2, 0]);
+
+        // Due once the requested time has passed
+        let vest = key(4, now + 60);
+        let is_due = |time| OrderingKey::<(), Vesting>::is_due(&vest, time, 0);
+        assert!(!is_due(now + 59));
+        assert!(is_due(now + 60));
+    }
+
+    #[test]
+    fn test_scheduled_drain_cutoff() {
+        let now = 1_700_000_000;
+        let is_drained = |execute_at, drain_at| {
+            let key = TimestampKey::key(now, 0, &(), &Vesting(execute_at));
+            let due = drain_cutoff(drain_at, drain_at, 0, 1);
+            OrderingKey::<(), Vesting>::is_due(&key, due, 0)
+        };
+        // Scheduled for the past or for now, but queued now
+        for execute_at in [0, now - 1, now] {
+            assert!(!is_drained(execute_at, now));
+            assert!(is_drained(execute_at, now + 1));
+        }
+        assert!(!is_drained(now + 60, now + 60));
+        assert!(is_drained(now + 60, now + 61));
+    }
+
+    #[test]
+    fn test_is_due() {
+        let key = <FifoKey as OrderingKey<u64, ()>>::key(3, 1, &1, &());
+        let is_due = |slot, delay| OrderingKey::<u64, ()>::is_due(&key, slot, delay);
+        // Same slot processing
+        assert!(is_due(3, 0));
+        assert!(!is_due(3, 1));
+        assert!(is_due(4, 1));
+        assert!(!is_due(4, 2));
+        assert!(!is_due(u64::MAX - 1, u64::MAX));
+    }
+
+    #[test]
+    fn test_drain_cutoff() {
+        let key = |slot| <FifoKey as OrderingKey<u64, ()>>::key(slot, 0, &1, &());
+        let is_drained = |slot, now, delay, min_age| {
+            let due = drain_cutoff(now, now, delay, min_age);
+            OrderingKey::<u64, ()>::is_due(&key(slot), due, delay)
+        };
+        // Never in the slot it was queued, even without a delay
+        assert!(!is_drained(3, 3, 0, 0));
+        assert!(!is_drained(3, 3, 0, 1));
+        assert!(is_drained(3, 4, 0, 1));
+        assert!(!is_drained(3, 4, 1, 2));
+        assert!(is_drained(3, 5, 1, 2));
+        // The delay still applies
+        assert!(!is_drained(3, 4, 2, 1));
+        assert!(is_drained(3, 5, 2, 1));
+        assert_eq!(drain_cutoff(2, 10, 1, 1), 2);
+        assert_eq!(drain_cutoff(0, 0, 0, 1), 0);
+    }
+}
@@ -0,0 +1,402 @@
+//! Paged queue backend
+//!
+//! The primary page lives in the program state. Once it fills up, new entries spill into
+//! overflow page accounts linked through the `next_page` field of each page header.
+//!
+//! New entries always go to the tail page, so each page holds a later stretch of the queue
+//! than the one before it. Ordering is exact within a page and pages drain in chain order:
+//! once the primary page empties, the first overflow page's entries are moved into it and that
+//! page is unlinked, after which it can be handed back in as a spare. The head of the queue is
+//! thus always in the primary page, so states peek, pop and drain it like an unpaged queue, see
+//! [`AsyncState::OVERFLOW_PAGES`](crate::AsyncState::OVERFLOW_PAGES).
+//!
+//! Pages are the remaining accounts of an instruction the program owns, see [`Pages`]. Every
+//! page linked into the chain has to be among them to queue into it, and the first one to
+//! refill the primary page.
+
+use std::ops::DerefMut;
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::{AccountInfo, RefMut},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    ProgramResult,
+};
+use sokoban::{red_black_tree::RBNode, NodeAllocatorMap, RedBlackTree};
+
+use crate::queue::{peek_min, pop_min, Payload, QueueKey};
+
+/// Marks a page as initialized
+pub const PAGE_TAG: u64 = u64::from_le_bytes(*b"apqpage\0");
+
+/// Stands in for "no page" in `next_page` and `queue`
+pub const NO_PAGE: Pubkey = [0; 32];
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct PageHeader {
+    /// [`PAGE_TAG`] once the page has been initialized
+    pub tag: u64,
+
+    /// The state account whose chain this page is linked into
+    ///
+    /// [`NO_PAGE`] for the primary page and for spare pages
+    pub queue: Pubkey,
+
+    /// The page after this one, [`NO_PAGE`] if this is the tail
+    pub next_page: Pubkey,
+}
+
+/// A page of the queue, the primary one held by the state and the others in their own accounts
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct QueuePage<K: QueueKey, V: Payload, const N: usize> {
+    pub header: PageHeader,
+    pub queue: RedBlackTree<K, V, N>,
+}
+
+unsafe impl<K: QueueKey, V: Payload, const N: usize> Zeroable for QueuePage<K, V, N> {}
+unsafe impl<K: QueueKey, V: Payload, const N: usize> Pod for QueuePage<K, V, N> {}
+
+impl<K: QueueKey, V: Payload, const N: usize> QueuePage<K, V, N> {
+    /// Initializes a zeroed page
+    pub fn initialize(&mut self) {
+        self.header = PageHeader {
+            tag: PAGE_TAG,
+            queue: NO_PAGE,
+            next_page: NO_PAGE,
+        };
+        self.queue.initialize();
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.header.tag == PAGE_TAG
+    }
+}
+
+/// Where a paged queue's overflow pages come from: the accounts `program_id` owns among an
+/// instruction's remaining accounts
+#[derive(Copy, Clone)]
+pub struct Pages<'a> {
+    pub program_id: &'a Pubkey,
+    /// The state account holding the primary page
+    pub queue: &'a Pubkey,
+    pub accounts: &'a [AccountInfo],
+}
+
+impl<'a> Pages<'a> {
+    pub fn new(program_id: &'a Pubkey, queue: &'a Pubkey, accounts: &'a [AccountInfo]) -> Self {
+        Pages {
+            program_id,
+            queue,
+            accounts,
+        }
+    }
+
+    /// Resolves the overflow chain of `primary`, see [`PagedQueue::load`]
+    pub fn load<'b, K: QueueKey, V: Payload, const N: usize>(
+        &self,
+        primary: &'b mut QueuePage<K, V, N>,
+    ) -> Result<PagedQueue<'b, K, V, N>, ProgramError>
+    where
+        'a: 'b,
+    {
+        PagedQueue::load(self.program_id, self.queue, primary, self.accounts)
+    }
+
+    /// Moves the first overflow page's entries into `primary` once it is empty, see
+    /// [`PagedQueue::refill`]. Only needs the pages then. Returns whether it did.
+    pub fn refill<K: QueueKey, V: Payload, const N: usize>(
+        &self,
+        primary: &mut QueuePage<K, V, N>,
+    ) -> Result<bool, ProgramError> {
+        if !primary.queue.is_empty() || primary.header.next_page == NO_PAGE {
+            return Ok(false);
+        }
+        Ok(self.load(primary)?.refill())
+    }
+}
+
+/// A primary page and the overflow pages linked to it
+pub struct PagedQueue<'a, K, V, const N: usize, P = RefMut<'a, QueuePage<K, V, N>>>
+where
+    K: QueueKey,
+    V: Payload,
+{
+    /// Key of the state account holding the primary page
+    queue_key: Pubkey,
+    primary: &'a mut QueuePage<K, V, N>,
+    /// Linked overflow pages, in chain order
+    chain: Vec<(Pubkey, P)>,
+    /// Unlinked pages that can be appended when the tail fills up
+    spare: Vec<(Pubkey, P)>,
+}
+
+impl<'a, K: QueueKey, V: Payload, const N: usize> PagedQueue<'a, K, V, N> {
+    /// Resolves the overflow chain of `primary` from the accounts `program_id` owns among
+    /// `accounts`.
+    ///
+    /// They must include every page linked into the chain, in any order. Any other page is
+    /// kept as a spare to be linked when the tail fills up.
+    pub fn load(
+        program_id: &Pubkey,
+        queue_key: &Pubkey,
+        primary: &'a mut QueuePage<K, V, N>,
+        accounts: &'a [AccountInfo],
+    ) -> Result<Self, ProgramError> {
+        let mut loaded = Vec::new();
+        for page in accounts.iter().filter(|page| page.is_owned_by(program_id)) {
+            if !page.is_writable() {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let data = RefMut::filter_map(page.try_borrow_mut_data()?, |data| {
+                bytemuck::try_from_bytes_mut(data).ok()
+            })
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+            loaded.push((*page.key(), data));
+        }
+
+        Self::link_pages(queue_key, primary, loaded)
+    }
+}
+
+impl<'a, K, V, const N: usize, P> PagedQueue<'a, K, V, N, P>
+where
+    K: QueueKey,
+    V: Payload,
+    P: DerefMut<Target = QueuePage<K, V, N>>,
+{
+    fn link_pages(
+        queue_key: &Pubkey,
+        primary: &'a mut QueuePage<K, V, N>,
+        mut pages: Vec<(Pubkey, P)>,
+    ) -> Result<Self, ProgramError> {
+        let mut chain = Vec::new();
+        let mut next = primary.header.next_page;
+        while next != NO_PAGE {
+            let position = pages
+                .iter()
+                .position(|(key, _)| *key == next)
+                .ok_or(ProgramError::NotEnoughAccountKeys)?;
+            let (key, page) = pages.remove(position);
+            if !page.is_initialized() || page.header.queue != *queue_key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            next = page.header.next_page;
+            chain.push((key, page));
+        }
+
+        // Whatever is left must be free to link
+        if pages
+            .iter()
+            .any(|(_, page)| page.is_initialized() && page.header.queue != NO_PAGE)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(PagedQueue {
+            queue_key: *queue_key,
+            primary,
+            chain,
+            spare: pages,
+        })
+    }
+
+    /// Number of entries across all pages
+    pub fn len(&self) -> usize {
+        self.primary.queue.len()
+            + self
+                .chain
+                .iter()
+                .map(|(_, page)| page.queue.len())
+                .sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of linked overflow pages
+    pub fn num_overflow_pages(&self) -> usize {
+        self.chain.len()
+    }
+
+    fn tail_mut(&mut self) -> &mut QueuePage<K, V, N> {
+        match self.chain.last_mut() {
+            Some((_, page)) => page,
+            None => self.primary,
+        }
+    }
+
+    /// Inserts into the tail page, linking the first spare page if the tail is full.
+    ///
+    /// Errors with `NotEnoughAccountKeys` if the tail is full and no spare was supplied.
+    pub fn insert(&mut self, key: K, value: V) -> ProgramResult {
+        if self.tail_mut().queue.insert(key, value).is_some() {
+            return Ok(());
+        }
+
+        // In the order they were given
+        if self.spare.is_empty() {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let (page_key, mut page) = self.spare.remove(0);
+        if !page.is_initialized() {
+            page.initialize();
+        }
+        page.header.queue = self.queue_key;
+        page.header.next_page = NO_PAGE;
+        page.queue
+            .insert(key, value)
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+
+        self.tail_mut().header.next_page = page_key;
+        self.chain.push((page_key, page));
+        Ok(())
+    }
+
+    /// The next entry to be drained, always in the primary page
+    pub fn peek(&self) -> Option<&RBNode<K, V>> {
+        peek_min(&self.primary.queue).map(|(_addr, node)| node)
+    }
+
+    /// Pops the next entry, refilling the primary page if that empties it
+    pub fn pop(&mut self) -> Option<RBNode<K, V>> {
+        self.refill();
+        let node = pop_min(&mut self.primary.queue)?;
+        self.refill();
+        Some(node)
+    }
+
+    /// Moves the first overflow page's entries into the primary page if it is empty,
+    /// unlinking that page to be used as a spare. Returns whether it did.
+    pub fn refill(&mut self) -> bool {
+        if !self.primary.queue.is_empty() || self.chain.is_empty() {
+            return false;
+        }
+
+        let (page_key, mut page) = self.chain.remove(0);
+        // Pages are the same size, so everything fits in the empty primary page
+        while let Some(node) = pop_min(&mut page.queue) {
+            self.primary.queue.insert(node.key, node.value);
+        }
+        self.primary.header.next_page = page.header.next_page;
+        page.header.queue = NO_PAGE;
+        page.header.next_page = NO_PAGE;
+        self.spare.push((page_key, page));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Page = QueuePage<u64, u64, 4>;
+
+    fn page() -> Box<Page> {
+        let mut page = Box::new(Page::zeroed());
+        page.initialize();
+        page
+    }
+
+    #[test]
+    fn test_overflow_and_drain_in_page_order() {
+        let queue_key = [7; 32];
+        let mut primary = page();
+        let spares = vec![([1; 32], Box::new(Page::zeroed())), ([2; 32], page())];
+
+        let mut queue = PagedQueue::link_pages(&queue_key, &mut primary, spares).unwrap();
+        for key in (0..12).rev() {
+            queue.insert(key, key * 10).unwrap();
+        }
+        assert_eq!(queue.len(), 12);
+        assert_eq!(queue.num_overflow_pages(), 2);
+
+        // Full primary and tail with no spares left
+        assert_eq!(
+            queue.insert(100, 0),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+
+        // Primary holds 11..=8, then 7..=4, then 3..=0, each moved into it in turn
+        assert_eq!(queue.peek().map(|node| node.key), Some(8));
+        let drained: Vec<u64> = std::iter::from_fn(|| queue.pop().map(|node| node.key)).collect();
+        assert_eq!(drained, [8, 9, 10, 11, 4, 5, 6, 7, 0, 1, 2, 3]);
+        assert_eq!(queue.num_overflow_pages(), 0);
+        assert!(queue.is_empty());
+        drop(queue);
+        assert_eq!(primary.header.next_page, NO_PAGE);
+    }
+
+    #[test]
+    fn test_reload_chain() {
+        let queue_key = [7; 32];
+        let mut primary = page();
+        let mut overflow = page();
+
+        let mut queue =
+            PagedQueue::link_pages(&queue_key, &mut primary, vec![([1; 32], &mut *overflow)])
+                .unwrap();
+        for key in 0..6 {
+            queue.insert(key, 0).unwrap();
+        }
+        drop(queue);
+        assert_eq!(primary.header.next_page, [1; 32]);
+        assert_eq!(overflow.header.queue, queue_key);
+
+        // Linked pages must be supplied
+        assert!(matches!(
+            PagedQueue::<u64, u64, 4, &mut Page>::link_pages(&queue_key, &mut primary, vec![]),
+            Err(ProgramError::NotEnoughAccountKeys)
+        ));
+
+        // Pages linked into another queue can't be used as spares
+        assert!(matches!(
+            PagedQueue::link_pages(&[8; 32], &mut page(), vec![([1; 32], &mut *overflow)]),
+            Err(ProgramError::InvalidAccountData)
+        ));
+
+        let queue =
+            PagedQueue::link_pages(&queue_key, &mut primary, vec![([1; 32], &mut *overflow)])
+                .unwrap();
+        assert_eq!(queue.len(), 6);
+        assert_eq!(queue.peek().map(|node| node.key), Some(0));
+    }
+
+    #[test]
+    fn test_refill() {
+        let queue_key = [7; 32];
+        let mut primary = page();
+        let mut overflow = page();
+
+        let mut queue =
+            PagedQueue::link_pages(&queue_key, &mut primary, vec![([1; 32], &mut *overflow)])
+                .unwrap();
+        for key in 0..6 {
+            queue.insert(key, 0).unwrap();
+        }
+        // Not while the primary page holds entries
+        assert!(!queue.refill());
+        for key in 0..4 {
+            assert_eq!(
+                pop_min(&mut queue.primary.queue).map(|node| node.key),
+                Some(key)
+            );
+        }
+        assert!(queue.refill());
+        assert_eq!(queue.num_overflow_pages(), 0);
+        assert_eq!(queue.peek().map(|node| node.key), Some(4));
+        drop(queue);
+        assert_eq!(primary.header.next_page, NO_PAGE);
+        assert_eq!(
+            overflow.header,
+            PageHeader {
+                tag: PAGE_TAG,
+                ..Default::default()
+            }
+        );
+        assert!(overflow.queue.is_empty());
+    }
+}
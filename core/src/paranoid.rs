@@ -0,0 +1,192 @@
+//! Hardened builds for audits and testnets
+//!
+//! With the `paranoid` feature, the dispatch checks
+//! [`AsyncState::check_invariants`](crate::AsyncState::check_invariants) after every
+//! instruction, before anything is written back, and [`add`] and [`sub`] fail instead of
+//! saturating. Mainnet builds leave it off and pay for neither.
+//!
+//! [`verify_queue_integrity`] walks a tree's structure, which [`check_tree`] extends with the
+//! red-black invariants the queue's depth relies on. The verify state instruction runs the
+//! same checks on demand in any build, see [`crate::dispatch`].
+
+use pinocchio::{program_error::ProgramError, ProgramResult};
+use sokoban::{NodeAllocatorMap, RedBlackTree, SENTINEL};
+
+/// Custom program error for state that breaks an invariant, spelling `APQ` followed by A
+pub const INVARIANT_VIOLATED: u32 = 0x4150_510A;
+
+/// Whether the build is hardened
+pub const ENABLED: bool = cfg!(feature = "paranoid");
+
+/// `a + b`, failing on overflow in hardened builds and saturating otherwise
+pub fn add(a: u64, b: u64) -> Result<u64, ProgramError> {
+    match ENABLED {
+        true => a.checked_add(b).ok_or(ProgramError::ArithmeticOverflow),
+        false => Ok(a.saturating_add(b)),
+    }
+}
+
+/// `a - b`, failing on underflow in hardened builds and saturating otherwise
+pub fn sub(a: u64, b: u64) -> Result<u64, ProgramError> {
+    match ENABLED {
+        true => a.checked_sub(b).ok_or(ProgramError::ArithmeticOverflow),
+        false => Ok(a.saturating_sub(b)),
+    }
+}
+
+/// Fails with [`INVARIANT_VIOLATED`] unless the root is the sentinel exactly when `tree` is
+/// empty, every link stays within its capacity, keys strictly ascend in order and as many
+/// nodes are reachable as it counts
+pub fn verify_queue_integrity<K, V, const N: usize>(tree: &RedBlackTree<K, V, N>) -> ProgramResult
+where
+    K: bytemuck::Pod + Ord + Default + std::fmt::Debug,
+    V: bytemuck::Pod + Default,
+{
+    let violated = ProgramError::Custom(INVARIANT_VIOLATED);
+    if (tree.root == SENTINEL) != (tree.len() == 0)
+        || tree.root as usize > N
+        || tree.get_parent(tree.root) != SENTINEL
+    {
+        return Err(violated);
+    }
+    // In order, with the path down to the next node on the stack. Neither the path nor the
+    // walk can be longer than the tree without a cycle.
+    let mut path = Vec::new();
+    let mut node = tree.root;
+    let mut previous: Option<&K> = None;
+    let mut reached = 0;
+    loop {
+        while node != SENTINEL {
+            if node as usize > N || path.len() >= tree.len() {
+                return Err(violated);
+            }
+            path.push(node);
+            node = tree.get_left(node);
+        }
+        let Some(next) = path.pop() else {
+            break;
+        };
+        reached += 1;
+        let key = &tree.get_node(next).key;
+        if reached > tree.len() || previous.is_some_and(|previous| key <= previous) {
+            return Err(violated);
+        }
+        previous = Some(key);
+        node = tree.get_right(next);
+    }
+    match reached == tree.len() {
+        true => Ok(()),
+        false => Err(violated),
+    }
+}
+
+/// Fails with [`INVARIANT_VIOLATED`] unless `tree` passes [`verify_queue_integrity`] and is
+/// a valid red-black tree
+pub fn check_tree<K, V, const N: usize>(tree: &RedBlackTree<K, V, N>) -> ProgramResult
+where
+    K: bytemuck::Pod + Ord + Default + std::fmt::Debug,
+    V: bytemuck::Pod + Default,
+{
+    verify_queue_integrity(tree)?;
+    let violated = ProgramError::Custom(INVARIANT_VIOLATED);
+    if tree.root == SENTINEL {
+        return Ok(());
+    }
+    if tree.is_red(tree.root) {
+        return Err(violated);
+    }
+    let mut nodes = 0;
+    match check_subtree(tree, tree.root, None, None, &mut nodes) {
+        Some(_) if nodes == tree.len() => Ok(()),
+        _ => Err(violated),
+    }
+}
+
+/// Black height of the subtree at `node`, whose keys lie strictly between `lower` and
+/// `upper`, or `None` if it breaks an invariant. Recursion is as deep as the tree, which stays
+/// within twice the log of its capacity.
+fn check_subtree<K, V, const N: usize>(
+    tree: &RedBlackTree<K, V, N>,
+    node: u32,
+    lower: Option<&K>,
+    upper: Option<&K>,
+    nodes: &mut usize,
+) -> Option<u32>
+where
+    K: bytemuck::Pod + Ord + Default + std::fmt::Debug,
+    V: bytemuck::Pod + Default,
+{
+    if node == SENTINEL {
+        return Some(1);
+    }
+    *nodes += 1;
+    if *nodes > tree.len() {
+        // A cycle, or nodes the tree doesn't count
+        return None;
+    }
+    let key = &tree.get_node(node).key;
+    if lower.is_some_and(|lower| key <= lower) || upper.is_some_and(|upper| key >= upper) {
+        return None;
+    }
+    let mut heights = [0; 2];
+    for (height, (child, lower, upper)) in heights.iter_mut().zip([
+        (tree.get_left(node), lower, Some(key)),
+        (tree.get_right(node), Some(key), upper),
+    ]) {
+        if child != SENTINEL
+            && (tree.get_parent(child) != node || (tree.is_red(node) && tree.is_red(child)))
+        {
+            return None;
+        }
+        *height = check_subtree(tree, child, lower, upper, nodes)?;
+    }
+    (heights[0] == heights[1]).then(|| heights[0] + tree.is_black(node) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_tree() {
+        let mut tree: Box<RedBlackTree<u64, u64, 64>> = bytemuck::zeroed_box();
+        tree.initialize();
+        assert_eq!(check_tree(&tree), Ok(()));
+        for key in [5, 3, 9, 1, 4, 7, 11, 2, 8, 6, 10] {
+            tree.insert(key, key);
+            assert_eq!(check_tree(&tree), Ok(()));
+        }
+        tree.remove(&7);
+        assert_eq!(check_tree(&tree), Ok(()));
+
+        assert_eq!(verify_queue_integrity(&tree), Ok(()));
+
+        // Keys out of order
+        let root = tree.root;
+        tree.get_node_mut(root).key = 100;
+        assert_eq!(
+            verify_queue_integrity(&tree),
+            Err(ProgramError::Custom(INVARIANT_VIOLATED))
+        );
+        assert!(check_tree(&tree).is_err());
+
+        // A link out of the tree's capacity
+        tree.get_node_mut(root).key = 5;
+        let mut linked: Box<RedBlackTree<u64, u64, 64>> = bytemuck::zeroed_box();
+        linked.initialize();
+        linked.insert(1, 1);
+        let bytes: &mut [u8] = bytemuck::bytes_of_mut(&mut *linked);
+        bytes[..4].copy_from_slice(&65u32.to_le_bytes());
+        assert!(verify_queue_integrity(&linked).is_err());
+
+        assert_eq!(add(u64::MAX, 0), Ok(u64::MAX));
+        assert_eq!(
+            add(u64::MAX, 1),
+            match ENABLED {
+                true => Err(ProgramError::ArithmeticOverflow),
+                false => Ok(u64::MAX),
+            }
+        );
+        assert_eq!(sub(1, 2).is_err(), ENABLED);
+    }
+}
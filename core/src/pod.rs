@@ -0,0 +1,106 @@
+//! Checked reads of plain old data from instruction and account bytes
+//!
+//! Instruction data comes at any alignment and length, so casting its pointer is undefined
+//! behaviour as soon as a client sends something unexpected. These go through bytemuck, and
+//! fail with [`ProgramError::InvalidInstructionData`] instead.
+//!
+//! [`decode_variant`] reads the `u64` instruction variant every instruction starts with, for
+//! enums numbered from zero.
+
+use bytemuck::Pod;
+use pinocchio::program_error::ProgramError;
+
+/// Copies a `T` out of the start of `bytes`, which may be longer, at any alignment
+pub fn read_pod<T: Pod>(bytes: &[u8]) -> Result<T, ProgramError> {
+    bytes
+        .get(..size_of::<T>())
+        .and_then(|bytes| bytemuck::try_pod_read_unaligned(bytes).ok())
+        .ok_or(ProgramError::InvalidInstructionData)
+}
+
+/// Borrows a `T` at the start of `bytes`, which may be longer, if they are aligned for it
+pub fn ref_pod<T: Pod>(bytes: &[u8]) -> Result<&T, ProgramError> {
+    bytes
+        .get(..size_of::<T>())
+        .and_then(|bytes| bytemuck::try_from_bytes(bytes).ok())
+        .ok_or(ProgramError::InvalidInstructionData)
+}
+
+/// Instruction enums whose variants are `0..COUNT`, e.g. a `#[repr(u64)]` enum without
+/// explicit gaps
+pub trait Variant: Copy {
+    const COUNT: u64;
+
+    /// # Safety
+    ///
+    /// `value` must be a variant, i.e. `value < Self::COUNT`
+    unsafe fn from_u64_unchecked(value: u64) -> Self;
+}
+
+/// The variant in the first 8 bytes of `bytes`, at any alignment. Decoding is on the path of
+/// every instruction, so it is one read and one comparison, and leaves logging the error to
+/// the caller.
+#[inline(always)]
+pub fn decode_variant<E: Variant>(bytes: &[u8]) -> Result<E, ProgramError> {
+    match bytes.first_chunk() {
+        Some(value) if u64::from_le_bytes(*value) < E::COUNT => {
+            // SAFETY: checked against the count
+            Ok(unsafe { E::from_u64_unchecked(u64::from_le_bytes(*value)) })
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_reads() {
+        let words = [7_u64, 9];
+        let bytes: &[u8] = bytemuck::cast_slice(&words);
+
+        assert_eq!(read_pod::<u64>(bytes), Ok(7));
+        assert_eq!(read_pod::<u64>(&bytes[1..9]), Ok(9 << 56));
+        assert_eq!(
+            read_pod::<u64>(&bytes[..7]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+
+        assert_eq!(ref_pod::<u64>(&bytes[8..]), Ok(&9));
+        assert_eq!(
+            ref_pod::<u64>(&bytes[1..]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    #[repr(u64)]
+    enum Ix {
+        A = 0,
+        B = 1,
+    }
+
+    impl Variant for Ix {
+        const COUNT: u64 = 2;
+
+        unsafe fn from_u64_unchecked(value: u64) -> Self {
+            unsafe { std::mem::transmute(value) }
+        }
+    }
+
+    #[test]
+    fn test_decode_variant() {
+        let bytes: Vec<u8> = [0].into_iter().chain(1_u64.to_le_bytes()).collect();
+        assert_eq!(decode_variant::<Ix>(&bytes[1..]), Ok(Ix::B));
+        assert_eq!(decode_variant::<Ix>(&[0; 9]), Ok(Ix::A));
+        assert_eq!(
+            decode_variant::<Ix>(&2_u64.to_le_bytes()),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert_eq!(
+            decode_variant::<Ix>(&[1; 7]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt::Debug;
+
+use bytemuck::Pod;
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey};
+use sokoban::{red_black_tree::RBNode, NodeAllocatorMap, RedBlackTree, SENTINEL};
+
+use crate::AsyncState;
+
+/// Everything sokoban needs from a tree key
+pub trait QueueKey: Debug + Ord + Copy + Default + Pod {}
+impl<T: Debug + Ord + Copy + Default + Pod> QueueKey for T {}
+
+/// Data stored alongside each queued key, e.g. the user and instruction arguments
+pub trait Payload: Copy + Default + Pod {}
+impl<T: Copy + Default + Pod> Payload for T {}
+
+/// Payloads that know who queued them, for
+/// [`AsyncState::iter_by_user`](crate::AsyncState::iter_by_user)
+pub trait UserPayload: Payload {
+    fn user(&self) -> &Pubkey;
+}
+
+/// A queued instruction's key and payload
+pub type Entry<K, V> = RBNode<K, V>;
+
+/// Bytes of encoded instruction arguments a payload can carry, see [`ArgsSlot`]
+pub const ARGS_LEN: usize = 32;
+
+/// Fixed-size room in a payload for the instruction's arguments, written with
+/// [`AsyncIx::encode_args`](crate::AsyncIx::encode_args) when queueing and read back with
+/// [`AsyncIx::decode_args`](crate::AsyncIx::decode_args) when processing
+pub type ArgsSlot = [u8; ARGS_LEN];
+
+/// Encodes plain old data args, which must fit in [`ARGS_LEN`] bytes
+pub fn encode_pod_args<T: Pod>(args: &T) -> Result<ArgsSlot, ProgramError> {
+    let bytes = bytemuck::bytes_of(args);
+    let mut slot = [0; ARGS_LEN];
+    slot.get_mut(..bytes.len())
+        .ok_or(ProgramError::InvalidArgument)?
+        .copy_from_slice(bytes);
+    Ok(slot)
+}
+
+/// Decodes args written by [`encode_pod_args`]
+pub fn decode_pod_args<T: Pod>(slot: &ArgsSlot) -> Result<T, ProgramError> {
+    crate::pod::read_pod(slot).map_err(|_| ProgramError::InvalidAccountData)
+}
+
+/// Address of the node with the smallest key, i.e. the next one to be drained
+pub fn min_addr<K: QueueKey, V: Payload, const N: usize>(
+    tree: &RedBlackTree<K, V, N>,
+) -> Option<u32> {
+    let mut addr = tree.root;
+    if addr == SENTINEL {
+        return None;
+    }
+
+    let mut last_addr = addr;
+    while addr != SENTINEL {
+        last_addr = addr;
+        addr = tree.get_left(addr);
+    }
+
+    Some(last_addr)
+}
+
+pub fn peek_min<K: QueueKey, V: Payload, const N: usize>(
+    tree: &RedBlackTree<K, V, N>,
+) -> Option<(u32, &RBNode<K, V>)> {
+    let addr = min_addr(tree)?;
+    Some((addr, tree.get_node(addr)))
+}
+
+pub fn pop_min<K: QueueKey, V: Payload, const N: usize>(
+    tree: &mut RedBlackTree<K, V, N>,
+) -> Option<RBNode<K, V>> {
+    // TODO: change sokoban to allow for remove_addr,
+    // currenly called _remove_tree_node
+    let (_addr, &val) = peek_min(tree)?;
+    tree.remove(&val.key);
+    Some(val)
+}
+
+/// What a bulk cancel did, see [`cancel_all`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CancelAll {
+    pub cancelled: u32,
+    /// Lamports to refund from escrow
+    pub refund: u64,
+    /// Whether the user has entries left, to cancel in another transaction
+    pub more: bool,
+}
+
+/// Cancels up to `max` of `user`'s entries in drain order, each with
+/// [`AsyncState::cancel_async`]. Finding them scans the whole queue, so `max` only bounds the
+/// cancelling, and users with more entries than fit in one transaction's compute cancel again
+/// until nothing is left. States with a [`UserIndex`](crate::user_index::UserIndex) use
+/// [`cancel_keys`] with the keys it holds instead.
+pub fn cancel_all<S: AsyncState>(
+    state: &mut S,
+    user: &Pubkey,
+    max: u32,
+) -> Result<CancelAll, ProgramError>
+where
+    S::Payload: UserPayload,
+{
+    let keys: Vec<S::Key> = state
+        .iter_by_user(user)
+        .map(|(key, _)| *key)
+        .take((max as usize).saturating_add(1))
+        .collect();
+    cancel_keys(state, user, &keys, max)
+}
+
+/// Cancels the first `max` of `user`'s entries under `keys`, see [`cancel_all`]
+pub fn cancel_keys<S: AsyncState>(
+    state: &mut S,
+    user: &Pubkey,
+    keys: &[S::Key],
+    max: u32,
+) -> Result<CancelAll, ProgramError> {
+    let max = max as usize;
+    let mut cancel = CancelAll {
+        more: keys.len() > max,
+        ..Default::default()
+    };
+    for key in keys.iter().take(max) {
+        let refund = state.cancel_async(user, key)?;
+        cancel.refund = cancel
+            .refund
+            .checked_add(refund)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        cancel.cancelled += 1;
+    }
+    Ok(cancel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pod_args_round_trip() {
+        let args = [5_u64, 7];
+        let slot = encode_pod_args(&args).unwrap();
+        assert_eq!(decode_pod_args::<[u64; 2]>(&slot), Ok(args));
+        assert_eq!(
+            encode_pod_args(&[0_u64; 5]),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Several queues in one state
+//!
+//! Some programs need separate queues, e.g. cancels drained before takes, or one queue per
+//! market. Each is its own tree in the state with its own key type. The state names them in
+//! [`AsyncState::QUEUES`], reports when each queue's head was queued with
+//! [`AsyncState::queue_head`] and processes from a queue with
+//! [`AsyncState::process_next_in`]. The drain then picks between the queues by
+//! [`AsyncState::DRAIN_POLICY`].
+//!
+//! Queue 0 is the one [`AsyncState::Key`] orders, which every other part of the state's
+//! interface (peeking, cancelling, viewing, ...) works on. [`CANCEL_FIRST`] is the usual two
+//! queue setup.
+
+use pinocchio::program_error::ProgramError;
+
+use crate::AsyncState;
+
+/// Most queues one state can declare
+pub const MAX_QUEUES: usize = 8;
+
+/// The default, a single queue
+pub const SINGLE: [&str; 1] = ["queue"];
+
+/// Cancels in queue 0 and takes in queue 1, with the default [`DrainPolicy::Ordered`] draining
+/// every due cancel before any take
+pub const CANCEL_FIRST: [&str; 2] = ["cancels", "takes"];
+
+/// How the drain picks between queues that have due entries
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DrainPolicy {
+    /// Lower queues are drained of due entries before higher ones
+    Ordered,
+    /// The head queued earliest goes first, ties going to the lower queue
+    Oldest,
+}
+
+impl DrainPolicy {
+    /// The queue to drain from next, given when each queue's head was queued
+    pub fn next(&self, heads: &[Option<u64>], now: u64, delay_slots: u64) -> Option<usize> {
+        let mut due = heads.iter().enumerate().filter_map(|(queue, head)| {
+            head.filter(|slot| slot.saturating_add(delay_slots) <= now)
+                .map(|slot| (queue, slot))
+        });
+        match self {
+            DrainPolicy::Ordered => due.next(),
+            DrainPolicy::Oldest => due.min_by_key(|&(queue, slot)| (slot, queue)),
+        }
+        .map(|(queue, _)| queue)
+    }
+}
+
+/// Processes entries due at `now` across the state's queues until `is_done`. Returns how many
+/// were processed.
+pub fn drain<S: AsyncState>(
+    state: &mut S,
+    now: u64,
+    is_done: impl Fn(u64) -> bool,
+) -> Result<u64, ProgramError> {
+    let queues = S::QUEUES.len();
+    if queues > MAX_QUEUES {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut processed = 0;
+    while !is_done(processed) {
+        let mut heads = [None; MAX_QUEUES];
+        for (queue, head) in heads[..queues].iter_mut().enumerate() {
+            *head = state.queue_head(queue);
+        }
+        let Some(queue) = S::DRAIN_POLICY.next(&heads[..queues], now, S::ASYNC_DELAY_SLOTS) else {
+            return Ok(processed);
+        };
+        let entry = match queue {
+            0 => state.peek_entry().copied(),
+            _ => None,
+        };
+        state.process_next_in(queue)?;
+        if let Some(entry) = entry {
+            state.on_item_processed(&entry)?;
+        }
+        processed += 1;
+    }
+    Ok(processed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_policy() {
+        // Queue 1's head is older, queue 2 isn't due yet
+        let heads = [Some(5), Some(3), Some(9), None];
+        assert_eq!(DrainPolicy::Ordered.next(&heads, 8, 1), Some(0));
+        assert_eq!(DrainPolicy::Oldest.next(&heads, 8, 1), Some(1));
+        assert_eq!(DrainPolicy::Oldest.next(&[Some(3), Some(3)], 8, 1), Some(0));
+
+        assert_eq!(DrainPolicy::Ordered.next(&[None, Some(9)], 8, 1), None);
+        assert_eq!(DrainPolicy::Oldest.next(&[], 8, 1), None);
+    }
+}
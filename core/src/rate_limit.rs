@@ -0,0 +1,158 @@
+//! Per-user enqueue limits
+//!
+//! The queue is shared, so a single user queueing as fast as they can pay could exhaust its
+//! capacity for everyone. States with
+//! [`AsyncState::MAX_ENQUEUES_PER_SLOT`](crate::AsyncState::MAX_ENQUEUES_PER_SLOT) set only
+//! let each user queue or commit that many instructions per slot, on the state's
+//! [`Schedule`](crate::ordering::Schedule). Only users who queued in the current slot need a
+//! record, so older ones are purged to make room.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey};
+use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+pub const MAX_RATE_LIMITED_USERS: usize = 256;
+
+/// Custom program error for [`RateLimitError::RateLimited`], spelling `APQ` followed by 1
+pub const RATE_LIMITED: u32 = 0x4150_5101;
+
+/// How many instructions a user queued in one slot
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Window {
+    pub slot: u64,
+    pub count: u64,
+}
+
+crate::impl_layout_hash!(Window {
+    slot: u64,
+    count: u64
+});
+
+pub type RateLimits = RedBlackTree<Pubkey, Window, MAX_RATE_LIMITED_USERS>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RateLimitError {
+    /// The user already queued the limit this slot
+    RateLimited,
+    /// More users queued this slot than there are records for
+    Full,
+}
+
+impl From<RateLimitError> for ProgramError {
+    fn from(err: RateLimitError) -> Self {
+        match err {
+            RateLimitError::RateLimited => ProgramError::Custom(RATE_LIMITED),
+            RateLimitError::Full => ProgramError::AccountDataTooSmall,
+        }
+    }
+}
+
+/// Counts an enqueue by `user` at `now`, failing if it would be more than `limit` this slot
+pub fn record(
+    store: &mut RateLimits,
+    user: &Pubkey,
+    now: u64,
+    limit: u64,
+) -> Result<(), RateLimitError> {
+    if let Some(window) = store.get_mut(user) {
+        if window.slot != now {
+            *window = Window {
+                slot: now,
+                count: 0,
+            };
+        }
+        if window.count >= limit {
+            return Err(RateLimitError::RateLimited);
+        }
+        window.count += 1;
+        return Ok(());
+    }
+
+    if limit == 0 {
+        return Err(RateLimitError::RateLimited);
+    }
+    let window = Window {
+        slot: now,
+        count: 1,
+    };
+    if store.insert(*user, window).is_none() {
+        purge_stale(store, now);
+        store.insert(*user, window).ok_or(RateLimitError::Full)?;
+    }
+    Ok(())
+}
+
+/// Takes back an enqueue by `user` at `now` that was [`record`]ed but didn't go through
+pub fn unrecord(store: &mut RateLimits, user: &Pubkey, now: u64) {
+    if let Some(window) = store.get_mut(user) {
+        if window.slot == now {
+            window.count = window.count.saturating_sub(1);
+        }
+    }
+}
+
+/// Drops the records of users who haven't queued at `now`. Returns how many were purged.
+pub fn purge_stale(store: &mut RateLimits, now: u64) -> usize {
+    let stale: Vec<Pubkey> = store
+        .iter()
+        .filter(|(_, window)| window.slot != now)
+        .map(|(user, _)| *user)
+        .collect();
+    for user in &stale {
+        store.remove(user);
+    }
+    stale.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(i: u16) -> Pubkey {
+        let mut user = [0; 32];
+        user[..2].copy_from_slice(&i.to_le_bytes());
+        user
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut store: Box<RateLimits> = bytemuck::zeroed_box();
+        store.initialize();
+
+        for _ in 0..2 {
+            record(&mut store, &user(1), 5, 2).unwrap();
+        }
+        assert_eq!(
+            record(&mut store, &user(1), 5, 2),
+            Err(RateLimitError::RateLimited)
+        );
+        assert_eq!(
+            ProgramError::from(RateLimitError::RateLimited),
+            ProgramError::Custom(RATE_LIMITED)
+        );
+        // Others have their own limit, and everyone's resets next slot
+        record(&mut store, &user(2), 5, 2).unwrap();
+        record(&mut store, &user(1), 6, 2).unwrap();
+
+        // An enqueue that didn't go through doesn't count
+        record(&mut store, &user(1), 6, 2).unwrap();
+        unrecord(&mut store, &user(1), 6);
+        assert_eq!(store.get(&user(1)).unwrap().count, 1);
+        // Only this slot's
+        unrecord(&mut store, &user(2), 6);
+        assert_eq!(store.get(&user(2)).unwrap().count, 1);
+
+        // A full store makes room by dropping users from earlier slots
+        for i in 3..=MAX_RATE_LIMITED_USERS as u16 {
+            record(&mut store, &user(i), 6, 2).unwrap();
+        }
+        assert_eq!(store.len(), MAX_RATE_LIMITED_USERS);
+        record(&mut store, &user(1000), 6, 2).unwrap();
+        assert!(store.get(&user(2)).is_none());
+        assert_eq!(
+            record(&mut store, &user(1001), 6, 2),
+            Err(RateLimitError::Full)
+        );
+    }
+}
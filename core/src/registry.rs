@@ -0,0 +1,294 @@
+//! Accounts referenced by index into a registry
+//!
+//! [`AccountRefs`](crate::accounts::AccountRefs) spend 32 bytes of every queued entry on each
+//! account referenced. Programs whose entries keep referencing the same few accounts, e.g. the
+//! vaults and mints of their markets, register those once in an [`AccountRegistry`] kept in a
+//! companion account, and entries record [`RegistryRefs`] of two bytes per account instead.
+//!
+//! The registry account is created by the client like a config account, zeroed, owned by the
+//! program and [`AccountRegistry::LEN`] long, and bound to one state account by
+//! [`AccountRegistry::init`]. The program registers accounts from its own instructions, loading
+//! and writing the registry around [`AccountRegistry::register`]. Keys are only ever appended,
+//! so an index stays valid for as long as entries referencing it are queued.
+//!
+//! To drain, the cranker reads the registry and the entries due, passes the registry account
+//! first in the remaining accounts and then only the [keys those entries need](needed_keys),
+//! in any order. The state's
+//! [`AsyncState::process_next_async_with`](crate::AsyncState::process_next_async_with) takes
+//! the registry off the front with [`split_registry`] and [resolves](RegistryRefs::resolve)
+//! each entry's references against the rest. The drain doesn't tell a state its own address,
+//! so states keep the address of their registry, e.g. recorded when it is initialized, and a
+//! cranker can't swap in a registry with other keys.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+
+/// Most accounts a registry holds
+pub const MAX_REGISTERED: usize = 64;
+
+/// Most registered accounts one queued instruction can reference
+pub const MAX_REGISTRY_REFS: usize = 8;
+
+#[derive(Copy, Clone, Zeroable, Pod, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct AccountRegistry {
+    /// Layout version, zero until initialized
+    pub version: u32,
+    _padding: [u8; 4],
+    /// State account the registry belongs to
+    pub state: Pubkey,
+    len: u64,
+    keys: [Pubkey; MAX_REGISTERED],
+}
+
+impl AccountRegistry {
+    pub const LEN: usize = size_of::<AccountRegistry>();
+
+    pub const VERSION: u32 = 1;
+
+    pub fn new(state: Pubkey) -> Self {
+        AccountRegistry {
+            version: Self::VERSION,
+            state,
+            ..Zeroable::zeroed()
+        }
+    }
+
+    /// Binds the zeroed `account` to `state` as an empty registry
+    pub fn init(program_id: &Pubkey, state: &Pubkey, account: &AccountInfo) -> ProgramResult {
+        if !account.is_owned_by(program_id) {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let version = account
+            .try_borrow_data()?
+            .get(..Self::LEN)
+            .ok_or(ProgramError::AccountDataTooSmall)?[..4]
+            .iter()
+            .any(|byte| *byte != 0);
+        if version {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        Self::new(*state).write(account)
+    }
+
+    /// Reads the registry of `state` from `account`, checking it is initialized for it
+    pub fn load(
+        program_id: &Pubkey,
+        state: &Pubkey,
+        account: &AccountInfo,
+    ) -> Result<Self, ProgramError> {
+        if !account.is_owned_by(program_id) {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let data = account.try_borrow_data()?;
+        let registry = Self::read(&data)?;
+        if registry.state != *state {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(registry)
+    }
+
+    /// Reads an initialized registry from account data, e.g. fetched by a client
+    pub fn read(data: &[u8]) -> Result<Self, ProgramError> {
+        let registry: AccountRegistry = data
+            .get(..Self::LEN)
+            .and_then(|bytes| bytemuck::try_pod_read_unaligned(bytes).ok())
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        if registry.version != Self::VERSION || registry.len as usize > MAX_REGISTERED {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(registry)
+    }
+
+    pub fn write(&self, account: &AccountInfo) -> ProgramResult {
+        account
+            .try_borrow_mut_data()?
+            .get_mut(..Self::LEN)
+            .ok_or(ProgramError::AccountDataTooSmall)?
+            .copy_from_slice(bytemuck::bytes_of(self));
+        Ok(())
+    }
+
+    pub fn keys(&self) -> &[Pubkey] {
+        &self.keys[..self.len as usize]
+    }
+
+    pub fn get(&self, index: u16) -> Option<&Pubkey> {
+        self.keys().get(index as usize)
+    }
+
+    pub fn index_of(&self, key: &Pubkey) -> Option<u16> {
+        self.keys()
+            .iter()
+            .position(|registered| registered == key)
+            .map(|index| index as u16)
+    }
+
+    /// The index of `key`, appending it if it isn't registered yet. Fails with
+    /// [`ProgramError::AccountDataTooSmall`] when the registry is full.
+    pub fn register(&mut self, key: &Pubkey) -> Result<u16, ProgramError> {
+        if let Some(index) = self.index_of(key) {
+            return Ok(index);
+        }
+        let slot = self
+            .keys
+            .get_mut(self.len as usize)
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        *slot = *key;
+        self.len += 1;
+        Ok(self.len as u16 - 1)
+    }
+}
+
+/// Indices of the registered accounts a queued instruction references
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct RegistryRefs {
+    indices: [u16; MAX_REGISTRY_REFS],
+    len: u64,
+}
+
+impl RegistryRefs {
+    pub fn new(indices: &[u16]) -> Result<Self, ProgramError> {
+        let mut refs = RegistryRefs::default();
+        refs.indices
+            .get_mut(..indices.len())
+            .ok_or(ProgramError::InvalidArgument)?
+            .copy_from_slice(indices);
+        refs.len = indices.len() as u64;
+        Ok(refs)
+    }
+
+    pub fn indices(&self) -> &[u16] {
+        &self.indices[..self.len as usize]
+    }
+
+    /// The referenced keys in the order they were referenced, failing with
+    /// [`ProgramError::InvalidArgument`] on an index the registry doesn't hold
+    pub fn keys(&self, registry: &AccountRegistry) -> Result<Vec<Pubkey>, ProgramError> {
+        self.indices()
+            .iter()
+            .map(|index| registry.get(*index).copied())
+            .collect::<Option<_>>()
+            .ok_or(ProgramError::InvalidArgument)
+    }
+
+    /// Finds each referenced account among `accounts`, in the order they were referenced
+    pub fn resolve<'a>(
+        &self,
+        registry: &AccountRegistry,
+        accounts: &'a [AccountInfo],
+    ) -> Result<Vec<&'a AccountInfo>, ProgramError> {
+        self.keys(registry)?
+            .iter()
+            .map(|key| {
+                accounts
+                    .iter()
+                    .find(|account| account.key() == key)
+                    .ok_or(ProgramError::NotEnoughAccountKeys)
+            })
+            .collect()
+    }
+}
+
+/// Loads the registry at `address` from the first of a drain's remaining `accounts`, returning
+/// it with the accounts after it
+pub fn split_registry<'a>(
+    program_id: &Pubkey,
+    address: &Pubkey,
+    accounts: &'a [AccountInfo],
+) -> Result<(AccountRegistry, &'a [AccountInfo]), ProgramError> {
+    let (account, rest) = accounts
+        .split_first()
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    if account.key() != address {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !account.is_owned_by(program_id) {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let registry = AccountRegistry::read(&account.try_borrow_data()?)?;
+    Ok((registry, rest))
+}
+
+/// Every key the entries referencing `refs` need, each once and in registry order, which is
+/// all a drain of those entries has to pass after the registry
+pub fn needed_keys<'r>(
+    registry: &AccountRegistry,
+    refs: impl IntoIterator<Item = &'r RegistryRefs>,
+) -> Result<Vec<Pubkey>, ProgramError> {
+    let mut needed = [false; MAX_REGISTERED];
+    for refs in refs {
+        for index in refs.indices() {
+            registry.get(*index).ok_or(ProgramError::InvalidArgument)?;
+            needed[*index as usize] = true;
+        }
+    }
+    Ok(registry
+        .keys()
+        .iter()
+        .zip(needed)
+        .filter_map(|(key, needed)| needed.then_some(*key))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = AccountRegistry::new([9; 32]);
+        assert_eq!(registry.register(&[1; 32]), Ok(0));
+        assert_eq!(registry.register(&[2; 32]), Ok(1));
+        assert_eq!(registry.register(&[1; 32]), Ok(0));
+        assert_eq!(registry.keys(), [[1; 32], [2; 32]]);
+        assert_eq!(registry.index_of(&[2; 32]), Some(1));
+        assert_eq!(registry.get(2), None);
+
+        let read = AccountRegistry::read(bytemuck::bytes_of(&registry)).unwrap();
+        assert_eq!(read, registry);
+        assert_eq!(
+            AccountRegistry::read(&[0; AccountRegistry::LEN]),
+            Err(ProgramError::UninitializedAccount)
+        );
+
+        let mut full = AccountRegistry::new([9; 32]);
+        for key in 0..MAX_REGISTERED {
+            full.register(&[key as u8; 32]).unwrap();
+        }
+        assert_eq!(
+            full.register(&[u8::MAX; 32]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_registry_refs() {
+        let mut registry = AccountRegistry::new([9; 32]);
+        for key in 1..=4 {
+            registry.register(&[key; 32]).unwrap();
+        }
+        let a = RegistryRefs::new(&[3, 1]).unwrap();
+        let b = RegistryRefs::new(&[1, 0]).unwrap();
+        assert_eq!(a.keys(&registry), Ok(vec![[4; 32], [2; 32]]));
+        assert_eq!(
+            needed_keys(&registry, [&a, &b]),
+            Ok(vec![[1; 32], [2; 32], [4; 32]])
+        );
+        assert_eq!(needed_keys(&registry, []), Ok(vec![]));
+
+        let missing = RegistryRefs::new(&[4]).unwrap();
+        assert_eq!(missing.keys(&registry), Err(ProgramError::InvalidArgument));
+        assert_eq!(
+            needed_keys(&registry, [&a, &missing]),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            RegistryRefs::new(&[0; MAX_REGISTRY_REFS + 1]),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+}
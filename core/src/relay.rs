@@ -0,0 +1,273 @@
+//! Gasless enqueues paid for by a relayer
+//!
+//! Users without SOL for fees can still queue: they sign the [`message`] authorizing one
+//! enqueue off-chain, e.g. with a wallet's sign message, and hand it to a relayer. The relayer
+//! sends a transaction with an ed25519 program instruction verifying the signature, followed
+//! by the relayed queue instruction, and pays the fees and the escrow. The entry is queued
+//! as the user's, so its refunds go to the user.
+//!
+//! The program can't check signatures itself, so [`verify_ed25519`] reads the instructions
+//! sysvar for an earlier ed25519 program instruction that verified the user's signature over
+//! the exact message. The runtime fails the whole transaction if that signature is invalid.
+//!
+//! The message names the program, the state, the user and an expiry at most
+//! [`MAX_RELAY_WINDOW`] ahead, so an authorization can't be replayed against another state,
+//! for another user or later on. Within its window it can't be relayed again either: states
+//! with [`AsyncState::RELAYED`](crate::AsyncState::RELAYED) keep the [`Relayed`]
+//! authorizations until they expire, see [`consume`]. Users authorizing the same enqueue twice
+//! sign it with different expiries, while different users' authorizations of the same enqueue
+//! are told apart by who signed them.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey,
+    sysvars::instructions::Instructions, ProgramResult,
+};
+use pinocchio_pubkey::pubkey;
+use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+use crate::{
+    commit::Hash,
+    queue::{peek_min, pop_min},
+};
+
+pub const ED25519_PROGRAM_ID: Pubkey = pubkey!("Ed25519SigVerify111111111111111111111111111");
+
+/// Custom program error for a missing, mismatched or expired authorization, spelling `APQ`
+/// followed by C
+pub const UNAUTHORIZED: u32 = 0x4150_510C;
+
+/// Furthest an authorization can expire, on the state's
+/// [`Schedule`](crate::ordering::Schedule)
+pub const MAX_RELAY_WINDOW: u64 = 150;
+
+/// Authorizations relayed within their windows at once, a slot's worth of relays at
+/// [`MAX_RELAY_WINDOW`]
+pub const MAX_RELAYED: usize = 256;
+
+/// Sets relayed authorizations apart from any other message a user signs
+const DOMAIN: &[u8] = b"apq relay v2";
+
+/// Where the first of the ed25519 instruction's signature offsets starts, after the signature
+/// count and a padding byte
+const OFFSETS_START: usize = 2;
+
+/// Signature, public key and message offsets of one signature
+const OFFSETS_LEN: usize = 14;
+
+/// An authorization that was relayed, kept until it expires so it isn't relayed again. The
+/// expiry comes first, so the expired ones are the first in [`RelayedStore`].
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Relayed {
+    pub expiry: u64,
+    /// The user who signed it
+    pub owner: Pubkey,
+    /// Of the signed [`message`]
+    pub hash: Hash,
+}
+
+crate::impl_layout_hash!(Relayed {
+    expiry: u64,
+    owner: Pubkey,
+    hash: Hash
+});
+
+/// Relayed authorizations and the relayer who paid for each
+pub type RelayedStore = RedBlackTree<Relayed, Pubkey, MAX_RELAYED>;
+
+/// What `owner` signs to authorize queueing `async_ix`, the async instruction data with queue
+/// args, into `state` until `expiry`
+pub fn message(
+    program_id: &Pubkey,
+    state: &Pubkey,
+    owner: &Pubkey,
+    expiry: u64,
+    async_ix: &[u8],
+) -> Vec<u8> {
+    [
+        DOMAIN,
+        program_id,
+        state,
+        owner,
+        &expiry.to_le_bytes(),
+        async_ix,
+    ]
+    .concat()
+}
+
+/// Fails with [`UNAUTHORIZED`] unless `now` is at most `expiry`, and `expiry` at most
+/// [`MAX_RELAY_WINDOW`] after `now`
+pub fn check_expiry(expiry: u64, now: u64) -> ProgramResult {
+    match (now..=now.saturating_add(MAX_RELAY_WINDOW)).contains(&expiry) {
+        true => Ok(()),
+        false => Err(ProgramError::Custom(UNAUTHORIZED)),
+    }
+}
+
+/// Records `owner`'s authorization with `message` until `expiry`, relayed by `relayer`,
+/// purging expired ones first. Fails with [`ProgramError::AccountAlreadyInitialized`] if it
+/// was relayed already.
+pub fn consume(
+    store: &mut RelayedStore,
+    message: &[u8],
+    expiry: u64,
+    owner: &Pubkey,
+    relayer: &Pubkey,
+    now: u64,
+) -> ProgramResult {
+    purge_expired(store, now);
+
+    let relayed = Relayed {
+        expiry,
+        owner: *owner,
+        hash: solana_sha256_hasher::hash(message).to_bytes(),
+    };
+    if store.get(&relayed).is_some() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    store
+        .insert(relayed, *relayer)
+        .ok_or(ProgramError::AccountDataTooSmall)?;
+    Ok(())
+}
+
+/// Drops the authorizations that expired before `now`, which can't be relayed anymore.
+/// Returns how many were purged.
+pub fn purge_expired(store: &mut RelayedStore, now: u64) -> usize {
+    let mut purged = 0;
+    while peek_min(store).is_some_and(|(_, first)| first.key.expiry < now) {
+        pop_min(store);
+        purged += 1;
+    }
+    purged
+}
+
+/// Fails with [`UNAUTHORIZED`] unless an ed25519 program instruction before the current one
+/// verifies a signature by `signer` over `message`. `instructions` is the instructions sysvar.
+pub fn verify_ed25519(
+    instructions: &AccountInfo,
+    signer: &Pubkey,
+    message: &[u8],
+) -> ProgramResult {
+    let instructions = Instructions::try_from(instructions)?;
+    for index in 0..instructions.load_current_index() {
+        let instruction = instructions.load_instruction_at(index as usize)?;
+        if instruction.get_program_id() == &ED25519_PROGRAM_ID
+            && verifies(instruction.get_instruction_data(), signer, message)
+        {
+            return Ok(());
+        }
+    }
+    Err(ProgramError::Custom(UNAUTHORIZED))
+}
+
+/// Whether the ed25519 program instruction `data` verifies a signature by `signer` over
+/// `message`. Only signatures with the key, signature and message in `data` itself count,
+/// since offsets into other instructions could point at anything.
+pub fn verifies(data: &[u8], signer: &Pubkey, message: &[u8]) -> bool {
+    let Some(&count) = data.first() else {
+        return false;
+    };
+    (0..count as usize).any(|i| {
+        let start = OFFSETS_START + i * OFFSETS_LEN;
+        let Some(offsets) = data.get(start..start + OFFSETS_LEN) else {
+            return false;
+        };
+        let [signature, signature_ix, key, key_ix, message_at, message_len, message_ix] =
+            [0, 2, 4, 6, 8, 10, 12]
+                .map(|at| u16::from_le_bytes([offsets[at], offsets[at + 1]]) as usize);
+        // The ed25519 program's index for its own instruction
+        let own = u16::MAX as usize;
+        [signature_ix, key_ix, message_ix] == [own; 3]
+            && data.get(signature..signature + 64).is_some()
+            && data.get(key..key + 32) == Some(signer)
+            && data.get(message_at..message_at + message_len) == Some(message)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ed25519 program data for one signature, laid out like the client's
+    fn ed25519_data(signer: &Pubkey, message: &[u8], instruction_index: u16) -> Vec<u8> {
+        let key = OFFSETS_START + OFFSETS_LEN;
+        let signature = key + 32;
+        let message_at = signature + 64;
+        let offsets = [
+            signature as u16,
+            instruction_index,
+            key as u16,
+            instruction_index,
+            message_at as u16,
+            message.len() as u16,
+            instruction_index,
+        ];
+        let mut data = vec![1, 0];
+        data.extend(offsets.iter().flat_map(|offset| offset.to_le_bytes()));
+        data.extend(signer);
+        data.extend([7; 64]);
+        data.extend(message);
+        data
+    }
+
+    #[test]
+    fn test_verifies() {
+        let user = [1; 32];
+        let message = message(&[2; 32], &[3; 32], &user, 10, &[4, 5]);
+        assert!(message.starts_with(DOMAIN));
+        assert_ne!(
+            message,
+            super::message(&[2; 32], &[3; 32], &[9; 32], 10, &[4, 5])
+        );
+
+        let data = ed25519_data(&user, &message, u16::MAX);
+        assert!(verifies(&data, &user, &message));
+        assert!(!verifies(&data, &[9; 32], &message));
+        assert!(!verifies(&data, &user, &message[1..]));
+        // Offsets into another instruction
+        assert!(!verifies(
+            &ed25519_data(&user, &message, 0),
+            &user,
+            &message
+        ));
+        assert!(!verifies(&data[..data.len() - 1], &user, &message));
+        assert!(!verifies(&[], &user, &message));
+    }
+
+    #[test]
+    fn test_consume() {
+        let mut store: Box<RelayedStore> = bytemuck::zeroed_box();
+        store.initialize();
+        let message = message(&[2; 32], &[3; 32], &[1; 32], 10, &[4, 5]);
+        consume(&mut store, &message, 10, &[1; 32], &[8; 32], 5).unwrap();
+        assert_eq!(
+            consume(&mut store, &message, 10, &[1; 32], &[8; 32], 10),
+            Err(ProgramError::AccountAlreadyInitialized)
+        );
+        // Signed again with another expiry
+        consume(&mut store, &message, 12, &[1; 32], &[8; 32], 10).unwrap();
+        // The same bytes can't be relayed as someone else's
+        consume(&mut store, &message, 12, &[9; 32], &[8; 32], 10).unwrap();
+        assert_eq!(store.len(), 3);
+
+        // Expired, so only the later ones are kept
+        assert_eq!(purge_expired(&mut store, 11), 1);
+        let kept: Vec<u64> = store.iter().map(|(relayed, _)| relayed.expiry).collect();
+        assert_eq!(kept, [12, 12]);
+    }
+
+    #[test]
+    fn test_check_expiry() {
+        assert_eq!(check_expiry(100, 100), Ok(()));
+        assert_eq!(check_expiry(100 + MAX_RELAY_WINDOW, 100), Ok(()));
+        for (expiry, now) in [(99, 100), (101 + MAX_RELAY_WINDOW, 100)] {
+            assert_eq!(
+                check_expiry(expiry, now),
+                Err(ProgramError::Custom(UNAUTHORIZED))
+            );
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Session keys queueing on a user's behalf
+//!
+//! Bots shouldn't hold the key to a user's funds just to queue for them. States with
+//! [`AsyncState::SESSIONS`](crate::AsyncState::SESSIONS) let the user delegate to a session
+//! key instead: the create session instruction records a [`Session`] naming the key, when it
+//! expires and which instructions it may queue, and the delegated queue instruction, signed by
+//! the key, queues as the user. The key escrows, and refunds go to the user whose entry it is.
+//!
+//! The session account is created by the client like a config account, zeroed, owned by the
+//! program and exactly [`Session::LEN`] long, one per delegation to an initialized state. The
+//! user or the key can revoke a session at any time, which zeroes it so it can be reused.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+
+/// Custom program error for a session key queueing what it isn't allowed to, spelling `APQ`
+/// followed by D
+pub const SESSION_DENIED: u32 = 0x4150_510D;
+
+/// What the user sets, the data of the create session instruction
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct SessionParams {
+    /// Signs delegated queue instructions
+    pub key: Pubkey,
+    /// Last time the key can queue, on the state's [`Schedule`](crate::ordering::Schedule)
+    pub expiry: u64,
+    /// Bit `n` set lets the key queue async instructions with variant `n`
+    pub ixn_mask: u64,
+}
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Session {
+    /// Layout version, zero until created and after being revoked
+    pub version: u32,
+    /// [`Session::DISCRIMINATOR`] once created, telling sessions apart from the program's
+    /// other accounts of the same version
+    pub discriminator: [u8; 4],
+    /// State account the session queues into
+    pub state: Pubkey,
+    /// Whose entries the key queues
+    pub user: Pubkey,
+    pub params: SessionParams,
+}
+
+impl Session {
+    pub const LEN: usize = size_of::<Session>();
+
+    pub const VERSION: u32 = 1;
+
+    pub const DISCRIMINATOR: [u8; 4] = *b"sesn";
+
+    pub fn new(state: Pubkey, user: Pubkey, params: SessionParams) -> Self {
+        Session {
+            version: Self::VERSION,
+            discriminator: Self::DISCRIMINATOR,
+            state,
+            user,
+            params,
+        }
+    }
+
+    /// Reads the session for `state` from `account`, checking it is created for it
+    pub fn load(
+        program_id: &Pubkey,
+        state: &Pubkey,
+        account: &AccountInfo,
+    ) -> Result<Self, ProgramError> {
+        if !account.is_owned_by(program_id) {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let session = Self::read(&account.try_borrow_data()?)?;
+        if session.version != Self::VERSION {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if session.discriminator != Self::DISCRIMINATOR || session.state != *state {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(session)
+    }
+
+    fn read(data: &[u8]) -> Result<Self, ProgramError> {
+        data.get(..Self::LEN)
+            .and_then(|bytes| bytemuck::try_pod_read_unaligned(bytes).ok())
+            .ok_or(ProgramError::AccountDataTooSmall)
+    }
+
+    pub fn write(&self, account: &AccountInfo) -> ProgramResult {
+        account
+            .try_borrow_mut_data()?
+            .get_mut(..Self::LEN)
+            .ok_or(ProgramError::AccountDataTooSmall)?
+            .copy_from_slice(bytemuck::bytes_of(self));
+        Ok(())
+    }
+
+    /// Whether the key may queue async instructions with variant `ixn`
+    pub fn allows(&self, ixn: u64) -> bool {
+        ixn < u64::BITS as u64 && self.params.ixn_mask & (1 << ixn) != 0
+    }
+
+    /// Fails unless `key` is this session's key and signed, and may queue `ixn` for `user` at
+    /// `now`, with [`SESSION_DENIED`] if the session expired or doesn't allow `ixn`
+    pub fn check(&self, user: &Pubkey, key: &AccountInfo, ixn: u64, now: u64) -> ProgramResult {
+        if !key.is_signer() {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *key.key() != self.params.key || *user != self.user {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        if now > self.params.expiry || !self.allows(ixn) {
+            return Err(ProgramError::Custom(SESSION_DENIED));
+        }
+        Ok(())
+    }
+}
+
+/// Records a session for the signing `user` in the zeroed `account`, exactly
+/// [`Session::LEN`] long, from the instruction `data`. Dispatch checks that `state` is an
+/// initialized state of the program first.
+pub fn process_create(
+    program_id: &Pubkey,
+    state: &Pubkey,
+    user: &AccountInfo,
+    account: &AccountInfo,
+    data: &[u8],
+) -> ProgramResult {
+    if !user.is_signer() {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if !account.is_owned_by(program_id) {
+        return Err(ProgramError::IllegalOwner);
+    }
+    if account.data_len() != Session::LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if Session::read(&account.try_borrow_data()?)?.version != 0 {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    let params = data
+        .get(..size_of::<SessionParams>())
+        .and_then(|bytes| bytemuck::try_pod_read_unaligned(bytes).ok())
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    Session::new(*state, *user.key(), params).write(account)
+}
+
+/// Zeroes the session in `account`, signed by its user or its key
+pub fn process_revoke(
+    program_id: &Pubkey,
+    state: &Pubkey,
+    signer: &AccountInfo,
+    account: &AccountInfo,
+) -> ProgramResult {
+    let session = Session::load(program_id, state, account)?;
+    if !signer.is_signer() || ![session.user, session.params.key].contains(signer.key()) {
+        return Err(ProgramError::IncorrectAuthority);
+    }
+    Session::default().write(account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let params = SessionParams {
+            key: [2; 32],
+            expiry: 100,
+            ixn_mask: 0b101 | 1 << 63,
+        };
+        let session = Session::new([1; 32], [3; 32], params);
+        let allowed: Vec<u64> = (0..70).filter(|ixn| session.allows(*ixn)).collect();
+        assert_eq!(allowed, [0, 2, 63]);
+        assert!(!Session::default().allows(0));
+        assert_eq!(Session::read(bytemuck::bytes_of(&session)), Ok(session));
+        assert_eq!(
+            Session::read(&[0; Session::LEN - 1]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Queues sharded across several state accounts
+//!
+//! Every instruction writing to a state account is serialized with every other one writing to
+//! it, so a single queue caps how many users can enqueue per slot. States with
+//! [`AsyncState::SHARDS`] set split their queue over that many accounts of the same layout,
+//! and each user enqueues into the shard [`route`] picks for them. Shard indexes are recorded
+//! in the [`StateHeader`](crate::header::StateHeader) and set by the admin while the shard is
+//! empty.
+//!
+//! Draining takes every shard at once, shard 0 as the state account and the rest as remaining
+//! accounts in order, and merges them by key so the sharded queue drains in the same order a
+//! single one would. The drain start and end hooks run on shard 0, and
+//! [`AsyncState::on_item_processed`] on the shard the entry came from.
+
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey};
+
+use crate::AsyncState;
+
+/// The shard `user` enqueues into, out of `shards`
+pub fn route(user: &Pubkey, shards: u8) -> u8 {
+    let hash = solana_sha256_hasher::hashv(&[user]).to_bytes();
+    let (prefix, _) = hash.split_first_chunk().unwrap();
+    (u64::from_le_bytes(*prefix) % u64::from(shards.max(1))) as u8
+}
+
+/// Processes entries due at `now` across `shards`, smallest key first, until `is_done` with
+/// the total processed. Counts how many were processed from each shard into `processed`.
+pub fn drain<S: AsyncState>(
+    shards: &mut [&mut S],
+    now: u64,
+    processed: &mut [u64],
+    is_done: impl Fn(u64) -> bool,
+) -> Result<(), ProgramError> {
+    while !is_done(processed.iter().sum()) {
+        let next = shards
+            .iter()
+            .enumerate()
+            .filter(|(_, shard)| shard.has_pending_async(now))
+            .filter_map(|(i, shard)| shard.peek_entry().map(|entry| (entry.key, i)))
+            .min();
+        let Some((_, i)) = next else {
+            return Ok(());
+        };
+        let entry = shards[i].peek_entry().copied();
+        shards[i].process_next_async()?;
+        if let Some(entry) = entry {
+            shards[i].on_item_processed(&entry)?;
+        }
+        processed[i] += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let mut counts = [0; 4];
+        for i in 0..=255 {
+            let shard = route(&[i; 32], 4);
+            assert_eq!(route(&[i; 32], 4), shard);
+            counts[shard as usize] += 1;
+        }
+        // Roughly even
+        assert!(counts.iter().all(|&count| count > 32), "{counts:?}");
+        assert_eq!(route(&[7; 32], 1), 0);
+        assert_eq!(route(&[7; 32], 0), 0);
+    }
+}
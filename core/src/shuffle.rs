@@ -0,0 +1,76 @@
+//! Randomized execution order within a slot
+//!
+//! Sequence numbers hand same-slot ties to whoever landed first, which rewards latency. With
+//! [`AsyncState::SHUFFLE_SLOT`](crate::AsyncState::SHUFFLE_SLOT) each slot's batch is instead
+//! shuffled with a seed anyone can recompute from the SlotHashes sysvar and the batch's slot.
+//!
+//! The seed is the most recent slot hash at drain time, so a cranker can only pick between
+//! seeds by delaying the drain, and the leader of the previous slot has some influence over
+//! it. Good enough to take the edge off latency races, not for anything worth grinding.
+
+use pinocchio::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
+use pinocchio_pubkey::pubkey;
+
+pub const SLOT_HASHES_ID: Pubkey = pubkey!("SysvarS1otHashes111111111111111111111111111");
+
+/// Most recent hash in the SlotHashes sysvar, which is laid out as a `u64` entry count
+/// followed by `(slot: u64, hash: [u8; 32])` entries, newest first
+pub fn recent_slot_hash(slot_hashes: &AccountInfo) -> Result<[u8; 32], ProgramError> {
+    if *slot_hashes.key() != SLOT_HASHES_ID {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let data = slot_hashes.try_borrow_data()?;
+    data.get(16..48)
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+/// Seed for shuffling the batch queued in `slot`
+pub fn seed(slot_hash: &[u8; 32], slot: u64) -> u64 {
+    let hash = solana_sha256_hasher::hashv(&[slot_hash, &slot.to_le_bytes()]).to_bytes();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+/// Fisher-Yates shuffle driven by splitmix64, one multiply-shift per swap instead of a
+/// hash or division
+pub fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+    for i in (1..items.len()).rev() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        // Uniform in 0..=i, up to a negligible bias
+        let j = ((z as u128 * (i as u128 + 1)) >> 64) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shuffle_is_deterministic_permutation() {
+        let shuffled = |seed| {
+            let mut items: Vec<u32> = (0..32).collect();
+            shuffle(&mut items, seed);
+            items
+        };
+
+        let a = shuffled(seed(&[1; 32], 10));
+        assert_eq!(a, shuffled(seed(&[1; 32], 10)));
+        assert_ne!(a, (0..32).collect::<Vec<u32>>());
+
+        let mut sorted = a.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..32).collect::<Vec<u32>>());
+
+        // Either input changes the order
+        assert_ne!(a, shuffled(seed(&[2; 32], 10)));
+        assert_ne!(a, shuffled(seed(&[1; 32], 11)));
+    }
+}
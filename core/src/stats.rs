@@ -0,0 +1,87 @@
+//! Queue throughput and backlog counters
+//!
+//! [`QueueStats`] lives in the [`StateHeader`](crate::header::StateHeader) and is kept up to
+//! date by [`dispatch`](crate::dispatch), so operators can monitor a queue from its account
+//! data alone.
+
+use bytemuck::{Pod, Zeroable};
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct QueueStats {
+    /// Entries ever queued, revealed commitments included
+    pub total_enqueued: u64,
+    /// Entries ever drained, whether they succeeded or were dead-lettered
+    pub total_processed: u64,
+    /// Entries ever cancelled by their user
+    pub total_cancelled: u64,
+    /// Most entries queued at once
+    pub max_depth: u64,
+    /// When entries were last drained, on the state's
+    /// [`AsyncState::SCHEDULE`](crate::AsyncState::SCHEDULE). Zero if none have been
+    pub last_processed_slot: u64,
+}
+
+crate::impl_layout_hash!(QueueStats {
+    total_enqueued: u64,
+    total_processed: u64,
+    total_cancelled: u64,
+    max_depth: u64,
+    last_processed_slot: u64
+});
+
+impl QueueStats {
+    /// Entries queued right now
+    pub fn depth(&self) -> u64 {
+        self.total_enqueued
+            .saturating_sub(self.total_processed)
+            .saturating_sub(self.total_cancelled)
+    }
+
+    pub fn record_enqueued(&mut self) {
+        self.total_enqueued += 1;
+        self.max_depth = self.max_depth.max(self.depth());
+    }
+
+    /// Records `count` entries drained at `now`
+    pub fn record_processed(&mut self, count: u64, now: u64) {
+        if count > 0 {
+            self.total_processed += count;
+            self.last_processed_slot = now;
+        }
+    }
+
+    pub fn record_cancelled(&mut self) {
+        self.total_cancelled += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depth() {
+        let mut stats = QueueStats::default();
+        for _ in 0..3 {
+            stats.record_enqueued();
+        }
+        stats.record_cancelled();
+        stats.record_processed(0, 5);
+        assert_eq!(stats.last_processed_slot, 0);
+        stats.record_processed(1, 7);
+        stats.record_enqueued();
+        assert_eq!(
+            stats,
+            QueueStats {
+                total_enqueued: 4,
+                total_processed: 1,
+                total_cancelled: 1,
+                max_depth: 3,
+                last_processed_slot: 7,
+            }
+        );
+        assert_eq!(stats.depth(), 2);
+    }
+}
@@ -0,0 +1,69 @@
+//! Instruction data validation
+//!
+//! Instruction data is read front to back, so by default bytes after what an instruction reads
+//! are ignored. That makes a client encoding the wrong layout, e.g. an extra field, succeed with
+//! something other than it meant. States with
+//! [`AsyncState::STRICTNESS`](crate::AsyncState::STRICTNESS) set to [`Strictness::Strict`] reject
+//! leftover bytes instead: the dispatch checks the shared instructions, and the state checks its
+//! own with [`check_consumed`] once it has parsed them.
+
+use pinocchio::program_error::ProgramError;
+
+/// Custom program error for [`DataError::Empty`], spelling `APQ` followed by 5
+pub const EMPTY_DATA: u32 = 0x4150_5105;
+
+/// Custom program error for [`DataError::TrailingBytes`], spelling `APQ` followed by 6
+pub const TRAILING_BYTES: u32 = 0x4150_5106;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Strictness {
+    /// Ignore bytes after what the instruction reads
+    Lenient,
+    /// Reject them
+    Strict,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DataError {
+    /// No instruction data at all, not even the discriminator
+    Empty,
+    /// Bytes left over after the instruction was read
+    TrailingBytes,
+}
+
+impl From<DataError> for ProgramError {
+    fn from(err: DataError) -> Self {
+        match err {
+            DataError::Empty => ProgramError::Custom(EMPTY_DATA),
+            DataError::TrailingBytes => ProgramError::Custom(TRAILING_BYTES),
+        }
+    }
+}
+
+/// Checks that reading `used` bytes of `data` left nothing over, if `strictness` asks for it
+pub fn check_consumed(data: &[u8], used: usize, strictness: Strictness) -> Result<(), DataError> {
+    if strictness == Strictness::Strict && data.len() > used {
+        return Err(DataError::TrailingBytes);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_consumed() {
+        assert_eq!(check_consumed(&[0; 8], 8, Strictness::Strict), Ok(()));
+        assert_eq!(check_consumed(&[0; 9], 9, Strictness::Strict), Ok(()));
+        assert_eq!(
+            check_consumed(&[0; 9], 8, Strictness::Strict),
+            Err(DataError::TrailingBytes)
+        );
+        assert_eq!(check_consumed(&[0; 9], 8, Strictness::Lenient), Ok(()));
+        assert_eq!(
+            ProgramError::from(DataError::Empty),
+            ProgramError::Custom(EMPTY_DATA)
+        );
+    }
+}
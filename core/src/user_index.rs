@@ -0,0 +1,223 @@
+//! Opt-in index from users to their queued entries
+//!
+//! Finding a user's entries otherwise scans the whole queue, see
+//! [`AsyncState::iter_by_user`](crate::AsyncState::iter_by_user), which gets expensive for
+//! cancels and dedupe once the queue is deep. States that need it keep a [`UserIndex`] next
+//! to their queue, in the state account or a companion one, and go through [`insert`] and
+//! [`remove`] instead of the tree's own methods whenever an entry enters or leaves the queue,
+//! drained or cancelled. [`entries`] and [`keys`] then read a user's entries in `O(log n)`.
+//!
+//! The index holds sokoban node addresses, which stay put for as long as the entry is queued.
+//! Debug builds check after every update that index and queue agree, see [`is_consistent`].
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{program_error::ProgramError, pubkey::Pubkey};
+use sokoban::{NodeAllocatorMap, RedBlackTree, SENTINEL};
+
+use crate::queue::{Entry, Payload, QueueKey, UserPayload};
+
+/// Entries one user can have queued at once while indexed
+pub const MAX_ENTRIES_PER_USER: usize = 15;
+
+/// Queue node addresses of one user's entries, in no particular order
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct UserEntries {
+    pub len: u32,
+    pub addrs: [u32; MAX_ENTRIES_PER_USER],
+}
+
+crate::impl_layout_hash!(UserEntries {
+    len: u32,
+    addrs: [u32; MAX_ENTRIES_PER_USER]
+});
+
+impl UserEntries {
+    pub fn addrs(&self) -> &[u32] {
+        &self.addrs[..self.len as usize]
+    }
+}
+
+/// Entries of up to `N` users with something queued
+pub type UserIndex<const N: usize> = RedBlackTree<Pubkey, UserEntries, N>;
+
+/// Node addresses of `user`'s queued entries
+pub fn addrs<'a, const N: usize>(index: &'a UserIndex<N>, user: &Pubkey) -> &'a [u32] {
+    index.get(user).map(UserEntries::addrs).unwrap_or_default()
+}
+
+/// `user`'s queued entries, in no particular order
+pub fn entries<'a, K: QueueKey, V: Payload, const N: usize, const M: usize>(
+    index: &'a UserIndex<N>,
+    queue: &'a RedBlackTree<K, V, M>,
+    user: &Pubkey,
+) -> impl Iterator<Item = &'a Entry<K, V>> {
+    addrs(index, user).iter().map(|&addr| queue.get_node(addr))
+}
+
+/// Keys of `user`'s queued entries in drain order
+pub fn keys<K: QueueKey, V: Payload, const N: usize, const M: usize>(
+    index: &UserIndex<N>,
+    queue: &RedBlackTree<K, V, M>,
+    user: &Pubkey,
+) -> Vec<K> {
+    let mut keys: Vec<K> = entries(index, queue, user).map(|entry| entry.key).collect();
+    keys.sort_unstable();
+    keys
+}
+
+/// Queues `value` under `key` and indexes it, returning its node address. Fails with
+/// `AccountDataTooSmall`, leaving both untouched, if the queue or the index is full or the
+/// user already has [`MAX_ENTRIES_PER_USER`] entries queued, and with `InvalidArgument` if
+/// `key` is queued already.
+pub fn insert<K: QueueKey, V: UserPayload, const N: usize, const M: usize>(
+    index: &mut UserIndex<N>,
+    queue: &mut RedBlackTree<K, V, M>,
+    key: K,
+    value: V,
+) -> Result<u32, ProgramError> {
+    let user = *value.user();
+    let mut entries = index.get(&user).copied().unwrap_or_default();
+    if queue.contains(&key) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if entries.len as usize == MAX_ENTRIES_PER_USER {
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    let addr = queue
+        .insert(key, value)
+        .ok_or(ProgramError::AccountDataTooSmall)?;
+    entries.addrs[entries.len as usize] = addr;
+    entries.len += 1;
+    if index.insert(user, entries).is_none() {
+        queue.remove(&key);
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    debug_assert!(is_consistent(index, queue));
+    Ok(addr)
+}
+
+/// Removes the entry under `key` from the queue and the index, returning its payload
+pub fn remove<K: QueueKey, V: UserPayload, const N: usize, const M: usize>(
+    index: &mut UserIndex<N>,
+    queue: &mut RedBlackTree<K, V, M>,
+    key: &K,
+) -> Option<V> {
+    let addr = queue.get_addr(key);
+    if addr == SENTINEL {
+        return None;
+    }
+    let value = queue.remove(key)?;
+
+    let user = value.user();
+    if let Some(entries) = index.get_mut(user) {
+        if let Some(at) = entries.addrs().iter().position(|&indexed| indexed == addr) {
+            entries.len -= 1;
+            entries.addrs[at] = entries.addrs[entries.len as usize];
+            entries.addrs[entries.len as usize] = 0;
+        }
+        if entries.len == 0 {
+            index.remove(user);
+        }
+    }
+
+    debug_assert!(is_consistent(index, queue));
+    Some(value)
+}
+
+/// Whether every queued entry is indexed exactly once under its user, and nothing else is.
+/// Walks both trees, so it is meant for debug assertions and tests.
+pub fn is_consistent<K: QueueKey, V: UserPayload, const N: usize, const M: usize>(
+    index: &UserIndex<N>,
+    queue: &RedBlackTree<K, V, M>,
+) -> bool {
+    let mut indexed = 0;
+    for (user, entries) in index.iter() {
+        if entries.len == 0 || entries.len as usize > MAX_ENTRIES_PER_USER {
+            return false;
+        }
+        for &addr in entries.addrs() {
+            let entry = queue.get_node(addr);
+            if queue.get_addr(&entry.key) != addr || entry.value.user() != user {
+                return false;
+            }
+        }
+        indexed += entries.len as usize;
+    }
+    indexed == queue.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+    #[repr(C)]
+    struct Queued {
+        user: Pubkey,
+    }
+
+    impl UserPayload for Queued {
+        fn user(&self) -> &Pubkey {
+            &self.user
+        }
+    }
+
+    const ALICE: Queued = Queued { user: [1; 32] };
+    const BOB: Queued = Queued { user: [2; 32] };
+
+    #[test]
+    fn test_insert_remove() {
+        let mut index = UserIndex::<4>::new();
+        let mut queue = RedBlackTree::<u64, Queued, 32>::new();
+        for (key, payload) in [(3, ALICE), (1, BOB), (2, ALICE), (5, ALICE)] {
+            insert(&mut index, &mut queue, key, payload).unwrap();
+        }
+        assert_eq!(keys(&index, &queue, &ALICE.user), [2, 3, 5]);
+        assert_eq!(keys(&index, &queue, &BOB.user), [1]);
+
+        assert_eq!(remove(&mut index, &mut queue, &3), Some(ALICE));
+        assert_eq!(remove(&mut index, &mut queue, &3), None);
+        assert_eq!(remove(&mut index, &mut queue, &1), Some(BOB));
+        assert_eq!(keys(&index, &queue, &ALICE.user), [2, 5]);
+        assert!(addrs(&index, &BOB.user).is_empty());
+        assert_eq!(index.len(), 1);
+        assert!(is_consistent(&index, &queue));
+
+        // Only indexed mutations keep the two in step
+        queue.remove(&5);
+        assert!(!is_consistent(&index, &queue));
+    }
+
+    #[test]
+    fn test_full() {
+        let mut index = UserIndex::<4>::new();
+        let mut queue = RedBlackTree::<u64, Queued, 32>::new();
+        for key in 0..MAX_ENTRIES_PER_USER as u64 {
+            insert(&mut index, &mut queue, key, ALICE).unwrap();
+        }
+        assert_eq!(
+            insert(&mut index, &mut queue, 100, ALICE),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        assert_eq!(
+            insert(&mut index, &mut queue, 0, BOB),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(queue.len(), MAX_ENTRIES_PER_USER);
+
+        for user in 2..5 {
+            let payload = Queued { user: [user; 32] };
+            insert(&mut index, &mut queue, 100 + user as u64, payload).unwrap();
+        }
+        // Every index slot is taken, so the queue insert is rolled back
+        let payload = Queued { user: [9; 32] };
+        assert_eq!(
+            insert(&mut index, &mut queue, 200, payload),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        assert!(!queue.contains(&200));
+        assert!(is_consistent(&index, &queue));
+    }
+}
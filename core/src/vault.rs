@@ -0,0 +1,161 @@
+//! Payment for paid resources, e.g. actions sold by a sync instruction
+//!
+//! A [`Vault`] says what a unit costs and which account the payment goes into, either lamports
+//! through the system program or SPL tokens through the token program. It is [`Pod`] so that
+//! programs can keep it in their state and let the admin reprice. Programs that owe payments
+//! back, e.g. withdrawals of deposited funds, pay them out of a lamport vault they own.
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+use pinocchio_token::state::TokenAccount;
+
+use crate::{error::ApqError, escrow};
+
+/// Paid in lamports
+const NO_MINT: Pubkey = [0; 32];
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Vault {
+    /// Account payments go into, a token account of `mint` when paid in tokens
+    pub address: Pubkey,
+    /// Token paid in, all zeroes for lamports
+    pub mint: Pubkey,
+    /// Per unit, in lamports or the mint's base units. Zero is free.
+    pub price: u64,
+}
+
+crate::impl_layout_hash!(Vault {
+    address: Pubkey,
+    mint: Pubkey,
+    price: u64
+});
+
+impl Vault {
+    pub fn lamports(address: Pubkey, price: u64) -> Self {
+        Vault {
+            address,
+            mint: NO_MINT,
+            price,
+        }
+    }
+
+    pub fn tokens(address: Pubkey, mint: Pubkey, price: u64) -> Self {
+        Vault {
+            address,
+            mint,
+            price,
+        }
+    }
+
+    pub fn is_tokens(&self) -> bool {
+        self.mint != NO_MINT
+    }
+
+    /// Price of `units`
+    pub fn cost(&self, units: u64) -> Result<u64, ProgramError> {
+        self.price
+            .checked_mul(units)
+            .ok_or(ProgramError::ArithmeticOverflow)
+    }
+
+    /// Charges `payer`, who must sign, for `units`
+    ///
+    /// `accounts` are `[vault, system program]` for lamports, and
+    /// `[payer token account, vault, token program]` for tokens. Free vaults need none.
+    pub fn charge(
+        &self,
+        payer: &AccountInfo,
+        accounts: &[AccountInfo],
+        units: u64,
+    ) -> ProgramResult {
+        let cost = self.cost(units)?;
+        if cost == 0 {
+            return Ok(());
+        }
+        if !payer.is_signer() {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if !self.is_tokens() {
+            let [vault, _system_program, ..] = accounts else {
+                return Err(ProgramError::NotEnoughAccountKeys);
+            };
+            self.check_address(vault)?;
+            return pinocchio_system::instructions::Transfer {
+                from: payer,
+                to: vault,
+                lamports: cost,
+            }
+            .invoke();
+        }
+
+        let [from, vault, token_program, ..] = accounts else {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        };
+        self.check_address(vault)?;
+        if *token_program.key() != pinocchio_token::ID {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // The token program checks that the payer may spend `from`, and that its mint matches
+        if *TokenAccount::from_account_info(vault)?.mint() != self.mint {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        pinocchio_token::instructions::Transfer {
+            from,
+            to: vault,
+            authority: payer,
+            amount: cost,
+        }
+        .invoke()
+    }
+
+    /// Pays `recipient` for `units` out of the vault, the inverse of [`Vault::charge`]
+    ///
+    /// `accounts` are `[vault]`, which the program must own to be debited. Free vaults need
+    /// none. Token vaults would need an authority the program signs for, which they don't
+    /// record, so paying out of them is [`ApqError::Unsupported`].
+    pub fn pay_out(
+        &self,
+        accounts: &[AccountInfo],
+        recipient: &AccountInfo,
+        units: u64,
+    ) -> ProgramResult {
+        let cost = self.cost(units)?;
+        if cost == 0 {
+            return Ok(());
+        }
+        if self.is_tokens() {
+            return Err(ApqError::Unsupported.into());
+        }
+        let vault = accounts.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+        self.check_address(vault)?;
+        escrow::pay(vault, recipient, cost)
+    }
+
+    /// Checks that `vault` is the account payments go into
+    pub fn check_address(&self, vault: &AccountInfo) -> ProgramResult {
+        if *vault.key() != self.address {
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost() {
+        let vault = Vault::tokens([1; 32], [2; 32], 3);
+        assert!(vault.is_tokens());
+        assert_eq!(vault.cost(4), Ok(12));
+        assert_eq!(vault.cost(u64::MAX), Err(ProgramError::ArithmeticOverflow));
+        assert!(!Vault::lamports([1; 32], 3).is_tokens());
+        assert_eq!(Vault::default().cost(u64::MAX), Ok(0));
+    }
+}
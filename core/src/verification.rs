@@ -0,0 +1,162 @@
+//! Model checking harnesses for the queue
+//!
+//! Downstream programs escrow funds behind the queue, so its two promises are checked for
+//! every sequence of inserts and removes up to a bound rather than sampled: [`pop_min`] always
+//! returns the smallest key, and the free list never loses or double books a node, so the tree
+//! can always be filled to exactly its capacity. Run the harnesses with `cargo kani -p
+//! apq-core`. The tests check the same properties exhaustively over a smaller domain.
+
+use sokoban::{NodeAllocatorMap, RedBlackTree, SENTINEL};
+
+use crate::{
+    paranoid::check_tree,
+    queue::{peek_min, pop_min},
+};
+
+/// Small enough for the model checker to unwind every loop
+const CAPACITY: usize = 4;
+
+type Tree = RedBlackTree<u64, u64, CAPACITY>;
+
+/// An insert of `key`, or its removal
+#[derive(Copy, Clone, Debug)]
+struct Op {
+    remove: bool,
+    key: u8,
+}
+
+fn apply(ops: &[Op]) -> Tree {
+    let mut tree = Tree::new();
+    for op in ops {
+        let key = op.key as u64;
+        match op.remove {
+            true => {
+                tree.remove(&key);
+            }
+            false => {
+                tree.insert(key, key);
+            }
+        }
+    }
+    tree
+}
+
+/// Popping empties the tree in strictly ascending order, each pop returning what peeking
+/// showed and the smallest key left
+fn check_pop_min(ops: &[Op]) {
+    let mut tree = apply(ops);
+    let mut previous = None;
+    for _ in 0..=CAPACITY {
+        let peeked = peek_min(&tree).map(|(_, node)| *node);
+        let Some(popped) = pop_min(&mut tree) else {
+            assert!(peeked.is_none());
+            assert_eq!(tree.len(), 0);
+            assert_eq!(tree.root, SENTINEL);
+            return;
+        };
+        assert_eq!(peeked.map(|node| node.key), Some(popped.key));
+        assert_eq!(popped.value, popped.key);
+        assert!(previous < Some(popped.key));
+        assert!(tree.iter().all(|(key, _)| *key > popped.key));
+        assert!(tree.get(&popped.key).is_none());
+        previous = Some(popped.key);
+    }
+    panic!("popped more than the capacity");
+}
+
+/// Nodes in use have distinct addresses within the capacity, and the tree takes exactly as
+/// many new keys as it has room for, each at a node not in use
+fn check_free_list(ops: &[Op]) {
+    let mut tree = apply(ops);
+    assert_eq!(check_tree(&tree), Ok(()));
+
+    let mut used = [SENTINEL; CAPACITY];
+    let mut len = 0;
+    for (key, _) in tree.iter() {
+        let addr = tree.get_addr(key);
+        assert!(addr != SENTINEL && addr as usize <= CAPACITY);
+        assert!(!used[..len].contains(&addr));
+        used[len] = addr;
+        len += 1;
+    }
+    assert_eq!(len, tree.len());
+
+    // Keys past any an op can insert
+    for fresh in 0..CAPACITY - len {
+        let addr = tree.insert(u8::MAX as u64 + 1 + fresh as u64, 0);
+        let addr = addr.expect("free node");
+        assert!(addr != SENTINEL && addr as usize <= CAPACITY);
+        assert!(!used[..len + fresh].contains(&addr));
+        used[len + fresh] = addr;
+    }
+    assert_eq!(tree.len(), CAPACITY);
+    assert_eq!(tree.insert(u64::MAX, 0), None);
+    assert_eq!(check_tree(&tree), Ok(()));
+}
+
+#[cfg(kani)]
+mod harnesses {
+    use super::*;
+
+    /// Long enough to fill the tree, empty it and refill it
+    const OPS: usize = 2 * CAPACITY + 1;
+
+    fn any_ops() -> [Op; OPS] {
+        [(); OPS].map(|_| Op {
+            remove: kani::any(),
+            key: kani::any(),
+        })
+    }
+
+    #[kani::proof]
+    #[kani::unwind(12)]
+    fn pop_min_returns_minimum() {
+        let ops = any_ops();
+        let len: usize = kani::any();
+        kani::assume(len <= OPS);
+        check_pop_min(&ops[..len]);
+    }
+
+    #[kani::proof]
+    #[kani::unwind(12)]
+    fn insert_and_remove_keep_free_list() {
+        let ops = any_ops();
+        let len: usize = kani::any();
+        kani::assume(len <= OPS);
+        check_free_list(&ops[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every sequence of up to `max` ops on keys below `keys`
+    fn sequences(max: usize, keys: u8, check: impl Fn(&[Op])) {
+        let mut ops = vec![];
+        fn extend(ops: &mut Vec<Op>, max: usize, keys: u8, check: &impl Fn(&[Op])) {
+            check(ops);
+            if ops.len() == max {
+                return;
+            }
+            for remove in [false, true] {
+                for key in 0..keys {
+                    ops.push(Op { remove, key });
+                    extend(ops, max, keys, check);
+                    ops.pop();
+                }
+            }
+        }
+        extend(&mut ops, max, keys, &check);
+    }
+
+    #[test]
+    fn test_pop_min_returns_minimum() {
+        sequences(5, CAPACITY as u8 + 1, check_pop_min);
+    }
+
+    #[test]
+    fn test_insert_and_remove_keep_free_list() {
+        sequences(5, CAPACITY as u8 + 1, check_free_list);
+    }
+}
@@ -0,0 +1,104 @@
+//! Read-only queue introspection
+//!
+//! The view tag writes a window of the queue, in drain order, to the transaction's return
+//! data, so clients and keepers can simulate it to see pending work instead of walking the
+//! tree off-chain. The instruction data is an optional `start: u32` and `count: u32`, and the
+//! return data is an entry count `n: u32` followed by `n` `(key, payload)` pairs. Windows that
+//! don't fit in the return data are cut short, so page with `start += n` until `n` is zero.
+
+use pinocchio::{
+    cpi::{set_return_data, MAX_RETURN_DATA},
+    program_error::ProgramError,
+    ProgramResult,
+};
+
+use crate::{
+    queue::{Payload, QueueKey},
+    AsyncState,
+};
+
+/// Handles the view instruction
+pub fn process<S: AsyncState>(state: &S, data: &[u8]) -> ProgramResult {
+    let arg = |at: usize| {
+        data.get(at..at + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    };
+    let start = arg(0).unwrap_or(0);
+    let count = arg(4).unwrap_or(usize::MAX);
+
+    set_return_data(&encode(state.entries().skip(start).take(count)));
+    Ok(())
+}
+
+/// Encodes as many of `entries` as fit in the return data
+pub fn encode<'a, K: QueueKey, V: Payload>(
+    entries: impl Iterator<Item = (&'a K, &'a V)>,
+) -> Vec<u8> {
+    let entry_len = size_of::<K>() + size_of::<V>();
+    let mut encoded = Vec::with_capacity(MAX_RETURN_DATA);
+    encoded.extend_from_slice(&0_u32.to_le_bytes());
+
+    let mut n = 0_u32;
+    for (key, value) in entries {
+        if encoded.len() + entry_len > MAX_RETURN_DATA {
+            break;
+        }
+        encoded.extend_from_slice(bytemuck::bytes_of(key));
+        encoded.extend_from_slice(bytemuck::bytes_of(value));
+        n += 1;
+    }
+    encoded[..4].copy_from_slice(&n.to_le_bytes());
+    encoded
+}
+
+/// Decodes the return data of the view instruction
+pub fn decode<K: QueueKey, V: Payload>(data: &[u8]) -> Result<Vec<(K, V)>, ProgramError> {
+    let (n, entries) = data
+        .split_first_chunk::<4>()
+        .ok_or(ProgramError::InvalidAccountData)?;
+    let entry_len = size_of::<K>() + size_of::<V>();
+    if entries.len() != u32::from_le_bytes(*n) as usize * entry_len {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(entries
+        .chunks_exact(entry_len)
+        .map(|entry| {
+            let (key, value) = entry.split_at(size_of::<K>());
+            (
+                bytemuck::pod_read_unaligned(key),
+                bytemuck::pod_read_unaligned(value),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+    use super::*;
+
+    #[test]
+    fn test_encode_pages() {
+        let mut tree = RedBlackTree::<u64, [u64; 7], 128>::new();
+        for key in (0..100).rev() {
+            tree.insert(key, [key; 7]);
+        }
+
+        // 64 bytes per entry, so 15 fit after the count
+        let page = decode::<u64, [u64; 7]>(&encode(tree.iter())).unwrap();
+        assert_eq!(page.len(), 15);
+        assert_eq!(page[0], (0, [0; 7]));
+        assert_eq!(page[14], (14, [14; 7]));
+
+        let page = decode::<u64, [u64; 7]>(&encode(tree.iter().skip(95).take(3))).unwrap();
+        let keys: Vec<u64> = page.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, [95, 96, 97]);
+
+        assert_eq!(
+            decode::<u64, [u64; 7]>(&encode(tree.iter())[..100]),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+}
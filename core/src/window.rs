@@ -0,0 +1,233 @@
+//! Drain windows closing each slot's auction
+//!
+//! Batch auctions settle the entries of one slot together, and are only fair if an entry
+//! misses its batch for good when no drain settles it in time, rather than landing in a later
+//! batch or whenever a crank gets to it. States with a [`Config`](crate::config::Config)
+//! account can set [`ConfigParams::drain_window`](crate::config::ConfigParams::drain_window):
+//! the entries queued in a slot can then only be drained in the window of that many slots
+//! once they are due, e.g. slots `S+1..=S+5` for entries queued in `S` with a one slot delay
+//! and a window of five.
+//!
+//! Only states with [`AsyncState::DRAIN_WINDOW`] can set one. Once the window closed the
+//! entries expire: every drain first removes those of closed slots through
+//! [`AsyncState::expire_entry`], so [`AsyncState::has_pending_async`] and the drain after it
+//! only ever see open slots. Keys sort by slot first, so those are at the head of the queue
+//! and a drain only looks at the entries it expires, at most [`MAX_EXPIRED_PER_DRAIN`]. A drain
+//! that leaves closed entries behind processes nothing, and the next one expires more. Like
+//! [`compact`](crate::compact), their escrow is forfeited but for the crank bounty, which pays
+//! the drain.
+
+use pinocchio::{program_error::ProgramError, ProgramResult};
+
+use crate::{error::ApqError, ordering::OrderingKey, AsyncState};
+
+/// Most entries one drain expires, so that a long stalled queue is expired over several
+/// drains instead of one too many for a transaction's compute
+pub const MAX_EXPIRED_PER_DRAIN: u64 = 64;
+
+/// Whether the window of slots entries queued in `slot` can be drained in has closed for a
+/// drain at `due`, `delay_slots` after they were queued. A zero `window` never closes.
+pub fn is_closed(slot: u64, due: u64, delay_slots: u64, window: u64) -> bool {
+    window != 0 && slot.saturating_add(delay_slots).saturating_add(window) <= due
+}
+
+/// Fails with [`ApqError::Unsupported`] if a config sets a `window` for a state without
+/// [`AsyncState::DRAIN_WINDOW`]
+pub fn check_window<S: AsyncState>(window: u32) -> ProgramResult {
+    if window != 0 && !S::DRAIN_WINDOW {
+        return Err(ApqError::Unsupported.into());
+    }
+    Ok(())
+}
+
+/// Whether the next entry's window closed at `due`
+pub fn is_head_closed<S: AsyncState>(state: &S, due: u64, window: u64) -> bool {
+    state
+        .peek_entry()
+        .is_some_and(|entry| is_closed(entry.key.slot(), due, S::ASYNC_DELAY_SLOTS, window))
+}
+
+/// Removes up to `max` entries from the head of the queue whose window closed at `due`,
+/// returning how many
+pub fn expire_closed<S: AsyncState>(
+    state: &mut S,
+    due: u64,
+    window: u64,
+    max: u64,
+) -> Result<u64, ProgramError> {
+    let mut expired = 0;
+    while expired < max {
+        let Some(key) = state.peek_entry().map(|entry| entry.key) else {
+            break;
+        };
+        if !is_closed(key.slot(), due, S::ASYNC_DELAY_SLOTS, window) {
+            break;
+        }
+        state.expire_entry(&key)?;
+        expired += 1;
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use pinocchio::account_info::AccountInfo;
+    use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+    use super::*;
+    use crate::{
+        error,
+        ordering::FifoKey,
+        queue::{peek_min, pop_min, Entry},
+        AsyncIx, FromBytes, SyncIx,
+    };
+
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    struct Noop;
+
+    impl FromBytes for Noop {
+        type Target<'a> = Noop;
+        type TargetMut<'a> = Noop;
+        fn from_bytes(_bytes: &[u8]) -> Result<Noop, ProgramError> {
+            Ok(Noop)
+        }
+        fn from_bytes_mut(_bytes: &mut [u8]) -> Result<Noop, ProgramError> {
+            Ok(Noop)
+        }
+    }
+
+    impl SyncIx for Noop {
+        fn process<S: AsyncState>(&self, _: &[u8], _: &[AccountInfo], _: &mut S) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    impl AsyncIx for Noop {
+        type Args = ();
+        fn process<S: AsyncState>(&self, _args: &(), _state: &mut S) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    type Tree = RedBlackTree<FifoKey, u64, 8>;
+
+    /// Entries hold the slot they were queued in, with or without drain windows
+    struct Mock<const WINDOW: bool> {
+        queue: Box<Tree>,
+        expired: Vec<u64>,
+    }
+
+    impl<const WINDOW: bool> FromBytes for Mock<WINDOW> {
+        type Target<'a> = &'a Self;
+        type TargetMut<'a> = &'a mut Self;
+        fn from_bytes(_bytes: &[u8]) -> Result<&Self, ProgramError> {
+            Err(ProgramError::InvalidAccountData)
+        }
+        fn from_bytes_mut(_bytes: &mut [u8]) -> Result<&mut Self, ProgramError> {
+            Err(ProgramError::InvalidAccountData)
+        }
+    }
+
+    impl<const WINDOW: bool> AsyncState for Mock<WINDOW> {
+        type SyncIx = Noop;
+        type AsyncIx = Noop;
+        type Payload = u64;
+        type QueueArgs = ();
+        type Key = FifoKey;
+
+        const ASYNC_DELAY_SLOTS: u64 = 1;
+        const DRAIN_WINDOW: bool = WINDOW;
+
+        fn initialize(&mut self) {}
+
+        fn queue_args(_user: &AccountInfo, _data: &[u8]) -> Result<(), ProgramError> {
+            Ok(())
+        }
+
+        fn queue_async(&mut self, _ix: &Noop, _args: &(), _now: u64) -> ProgramResult {
+            Ok(())
+        }
+
+        fn expire_entry(&mut self, key: &FifoKey) -> ProgramResult {
+            self.queue
+                .remove(key)
+                .ok_or(ProgramError::InvalidArgument)?;
+            self.expired.push(key.seq);
+            Ok(())
+        }
+
+        fn process_next_async(&mut self) -> ProgramResult {
+            Ok(())
+        }
+
+        fn has_pending_async(&self, _now: u64) -> bool {
+            false
+        }
+
+        fn entries(&self) -> impl Iterator<Item = (&FifoKey, &u64)> {
+            self.queue.iter()
+        }
+
+        fn peek_entry(&self) -> Option<&Entry<FifoKey, u64>> {
+            peek_min(&self.queue).map(|(_addr, node)| node)
+        }
+
+        fn pop_entry(&mut self) -> Option<Entry<FifoKey, u64>> {
+            pop_min(&mut self.queue)
+        }
+
+        fn process_entry(&mut self, _entry: &Entry<FifoKey, u64>) -> ProgramResult {
+            Ok(())
+        }
+    }
+
+    /// Two entries queued in each of slots 10 through 12
+    fn mock() -> Mock<true> {
+        let mut queue: Box<Tree> = bytemuck::zeroed_box();
+        queue.initialize();
+        for seq in 0..6 {
+            let slot = 10 + seq / 2;
+            queue.insert(FifoKey { slot, seq }, slot);
+        }
+        Mock {
+            queue,
+            expired: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_expire_closed() {
+        let mut state = mock();
+        // No window, or none closed yet: slot 10's closes at 16 with a window of five
+        assert_eq!(expire_closed(&mut state, 100, 0, 8), Ok(0));
+        assert_eq!(expire_closed(&mut state, 15, 5, 8), Ok(0));
+        assert!(!is_head_closed(&state, 15, 5));
+
+        // At most `max` at once, leaving the closed head for the next drain
+        assert_eq!(expire_closed(&mut state, 17, 5, 3), Ok(3));
+        assert!(is_head_closed(&state, 17, 5));
+        assert_eq!(expire_closed(&mut state, 17, 5, 3), Ok(1));
+        assert!(!is_head_closed(&state, 17, 5));
+        assert_eq!(state.expired, [0, 1, 2, 3]);
+        assert_eq!(state.queue.len(), 2);
+    }
+
+    #[test]
+    fn test_check_window() {
+        assert_eq!(check_window::<Mock<true>>(5), Ok(()));
+        assert_eq!(check_window::<Mock<false>>(0), Ok(()));
+        assert_eq!(
+            check_window::<Mock<false>>(5),
+            Err(ProgramError::Custom(error::UNSUPPORTED))
+        );
+    }
+
+    #[test]
+    fn test_is_closed() {
+        // Queued in slot 10 with a one slot delay, drainable in 11 through 15
+        let open: Vec<u64> = (0..20).filter(|due| !is_closed(10, *due, 1, 5)).collect();
+        assert_eq!(open, (0..16).collect::<Vec<_>>());
+        assert!(!is_closed(10, u64::MAX, 1, 0));
+        assert!(!is_closed(u64::MAX, u64::MAX - 1, 1, 5));
+    }
+}
@@ -1,17 +1,11 @@
 use std::array::from_ref;
-use std::path::Path;
 
-use counter::{CounterAsyncIx, CounterState};
-use litesvm::LiteSVM;
-use sokoban::NodeAllocatorMap;
-use solana_instruction::{AccountMeta, Instruction};
-use solana_keypair::Keypair;
-use solana_program::clock::Clock;
-use solana_program::message::Message;
-use solana_program::system_instruction;
+use apq_client::queue;
+use apq_core::AsyncState;
+use apq_testkit::TestKit;
+use counter::{client::CounterClient, CounterAsyncIx, CounterState};
+use solana_instruction::Instruction;
 use solana_pubkey::Pubkey;
-use solana_signer::Signer;
-use solana_transaction::Transaction;
 
 // Counter program ID
 const COUNTER_PROGRAM_ID: Pubkey =
@@ -21,46 +15,19 @@ fn main() {
     println!("=== Advanced Async/Sync Counter Demo ===\n");
     println!("NOTE: Each operation uses a unique user to simulate real-world usage\n");
 
-    let mut svm = LiteSVM::new()
-        .with_blockhash_check(false)
-        .with_sigverify(false)
-        .with_transaction_history(50);
-
     // Load program
-    let path = Path::new("../target/deploy/counter.so");
+    let path = apq_testkit::deploy_path("counter");
     println!(
         "Loading program from: {} (exists: {})",
         path.display(),
         path.exists()
     );
-    svm.add_program_from_file(COUNTER_PROGRAM_ID, path).unwrap();
-    let svm = &mut svm;
-
-    let payer = Pubkey::new_unique();
-    svm.airdrop(&payer, 10_000_000_000).unwrap();
-
-    let state_account = Keypair::new();
+    let kit = &mut TestKit::new(COUNTER_PROGRAM_ID, &path).unwrap();
 
-    // Calculate actual state size
-    let state_size = std::mem::size_of::<CounterState>();
-    println!("State size: {} bytes", state_size);
-
-    // Create state account
-    let create_ix = system_instruction::create_account(
-        &payer,
-        &state_account.pubkey(),
-        svm.minimum_balance_for_rent_exemption(state_size),
-        state_size as u64,
-        &COUNTER_PROGRAM_ID,
-    );
-
-    execute(
-        svm,
-        &payer,
-        from_ref(&create_ix),
-        &[state_account.pubkey()],
-        "Create state account",
-    );
+    // Create state account, sized from the state type
+    println!("State size: {} bytes", CounterState::LEN);
+    let state_account = kit.create_state::<CounterState>();
+    let client = CounterClient::new(COUNTER_PROGRAM_ID, state_account);
 
     // Create multiple users with names
     let users = vec![
@@ -86,67 +53,67 @@ fn main() {
         ),
     ];
 
-    // Alice refills many actions for everyone
-    for _ in 0..100 {
-        let refill_ix = create_sync_instruction(&state_account.pubkey(), &users[0].1, 0);
-        execute(svm, &payer, from_ref(&refill_ix), &[], "");
+    // Users pay the crank bounty when queueing
+    for (_, user) in &users {
+        kit.svm.airdrop(user, 1_000_000_000).unwrap();
+    }
+
+    // Each user refills their own actions
+    for (_, user) in &users {
+        let refill_ix = client.refill_actions(user, &[]);
+        execute(kit, from_ref(&refill_ix), "");
     }
 
     // Show all users
     println!("\nUsers participating:");
     for (name, user) in &users {
-        println!("  {} -> {}", name, short_pubkey(&user));
+        println!("  {} -> {}", name, short_pubkey(user));
     }
 
     // Queue operations from different users with a story
     println!("\nUsers queuing operations:");
 
     // Alice increments
-    let ix = create_async_instruction(&state_account.pubkey(), &users[0].1, 1);
-    execute(svm, &payer, from_ref(&ix), &[], "Alice queues increment");
+    let ix = client.increment(&users[0].1, 1, 0, &Pubkey::default());
+    execute(kit, from_ref(&ix), "Alice queues increment");
 
     // Bob decrements
-    let ix = create_async_instruction(&state_account.pubkey(), &users[1].1, 0);
-    execute(svm, &payer, from_ref(&ix), &[], "Bob queues decrement");
+    let ix = client.decrement(&users[1].1, 1, 0, &Pubkey::default());
+    execute(kit, from_ref(&ix), "Bob queues decrement");
 
     // Carol increments
-    let ix = create_async_instruction(&state_account.pubkey(), &users[2].1, 1);
-    execute(svm, &payer, from_ref(&ix), &[], "Carol queues increment");
+    let ix = client.increment(&users[2].1, 1, 0, &Pubkey::default());
+    execute(kit, from_ref(&ix), "Carol queues increment");
 
     // Dave decrements
-    let ix = create_async_instruction(&state_account.pubkey(), &users[3].1, 0);
-    execute(svm, &payer, from_ref(&ix), &[], "Dave queues decrement");
+    let ix = client.decrement(&users[3].1, 1, 0, &Pubkey::default());
+    execute(kit, from_ref(&ix), "Dave queues decrement");
 
     // Eve increments
-    let ix = create_async_instruction(&state_account.pubkey(), &users[4].1, 1);
-    execute(svm, &payer, from_ref(&ix), &[], "Eve queues increment");
+    let ix = client.increment(&users[4].1, 5, 0, &Pubkey::default());
+    execute(kit, from_ref(&ix), "Eve queues increment by 5");
 
-    print_detailed_state(
-        svm,
-        &state_account.pubkey(),
-        "After 5 users queue operations",
-    );
+    print_detailed_state(kit, &state_account, "After 5 users queue operations");
 
     // Process with a different user (system operator)
-    svm.warp_to_slot(get_current_slot(&svm) + 3);
-    let operator = Keypair::new();
+    kit.warp_slots(3);
+    let operator_funds = 1_000_000_000;
+    let operator = kit.user(operator_funds);
     println!(
         "\nSystem operator ({}) processing queue",
-        short_pubkey(&operator.pubkey())
+        short_pubkey(&operator)
     );
-    let process_ix = create_process_async_instruction(&state_account.pubkey(), &operator.pubkey());
-    execute(
-        svm,
-        &payer,
-        from_ref(&process_ix),
-        &[],
-        "Operator processes queue",
+    let process_ix = client.drain(&operator);
+    execute(kit, from_ref(&process_ix), "Operator processes queue");
+    println!(
+        "   Operator earned {} lamports in crank bounties",
+        kit.svm.get_balance(&operator).unwrap() - operator_funds
     );
 
     print_detailed_state(
-        &svm,
-        &state_account.pubkey(),
-        "After processing (Bob and Dave's decrements should execute first)",
+        kit,
+        &state_account,
+        "After processing (Bob and Dave's decrements run first and are dead-lettered)",
     );
 
     // Show some users doing more operations
@@ -158,26 +125,27 @@ fn main() {
     println!("\nNew users join:");
     println!("  Frank -> {}", short_pubkey(&frank));
     println!("  Grace -> {}", short_pubkey(&grace));
+    for user in [frank, grace] {
+        kit.svm.airdrop(&user, 1_000_000_000).unwrap();
+        let refill_ix = client.refill_actions(&user, &[]);
+        execute(kit, from_ref(&refill_ix), "");
+    }
 
-    let ix = create_async_instruction(&state_account.pubkey(), &frank, 0);
-    execute(svm, &payer, from_ref(&ix), &[], "Frank queues decrement");
+    let ix = client.decrement(&frank, 1, 0, &Pubkey::default());
+    execute(kit, from_ref(&ix), "Frank queues decrement");
 
-    let ix = create_async_instruction(&state_account.pubkey(), &grace, 1);
-    execute(svm, &payer, from_ref(&ix), &[], "Grace queues increment");
+    let ix = client.increment(&grace, 1, 0, &Pubkey::default());
+    execute(kit, from_ref(&ix), "Grace queues increment");
 
     print_detailed_state(
-        svm,
-        &state_account.pubkey(),
+        kit,
+        &state_account,
         "After new users join and queue operations",
     );
 
     // Final summary
     println!("\n=== Demo Complete ===");
-    print_detailed_state(&svm, &state_account.pubkey(), "Final program state");
-}
-
-fn get_current_slot(svm: &LiteSVM) -> u64 {
-    svm.get_sysvar::<Clock>().slot
+    print_detailed_state(kit, &state_account, "Final program state");
 }
 
 // Generate a short identifier for a pubkey (first 8 chars)
@@ -185,100 +153,52 @@ fn short_pubkey(pubkey: &Pubkey) -> String {
     pubkey.to_string()[..8].to_string()
 }
 
-fn create_sync_instruction(state_account: &Pubkey, user: &Pubkey, sync_ix: u64) -> Instruction {
-    let mut data = vec![0u8]; // 0 = sync instruction
-    data.extend_from_slice(&sync_ix.to_le_bytes());
-
-    Instruction {
-        program_id: COUNTER_PROGRAM_ID,
-        accounts: vec![
-            AccountMeta::new(*state_account, false),
-            AccountMeta::new_readonly(*user, false),
-        ],
-        data,
-    }
-}
-
-fn create_async_instruction(state_account: &Pubkey, user: &Pubkey, async_ix: u64) -> Instruction {
-    let mut data = vec![1u8]; // 1 = async instruction
-    data.extend_from_slice(&async_ix.to_le_bytes());
-
-    Instruction {
-        program_id: COUNTER_PROGRAM_ID,
-        accounts: vec![
-            AccountMeta::new(*state_account, false),
-            AccountMeta::new_readonly(*user, false),
-        ],
-        data,
-    }
-}
-
-fn create_process_async_instruction(state_account: &Pubkey, user: &Pubkey) -> Instruction {
-    Instruction {
-        program_id: COUNTER_PROGRAM_ID,
-        accounts: vec![
-            AccountMeta::new(*state_account, false),
-            AccountMeta::new_readonly(*user, false),
-        ],
-        data: vec![2u8], // 2 = process async
-    }
-}
-
 #[track_caller]
-fn execute(
-    svm: &mut LiteSVM,
-    &payer: &Pubkey,
-    instructions: &[Instruction],
-    additional_signers: &[Pubkey],
-    description: &str,
-) {
-    if description != "" {
+fn execute(kit: &mut TestKit, instructions: &[Instruction], description: &str) {
+    if !description.is_empty() {
         println!("\n>> {}", description);
     }
 
-    let mut signers = vec![payer];
-    signers.extend_from_slice(additional_signers);
-
-    let message = Message::new(instructions, Some(&payer));
-    let tx = Transaction::new_unsigned(message);
-
-    match svm.send_transaction(tx) {
-        Ok(res) => {
-            if !res.logs.is_empty() && description != "" {
-                println!("   Logs:");
-                for log in &res.logs {
-                    // Print all logs for debugging
-                    println!("     {}", log);
-                }
-            }
-        }
-        Err(e) => {
-            println!("called from {}", std::panic::Location::caller());
-            panic!("   ERROR: {:?}", e);
+    let res = kit.send_ok(instructions);
+    if !res.logs.is_empty() && !description.is_empty() {
+        println!("   Logs:");
+        for log in &res.logs {
+            // Print all logs for debugging
+            println!("     {}", log);
         }
     }
 }
 
-fn print_detailed_state(svm: &LiteSVM, state_account: &Pubkey, context: &str) {
+fn print_detailed_state(kit: &TestKit, state_account: &Pubkey, context: &str) {
     println!("\n[State: {}]", context);
 
-    if let Some(account) = svm.get_account(state_account) {
-        let state: &CounterState = bytemuck::from_bytes(&account.data);
+    let state = match kit.try_state::<CounterState>(state_account) {
+        Ok(state) => state,
+        Err(err) => panic!("  Invalid state: {err}"),
+    };
 
-        println!("  Sequence: {}", state.seq);
-        println!("  Num Actions: {}", state.num_actions);
-        println!("  Counter: {}", state.counter);
-
-        let queue = state.async_queue.iter();
-        println!("  Queued instructions:");
-        for (i, ixn) in queue.enumerate() {
-            let ixn_type: CounterAsyncIx = unsafe { core::mem::transmute(ixn.0.ixn_value) };
-            let user: Pubkey = Pubkey::new_from_array(*ixn.1);
-            let seq = ixn.0.seq;
-            let slot = ixn.0.slot;
-            println!("   {i:>3}: {ixn_type:?}; seq {seq} in slot {slot}; {user}");
-        }
-    } else {
-        panic!("  Account not found!");
+    println!("  Version: {}", state.header.version);
+    println!("  Sequence: {}", state.seq);
+    println!(
+        "  Users with actions: {}",
+        queue::entries(&state.balances).count()
+    );
+    println!("  Counter: {}", state.counter);
+
+    println!("  Queued instructions:");
+    for (i, entry) in queue::entries(&state.async_queue).enumerate() {
+        let (key, payload) = match entry {
+            Ok(entry) => entry,
+            Err(err) => panic!("  Invalid queue: {err}"),
+        };
+        let ixn_type = CounterAsyncIx::from_u64(key.ixn_value);
+        let user: Pubkey = Pubkey::new_from_array(payload.user);
+        let amount = payload.args().map(|args| args.amount).unwrap_or_default();
+        let bid = u64::MAX - key.bid_rank;
+        let seq = key.seq;
+        let slot = key.slot;
+        println!(
+            "   {i:>3}: {ixn_type:?} by {amount}; seq {seq} in slot {slot}; bid {bid}; {user}"
+        );
     }
 }
@@ -0,0 +1,81 @@
+//! Follows the lamports escrowed with queued actions through LiteSVM: refunded on cancel,
+//! kept on execution and collected into the vault
+//!
+//! Build the program with `cargo build-sbf` first.
+
+use std::array::from_ref;
+
+use apq_client::queue;
+use apq_core::{vault::Vault, AsyncState};
+use apq_testkit::TestKit;
+use counter::{client::CounterClient, CounterState};
+use solana_pubkey::Pubkey;
+
+const COUNTER_PROGRAM_ID: Pubkey =
+    solana_pubkey::pubkey!("CounterProgram111111111111111111111111111111");
+
+const ESCROW: u64 = CounterState::CRANK_BOUNTY + CounterState::DEPOSIT;
+
+fn main() {
+    let path = apq_testkit::deploy_path("counter");
+    let kit = &mut TestKit::new(COUNTER_PROGRAM_ID, &path).unwrap();
+    let state = kit.create_state::<CounterState>();
+    let client = CounterClient::new(COUNTER_PROGRAM_ID, state);
+
+    // The first instruction initializes the state and makes its signer the admin
+    let admin = kit.user(1_000_000_000);
+    let vault = kit.user(1_000_000);
+    kit.send_ok(&[
+        client.refill_actions(&admin, &[]),
+        client.set_vault(&admin, Vault::lamports(vault.to_bytes(), 0), &[]),
+    ]);
+    let rent = lamports(kit, &state);
+
+    // Alice queues two actions, one with a priority bid
+    let alice = kit.user(1_000_000_000);
+    let funds = lamports(kit, &alice);
+    kit.send_ok(&[
+        client.refill_actions(&alice, &[]),
+        client.refill_actions(&alice, &[]),
+        client.increment(&alice, 1, 0, &Pubkey::default()),
+        client.increment(&alice, 1, 7, &Pubkey::default()),
+    ]);
+    assert_eq!(lamports(kit, &state), rent + 2 * ESCROW + 7);
+    assert_eq!(lamports(kit, &alice), funds - 2 * ESCROW - 7);
+    println!("Alice escrowed {} lamports", 2 * ESCROW + 7);
+
+    // Cancelling the bid refunds all of its escrow
+    let decoded = kit.state::<CounterState>(&state);
+    let key = queue::entries(&decoded.async_queue)
+        .map(|entry| *entry.unwrap().0)
+        .find(|key| u64::MAX - key.bid_rank == 7)
+        .unwrap();
+    kit.send_ok(from_ref(&client.cancel(&alice, &key)));
+    assert_eq!(lamports(kit, &alice), funds - ESCROW);
+    println!("Cancelling refunded {} lamports", ESCROW + 7);
+
+    // Draining pays the bounty and keeps the deposit
+    kit.warp_slots(2);
+    let cranker = kit.user(1_000_000);
+    kit.send_ok(from_ref(&client.drain(&cranker)));
+    assert_eq!(
+        lamports(kit, &cranker),
+        1_000_000 + CounterState::CRANK_BOUNTY
+    );
+    kit.assert_state::<CounterState>(&state, |decoded| {
+        decoded.counter == 1 && decoded.deposits == CounterState::DEPOSIT
+    });
+    println!("Draining kept a {} lamport deposit", CounterState::DEPOSIT);
+
+    // Which the admin collects into the vault
+    let collect = client.collect_deposits(&admin, &vault, &[]);
+    kit.send_ok(from_ref(&collect));
+    assert_eq!(lamports(kit, &vault), 1_000_000 + CounterState::DEPOSIT);
+    assert_eq!(lamports(kit, &state), rent);
+    kit.assert_state::<CounterState>(&state, |decoded| decoded.deposits == 0);
+    println!("Collected the deposit into the vault");
+}
+
+fn lamports(kit: &TestKit, account: &Pubkey) -> u64 {
+    kit.svm.get_balance(account).unwrap_or_default()
+}
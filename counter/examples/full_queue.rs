@@ -0,0 +1,93 @@
+//! Fills the counter's queue to all 8192 entries through LiteSVM, recording what queueing
+//! costs as the tree deepens, then drains it across many transactions
+//!
+//! Build the program with `cargo build-sbf` first.
+
+use std::array::from_ref;
+
+use apq_client::queue;
+use apq_core::{cursor::DrainArgs, AsyncState};
+use apq_testkit::{assert_cu, TestKit};
+use counter::{client::CounterClient, CounterState};
+use solana_instruction::error::InstructionError;
+use solana_pubkey::Pubkey;
+use solana_transaction_error::TransactionError;
+
+const COUNTER_PROGRAM_ID: Pubkey =
+    solana_pubkey::pubkey!("CounterProgram111111111111111111111111111111");
+
+/// Entries the counter's queue holds
+const CAPACITY: usize = 8192;
+
+/// Entries each drain transaction processes
+const DRAIN_BATCH: u32 = 32;
+
+fn main() {
+    let path = apq_testkit::deploy_path("counter");
+    let kit = &mut TestKit::new(COUNTER_PROGRAM_ID, &path).unwrap();
+    let state = kit.create_state::<CounterState>();
+    let client = CounterClient::new(COUNTER_PROGRAM_ID, state);
+    let user = kit.user(1_000_000_000_000);
+    let cranker = kit.user(1_000_000);
+
+    // Compute units of the enqueue that brought the queue to each power of two
+    println!("{:>8} {:>10}", "entries", "enqueue CU");
+    for queued in 1..=CAPACITY {
+        kit.send_ok(from_ref(&client.refill_actions(&user, &[])));
+        // Distinct bids so the tree isn't only ever appended to on the right
+        let bid = (queued as u64 * 7_919) % 1_000;
+        let enqueue = kit.send_ok(from_ref(&client.increment(
+            &user,
+            1,
+            bid,
+            &Pubkey::default(),
+        )));
+        // The tree stays balanced, so the cost grows with its depth, not its size
+        assert_cu!(enqueue <= 30_000);
+        if queued.is_power_of_two() {
+            println!("{queued:>8} {:>10}", enqueue.compute_units_consumed);
+        }
+    }
+    assert_queued(kit, &state, CAPACITY);
+
+    // A full queue rejects the next entry and keeps the action it would have spent
+    kit.send_ok(from_ref(&client.refill_actions(&user, &[])));
+    let failed = kit
+        .send(from_ref(&client.increment(&user, 1, 0, &Pubkey::default())))
+        .expect_err("queued into a full queue");
+    assert_eq!(
+        failed.err,
+        TransactionError::InstructionError(0, InstructionError::AccountDataTooSmall)
+    );
+    assert_queued(kit, &state, CAPACITY);
+    kit.assert_state::<CounterState>(&state, |decoded| decoded.actions(&user.to_bytes()) == 1);
+    println!("The full queue rejected entry {}", CAPACITY + 1);
+
+    // Drains it in batches once everything is due
+    kit.warp_slots(CounterState::ASYNC_DELAY_SLOTS.max(CounterState::MIN_DRAIN_AGE_SLOTS));
+    let args = DrainArgs {
+        max_items: DRAIN_BATCH,
+        nonce: None,
+    };
+    let mut transactions = 0;
+    let mut most = 0;
+    while queue::entries(&kit.state::<CounterState>(&state).async_queue)
+        .next()
+        .is_some()
+    {
+        let drain = kit.send_ok(from_ref(&client.drain_with(&cranker, args)));
+        assert_cu!(drain <= u64::from(DRAIN_BATCH) * 15_000);
+        transactions += 1;
+        most = most.max(drain.compute_units_consumed);
+    }
+    assert_eq!(transactions, CAPACITY.div_ceil(DRAIN_BATCH as usize));
+    kit.assert_state::<CounterState>(&state, |decoded| decoded.counter == CAPACITY as u64);
+    println!("Drained {CAPACITY} entries in {transactions} transactions, at most {most} CU each");
+}
+
+#[track_caller]
+fn assert_queued(kit: &TestKit, state: &Pubkey, queued: usize) {
+    kit.assert_state::<CounterState>(state, |decoded| {
+        queue::entries(&decoded.async_queue).count() == queued
+    });
+}
@@ -0,0 +1,10 @@
+//! Prints the counter's IDL, e.g. `cargo run --example idl > counter.json`
+
+use solana_pubkey::Pubkey;
+
+const COUNTER_PROGRAM_ID: Pubkey =
+    solana_pubkey::pubkey!("CounterProgram111111111111111111111111111111");
+
+fn main() {
+    println!("{:#}", counter::idl::idl(COUNTER_PROGRAM_ID));
+}
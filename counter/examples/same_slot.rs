@@ -0,0 +1,73 @@
+//! Interleaves enqueues and drains in one slot through LiteSVM: whichever lands first, an
+//! entry is never drained in the slot it was queued
+//!
+//! Build the program with `cargo build-sbf` first.
+
+use std::array::from_ref;
+
+use apq_client::queue;
+use apq_core::AsyncState;
+use apq_testkit::TestKit;
+use counter::{client::CounterClient, CounterState};
+use solana_pubkey::Pubkey;
+
+const COUNTER_PROGRAM_ID: Pubkey =
+    solana_pubkey::pubkey!("CounterProgram111111111111111111111111111111");
+
+fn main() {
+    let path = apq_testkit::deploy_path("counter");
+    let kit = &mut TestKit::new(COUNTER_PROGRAM_ID, &path).unwrap();
+    let state = kit.create_state::<CounterState>();
+    let client = CounterClient::new(COUNTER_PROGRAM_ID, state);
+    let cranker = kit.user(1_000_000);
+
+    let [alice, bob, carol] = [(); 3].map(|_| kit.user(1_000_000_000));
+    for user in [alice, bob, carol] {
+        kit.send_ok(from_ref(&client.refill_actions(&user, &[])));
+    }
+
+    // Alice's increment is due once the delay has passed
+    kit.send_ok(from_ref(&client.increment(
+        &alice,
+        1,
+        0,
+        &Pubkey::default(),
+    )));
+    kit.warp_slots(CounterState::ASYNC_DELAY_SLOTS.max(CounterState::MIN_DRAIN_AGE_SLOTS));
+
+    // Bob queues ahead of the drain in its slot, and only Alice's is drained
+    kit.send_ok(from_ref(&client.increment(&bob, 10, 0, &Pubkey::default())));
+    kit.send_ok(from_ref(&client.drain(&cranker)));
+    assert_counter(kit, &state, 1, 1);
+
+    // Carol queues after the drain in the same slot, which leaves her entry queued too
+    kit.send_ok(from_ref(&client.drain(&cranker)));
+    kit.send_ok(from_ref(&client.increment(
+        &carol,
+        100,
+        0,
+        &Pubkey::default(),
+    )));
+    assert_counter(kit, &state, 1, 2);
+
+    // As does a drain in the same transaction
+    kit.send_ok(&[
+        client.increment(&alice, 1_000, 0, &Pubkey::default()),
+        client.drain(&cranker),
+    ]);
+    assert_counter(kit, &state, 1, 3);
+    println!("Nothing queued in slot {} was drained in it", kit.slot());
+
+    // A later slot drains all of them
+    kit.warp_slots(CounterState::ASYNC_DELAY_SLOTS.max(CounterState::MIN_DRAIN_AGE_SLOTS));
+    kit.send_ok(from_ref(&client.drain(&cranker)));
+    assert_counter(kit, &state, 1_111, 0);
+    println!("The next slot drained them");
+}
+
+#[track_caller]
+fn assert_counter(kit: &TestKit, state: &Pubkey, counter: u64, queued: usize) {
+    kit.assert_state::<CounterState>(state, |decoded| {
+        decoded.counter == counter && queue::entries(&decoded.async_queue).count() == queued
+    });
+}
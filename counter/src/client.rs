@@ -0,0 +1,133 @@
+//! Client for the `counter` program, generated from its IDL by `apq_client::codegen`.
+//! Don't edit it by hand, regenerate it with `APQ_UPDATE_GOLDEN=1`.
+
+#[allow(unused_imports)]
+use super::*;
+
+use apq_client::__private::{bytes_of, tag, AccountMeta, Instruction, Pubkey};
+use apq_client::{decode, decode::DecodedInstruction, instructions::InstructionBuilder, DecodeError};
+
+/// Builds the `counter` program's instructions, and derefs to the
+/// [`InstructionBuilder`] for the ones every program shares
+#[derive(Copy, Clone, Debug)]
+pub struct CounterClient {
+    pub builder: InstructionBuilder,
+}
+
+impl CounterClient {
+    pub fn new(program_id: Pubkey, state: Pubkey) -> Self {
+        CounterClient {
+            builder: InstructionBuilder::new(program_id, state),
+        }
+    }
+
+    /// Executed immediately
+    pub fn refill_actions(&self, user: &Pubkey, accounts: &[AccountMeta]) -> Instruction {
+        let data = 0_u64.to_le_bytes().to_vec();
+        self.builder.sync(user, &data, accounts)
+    }
+
+    /// Executed immediately
+    pub fn set_vault(&self, user: &Pubkey, vault: Vault, accounts: &[AccountMeta]) -> Instruction {
+        let mut data = 1_u64.to_le_bytes().to_vec();
+        data.extend_from_slice(bytes_of(&vault));
+        self.builder.sync(user, &data, accounts)
+    }
+
+    /// Executed immediately
+    pub fn collect_deposits(
+        &self,
+        user: &Pubkey,
+        vault: &Pubkey,
+        accounts: &[AccountMeta],
+    ) -> Instruction {
+        let data = 2_u64.to_le_bytes().to_vec();
+        let mut metas = vec![AccountMeta::new(*vault, false)];
+        metas.extend_from_slice(accounts);
+        self.builder.sync(user, &data, &metas)
+    }
+
+    /// Queued, escrowing the crank bounty and priority bid from user
+    pub fn decrement(
+        &self,
+        user: &Pubkey,
+        amount: u64,
+        priority_bid: u64,
+        referrer: &Pubkey,
+    ) -> Instruction {
+        let mut data = 0_u64.to_le_bytes().to_vec();
+        data.extend_from_slice(bytes_of(&amount));
+        data.extend_from_slice(bytes_of(&priority_bid));
+        data.extend_from_slice(referrer.as_ref());
+        self.builder.queue_async(user, &data)
+    }
+
+    /// Queued, escrowing the crank bounty and priority bid from user
+    pub fn increment(
+        &self,
+        user: &Pubkey,
+        amount: u64,
+        priority_bid: u64,
+        referrer: &Pubkey,
+    ) -> Instruction {
+        let mut data = 1_u64.to_le_bytes().to_vec();
+        data.extend_from_slice(bytes_of(&amount));
+        data.extend_from_slice(bytes_of(&priority_bid));
+        data.extend_from_slice(referrer.as_ref());
+        self.builder.queue_async(user, &data)
+    }
+
+    /// Describes instruction `data` sent with `accounts`, naming the instructions and
+    /// arguments of the IDL, see [`apq_client::decode`]
+    pub fn decode_instruction(
+        data: &[u8],
+        accounts: &[AccountMeta],
+    ) -> Result<DecodedInstruction, DecodeError> {
+        let (tag, variant, mut rest) = decode::split(data)?;
+        let (name, args): (&str, Vec<(&str, String)>) = match (tag, variant) {
+            (tag::SYNC, Some(0)) => ("refill_actions", vec![]),
+            (tag::SYNC, Some(1)) => (
+                "set_vault",
+                vec![
+                    ("vault", decode::take_arg::<Vault>(&mut rest)?),
+                ],
+            ),
+            (tag::SYNC, Some(2)) => ("collect_deposits", vec![]),
+            (tag::QUEUE, Some(0)) => (
+                "decrement",
+                vec![
+                    ("amount", decode::take_arg::<u64>(&mut rest)?),
+                    ("priority_bid", decode::take_arg::<u64>(&mut rest)?),
+                    ("referrer", decode::take_pubkey(&mut rest)?),
+                ],
+            ),
+            (tag::QUEUE, Some(1)) => (
+                "increment",
+                vec![
+                    ("amount", decode::take_arg::<u64>(&mut rest)?),
+                    ("priority_bid", decode::take_arg::<u64>(&mut rest)?),
+                    ("referrer", decode::take_pubkey(&mut rest)?),
+                ],
+            ),
+            _ => return decode::shared(data, accounts),
+        };
+        Ok(DecodedInstruction::new(tag, name, variant, args, rest, accounts))
+    }
+
+    /// [`Self::decode_instruction`] for the compact format, see
+    /// `apq_core::discriminator::Compact`
+    pub fn decode_compact_instruction(
+        data: &[u8],
+        accounts: &[AccountMeta],
+    ) -> Result<DecodedInstruction, DecodeError> {
+        Self::decode_instruction(&decode::expand_compact(data)?, accounts)
+    }
+}
+
+impl std::ops::Deref for CounterClient {
+    type Target = InstructionBuilder;
+
+    fn deref(&self) -> &Self::Target {
+        &self.builder
+    }
+}
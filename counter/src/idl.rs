@@ -0,0 +1,62 @@
+//! The counter's IDL, which [`crate::client`] is generated from
+
+use apq_client::{idl::IdlBuilder, idl_struct, idl_type};
+use serde_json::Value;
+use solana_pubkey::Pubkey;
+
+use crate::*;
+
+/// The IDL of the counter deployed at `address`
+pub fn idl(address: Pubkey) -> Value {
+    let queue_args = [
+        ("amount", idl_type!(u64)),
+        ("priority_bid", idl_type!(u64)),
+        ("referrer", idl_type!(pubkey)),
+    ];
+    IdlBuilder::new(address, "counter", env!("CARGO_PKG_VERSION"))
+        .sync_ix("refill_actions", CounterSyncIx::RefillActions as u64, &[])
+        .sync_ix(
+            "set_vault",
+            CounterSyncIx::SetVault as u64,
+            &[("vault", idl_type!(Vault))],
+        )
+        .sync_ix_with_accounts(
+            "collect_deposits",
+            CounterSyncIx::CollectDeposits as u64,
+            &[IdlBuilder::account("vault", true, false)],
+            &[],
+        )
+        .ty(idl_struct!(Vault {
+            address: pubkey,
+            mint: pubkey,
+            price: u64,
+        }))
+        .async_ix("decrement", CounterAsyncIx::Decrement as u64, &queue_args)
+        .async_ix("increment", CounterAsyncIx::Increment as u64, &queue_args)
+        .queue(
+            idl_struct!(AsyncIxKey {
+                slot: u64,
+                ixn_value: u64,
+                bid_rank: u64,
+                seq: u64,
+            }),
+            idl_struct!(CounterPayload {
+                user: pubkey,
+                args: [u8; 32],
+                referrer: pubkey,
+            }),
+        )
+        .state(idl_struct!(CounterState {
+            header: StateHeader,
+            seq: u64,
+            counter: u64,
+            async_queue: [u8; size_of::<RedBlackTree<AsyncIxKey, CounterPayload, 8192>>()],
+            commitments: [u8; size_of::<Commitments>()],
+            vault: Vault,
+            deposits: u64,
+            balances: [u8; size_of::<ActionBalances>()],
+            dead_letters: [u8; size_of::<DeadLetters>()],
+            relayed: [u8; size_of::<RelayedStore>()],
+        }))
+        .build()
+}
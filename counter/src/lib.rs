@@ -1,63 +1,65 @@
 #![allow(unexpected_cfgs)]
 
-use std::{
-    hint::black_box,
-    ops::{Deref, DerefMut},
-};
-
 use apq_core::{
+    balances::{self, Balances},
+    commit::Commitments,
+    compact,
+    context::{AccountsCtx, StateAccounts},
+    dead_letter::FailurePolicy,
     deser_containers::{OwnedOrBorrowed, OwnedOrBorrowedMut},
+    dispatch, emit, escrow,
+    events::{CancelledEvent, ProcessedEvent, QueuedEvent},
+    header::StateHeader,
+    log_debug, log_error, log_info,
+    migrate::{self, Migratable},
+    ordering::{self, bid_rank, OrderingKey, PriorityBid},
+    paranoid,
+    pod::{decode_variant, Variant},
+    queue::{self, peek_min, pop_min, ArgsSlot, CancelAll, Entry, UserPayload},
+    relay::RelayedStore,
+    strict::{self, Strictness},
+    vault::Vault,
     AsyncIx, AsyncState, FromBytes, Program, SyncIx,
 };
 use bytemuck::{Pod, Zeroable};
 use pinocchio::{
-    account_info::AccountInfo,
-    entrypoint,
-    program_error::ProgramError,
-    pubkey::Pubkey,
-    sysvars::{clock::Clock, Sysvar},
-    ProgramResult,
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
 };
-use sokoban::{red_black_tree::RBNode, NodeAllocatorMap, RedBlackTree, SENTINEL};
+use sokoban::{red_black_tree::RBNode, NodeAllocatorMap, RedBlackTree};
 
 // Counter program implementation
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u64)]
 pub enum CounterSyncIx {
+    /// Buys the signer one action at the [`CounterState::vault`] price
     RefillActions = 0,
+    /// Reprices actions, signed by the admin. Data is the new [`Vault`].
+    SetVault = 1,
+    /// Pays the [`CounterState::deposits`] of processed actions into the vault, whose account
+    /// comes first after the user. Signed by the admin.
+    CollectDeposits = 2,
 }
 
 impl CounterSyncIx {
-    /// can use macros to derive this without user error
-    const MAX_VARIANT: u64 = 0;
+    /// Owned decoding for clients, accepting exactly what the [`FromBytes`] impl does
+    pub fn decode_owned(bytes: &[u8]) -> Result<Self, ProgramError> {
+        decode_variant(bytes)
+    }
 }
 
-impl FromBytes for CounterSyncIx {
-    type Target<'a> = &'a Self;
-    type TargetMut<'a> = &'a mut Self;
-    fn from_bytes<'a>(bytes: &'a [u8]) -> Result<&'a Self, ProgramError> {
-        // We could do an owned version like this with 1 byte
-        let _ = black_box(
-            bytes
-                .get(0)
-                .is_some_and(|b| *b == 0)
-                .then_some(CounterSyncIx::RefillActions)
-                .ok_or(ProgramError::InvalidInstructionData),
-        );
-
-        // Or a zc version like this
-        let (ix, _rem) = bytes
-            .split_at_checked(8)
-            .ok_or(ProgramError::InvalidInstructionData)?;
-        if unsafe { *ix.as_ptr().cast::<u64>() } > CounterSyncIx::MAX_VARIANT {
-            return Err(ProgramError::InvalidInstructionData);
-        }
+impl Variant for CounterSyncIx {
+    const COUNT: u64 = 3;
 
-        Ok(unsafe { &*ix.as_ptr().cast::<CounterSyncIx>() })
+    unsafe fn from_u64_unchecked(value: u64) -> Self {
+        unsafe { core::mem::transmute(value) }
     }
+}
 
-    fn from_bytes_mut<'a>(_bytes: &'a mut [u8]) -> Result<&'a mut Self, ProgramError> {
-        unimplemented!("unused in this program")
+impl FromBytes for CounterSyncIx {
+    type Target<'a> = OwnedOrBorrowed<'a, Self>;
+    type TargetMut<'a> = OwnedOrBorrowedMut<'a, Self>;
+    fn from_bytes<'a>(bytes: &'a [u8]) -> Result<OwnedOrBorrowed<'a, Self>, ProgramError> {
+        Ok(OwnedOrBorrowed::Owned(Self::decode_owned(bytes)?))
     }
 }
 
@@ -70,106 +72,232 @@ pub enum CounterAsyncIx {
 
 impl CounterAsyncIx {
     const MAX_VARIANT: u64 = 1;
+
+    /// # Safety
+    ///
+    /// `a` must be a valid variant, i.e. `a <= CounterAsyncIx::MAX_VARIANT`
     pub unsafe fn from_u64_unchecked(a: u64) -> CounterAsyncIx {
         unsafe { core::mem::transmute(a) }
     }
+
+    /// `None` if `a` isn't a variant
+    pub fn from_u64(a: u64) -> Option<CounterAsyncIx> {
+        (a <= Self::MAX_VARIANT).then(|| unsafe { Self::from_u64_unchecked(a) })
+    }
+}
+
+impl Variant for CounterAsyncIx {
+    const COUNT: u64 = Self::MAX_VARIANT + 1;
+
+    unsafe fn from_u64_unchecked(value: u64) -> Self {
+        unsafe { core::mem::transmute(value) }
+    }
 }
 
 impl FromBytes for CounterAsyncIx {
     type Target<'a> = OwnedOrBorrowed<'a, Self>;
     type TargetMut<'a> = OwnedOrBorrowedMut<'a, Self>;
     fn from_bytes<'a>(bytes: &'a [u8]) -> Result<OwnedOrBorrowed<'a, Self>, ProgramError> {
-        let (ix, _rem) = bytes
-            .split_at_checked(8)
-            .ok_or(ProgramError::InvalidInstructionData)?;
-
-        if unsafe { ix.as_ptr().cast::<u64>().read_unaligned() } > CounterAsyncIx::MAX_VARIANT {
-            pinocchio_log::log!(
-                "got ix variant {} > {}",
-                unsafe { ix.as_ptr().cast::<u64>().read_unaligned() },
-                CounterAsyncIx::MAX_VARIANT
-            );
-            return Err(ProgramError::InvalidInstructionData);
-        }
-
-        Ok(OwnedOrBorrowed::Owned(unsafe {
-            ix.as_ptr().cast::<CounterAsyncIx>().read_unaligned()
-        }))
-    }
-
-    fn from_bytes_mut<'a>(
-        _bytes: &'a mut [u8],
-    ) -> Result<OwnedOrBorrowedMut<'a, Self>, ProgramError> {
-        unimplemented!()
+        let ix = decode_variant(bytes).inspect_err(|_| log_error!("Invalid async ix variant"))?;
+        Ok(OwnedOrBorrowed::Owned(ix))
     }
 }
 
-/// We first sort by auction (slot), then by ixn type, then by seq
+/// We first sort by auction (slot), then by ixn type, then by priority bid, then by seq
 #[derive(Copy, Clone, Zeroable, Pod, PartialEq, PartialOrd, Eq, Ord, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct AsyncIxKey {
     pub slot: u64,
     pub ixn_value: u64,
+    /// Higher bids sort first, see [`bid_rank`]
+    pub bid_rank: u64,
     pub seq: u64,
 }
 
+apq_core::impl_layout_hash!(AsyncIxKey {
+    slot: u64,
+    ixn_value: u64,
+    bid_rank: u64,
+    seq: u64
+});
+
+impl OrderingKey<CounterAsyncIx, QueueAsyncArgs> for AsyncIxKey {
+    fn key(slot: u64, seq: u64, ixn: &CounterAsyncIx, args: &QueueAsyncArgs) -> Self {
+        AsyncIxKey {
+            slot,
+            ixn_value: *ixn as u64,
+            bid_rank: bid_rank(args.priority_bid()),
+            seq,
+        }
+    }
+
+    fn slot(&self) -> u64 {
+        self.slot
+    }
+}
+
+/// What gets queued with each instruction
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct CounterPayload {
+    pub user: Pubkey,
+    /// The encoded [`CounterAsyncIxArgs`]
+    pub args: ArgsSlot,
+    /// Frontend the action was queued through, all zeroes if none
+    pub referrer: Pubkey,
+}
+
+apq_core::impl_layout_hash!(CounterPayload {
+    user: Pubkey,
+    args: ArgsSlot,
+    referrer: Pubkey
+});
+
+impl UserPayload for CounterPayload {
+    fn user(&self) -> &Pubkey {
+        &self.user
+    }
+}
+
+impl CounterPayload {
+    pub fn new(user: Pubkey, amount: u64) -> Result<Self, ProgramError> {
+        Ok(CounterPayload {
+            user,
+            args: CounterAsyncIx::encode_args(&CounterAsyncIxArgs { amount })?,
+            referrer: [0; 32],
+        })
+    }
+
+    pub fn args(&self) -> Result<CounterAsyncIxArgs, ProgramError> {
+        CounterAsyncIx::decode_args(&self.args)
+    }
+}
+
+/// Users with actions left at once
+pub const MAX_USERS: usize = 1024;
+
+/// Custom program error for queueing without actions left, see
+/// [`CounterSyncIx::RefillActions`]
+pub const NO_ACTIONS: u32 = 0x0;
+
+/// Custom program error for decrementing the counter past zero, which dead-letters the
+/// action, see [`CounterState::dead_letters`]
+pub const BELOW_ZERO: u32 = 0x1;
+
+/// Actions left per user
+pub type ActionBalances = Balances<u64, MAX_USERS>;
+
+/// Failed actions kept at once, see [`CounterState::dead_letters`]
+pub const MAX_DEAD_LETTERS: usize = 256;
+
+pub type DeadLetters = RedBlackTree<AsyncIxKey, CounterPayload, MAX_DEAD_LETTERS>;
+
 #[derive(Copy, Clone, Zeroable, Pod)]
 #[repr(C)]
 pub struct CounterState {
-    /// Sequence number to assign to each action for time priority
-    ///
-    /// Starts at 1 to also be an init check
-    pub seq: u64,
+    /// Layout version, see [`Migratable`]
+    pub header: StateHeader,
 
-    /// Number of actions left before you need to add more
+    /// Sequence number to assign to the next action for time priority
     ///
-    /// Analogous to a user balance for financial markets
-    pub num_actions: u64,
+    /// Starts at 1. Whether the state is initialized is up to the header's version, not this.
+    pub seq: u64,
 
     /// The counter value that everyone cares about
     ///
-    /// Analogous to market state + user balances for financial markets
+    /// Analogous to market state + user balances for financial markets, see the `orderbook`
+    /// example
     pub counter: u64,
 
     /// The asynchronous queue for decrements and increments
     ///
-    /// Analogous to cancels and takes for financial markets
-    pub async_queue: RedBlackTree<AsyncIxKey, Pubkey, 8192>,
+    /// Analogous to cancels and takes for financial markets, which the `orderbook` example
+    /// settles in one batch per slot
+    pub async_queue: RedBlackTree<AsyncIxKey, CounterPayload, 8192>,
+
+    /// Sealed actions waiting to be revealed, see [`apq_core::commit`]
+    pub commitments: Commitments,
+
+    /// Where refills are paid and what an action costs, free until the admin sets it. Can't be
+    /// the state account itself, which is borrowed while refilling.
+    pub vault: Vault,
+
+    /// Lamports of processed actions' [`AsyncState::DEPOSIT`]s and priority bids, held by the
+    /// state account until collected into the vault. Expired and dead-lettered actions keep
+    /// theirs too, see [`CounterState::kept`], and flushed ones their crank bounty as well.
+    pub deposits: u64,
+
+    /// Actions each user has left before they need to refill, spent by queueing and
+    /// refunded by cancelling
+    ///
+    /// Analogous to user balances for financial markets
+    pub balances: ActionBalances,
+
+    /// Actions that failed to process, e.g. decrementing past zero, kept until the authority
+    /// purges them. Their user gets the action back and their deposit and bid are kept like
+    /// a processed one's.
+    pub dead_letters: DeadLetters,
+
+    /// Authorizations relayers queued actions with, kept until they expire so each is only
+    /// relayed once, see [`apq_core::relay`]
+    pub relayed: RelayedStore,
 }
 
+// Changing the layout needs a new version, see `Migratable`
+apq_core::const_assert_state_layout!(CounterState, size = 1_329_968, align = 8);
+
+/// Length of the v1 layout, see [`CounterState::migrate`]
+const V1_LEN: usize = 1_262_312;
+
+/// Length of the v2 layout, before the relayed authorizations
+const V2_LEN: usize = 1_299_216;
+
 impl CounterState {
+    /// Boxed since the queue is far too large for the stack
     #[cfg(test)]
-    fn new() -> Self {
-        CounterState {
-            counter: 0,
-            num_actions: 0,
-            seq: 0,
-            async_queue: RedBlackTree::new(),
-        }
+    fn new() -> Box<Self> {
+        let mut state: Box<Self> = bytemuck::zeroed_box();
+        state.initialize();
+        state
     }
 
-    pub fn peek_async(&self) -> Option<(u32, &RBNode<AsyncIxKey, Pubkey>)> {
-        // this is kinda stupid af but lets do this for now lol
-        let mut addr = self.async_queue.root;
-        if addr == SENTINEL {
-            return None;
-        }
+    pub fn peek_async(&self) -> Option<(u32, &RBNode<AsyncIxKey, CounterPayload>)> {
+        peek_min(&self.async_queue)
+    }
 
-        let mut last_addr = addr;
-        while addr != SENTINEL {
-            last_addr = addr;
-            addr = self.async_queue.get_left(addr);
-        }
+    pub fn pop_async(&mut self) -> Option<RBNode<AsyncIxKey, CounterPayload>> {
+        pop_min(&mut self.async_queue)
+    }
 
-        Some((last_addr, self.async_queue.get_node(last_addr)))
+    /// Actions `user` has left
+    pub fn actions(&self, user: &Pubkey) -> u64 {
+        balances::balance(&self.balances, user)
     }
 
-    pub fn pop_async(&mut self) -> Option<RBNode<AsyncIxKey, Pubkey>> {
-        // TODO: change sokoban to allow for remove_addr,
-        // currenly called _remove_tree_node
-        let (_addr, &val) = self.peek_async()?;
-        self.async_queue.remove(&val.key);
-        Some(val)
+    /// Gives `user` `amount` more actions
+    pub fn credit_actions(&mut self, user: &Pubkey, amount: u64) -> ProgramResult {
+        balances::credit(&mut self.balances, user, amount)?;
+        Ok(())
+    }
+
+    /// Spends one of `user`'s actions, dropping users who run out to make room for others
+    pub fn debit_action(&mut self, user: &Pubkey) -> ProgramResult {
+        balances::debit(
+            &mut self.balances,
+            user,
+            1,
+            ProgramError::Custom(NO_ACTIONS),
+        )?;
+        Ok(())
+    }
+
+    /// What the state keeps of the escrow of the action queued under `key` once it leaves the
+    /// queue other than by cancelling: its deposit and priority bid. The crank bounty is paid
+    /// out by whoever removes it.
+    fn kept(key: &AsyncIxKey) -> u64 {
+        Self::DEPOSIT + (u64::MAX - key.bid_rank)
     }
 }
 
@@ -186,32 +314,110 @@ impl FromBytes for CounterState {
     }
 }
 
+impl Migratable for CounterState {
+    const VERSION: u32 = 3;
+    const LAYOUT_HASH: u64 = apq_core::layout_hash!(CounterState {
+        header: StateHeader,
+        seq: u64,
+        counter: u64,
+        async_queue: RedBlackTree<AsyncIxKey, CounterPayload, 8192>,
+        commitments: Commitments,
+        vault: Vault,
+        deposits: u64,
+        balances: ActionBalances,
+        dead_letters: DeadLetters,
+        relayed: RelayedStore,
+    });
+
+    fn migrate(from_version: u32, data: &mut [u8]) -> ProgramResult {
+        match from_version {
+            // v1 headers predate the layout hash and the dead letters came after, grow the
+            // account to the current length first
+            1 => {
+                migrate::insert_layout_hash(data, V1_LEN)?;
+                let state = Self::from_bytes_mut(data)?;
+                state.dead_letters.initialize();
+                state.relayed.initialize();
+                Ok(())
+            }
+            // The relayed authorizations came after v2, grown the same way
+            2 => {
+                if data.len() < V2_LEN + size_of::<RelayedStore>() {
+                    return Err(ProgramError::AccountDataTooSmall);
+                }
+                let state = Self::from_bytes_mut(data)?;
+                state.relayed.initialize();
+                Ok(())
+            }
+            _ => {
+                log_error!("Unknown state version {}", from_version);
+                Err(ProgramError::InvalidAccountData)
+            }
+        }
+    }
+}
+
 impl SyncIx for CounterSyncIx {
     fn process<S: AsyncState>(
         &self,
-        _data: &[u8],
-        _accounts: &[AccountInfo],
+        data: &[u8],
+        accounts: &[AccountInfo],
         state: &mut S,
     ) -> ProgramResult {
+        let StateAccounts {
+            state: state_account,
+            user,
+            rem,
+        } = StateAccounts::parse(accounts)?;
+        // This is a bit of a hack to access the concrete state
+        // In a real implementation, you might want a better pattern
+        let counter_state = unsafe { &mut *(state as *mut S as *mut CounterState) };
+
         match self {
             CounterSyncIx::RefillActions => {
-                // This is a bit of a hack to access the concrete state
-                // In a real implementation, you might want a better pattern
-                let counter_state = unsafe { &mut *(state as *mut S as *mut CounterState) };
-                counter_state.num_actions += 1;
-                pinocchio_log::log!(
-                    "Action requested. Total actions: {}",
-                    counter_state.num_actions
+                strict::check_consumed(data, 8, CounterState::STRICTNESS)?;
+                // Only the signer pays and is credited, see `Vault::charge` for the accounts
+                if !user.is_signer() {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                counter_state.vault.charge(user, rem, 1)?;
+                counter_state.credit_actions(user.key(), 1)?;
+                log_debug!(
+                    "Action requested. Actions left: {}",
+                    counter_state.actions(user.key())
                 );
                 Ok(())
             }
+            CounterSyncIx::SetVault => {
+                counter_state.header.admin.check_authority(user)?;
+                counter_state.vault = data
+                    .get(8..)
+                    .and_then(|vault| bytemuck::try_pod_read_unaligned(vault).ok())
+                    .ok_or(ProgramError::InvalidInstructionData)?;
+                log_info!("Actions now cost {}", counter_state.vault.price);
+                Ok(())
+            }
+            CounterSyncIx::CollectDeposits => {
+                strict::check_consumed(data, 8, CounterState::STRICTNESS)?;
+                counter_state.header.admin.check_authority(user)?;
+                let vault = rem.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+                counter_state.vault.check_address(vault)?;
+                // Lamports don't share a borrow with the state's data
+                escrow::pay(state_account, vault, counter_state.deposits)?;
+                log_info!("Collected {} lamports", counter_state.deposits);
+                counter_state.deposits = 0;
+                Ok(())
+            }
         }
     }
 }
 
 /// This could be an enum but for now we will make this a key for both inc/dec
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
 pub struct CounterAsyncIxArgs {
-    seq: u64,
+    /// How much to increment or decrement by
+    pub amount: u64,
 }
 
 impl AsyncIx for CounterAsyncIx {
@@ -222,88 +428,351 @@ impl AsyncIx for CounterAsyncIx {
 
         match self {
             CounterAsyncIx::Increment => {
-                counter_state.counter = counter_state.counter.saturating_add(1);
-                pinocchio_log::log!(
-                    "Incremented; Seq {}. New value: {}",
-                    args.seq,
+                counter_state.counter = paranoid::add(counter_state.counter, args.amount)?;
+                log_debug!(
+                    "Incremented by {}. New value: {}",
+                    args.amount,
                     counter_state.counter
                 );
             }
             CounterAsyncIx::Decrement => {
-                counter_state.counter = counter_state.counter.saturating_sub(1);
-                pinocchio_log::log!(
-                    "Decremented; Seq {}; New value: {}",
-                    args.seq,
+                counter_state.counter = counter_state
+                    .counter
+                    .checked_sub(args.amount)
+                    .ok_or(ProgramError::Custom(BELOW_ZERO))?;
+                log_debug!(
+                    "Decremented by {}. New value: {}",
+                    args.amount,
                     counter_state.counter
                 );
             }
         }
         Ok(())
     }
+
+    fn encode_args(args: &CounterAsyncIxArgs) -> Result<ArgsSlot, ProgramError> {
+        queue::encode_pod_args(args)
+    }
+
+    fn decode_args(slot: &ArgsSlot) -> Result<CounterAsyncIxArgs, ProgramError> {
+        queue::decode_pod_args(slot)
+    }
 }
 
 /// This could be an enum but for now we will make this a key for both inc/dec
 pub struct QueueAsyncArgs {
-    key: Pubkey,
+    payload: CounterPayload,
+    /// Lamports escrowed to be processed ahead of lower bids of the same type
+    priority_bid: u64,
+}
+
+impl QueueAsyncArgs {
+    /// Parses the optional `amount` (default 1), `priority_bid` (default 0) and `referrer`
+    /// (default none) following the async variant
+    fn parse(user: &Pubkey, data: &[u8]) -> Result<Self, ProgramError> {
+        let (numbers, referrer) = data.split_at(data.len().min(16));
+        let mut fields = numbers.chunks(8).map(|field| {
+            field
+                .try_into()
+                .map(u64::from_le_bytes)
+                .map_err(|_| ProgramError::InvalidInstructionData)
+        });
+        let amount = fields.next().transpose()?.unwrap_or(1);
+        let priority_bid = fields.next().transpose()?.unwrap_or(0);
+        // A partial referrer is left over like any other trailing bytes
+        let (referrer, used) = match referrer.get(..32) {
+            Some(referrer) => (referrer.try_into().unwrap(), 48),
+            None => ([0; 32], 16),
+        };
+        strict::check_consumed(data, used, CounterState::STRICTNESS)?;
+
+        Ok(QueueAsyncArgs {
+            payload: CounterPayload {
+                referrer,
+                ..CounterPayload::new(*user, amount)?
+            },
+            priority_bid,
+        })
+    }
+}
+
+impl PriorityBid for QueueAsyncArgs {
+    fn priority_bid(&self) -> u64 {
+        self.priority_bid
+    }
 }
 
 impl AsyncState for CounterState {
     type SyncIx = CounterSyncIx;
     type AsyncIx = CounterAsyncIx;
+    type Payload = CounterPayload;
 
     type QueueArgs = QueueAsyncArgs;
+    type Key = AsyncIxKey;
+
+    /// Enough to cover the drain transaction's signature fee
+    const CRANK_BOUNTY: u64 = 5_000;
+
+    /// Kept once the action runs, see [`CounterState::deposits`]
+    const DEPOSIT: u64 = 10_000;
+
+    /// About a minute to reveal
+    const COMMIT_EXPIRY_SLOTS: u64 = 150;
+
+    /// About a day, after which anyone can compact the action away
+    const ENTRY_TTL_SLOTS: u64 = 216_000;
+
+    const STRICTNESS: Strictness = Strictness::Strict;
+
+    /// Decrementing past zero fails, which mustn't hold up the actions behind it
+    const FAILURE_POLICY: FailurePolicy = FailurePolicy::DeadLetter;
+
+    /// Users without SOL can have relayers count for them, see [`apq_core::relay`]
+    const RELAYED: bool = true;
+
+    /// Bots can count for users with session keys, see [`apq_core::session`]
+    const SESSIONS: bool = true;
+
+    fn initialize(&mut self) {
+        let CounterState {
+            ref mut seq,
+            ref mut async_queue,
+            ref mut commitments,
+            ref mut balances,
+            ref mut dead_letters,
+            ref mut relayed,
+            // zero initialized
+            header: _,
+            counter: _,
+            vault: _,
+            deposits: _,
+        } = self;
+        *seq = 1;
+        async_queue.initialize();
+        commitments.initialize();
+        balances.initialize();
+        dead_letters.initialize();
+        relayed.initialize();
+    }
+
+    fn queue_args(user: &AccountInfo, data: &[u8]) -> Result<QueueAsyncArgs, ProgramError> {
+        QueueAsyncArgs::parse(user.key(), data.get(8..).unwrap_or_default())
+    }
+
+    fn priority_bid(args: &QueueAsyncArgs) -> u64 {
+        args.priority_bid
+    }
 
     fn queue_async(
         &mut self,
         ixn: &Self::AsyncIx,
         args: &Self::QueueArgs,
+        now: u64,
     ) -> Result<(), ProgramError> {
-        if self.num_actions == 0 {
-            return Err(ProgramError::Custom(0x0));
-        }
+        let seq = self.next_seq()?;
+        self.queue_async_at(ixn, args, now, seq)
+    }
+
+    fn queue_async_at(
+        &mut self,
+        ixn: &Self::AsyncIx,
+        args: &Self::QueueArgs,
+        slot: u64,
+        seq: u64,
+    ) -> ProgramResult {
+        self.debit_action(&args.payload.user)?;
         // Insert in priority order
-        let slot = get_slot();
-        let key = AsyncIxKey {
-            ixn_value: *ixn as u64,
-            slot,
-            seq: self.seq,
-        };
-        self.seq += 1;
-        self.num_actions -= 1;
-        self.async_queue.insert(key, args.key);
+        let key = Self::Key::key(slot, seq, ixn, args);
+        self.async_queue
+            .insert(key, args.payload)
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        emit!(
+            QueuedEvent {
+                user: args.payload.user,
+                ixn: key.ixn_value,
+                priority_bid: args.priority_bid,
+            },
+            key
+        );
 
-        let log_msg = format!(
-            "Queued async instruction {:?} in slot {} with seq {}. Queue length: {}",
-            ixn,
+        log_debug!(
+            "Queued async instruction {} in slot {} with seq {}. Queue length: {}",
+            key.ixn_value,
             slot,
             key.seq,
             self.async_queue.len()
         );
-        pinocchio::msg!(&log_msg);
 
         Ok(())
     }
 
+    fn cancel_async(&mut self, user: &Pubkey, key: &AsyncIxKey) -> Result<u64, ProgramError> {
+        let payload = *self
+            .async_queue
+            .get(key)
+            .ok_or(ProgramError::InvalidArgument)?;
+        if payload.user != *user {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        self.async_queue.remove(key);
+        self.credit_actions(user, 1)?;
+
+        let refund = Self::CRANK_BOUNTY + Self::DEPOSIT + (u64::MAX - key.bid_rank);
+        emit!(
+            CancelledEvent {
+                user: *user,
+                ixn: key.ixn_value,
+                refund,
+            },
+            *key
+        );
+        Ok(refund)
+    }
+
+    fn cancel_all_async(&mut self, user: &Pubkey, max: u32) -> Result<CancelAll, ProgramError> {
+        queue::cancel_all(self, user, max)
+    }
+
+    fn expire_entry(&mut self, key: &AsyncIxKey) -> ProgramResult {
+        let payload = self
+            .async_queue
+            .remove(key)
+            .ok_or(ProgramError::InvalidArgument)?;
+        // The action never ran, but its deposit and bid are kept like a processed one's
+        self.credit_actions(&payload.user, 1)?;
+        self.deposits = paranoid::add(self.deposits, Self::kept(key))?;
+        emit!(
+            CancelledEvent {
+                user: payload.user,
+                ixn: key.ixn_value,
+                refund: 0,
+            },
+            *key
+        );
+        Ok(())
+    }
+
+    /// Expires the next entry, keeping its crank bounty too since no crank removed it
+    fn flush_entry(&mut self) -> Result<bool, ProgramError> {
+        let Some(key) = self.peek_async().map(|(_, next)| next.key) else {
+            return Ok(false);
+        };
+        self.expire_entry(&key)?;
+        self.deposits = paranoid::add(self.deposits, Self::CRANK_BOUNTY)?;
+        Ok(true)
+    }
+
+    fn replace_async(
+        &mut self,
+        user: &Pubkey,
+        old_seq: u64,
+        ixn: &CounterAsyncIx,
+        args: &QueueAsyncArgs,
+        now: u64,
+    ) -> Result<u64, ProgramError> {
+        // Sequence numbers are unique, but the queue isn't sorted by them
+        let key = *self
+            .async_queue
+            .iter()
+            .map(|(key, _)| key)
+            .find(|key| key.seq == old_seq)
+            .ok_or(ProgramError::InvalidArgument)?;
+        let refund = self.cancel_async(user, &key)?;
+        if Self::REPLACE_KEEPS_PRIORITY {
+            self.queue_async_at(ixn, args, key.slot, key.seq)?;
+        } else {
+            self.queue_async(ixn, args, now)?;
+        }
+        Ok(refund)
+    }
+
+    fn next_seq(&mut self) -> Result<u64, ProgramError> {
+        ordering::next_seq(&mut self.seq)
+    }
+
+    fn commitments(&mut self) -> Option<&mut Commitments> {
+        Some(&mut self.commitments)
+    }
+
+    fn relayed(&mut self) -> Option<&mut RelayedStore> {
+        Some(&mut self.relayed)
+    }
+
+    fn rebuild_free_list(&mut self) -> bool {
+        compact::reset_if_empty(&mut self.async_queue)
+    }
+
+    fn is_settled(&self) -> bool {
+        self.async_queue.is_empty()
+            && self.deposits == 0
+            && self.balances.is_empty()
+            && self.dead_letters.is_empty()
+    }
+
+    fn check_invariants(&self) -> ProgramResult {
+        paranoid::check_tree(&self.async_queue)?;
+        paranoid::check_tree(&self.balances)
+    }
+
     fn process_next_async(&mut self) -> ProgramResult {
         if let Some(next) = self.pop_async() {
-            let ixn = unsafe { std::mem::transmute::<&u64, &CounterAsyncIx>(&next.key.ixn_value) };
-            let args = CounterAsyncIxArgs { seq: next.key.seq };
-            ixn.process(&args, self)?;
+            self.process_entry(&next)?;
         }
         Ok(())
     }
 
+    fn process_entry(&mut self, entry: &Entry<AsyncIxKey, CounterPayload>) -> ProgramResult {
+        let ixn = CounterAsyncIx::from_u64(entry.key.ixn_value)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        log_debug!("Processing seq {}", entry.key.seq);
+        let args = entry.value.args()?;
+        // Failures must leave the state untouched for the dead letters
+        let deposits = paranoid::add(self.deposits, Self::kept(&entry.key))?;
+        let result = ixn.process(&args, self);
+        emit!(
+            ProcessedEvent::new(entry.value.user, entry.key.ixn_value, &result)
+                .referred_by(entry.value.referrer),
+            entry.key
+        );
+        result?;
+        self.deposits = deposits;
+        Ok(())
+    }
+
+    fn dead_letter(&mut self, entry: &Entry<AsyncIxKey, CounterPayload>) -> ProgramResult {
+        self.dead_letters
+            .insert(entry.key, entry.value)
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        self.credit_actions(&entry.value.user, 1)?;
+        self.deposits = paranoid::add(self.deposits, Self::kept(&entry.key))?;
+        Ok(())
+    }
+
+    fn purge_dead_letters(&mut self) -> usize {
+        let purged = self.dead_letters.len();
+        while pop_min(&mut self.dead_letters).is_some() {}
+        purged
+    }
+
     fn has_pending_async(&self, slot: u64) -> bool {
         let Some((_addr, val)) = self.peek_async() else {
             return false;
         };
 
-        val.key.slot + 1 <= slot
+        val.key.is_due(slot, Self::ASYNC_DELAY_SLOTS)
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&AsyncIxKey, &CounterPayload)> {
+        self.async_queue.iter()
     }
-}
 
-fn get_slot() -> u64 {
-    Clock::get().unwrap().slot
+    fn peek_entry(&self) -> Option<&Entry<AsyncIxKey, CounterPayload>> {
+        self.peek_async().map(|(_addr, node)| node)
+    }
+
+    fn pop_entry(&mut self) -> Option<Entry<AsyncIxKey, CounterPayload>> {
+        self.pop_async()
+    }
 }
 
 pub struct CounterProgram;
@@ -314,78 +783,32 @@ impl Program for CounterProgram {
     type State = CounterState;
 
     fn process(
-        _program_id: &Pubkey,
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
         instruction_data: &[u8],
     ) -> ProgramResult {
-        let [state_account, user, _rem @ ..] = accounts else {
-            return Err(ProgramError::NotEnoughAccountKeys);
-        };
-
-        // Load state with zero-copy
-        let mut state_data = state_account.try_borrow_mut_data()?;
-        // Check if this is an initialization
-        if unsafe { *state_data.as_ptr().cast::<u64>() == 0 } {
-            initialize_state(&mut state_data);
-        }
-        let mut state = Self::State::from_bytes_mut(&mut state_data[..])?;
-
-        // Parse instruction
-        let ix_type = instruction_data[0];
-        let ix_data = &instruction_data[1..];
-
-        match ix_type {
-            0 => {
-                pinocchio::msg!("Executing Synchronous Instruction");
-
-                // Sync instruction
-                let sync_ix = Self::Sync::from_bytes(&mut &ix_data[..])?;
-                sync_ix.process(ix_data, accounts, state.deref_mut())?;
-            }
-            1 => {
-                pinocchio::msg!("Queueing Aynchronous Instruction");
-
-                // Async instruction - queue it
-                let async_ix = Self::Async::from_bytes(&mut &ix_data[..])?;
-                let args = QueueAsyncArgs { key: *user.key() };
-                state.queue_async(async_ix.deref(), &args)?;
-            }
-            2 => {
-                pinocchio::msg!("Executing Aynchronous Instruction");
-
-                // Process next async instruction
-                let slot = get_slot();
-                while state.has_pending_async(slot) {
-                    state.process_next_async()?;
-                }
-
-                pinocchio_log::log!("No pending async instructions");
-            }
-            _ => return Err(ProgramError::InvalidInstructionData),
-        }
-
-        // TODO: Save state when owned. in this example we never used owned so not important
-        // state.serialize(&mut &mut state_data[..])?;
-
-        Ok(())
+        dispatch::process::<Self>(program_id, accounts, instruction_data)
     }
 }
 
-fn initialize_state(state_data: &mut [u8]) {
-    pinocchio_log::log!("Initializing state");
-    let CounterState {
-        ref mut seq,
-        ref mut async_queue,
-        // zero initialized
-        num_actions: _,
-        counter: _,
-    } = bytemuck::from_bytes_mut(&mut state_data[..]);
-    *seq = 1;
-    async_queue.initialize();
-}
-
+// The macro expands to an unqualified `entrypoint!`
+#[cfg(not(feature = "no-entrypoint"))]
+use pinocchio::entrypoint;
+#[cfg(not(feature = "no-entrypoint"))]
 entrypoint!(process_instruction);
 
+#[cfg(feature = "client")]
+#[rustfmt::skip]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod idl;
+
+#[cfg(feature = "wasm")]
+apq_client::wasm_state! {
+    /// The counter's state decoded from account data, for web UIs
+    pub struct Counter(CounterState) { queue: async_queue }
+}
+
 // #[inline(always)]
 pub fn process_instruction(
     program_id: &Pubkey,
@@ -397,20 +820,57 @@ pub fn process_instruction(
 
 #[cfg(test)]
 mod tests {
+    use apq_core::{
+        close,
+        commit::{self, Commitment},
+        cursor::DrainArgs,
+        discriminator::{Compact, Discriminator, Tag},
+        flush,
+        header::StateHeader,
+        relay,
+        session::{Session, SessionParams},
+    };
+    use apq_testkit::host::Host;
+
     use super::*;
+    use crate::client::CounterClient;
+
+    #[test]
+    fn test_layout() {
+        let layout = apq_core::state_layout!(CounterState {
+            header,
+            seq,
+            counter,
+            async_queue,
+            commitments,
+            vault,
+            deposits,
+            balances,
+            dead_letters,
+            relayed
+        });
+        let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/golden/CounterState.txt");
+        apq_core::layout::assert_golden(&layout, golden);
+    }
 
     #[test]
     #[rustfmt::skip]
     fn test_priority_queue() {
         let mut state = CounterState::new();
+        state.credit_actions(&[0; 32], 4).unwrap();
+        let args = QueueAsyncArgs {
+            payload: CounterPayload::new([0; 32], 1).unwrap(),
+            priority_bid: 0,
+        };
 
         // Queue items with different priorities
-        state.queue_async(&CounterAsyncIx::Increment, &QueueAsyncArgs { key: [0; 32] }).unwrap();
-        state.queue_async(&CounterAsyncIx::Decrement, &QueueAsyncArgs { key: [0; 32] }).unwrap();
-        state.queue_async(&CounterAsyncIx::Increment, &QueueAsyncArgs { key: [0; 32] }).unwrap();
-        state.queue_async(&CounterAsyncIx::Decrement, &QueueAsyncArgs { key: [0; 32] }).unwrap();
+        state.queue_async(&CounterAsyncIx::Increment, &args, 0).unwrap();
+        state.queue_async(&CounterAsyncIx::Decrement, &args, 0).unwrap();
+        state.queue_async(&CounterAsyncIx::Increment, &args, 0).unwrap();
+        state.queue_async(&CounterAsyncIx::Decrement, &args, 0).unwrap();
 
         assert_eq!(state.async_queue.len(), 4);
+        assert_eq!(state.check_invariants(), Ok(()));
 
         // Pop should give us items in priority order
         for _ in 0..2 {
@@ -431,4 +891,792 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_priority_bid() {
+        let mut state = CounterState::new();
+        for bid in [5, 0, 7] {
+            state.credit_actions(&[bid; 32], 1).unwrap();
+            let args = QueueAsyncArgs::parse(
+                &[bid; 32],
+                &[1, 0, 0, 0, 0, 0, 0, 0, bid, 0, 0, 0, 0, 0, 0, 0],
+            )
+            .unwrap();
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args, 0)
+                .unwrap();
+        }
+
+        // Highest bid first among increments
+        let users: Vec<u8> = std::iter::from_fn(|| state.pop_async())
+            .map(|node| node.value.user[0])
+            .collect();
+        assert_eq!(users, [7, 5, 0]);
+    }
+
+    #[test]
+    fn test_iter_pending_and_by_user() {
+        let mut state = CounterState::new();
+        for (user, slot) in [(1, 0), (2, 5), (1, 5)] {
+            state.credit_actions(&[user; 32], 1).unwrap();
+            let args = QueueAsyncArgs::parse(&[user; 32], &[]).unwrap();
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args, slot)
+                .unwrap();
+        }
+
+        let due: Vec<u64> = state.iter_pending(1).map(|(key, _)| key.slot).collect();
+        assert_eq!(due, [0]);
+        assert_eq!(state.iter_pending(6).count(), 3);
+
+        let slots: Vec<u64> = state
+            .iter_by_user(&[1; 32])
+            .map(|(key, _)| key.slot)
+            .collect();
+        assert_eq!(slots, [0, 5]);
+        assert_eq!(state.iter_by_user(&[3; 32]).count(), 0);
+    }
+
+    #[test]
+    fn test_cancel_all() {
+        let mut state = CounterState::new();
+        for (user, slot) in [(1, 0), (2, 0), (1, 1), (1, 2)] {
+            state.credit_actions(&[user; 32], 1).unwrap();
+            let args = QueueAsyncArgs::parse(&[user; 32], &[]).unwrap();
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args, slot)
+                .unwrap();
+        }
+
+        let cancel = state.cancel_all_async(&[1; 32], 2).unwrap();
+        assert_eq!((cancel.cancelled, cancel.more), (2, true));
+        assert_eq!(
+            cancel.refund,
+            2 * (CounterState::CRANK_BOUNTY + CounterState::DEPOSIT)
+        );
+        let cancel = state.cancel_all_async(&[1; 32], u32::MAX).unwrap();
+        assert_eq!((cancel.cancelled, cancel.more), (1, false));
+
+        assert_eq!(state.actions(&[1; 32]), 3);
+        assert_eq!(state.iter_by_user(&[1; 32]).count(), 0);
+        assert_eq!(state.iter_by_user(&[2; 32]).count(), 1);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut state = CounterState::new();
+        let ttl = CounterState::ENTRY_TTL_SLOTS;
+        for (user, slot) in [(1, 0), (2, 1), (1, 2), (2, ttl)] {
+            state.credit_actions(&[user; 32], 1).unwrap();
+            let args = QueueAsyncArgs::parse(&[user; 32], &[]).unwrap();
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args, slot)
+                .unwrap();
+        }
+        let commitment = Commitment {
+            user: [1; 32],
+            slot: 0,
+            seq: state.next_seq().unwrap(),
+        };
+        commit::commit(&mut state.commitments, [7; 32], commitment, 150).unwrap();
+
+        let compacted = compact::compact(&mut *state, ttl + 3, 2, true).unwrap();
+        assert_eq!((compacted.expired, compacted.more), (2, true));
+        assert_eq!(compacted.commitments, 1);
+        assert!(!compacted.rebuilt);
+        assert_eq!(state.deposits, 2 * CounterState::DEPOSIT);
+        assert_eq!(state.actions(&[1; 32]), 1);
+
+        let compacted = compact::compact(&mut *state, ttl + 3, 2, true).unwrap();
+        assert_eq!((compacted.expired, compacted.more), (1, false));
+        assert_eq!(state.async_queue.len(), 1);
+        assert!(!compacted.rebuilt);
+
+        state.pop_async().unwrap();
+        let compacted = compact::compact(&mut *state, ttl + 3, 2, true).unwrap();
+        assert_eq!(compacted.expired, 0);
+        assert!(compacted.rebuilt);
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut state = CounterState::new();
+        for (user, slot) in [(1, 0), (2, 0), (1, 1)] {
+            state.credit_actions(&[user; 32], 1).unwrap();
+            let args = QueueAsyncArgs::parse(&[user; 32], &[]).unwrap();
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args, slot)
+                .unwrap();
+        }
+
+        let flushed = flush::flush(&mut *state, 2).unwrap();
+        assert_eq!((flushed.flushed, flushed.more), (2, true));
+        assert_eq!(state.async_queue.len(), 1);
+        // Flushed like expired, the action back and the deposit kept, with the bounty
+        assert_eq!(state.actions(&[1; 32]), 1);
+        assert_eq!(state.actions(&[2; 32]), 1);
+        let escrow = CounterState::CRANK_BOUNTY + CounterState::DEPOSIT;
+        assert_eq!(state.deposits, 2 * escrow);
+
+        let flushed = flush::flush(&mut *state, 2).unwrap();
+        assert_eq!((flushed.flushed, flushed.more), (1, false));
+        assert!(state.async_queue.is_empty());
+        assert_eq!(flush::flush(&mut *state, 2).unwrap(), Default::default());
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        let mut state = CounterState::new();
+        state.header = StateHeader::new(CounterState::VERSION);
+        state.header.layout_hash = CounterState::LAYOUT_HASH;
+        state.counter = 7;
+        state.credit_actions(&[1; 32], 3).unwrap();
+        let migrated = bytemuck::bytes_of(&*state);
+
+        // v1 is the same without the header's layout hash and the dead letters, grown since
+        let mut data = vec![0; migrated.len()];
+        data[..8].copy_from_slice(&migrated[..8]);
+        data[..4].copy_from_slice(&1_u32.to_le_bytes());
+        data[8..V1_LEN].copy_from_slice(&migrated[16..V1_LEN + 8]);
+        let v1 = data.clone();
+        migrate::migrate_state::<CounterState>(&mut data).unwrap();
+        assert!(data == migrated);
+
+        // Not grown yet
+        assert_eq!(
+            migrate::migrate_state::<CounterState>(&mut v1.clone()[..V1_LEN]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_migrate_from_v2() {
+        let mut state = CounterState::new();
+        state.header = StateHeader::new(CounterState::VERSION);
+        state.header.layout_hash = CounterState::LAYOUT_HASH;
+        state.counter = 7;
+        let migrated = bytemuck::bytes_of(&*state);
+
+        // v2 is the same without the relayed authorizations, grown since
+        let mut data = vec![0; migrated.len()];
+        data[..V2_LEN].copy_from_slice(&migrated[..V2_LEN]);
+        data[..4].copy_from_slice(&2_u32.to_le_bytes());
+        let v2 = data.clone();
+        migrate::migrate_state::<CounterState>(&mut data).unwrap();
+        assert!(data == migrated);
+
+        assert_eq!(
+            migrate::migrate_state::<CounterState>(&mut v2.clone()[..V2_LEN]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_close_needs_settling() {
+        let mut state = CounterState::new();
+        let mut header = StateHeader::new(CounterState::VERSION);
+        assert_eq!(close::check_settled(&mut *state, &header), Ok(()));
+
+        let not_settled = Err(ProgramError::Custom(close::NOT_SETTLED));
+        state.credit_actions(&[1; 32], 1).unwrap();
+        let args = QueueAsyncArgs::parse(&[1; 32], &[]).unwrap();
+        state
+            .queue_async(&CounterAsyncIx::Increment, &args, 0)
+            .unwrap();
+        assert_eq!(close::check_settled(&mut *state, &header), not_settled);
+        // Processed, its deposit is kept until collected
+        state.process_next_async().unwrap();
+        assert_eq!(close::check_settled(&mut *state, &header), not_settled);
+        state.deposits = 0;
+        assert_eq!(close::check_settled(&mut *state, &header), Ok(()));
+
+        // Unspent actions are owed to their users
+        state.credit_actions(&[1; 32], 1).unwrap();
+        assert_eq!(close::check_settled(&mut *state, &header), not_settled);
+        state
+            .queue_async(&CounterAsyncIx::Increment, &args, 0)
+            .unwrap();
+        // So are failed ones, until purged
+        let failed = state.pop_async().unwrap();
+        state.dead_letter(&failed).unwrap();
+        state.debit_action(&[1; 32]).unwrap();
+        state.deposits = 0;
+        assert_eq!(close::check_settled(&mut *state, &header), not_settled);
+        state.purge_dead_letters();
+        assert_eq!(close::check_settled(&mut *state, &header), Ok(()));
+
+        header.fees.accrue(5).unwrap();
+        assert_eq!(close::check_settled(&mut *state, &header), not_settled);
+    }
+
+    #[test]
+    fn test_reveal_keeps_commit_priority() {
+        let mut state = CounterState::new();
+        state.credit_actions(&[1; 32], 1).unwrap();
+        state.credit_actions(&[2; 32], 1).unwrap();
+        let args = |user| QueueAsyncArgs {
+            payload: CounterPayload::new([user; 32], 1).unwrap(),
+            priority_bid: 0,
+        };
+
+        // Alice commits before Bob queues in the open
+        let seq = state.next_seq().unwrap();
+        let commitment = Commitment {
+            user: [1; 32],
+            slot: 0,
+            seq,
+        };
+        commit::commit(&mut state.commitments, [7; 32], commitment, 150).unwrap();
+        state
+            .queue_async(&CounterAsyncIx::Increment, &args(2), 1)
+            .unwrap();
+
+        let revealed = commit::reveal(&mut state.commitments, &[7; 32], &[1; 32], 3, 150).unwrap();
+        state
+            .queue_async_at(
+                &CounterAsyncIx::Increment,
+                &args(1),
+                revealed.slot,
+                revealed.seq,
+            )
+            .unwrap();
+
+        let users: Vec<u8> = std::iter::from_fn(|| state.pop_async())
+            .map(|node| node.value.user[0])
+            .collect();
+        assert_eq!(users, [1, 2]);
+    }
+
+    #[test]
+    fn test_shuffled_drain_processes_whole_slot() {
+        let mut state = CounterState::new();
+        state.credit_actions(&[0; 32], 5).unwrap();
+        let args = QueueAsyncArgs::parse(&[0; 32], &[]).unwrap();
+        for _ in 0..5 {
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args, 10)
+                .unwrap();
+        }
+
+        // Not due until the next slot
+        assert_eq!(state.process_next_shuffled(10, &[1; 32]), Ok(0));
+        assert_eq!(state.process_next_shuffled(11, &[1; 32]), Ok(5));
+        assert_eq!(state.counter, 5);
+        assert!(state.async_queue.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_refunds_escrow() {
+        let mut state = CounterState::new();
+        state.credit_actions(&[1; 32], 1).unwrap();
+        let args =
+            QueueAsyncArgs::parse(&[1; 32], &[1, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0])
+                .unwrap();
+        state
+            .queue_async(&CounterAsyncIx::Increment, &args, 0)
+            .unwrap();
+        let (_addr, node) = state.peek_async().unwrap();
+        let key = node.key;
+
+        assert_eq!(
+            state.cancel_async(&[2; 32], &key),
+            Err(ProgramError::IncorrectAuthority)
+        );
+        assert_eq!(
+            state.cancel_async(&[1; 32], &key),
+            Ok(CounterState::CRANK_BOUNTY + CounterState::DEPOSIT + 7)
+        );
+        assert!(state.async_queue.is_empty());
+        assert_eq!(state.actions(&[1; 32]), 1);
+        assert_eq!(
+            state.cancel_async(&[1; 32], &key),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_replace_goes_to_back() {
+        let mut state = CounterState::new();
+        let args = |user, amount| QueueAsyncArgs {
+            payload: CounterPayload::new([user; 32], amount).unwrap(),
+            priority_bid: 0,
+        };
+        for user in [1, 2] {
+            state.credit_actions(&[user; 32], 1).unwrap();
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args(user, 1), 0)
+                .unwrap();
+        }
+
+        assert_eq!(
+            state.replace_async(&[2; 32], 1, &CounterAsyncIx::Increment, &args(2, 3), 1),
+            Err(ProgramError::IncorrectAuthority)
+        );
+        assert_eq!(
+            state.replace_async(&[1; 32], 9, &CounterAsyncIx::Increment, &args(1, 3), 1),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            state.replace_async(&[1; 32], 1, &CounterAsyncIx::Increment, &args(1, 3), 1),
+            Ok(CounterState::CRANK_BOUNTY + CounterState::DEPOSIT)
+        );
+
+        let entries: Vec<(u8, u64)> = std::iter::from_fn(|| state.pop_async())
+            .map(|node| (node.value.user[0], node.value.args().unwrap().amount))
+            .collect();
+        assert_eq!(entries, [(2, 1), (1, 3)]);
+    }
+
+    #[test]
+    fn test_actions_are_per_user() {
+        let mut state = CounterState::new();
+        state.credit_actions(&[1; 32], 2).unwrap();
+        let args = |user| QueueAsyncArgs::parse(&[user; 32], &[]).unwrap();
+
+        // Someone else's refill doesn't pay for Bob's queueing
+        assert_eq!(
+            state.queue_async(&CounterAsyncIx::Increment, &args(2), 0),
+            Err(ProgramError::Custom(NO_ACTIONS))
+        );
+        for _ in 0..2 {
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args(1), 0)
+                .unwrap();
+        }
+        assert_eq!(
+            state.queue_async(&CounterAsyncIx::Increment, &args(1), 0),
+            Err(ProgramError::Custom(NO_ACTIONS))
+        );
+
+        // Spent balances make room for other users
+        assert_eq!(state.actions(&[1; 32]), 0);
+        assert_eq!(state.balances.len(), 0);
+    }
+
+    #[test]
+    fn test_rejects_trailing_bytes() {
+        let user = [1; 32];
+        assert!(QueueAsyncArgs::parse(&user, &[0; 16]).is_ok());
+        assert_eq!(
+            QueueAsyncArgs::parse(&user, &[0; 24]).map(|args| args.priority_bid),
+            Err(ProgramError::Custom(strict::TRAILING_BYTES))
+        );
+    }
+
+    #[test]
+    fn test_seq_exhaustion() {
+        let mut state = CounterState::new();
+        state.seq = u64::MAX - 1;
+        state.credit_actions(&[1; 32], 2).unwrap();
+        let args = QueueAsyncArgs::parse(&[1; 32], &[]).unwrap();
+
+        state
+            .queue_async(&CounterAsyncIx::Increment, &args, 0)
+            .unwrap();
+        // Nothing is queued or spent
+        assert_eq!(
+            state.queue_async(&CounterAsyncIx::Increment, &args, 0),
+            Err(ProgramError::Custom(ordering::SEQ_EXHAUSTED))
+        );
+        assert_eq!(state.async_queue.len(), 1);
+        assert_eq!(state.actions(&[1; 32]), 1);
+    }
+
+    #[test]
+    fn test_referrer_is_carried() {
+        let data = [&5u64.to_le_bytes()[..], &0u64.to_le_bytes(), &[9; 32]].concat();
+        let args = QueueAsyncArgs::parse(&[1; 32], &data).unwrap();
+        assert_eq!(args.payload.referrer, [9; 32]);
+        assert_eq!(
+            QueueAsyncArgs::parse(&[1; 32], &data[..20]).map(|args| args.priority_bid),
+            Err(ProgramError::Custom(strict::TRAILING_BYTES))
+        );
+        let args = QueueAsyncArgs::parse(&[1; 32], &data[..16]).unwrap();
+        assert_eq!(args.payload.referrer, [0; 32]);
+
+        let mut state = CounterState::new();
+        state.credit_actions(&[1; 32], 1).unwrap();
+        let args = QueueAsyncArgs::parse(&[1; 32], &data).unwrap();
+        state
+            .queue_async(&CounterAsyncIx::Increment, &args, 0)
+            .unwrap();
+        assert_eq!(state.pop_async().unwrap().value.referrer, [9; 32]);
+    }
+
+    #[test]
+    fn test_processing_keeps_deposit() {
+        let mut state = CounterState::new();
+        state.credit_actions(&[1; 32], 2).unwrap();
+        let args = QueueAsyncArgs::parse(&[1; 32], &[]).unwrap();
+        for _ in 0..2 {
+            state
+                .queue_async(&CounterAsyncIx::Increment, &args, 0)
+                .unwrap();
+        }
+
+        // The cancelled action's deposit is refunded instead
+        let (_addr, node) = state.peek_async().unwrap();
+        let key = node.key;
+        state.cancel_async(&[1; 32], &key).unwrap();
+        state.process_next_async().unwrap();
+        assert_eq!(state.deposits, CounterState::DEPOSIT);
+    }
+
+    /// The counter run through the dispatch on a [`Host`], with a client for its state
+    fn host() -> (Host, CounterClient) {
+        let program_id = solana_pubkey::Pubkey::new_unique();
+        let mut host = Host::new(program_id, dispatch::process_with::<CounterProgram, Tag>);
+        let state = host.create_state::<CounterState>();
+        (host, CounterClient::new(program_id, state))
+    }
+
+    #[test]
+    fn test_drain_dead_letters_failures() {
+        let (mut host, client) = host();
+        let state = client.state;
+        let user = host.user(0);
+        for _ in 0..3 {
+            host.send(&client.refill_actions(&user, &[])).unwrap();
+        }
+
+        // Decrementing past zero fails, and mustn't hold up the increment behind it
+        host.send(&client.decrement(&user, 5, 0, &Default::default()))
+            .unwrap();
+        host.send(&client.increment(&user, 3, 0, &Default::default()))
+            .unwrap();
+        // What the escrow transfers would have paid, CPIs don't run on the host
+        let escrow = CounterState::CRANK_BOUNTY + CounterState::DEPOSIT;
+        host.airdrop(&state, 2 * escrow);
+        host.slot = 1;
+        let cranker = host.user(0);
+        host.send(&client.drain(&cranker)).unwrap();
+
+        let counter = host.state::<CounterState>(&state);
+        assert_eq!(counter.counter, 3);
+        assert!(counter.async_queue.is_empty());
+        assert_eq!(counter.dead_letters.len(), 1);
+        assert_eq!(counter.header.stats.total_processed, 2);
+        // The failed decrement's action is given back, its deposit kept like the increment's
+        assert_eq!(counter.actions(&user.to_bytes()), 2);
+        assert_eq!(counter.deposits, 2 * CounterState::DEPOSIT);
+        assert_eq!(host.lamports(&cranker), 2 * CounterState::CRANK_BOUNTY);
+        assert_eq!(host.lamports(&state), counter.deposits);
+
+        // Only the authority purges
+        let other = host.user(0);
+        assert!(host.send(&client.purge_dead_letters(&other)).is_err());
+        host.send(&client.purge_dead_letters(&user)).unwrap();
+        assert!(host.state::<CounterState>(&state).dead_letters.is_empty());
+    }
+
+    #[test]
+    fn test_drain_stops_at_max_items() {
+        let (mut host, client) = host();
+        let state = client.state;
+        let user = host.user(0);
+        for _ in 0..3 {
+            host.send(&client.refill_actions(&user, &[])).unwrap();
+            host.send(&client.increment(&user, 1, 0, &Default::default()))
+                .unwrap();
+        }
+        let escrow = CounterState::CRANK_BOUNTY + CounterState::DEPOSIT;
+        host.airdrop(&state, 3 * escrow);
+        host.slot = 1;
+
+        // Each drain resumes where the last one stopped
+        let cranker = host.user(0);
+        let args = DrainArgs {
+            max_items: 2,
+            nonce: None,
+        };
+        host.send(&client.drain_with(&cranker, args)).unwrap();
+        let counter = host.state::<CounterState>(&state);
+        assert_eq!(counter.counter, 2);
+        assert_eq!(counter.async_queue.len(), 1);
+        assert!(counter.header.cursor.is_partial());
+        assert_eq!(host.lamports(&cranker), 2 * CounterState::CRANK_BOUNTY);
+
+        host.send(&client.drain_with(&cranker, args)).unwrap();
+        let counter = host.state::<CounterState>(&state);
+        assert_eq!(counter.counter, 3);
+        assert!(!counter.header.cursor.is_partial());
+    }
+
+    #[test]
+    fn test_deposits_keep_bids() {
+        let (mut host, client) = host();
+        let state = client.state;
+        let user = host.user(0);
+        for _ in 0..3 {
+            host.send(&client.refill_actions(&user, &[])).unwrap();
+        }
+        let escrow = CounterState::CRANK_BOUNTY + CounterState::DEPOSIT;
+        for bid in [100, 7] {
+            host.send(&client.increment(&user, 1, bid, &Default::default()))
+                .unwrap();
+        }
+        host.airdrop(&state, 2 * escrow + 107);
+        host.slot = 1;
+        let cranker = host.user(0);
+        host.send(&client.drain(&cranker)).unwrap();
+
+        // Processed actions keep their bids along with their deposits
+        let counter = host.state::<CounterState>(&state);
+        assert_eq!(counter.counter, 2);
+        assert_eq!(counter.deposits, 2 * CounterState::DEPOSIT + 107);
+        assert_eq!(host.lamports(&state), counter.deposits);
+
+        // As do expired ones
+        host.send(&client.increment(&user, 1, 50, &Default::default()))
+            .unwrap();
+        host.airdrop(&state, escrow + 50);
+        host.slot += CounterState::ENTRY_TTL_SLOTS + 1;
+        host.send(&client.compact(&cranker, 8, false)).unwrap();
+        let counter = host.state::<CounterState>(&state);
+        assert!(counter.async_queue.is_empty());
+        assert_eq!(counter.deposits, 3 * CounterState::DEPOSIT + 157);
+        assert_eq!(host.lamports(&state), counter.deposits);
+        assert_eq!(host.lamports(&cranker), 3 * CounterState::CRANK_BOUNTY);
+    }
+
+    #[test]
+    fn test_sessions_need_a_state() {
+        let (mut host, client) = host();
+        let program_id = host.program_id;
+        let (user, key) = (host.user(0), host.user(0));
+        host.send(&client.refill_actions(&user, &[])).unwrap();
+        let params = SessionParams {
+            key: key.to_bytes(),
+            expiry: 10,
+            ixn_mask: 1 << CounterAsyncIx::Increment as u64,
+        };
+
+        let long = host.create_account(Session::LEN + 1, &program_id, 0);
+        assert_eq!(
+            host.send(&client.create_session(&user, &long, &params)),
+            Err(ProgramError::InvalidAccountData)
+        );
+        // Only for initialized states of the program, not any account named as one
+        let session = host.create_account(Session::LEN, &program_id, 0);
+        let uninitialized = CounterClient::new(program_id, host.create_state::<CounterState>());
+        assert_eq!(
+            host.send(&uninitialized.create_session(&user, &session, &params)),
+            Err(ProgramError::InvalidAccountData)
+        );
+        let foreign = host.create_account(CounterState::LEN, &Default::default(), 0);
+        assert_eq!(
+            host.send(
+                &CounterClient::new(program_id, foreign).create_session(&user, &session, &params)
+            ),
+            Err(ProgramError::IllegalOwner)
+        );
+
+        host.send(&client.create_session(&user, &session, &params))
+            .unwrap();
+        let increment = client.increment(&user, 1, 0, &Default::default());
+        host.send(&client.queue_delegated(&key, &user, &session, &increment.data[1..]))
+            .unwrap();
+        let counter = host.state::<CounterState>(&client.state);
+        assert_eq!(counter.async_queue.len(), 1);
+        assert_eq!(counter.actions(&user.to_bytes()), 0);
+    }
+
+    #[test]
+    fn test_relayed_once() {
+        let (mut host, client) = host();
+        let (relayer, owner) = (host.user(0), host.user(0));
+        host.send(&client.refill_actions(&owner, &[])).unwrap();
+        host.slot = 100;
+        let increment = client.increment(&owner, 1, 0, &Default::default());
+        let relayed = |expiry| {
+            apq_client::relay::relayed(
+                &client,
+                &relayer,
+                &owner,
+                expiry,
+                &increment.data[1..],
+                &[0; 64],
+            )
+        };
+
+        host.send_transaction(&relayed(110)).unwrap();
+        let counter = host.state::<CounterState>(&client.state);
+        assert_eq!(counter.async_queue.len(), 1);
+        assert_eq!(counter.relayed.len(), 1);
+
+        // Relayed again within its window
+        assert_eq!(
+            host.send_transaction(&relayed(110)),
+            Err(ProgramError::AccountAlreadyInitialized)
+        );
+        let [_, queue] = relayed(110);
+        assert_eq!(
+            host.send_transaction(&[queue]),
+            Err(ProgramError::Custom(relay::UNAUTHORIZED))
+        );
+        host.slot = 111;
+        assert_eq!(
+            host.send_transaction(&relayed(110)),
+            Err(ProgramError::Custom(relay::UNAUTHORIZED))
+        );
+
+        // Another authorization, the expired one purged to make room
+        host.send(&client.refill_actions(&owner, &[])).unwrap();
+        host.send_transaction(&relayed(120)).unwrap();
+        let counter = host.state::<CounterState>(&client.state);
+        assert_eq!(counter.async_queue.len(), 2);
+        assert_eq!(counter.relayed.len(), 1);
+    }
+
+    #[test]
+    fn test_relayed_per_owner() {
+        let (mut host, client) = host();
+        let (relayer, alice, bob) = (host.user(0), host.user(0), host.user(0));
+        host.send(&client.refill_actions(&alice, &[])).unwrap();
+        host.send(&client.refill_actions(&bob, &[])).unwrap();
+        host.slot = 100;
+        let increment = client.increment(&alice, 1, 0, &Default::default());
+        let relayed = |owner| {
+            apq_client::relay::relayed(
+                &client,
+                &relayer,
+                owner,
+                110,
+                &increment.data[1..],
+                &[0; 64],
+            )
+        };
+
+        // Both sign the same payload with the same expiry
+        host.send_transaction(&relayed(&alice)).unwrap();
+        host.send_transaction(&relayed(&bob)).unwrap();
+        let counter = host.state::<CounterState>(&client.state);
+        assert_eq!(counter.async_queue.len(), 2);
+        assert_eq!(counter.relayed.len(), 2);
+        assert!(counter
+            .relayed
+            .iter()
+            .all(|(_, paid_by)| *paid_by == relayer.to_bytes()));
+    }
+
+    #[test]
+    fn test_failed_entry_leaves_state_untouched() {
+        let mut state = CounterState::new();
+        state.credit_actions(&[1; 32], 1).unwrap();
+        let args = QueueAsyncArgs::parse(&[1; 32], &5_u64.to_le_bytes()).unwrap();
+        state
+            .queue_async(&CounterAsyncIx::Decrement, &args, 0)
+            .unwrap();
+        let entry = state.pop_entry().unwrap();
+        let before = bytemuck::bytes_of(&*state).to_vec();
+        assert!(state.process_entry(&entry).is_err());
+        assert!(bytemuck::bytes_of(&*state) == before);
+    }
+
+    #[test]
+    fn test_client_is_generated() {
+        let idl = crate::idl::idl(Default::default());
+        let source = apq_client::codegen::client(&idl, "CounterClient").unwrap();
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/client.rs");
+        apq_client::codegen::assert_generated(&source, path);
+    }
+
+    #[test]
+    fn test_client_matches_wire_format() {
+        let client = CounterClient::new(Default::default(), Default::default());
+        let user = solana_pubkey::Pubkey::new_unique();
+
+        let ix = client.refill_actions(&user, &[]);
+        assert_eq!(ix.data[0], dispatch::tag::SYNC);
+        assert_eq!(
+            ix.data[1..],
+            (CounterSyncIx::RefillActions as u64).to_le_bytes()
+        );
+
+        let vault = Vault::lamports([3; 32], 1_000);
+        let ix = client.set_vault(&user, vault, &[]);
+        assert_eq!(
+            CounterSyncIx::decode_owned(&ix.data[1..]),
+            Ok(CounterSyncIx::SetVault)
+        );
+        assert_eq!(ix.data[9..], *bytemuck::bytes_of(&vault));
+
+        let referrer = solana_pubkey::Pubkey::new_unique();
+        let ix = client.increment(&user, 5, 7, &referrer);
+        let (&tag, data) = ix.data.split_first().unwrap();
+        assert_eq!(tag, dispatch::tag::QUEUE);
+        assert_eq!(
+            *CounterAsyncIx::from_bytes(data).unwrap(),
+            CounterAsyncIx::Increment
+        );
+        let args = QueueAsyncArgs::parse(&user.to_bytes(), &data[8..]).unwrap();
+        assert_eq!(
+            (args.payload.args().unwrap().amount, args.priority_bid),
+            (5, 7)
+        );
+        assert_eq!(args.payload.referrer, referrer.to_bytes());
+
+        // The vault is the account the IDL declares after the user, writable
+        let vault = solana_pubkey::Pubkey::new_unique();
+        let ix = client.collect_deposits(&user, &vault, &[]);
+        assert_eq!(
+            ix.accounts[2],
+            solana_instruction::AccountMeta::new(vault, false)
+        );
+    }
+
+    #[test]
+    fn test_client_decodes_instructions() {
+        let client = CounterClient::new(Default::default(), Default::default());
+        let user = solana_pubkey::Pubkey::new_unique();
+
+        let referrer = solana_pubkey::Pubkey::new_unique();
+        let ix = client.increment(&user, 5, 7, &referrer);
+        let decoded = CounterClient::decode_instruction(&ix.data, &ix.accounts).unwrap();
+        assert_eq!(decoded.name, "increment");
+        assert_eq!(decoded.variant, Some(CounterAsyncIx::Increment as u64));
+        assert_eq!(
+            decoded.to_string(),
+            format!("increment(amount: 5, priority_bid: 7, referrer: {referrer}) by {user}")
+        );
+
+        let ix = client.set_vault(&user, Vault::lamports([3; 32], 1_000), &[]);
+        let decoded = CounterClient::decode_instruction(&ix.data, &ix.accounts).unwrap();
+        assert_eq!(
+            (decoded.name.as_str(), decoded.args.len()),
+            ("set_vault", 1)
+        );
+
+        let ix = client.drain(&user);
+        let decoded = CounterClient::decode_instruction(&ix.data, &ix.accounts).unwrap();
+        assert_eq!(decoded.name, "drain");
+        assert!(CounterClient::decode_instruction(&ix.data[..0], &ix.accounts).is_err());
+
+        // The compact format is seven bytes shorter and dispatches the same
+        let compact = CounterClient {
+            builder: client.builder.with_compact_variants(),
+        };
+        let full = client.increment(&user, 5, 7, &Default::default());
+        let ix = compact.increment(&user, 5, 7, &Default::default());
+        assert_eq!(ix.data.len(), full.data.len() - 7);
+        let (tag, data) = Compact::decode(&ix.data).unwrap();
+        let (full_tag, full_data) = Tag::decode(&full.data).unwrap();
+        assert_eq!((tag, &*data), (full_tag, &*full_data));
+        assert_eq!(
+            CounterClient::decode_compact_instruction(&ix.data, &ix.accounts),
+            CounterClient::decode_instruction(&full.data, &full.accounts)
+        );
+    }
+
+    #[test]
+    fn test_sync_ix_decoders_agree() {
+        use apq_testkit::differential;
+
+        let inputs = differential::inputs(24, 512, 1057);
+        differential::assert_same_decoding(
+            &inputs,
+            differential::decode::<CounterSyncIx>,
+            |bytes| CounterSyncIx::decode_owned(bytes).ok(),
+        );
+    }
 }
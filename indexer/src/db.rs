@@ -0,0 +1,328 @@
+//! The SQLite database of queue entries and drains
+
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde_json::{json, Value};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        key TEXT PRIMARY KEY,
+        user TEXT NOT NULL,
+        ixn INTEGER NOT NULL,
+        priority_bid INTEGER NOT NULL DEFAULT 0,
+        status TEXT NOT NULL,
+        result INTEGER,
+        refund INTEGER,
+        queued_slot INTEGER,
+        queued_signature TEXT,
+        closed_slot INTEGER,
+        closed_signature TEXT
+    );
+    CREATE INDEX IF NOT EXISTS entries_by_user ON entries (user, status);
+    CREATE TABLE IF NOT EXISTS drains (
+        signature TEXT NOT NULL,
+        slot INTEGER NOT NULL,
+        cranker TEXT NOT NULL,
+        drained INTEGER NOT NULL,
+        bounty INTEGER NOT NULL
+    );
+";
+
+/// Where a queued instruction is at
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pending,
+    /// Processed successfully
+    Executed,
+    /// Processed with an error, see [`Entry::result`]
+    Failed,
+    Cancelled,
+}
+
+impl Status {
+    pub const ALL: [Status; 4] = [
+        Status::Pending,
+        Status::Executed,
+        Status::Failed,
+        Status::Cancelled,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Executed => "executed",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        Status::ALL
+            .into_iter()
+            .find(|known| known.as_str() == status)
+    }
+}
+
+/// How an entry left the queue
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Executed,
+    /// Processed with this program error code
+    Failed(u64),
+    Cancelled {
+        refund: u64,
+    },
+}
+
+impl Outcome {
+    fn status(&self) -> Status {
+        match self {
+            Outcome::Executed => Status::Executed,
+            Outcome::Failed(_) => Status::Failed,
+            Outcome::Cancelled { .. } => Status::Cancelled,
+        }
+    }
+}
+
+/// Where an event was seen
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Seen {
+    pub slot: u64,
+    pub signature: String,
+}
+
+/// A queued instruction as far as its events tell. Entries first seen processed or
+/// cancelled, e.g. queued before the indexer started, lack what only the queued event has.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Hex of the queue key bytes
+    pub key: String,
+    pub user: String,
+    pub ixn: u64,
+    pub priority_bid: u64,
+    pub status: Status,
+    /// Program error code of a failed entry
+    pub result: Option<u64>,
+    /// Lamports refunded on cancellation
+    pub refund: Option<u64>,
+    pub queued: Option<Seen>,
+    /// Where it was processed or cancelled
+    pub closed: Option<Seen>,
+}
+
+impl Entry {
+    pub fn to_json(&self) -> Value {
+        let seen = |seen: &Option<Seen>| {
+            seen.as_ref()
+                .map(|seen| json!({ "slot": seen.slot, "signature": seen.signature }))
+        };
+        json!({
+            "key": self.key,
+            "user": self.user,
+            "ixn": self.ixn,
+            "priority_bid": self.priority_bid,
+            "status": self.status.as_str(),
+            "result": self.result,
+            "refund": self.refund,
+            "queued": seen(&self.queued),
+            "closed": seen(&self.closed),
+        })
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let seen = |slot: Option<i64>, signature: Option<String>| {
+            Some(Seen {
+                slot: slot? as u64,
+                signature: signature?,
+            })
+        };
+        let status: String = row.get("status")?;
+        Ok(Entry {
+            key: row.get("key")?,
+            user: row.get("user")?,
+            ixn: row.get::<_, i64>("ixn")? as u64,
+            priority_bid: row.get::<_, i64>("priority_bid")? as u64,
+            status: Status::parse(&status).unwrap_or(Status::Pending),
+            result: row.get::<_, Option<i64>>("result")?.map(|code| code as u64),
+            refund: row
+                .get::<_, Option<i64>>("refund")?
+                .map(|refund| refund as u64),
+            queued: seen(row.get("queued_slot")?, row.get("queued_signature")?),
+            closed: seen(row.get("closed_slot")?, row.get("closed_signature")?),
+        })
+    }
+}
+
+/// SQLite stores signed integers, so `u64`s are stored as their bits
+fn int(value: u64) -> i64 {
+    value as i64
+}
+
+pub struct Db {
+    connection: Connection,
+}
+
+impl Db {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        Self::init(Connection::open(path).map_err(|err| err.to_string())?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, String> {
+        Self::init(Connection::open_in_memory().map_err(|err| err.to_string())?)
+    }
+
+    fn init(connection: Connection) -> Result<Self, String> {
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|err| err.to_string())?;
+        Ok(Db { connection })
+    }
+
+    pub fn queued(
+        &self,
+        key: &str,
+        user: &str,
+        ixn: u64,
+        priority_bid: u64,
+        seen: &Seen,
+    ) -> Result<(), String> {
+        self.connection
+            .execute(
+                "INSERT INTO entries
+                    (key, user, ixn, priority_bid, status, queued_slot, queued_signature)
+                VALUES (?1, ?2, ?3, ?4, 'pending', ?5, ?6)
+                ON CONFLICT (key) DO UPDATE SET
+                    priority_bid = excluded.priority_bid,
+                    queued_slot = excluded.queued_slot,
+                    queued_signature = excluded.queued_signature",
+                params![
+                    key,
+                    user,
+                    int(ixn),
+                    int(priority_bid),
+                    int(seen.slot),
+                    seen.signature
+                ],
+            )
+            .map(drop)
+            .map_err(|err| err.to_string())
+    }
+
+    /// Closes the entry under `key`, inserting it if it was never seen queued
+    pub fn closed(
+        &self,
+        key: &str,
+        user: &str,
+        ixn: u64,
+        outcome: Outcome,
+        seen: &Seen,
+    ) -> Result<(), String> {
+        let (result, refund) = match outcome {
+            Outcome::Executed => (None, None),
+            Outcome::Failed(code) => (Some(code), None),
+            Outcome::Cancelled { refund } => (None, Some(refund)),
+        };
+        self.connection
+            .execute(
+                "INSERT INTO entries
+                    (key, user, ixn, status, result, refund, closed_slot, closed_signature)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT (key) DO UPDATE SET
+                    status = excluded.status,
+                    result = excluded.result,
+                    refund = excluded.refund,
+                    closed_slot = excluded.closed_slot,
+                    closed_signature = excluded.closed_signature",
+                params![
+                    key,
+                    user,
+                    int(ixn),
+                    outcome.status().as_str(),
+                    result.map(int),
+                    refund.map(int),
+                    int(seen.slot),
+                    seen.signature
+                ],
+            )
+            .map(drop)
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn drained(
+        &self,
+        cranker: &str,
+        drained: u64,
+        bounty: u64,
+        seen: &Seen,
+    ) -> Result<(), String> {
+        self.connection
+            .execute(
+                "INSERT INTO drains (signature, slot, cranker, drained, bounty)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    seen.signature,
+                    int(seen.slot),
+                    cranker,
+                    int(drained),
+                    int(bounty)
+                ],
+            )
+            .map(drop)
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn entry(&self, key: &str) -> Result<Option<Entry>, String> {
+        self.connection
+            .query_row(
+                "SELECT * FROM entries WHERE key = ?1",
+                [key],
+                Entry::from_row,
+            )
+            .optional()
+            .map_err(|err| err.to_string())
+    }
+
+    /// Up to `limit` entries, optionally of one user and with one status, most recent first
+    pub fn entries(
+        &self,
+        user: Option<&str>,
+        status: Option<Status>,
+        limit: u32,
+    ) -> Result<Vec<Entry>, String> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT * FROM entries
+                WHERE (?1 IS NULL OR user = ?1) AND (?2 IS NULL OR status = ?2)
+                ORDER BY COALESCE(closed_slot, queued_slot) DESC
+                LIMIT ?3",
+            )
+            .map_err(|err| err.to_string())?;
+        let rows = statement
+            .query_map(
+                params![user, status.map(|status| status.as_str()), limit],
+                Entry::from_row,
+            )
+            .map_err(|err| err.to_string())?;
+        rows.collect::<Result<_, _>>()
+            .map_err(|err| err.to_string())
+    }
+
+    /// How many entries there are with each status
+    pub fn counts(&self) -> Result<Vec<(Status, u64)>, String> {
+        Status::ALL
+            .into_iter()
+            .map(|status| {
+                self.connection
+                    .query_row(
+                        "SELECT COUNT(*) FROM entries WHERE status = ?1",
+                        [status.as_str()],
+                        |row| row.get::<_, i64>(0),
+                    )
+                    .map(|count| (status, count as u64))
+                    .map_err(|err| err.to_string())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,164 @@
+//! The HTTP API
+//!
+//! | Request | Response |
+//! |---|---|
+//! | `GET /entries?user=&status=&limit=` | Entries, most recent first, all filters optional |
+//! | `GET /entries/<key>` | The entry under the hex queue key |
+//! | `GET /stats` | Entries per status |
+//! | `POST /logs` | Webhook ingesting `{ "slot", "signature", "err", "logs" }` |
+
+use std::sync::Mutex;
+
+use apq_core::queue::QueueKey;
+use serde_json::{json, Value};
+use tiny_http::{Header, Response, Server};
+
+use crate::{db::Status, source::Logs, Indexer};
+
+/// Most entries one request returns
+const MAX_LIMIT: u32 = 1000;
+
+/// Answers requests until the server shuts down
+pub fn serve<K: QueueKey>(server: &Server, indexer: &Mutex<Indexer<K>>) {
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    for mut request in server.incoming_requests() {
+        let mut body = String::new();
+        let (status, value) = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => {
+                let indexer = indexer
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                handle(&indexer, request.method().as_str(), request.url(), &body)
+            }
+            Err(err) => (400, json!({ "error": err.to_string() })),
+        };
+        let response = Response::from_string(value.to_string())
+            .with_status_code(status)
+            .with_header(content_type.clone());
+        if let Err(err) = request.respond(response) {
+            eprintln!("responding failed: {err}");
+        }
+    }
+}
+
+/// The status code and body answering `method` on `url`
+pub fn handle<K: QueueKey>(
+    indexer: &Indexer<K>,
+    method: &str,
+    url: &str,
+    body: &str,
+) -> (u16, Value) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let result = match (method, path.trim_end_matches('/')) {
+        ("GET", "/entries") => entries(indexer, query),
+        ("GET", "/stats") => indexer.db.counts().map(|counts| {
+            let counts: serde_json::Map<_, _> = counts
+                .into_iter()
+                .map(|(status, count)| (status.as_str().to_owned(), json!(count)))
+                .collect();
+            (200, Value::Object(counts))
+        }),
+        ("GET", path) if path.starts_with("/entries/") => indexer
+            .db
+            .entry(&path["/entries/".len()..])
+            .map(|entry| match entry {
+                Some(entry) => (200, entry.to_json()),
+                None => (404, json!({ "error": "no such entry" })),
+            }),
+        ("POST", "/logs") => ingest(indexer, body),
+        _ => Ok((404, json!({ "error": "no such route" }))),
+    };
+    result.unwrap_or_else(|err| (500, json!({ "error": err })))
+}
+
+fn entries<K: QueueKey>(indexer: &Indexer<K>, query: &str) -> Result<(u16, Value), String> {
+    let (mut user, mut status, mut limit) = (None, None, 100);
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "user" => user = Some(value),
+            "status" => match Status::parse(value) {
+                Some(known) => status = Some(known),
+                None => return Ok((400, json!({ "error": format!("unknown status {value}") }))),
+            },
+            "limit" => match value.parse::<u32>() {
+                Ok(value) => limit = value.min(MAX_LIMIT),
+                Err(_) => return Ok((400, json!({ "error": format!("bad limit {value}") }))),
+            },
+            _ => return Ok((400, json!({ "error": format!("unknown parameter {name}") }))),
+        }
+    }
+    let entries = indexer.db.entries(user, status, limit)?;
+    let entries: Vec<Value> = entries.iter().map(|entry| entry.to_json()).collect();
+    Ok((200, Value::Array(entries)))
+}
+
+fn ingest<K: QueueKey>(indexer: &Indexer<K>, body: &str) -> Result<(u16, Value), String> {
+    let logs = serde_json::from_str::<Value>(body).map_err(|err| err.to_string());
+    let logs = logs.and_then(|value| {
+        let slot = value["slot"].as_u64().ok_or("no slot")?;
+        Logs::from_json(slot, &value)
+    });
+    let logs = match logs {
+        Ok(logs) => logs,
+        Err(err) => return Ok((400, json!({ "error": err }))),
+    };
+    if logs.failed {
+        return Ok((200, json!({ "events": 0 })));
+    }
+    let events = indexer.ingest(logs.slot, &logs.signature, &logs.logs)?;
+    Ok((200, json!({ "events": events })))
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_core::{events::QueuedEvent, ordering::FifoKey};
+
+    use super::*;
+    use crate::{
+        db::Db,
+        key_hex,
+        tests::{invocation, keyed, PROGRAM},
+    };
+
+    #[test]
+    fn test_routes() {
+        let indexer = Indexer::<FifoKey>::new(PROGRAM, Db::open_in_memory().unwrap());
+        let key = FifoKey { slot: 1, seq: 0 };
+        let event = QueuedEvent {
+            user: [1; 32],
+            ixn: 0,
+            priority_bid: 0,
+        };
+        let body = json!({
+            "slot": 1,
+            "signature": "queue",
+            "logs": invocation(&PROGRAM, &[keyed(&event, &key)]),
+        });
+        let failed = json!({ "slot": 1, "signature": "x", "err": {}, "logs": body["logs"] });
+
+        assert_eq!(
+            handle(&indexer, "POST", "/logs", &failed.to_string()),
+            (200, json!({ "events": 0 }))
+        );
+        assert_eq!(
+            handle(&indexer, "POST", "/logs", &body.to_string()),
+            (200, json!({ "events": 1 }))
+        );
+        assert_eq!(handle(&indexer, "POST", "/logs", "{}").0, 400);
+
+        let (status, entry) = handle(&indexer, "GET", &format!("/entries/{}", key_hex(&key)), "");
+        assert_eq!((status, &entry["status"]), (200, &json!("pending")));
+        assert_eq!(handle(&indexer, "GET", "/entries/00", "").0, 404);
+
+        let user = solana_pubkey::Pubkey::from(event.user);
+        let query = format!("/entries?user={user}&status=pending&limit=5");
+        let (status, entries) = handle(&indexer, "GET", &query, "");
+        assert_eq!((status, entries.as_array().unwrap().len()), (200, 1));
+        assert_eq!(handle(&indexer, "GET", "/entries?status=lost", "").0, 400);
+
+        let (status, stats) = handle(&indexer, "GET", "/stats", "");
+        assert_eq!((status, &stats["pending"]), (200, &json!(1)));
+        assert_eq!(handle(&indexer, "DELETE", "/stats", "").0, 404);
+    }
+}
@@ -0,0 +1,196 @@
+//! Off-chain indexer of the queue events apq programs emit
+//!
+//! The indexer reads the [`apq_core::events`] a program logs, either from a websocket log
+//! subscription or pushed to its webhook by a Geyser plugin or log streaming service, and
+//! keeps every queued instruction and drain in a SQLite database. A small HTTP API serves
+//! pending, executed and cancelled entries per user to dashboards, see [`http`].
+//!
+//! Queue keys are program-defined, so an indexer is for one program and key type. Entries
+//! are told apart by their key, so index one state account per database.
+
+use std::marker::PhantomData;
+
+use apq_client::logs::{self, ProgramEvent};
+use apq_core::queue::QueueKey;
+use solana_pubkey::Pubkey;
+
+pub mod db;
+pub mod http;
+pub mod source;
+
+use db::{Db, Outcome, Seen};
+
+/// Hex of a queue key's bytes, which entries are stored under
+pub fn key_hex<K: QueueKey>(key: &K) -> String {
+    bytemuck::bytes_of(key)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Indexes one program's events into a database
+pub struct Indexer<K> {
+    pub program: Pubkey,
+    pub db: Db,
+    key: PhantomData<K>,
+}
+
+impl<K: QueueKey> Indexer<K> {
+    pub fn new(program: Pubkey, db: Db) -> Self {
+        Indexer {
+            program,
+            db,
+            key: PhantomData,
+        }
+    }
+
+    /// Stores the events of a successful transaction, returning how many there were
+    pub fn ingest(&self, slot: u64, signature: &str, logs: &[String]) -> Result<usize, String> {
+        let seen = Seen {
+            slot,
+            signature: signature.to_owned(),
+        };
+        let events = logs::parse::<K>(&self.program, logs);
+        for event in &events {
+            match event {
+                ProgramEvent::Queued(event, key) => self.db.queued(
+                    &key_hex(key),
+                    &Pubkey::from(event.user).to_string(),
+                    event.ixn,
+                    event.priority_bid,
+                    &seen,
+                )?,
+                ProgramEvent::Processed(event, key) => {
+                    let outcome = match event.result {
+                        0 => Outcome::Executed,
+                        code => Outcome::Failed(code),
+                    };
+                    let user = Pubkey::from(event.user).to_string();
+                    self.db
+                        .closed(&key_hex(key), &user, event.ixn, outcome, &seen)?
+                }
+                ProgramEvent::Cancelled(event, key) => {
+                    let outcome = Outcome::Cancelled {
+                        refund: event.refund,
+                    };
+                    let user = Pubkey::from(event.user).to_string();
+                    self.db
+                        .closed(&key_hex(key), &user, event.ixn, outcome, &seen)?
+                }
+                ProgramEvent::Drained(event) => self.db.drained(
+                    &Pubkey::from(event.cranker).to_string(),
+                    event.drained,
+                    event.bounty,
+                    &seen,
+                )?,
+                ProgramEvent::Initialized(_)
+                | ProgramEvent::Compacted(_)
+                | ProgramEvent::Flushed(_) => {}
+            }
+        }
+        Ok(events.len())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use apq_core::{
+        events::{CancelledEvent, DrainedEvent, Event, ProcessedEvent, QueuedEvent},
+        ordering::FifoKey,
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::*;
+    use crate::db::Status;
+
+    pub const PROGRAM: Pubkey = Pubkey::new_from_array([7; 32]);
+
+    /// What `emit!` logs
+    pub fn data_line(segments: &[&[u8]]) -> String {
+        let encoded: Vec<String> = segments.iter().map(|s| STANDARD.encode(s)).collect();
+        format!("Program data: {}", encoded.join(" "))
+    }
+
+    pub fn keyed<E: Event>(event: &E, key: &FifoKey) -> String {
+        data_line(&[
+            &E::DISCRIMINATOR,
+            bytemuck::bytes_of(event),
+            bytemuck::bytes_of(key),
+        ])
+    }
+
+    /// Logs of one invocation of `program` logging `lines`
+    pub fn invocation(program: &Pubkey, lines: &[String]) -> Vec<String> {
+        let mut logs = vec![format!("Program {program} invoke [1]")];
+        logs.extend_from_slice(lines);
+        logs.push(format!("Program {program} success"));
+        logs
+    }
+
+    #[test]
+    fn test_ingest() {
+        let indexer = Indexer::<FifoKey>::new(PROGRAM, Db::open_in_memory().unwrap());
+        let user = [1; 32];
+        let keys = [FifoKey { slot: 1, seq: 0 }, FifoKey { slot: 1, seq: 1 }];
+        let queued = keys.map(|key| {
+            let event = QueuedEvent {
+                user,
+                ixn: 1,
+                priority_bid: key.seq,
+            };
+            keyed(&event, &key)
+        });
+        let logs = invocation(&PROGRAM, &queued);
+        assert_eq!(indexer.ingest(1, "queue", &logs), Ok(2));
+
+        let processed = ProcessedEvent {
+            user,
+            ixn: 1,
+            result: 0,
+            referrer: [3; 32],
+        };
+        let cancelled = CancelledEvent {
+            user,
+            ixn: 1,
+            refund: 15_000,
+        };
+        let drained = DrainedEvent {
+            cranker: [2; 32],
+            drained: 1,
+            bounty: 5_000,
+        };
+        let logs = invocation(
+            &PROGRAM,
+            &[
+                keyed(&processed, &keys[0]),
+                data_line(&[&DrainedEvent::DISCRIMINATOR, bytemuck::bytes_of(&drained)]),
+                keyed(&cancelled, &keys[1]),
+            ],
+        );
+        assert_eq!(indexer.ingest(3, "drain", &logs), Ok(3));
+
+        let executed = indexer.db.entry(&key_hex(&keys[0])).unwrap().unwrap();
+        assert_eq!(executed.status, Status::Executed);
+        assert_eq!(executed.queued.unwrap().signature, "queue");
+        assert_eq!(executed.closed.unwrap().slot, 3);
+        let cancelled = indexer.db.entry(&key_hex(&keys[1])).unwrap().unwrap();
+        assert_eq!(
+            (cancelled.status, cancelled.refund, cancelled.priority_bid),
+            (Status::Cancelled, Some(15_000), 1)
+        );
+
+        let user = Pubkey::from(user).to_string();
+        let pending = indexer.db.entries(Some(&user), Some(Status::Pending), 10);
+        assert_eq!(pending, Ok(Vec::new()));
+        assert_eq!(indexer.db.entries(Some(&user), None, 10).unwrap().len(), 2);
+        assert_eq!(
+            indexer.db.counts(),
+            Ok(vec![
+                (Status::Pending, 0),
+                (Status::Executed, 1),
+                (Status::Failed, 0),
+                (Status::Cancelled, 1),
+            ])
+        );
+    }
+}
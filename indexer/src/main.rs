@@ -0,0 +1,115 @@
+//! Indexes a counter program's queue events
+//!
+//! ```text
+//! cargo run -p apq-indexer --release -- --ws ws://127.0.0.1:8900 --db counter.sqlite \
+//!     --listen 127.0.0.1:8080
+//! ```
+//!
+//! Subscribes to the program's logs and serves the HTTP API described in
+//! [`apq_indexer::http`], whose `POST /logs` webhook takes logs pushed from elsewhere. With
+//! `--ws none` the webhook is the only source.
+
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use apq_indexer::{db::Db, http, source::Subscription, Indexer};
+use counter::AsyncIxKey;
+use solana_pubkey::Pubkey;
+
+const COUNTER_PROGRAM_ID: Pubkey =
+    solana_pubkey::pubkey!("CounterProgram111111111111111111111111111111");
+
+struct Args {
+    ws: Option<String>,
+    program: Pubkey,
+    db: PathBuf,
+    listen: String,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            ws: Some("ws://127.0.0.1:8900".into()),
+            program: COUNTER_PROGRAM_ID,
+            db: "apq-indexer.sqlite".into(),
+            listen: "127.0.0.1:8080".into(),
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(flag) = argv.next() {
+            let value = argv.next().ok_or(format!("{flag} needs a value"))?;
+            match flag.as_str() {
+                "--ws" => args.ws = (value != "none").then_some(value),
+                "--program" => {
+                    args.program = value
+                        .parse()
+                        .map_err(|_| format!("`{value}` is not a pubkey"))?
+                }
+                "--db" => args.db = value.into(),
+                "--listen" => args.listen = value,
+                _ => return Err(format!("unknown flag {flag}")),
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Ingests the subscription's logs until it fails
+fn follow(ws: &str, indexer: &Mutex<Indexer<AsyncIxKey>>) -> Result<(), String> {
+    let program = indexer.lock().map_err(|err| err.to_string())?.program;
+    let mut subscription = Subscription::connect(ws, &program)?;
+    loop {
+        let logs = subscription.recv()?;
+        if logs.failed {
+            continue;
+        }
+        let indexer = indexer.lock().map_err(|err| err.to_string())?;
+        match indexer.ingest(logs.slot, &logs.signature, &logs.logs) {
+            Ok(0) => {}
+            Ok(events) => println!("{}: {events} events", logs.signature),
+            Err(err) => eprintln!("{}: {err}", logs.signature),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let db = match Db::open(&args.db) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("{}: {err}", args.db.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let server = match tiny_http::Server::http(&args.listen) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("{}: {err}", args.listen);
+            return ExitCode::FAILURE;
+        }
+    };
+    let indexer = Arc::new(Mutex::new(Indexer::new(args.program, db)));
+
+    let Some(ws) = args.ws else {
+        http::serve(&server, &indexer);
+        return ExitCode::SUCCESS;
+    };
+    let api = Arc::clone(&indexer);
+    thread::spawn(move || http::serve(&server, &api));
+    match follow(&ws, &indexer) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Transaction logs of a program from a websocket log subscription
+
+use std::net::TcpStream;
+
+use serde_json::{json, Value};
+use solana_pubkey::Pubkey;
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+/// A transaction's logs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Logs {
+    pub slot: u64,
+    pub signature: String,
+    /// Whether the transaction failed, so none of its events took effect
+    pub failed: bool,
+    pub logs: Vec<String>,
+}
+
+impl Logs {
+    /// Reads `{ "slot", "signature", "err", "logs" }`, the shape of both log notifications
+    /// and webhook bodies, with `err` optional
+    pub fn from_json(slot: u64, value: &Value) -> Result<Self, String> {
+        let logs = value["logs"]
+            .as_array()
+            .ok_or("no logs")?
+            .iter()
+            .map(|line| {
+                line.as_str()
+                    .map(str::to_owned)
+                    .ok_or("log line isn't a string")
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Logs {
+            slot,
+            signature: value["signature"]
+                .as_str()
+                .ok_or("no signature")?
+                .to_owned(),
+            failed: !value["err"].is_null(),
+            logs,
+        })
+    }
+}
+
+/// Logs of every transaction mentioning a program
+pub struct Subscription {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+}
+
+impl Subscription {
+    pub fn connect(url: &str, program: &Pubkey) -> Result<Self, String> {
+        let (mut socket, _response) = tungstenite::connect(url).map_err(|err| err.to_string())?;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "logsSubscribe",
+            "params": [{ "mentions": [program.to_string()] }, { "commitment": "confirmed" }],
+        });
+        socket
+            .send(Message::text(request.to_string()))
+            .map_err(|err| err.to_string())?;
+        Ok(Subscription { socket })
+    }
+
+    /// Blocks until the next transaction's logs
+    pub fn recv(&mut self) -> Result<Logs, String> {
+        loop {
+            let message = self.socket.read().map_err(|err| err.to_string())?;
+            if let Message::Text(text) = message {
+                if let Some(logs) = parse_notification(&text)? {
+                    return Ok(logs);
+                }
+            }
+        }
+    }
+}
+
+/// Parses a websocket message, `None` for anything but a log notification
+pub fn parse_notification(text: &str) -> Result<Option<Logs>, String> {
+    let message: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    if message["method"] != "logsNotification" {
+        return Ok(None);
+    }
+    let result = &message["params"]["result"];
+    let slot = result["context"]["slot"]
+        .as_u64()
+        .ok_or("logsNotification: no slot")?;
+    Logs::from_json(slot, &result["value"]).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_notification() {
+        let notification = r#"{"jsonrpc":"2.0","method":"logsNotification","params":{"result":{
+            "context":{"slot":5},"value":{"signature":"sig","err":null,
+            "logs":["Program log: hi"]}},"subscription":0}}"#;
+        assert_eq!(
+            parse_notification(notification),
+            Ok(Some(Logs {
+                slot: 5,
+                signature: "sig".to_owned(),
+                failed: false,
+                logs: vec!["Program log: hi".to_owned()],
+            }))
+        );
+
+        let failed = notification.replace("null", r#"{"InstructionError":[0,"Custom"]}"#);
+        assert!(parse_notification(&failed).unwrap().unwrap().failed);
+        assert_eq!(parse_notification(r#"{"result":0,"id":1}"#), Ok(None));
+    }
+}
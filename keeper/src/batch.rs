@@ -0,0 +1,112 @@
+//! Sizing drains to the compute budget
+//!
+//! Each drain costs a fixed overhead plus roughly the same for every entry it processes. The
+//! [`Estimator`] learns the per-entry cost from recent simulations and sizes drains to fit
+//! the transaction's compute units. When more is due than one drain fits, [`plan`] splits
+//! the backlog over several drains sent together, pinned to consecutive cursor nonces so each
+//! resumes where the previous one stops.
+
+use std::collections::VecDeque;
+
+/// Compute units a transaction may use at most
+pub const MAX_COMPUTE_UNITS: u32 = 1_400_000;
+
+/// Learns the compute units drains use from recent `(items, units)` samples
+#[derive(Clone, Debug)]
+pub struct Estimator {
+    /// Units a drain uses besides processing entries, including the compute budget
+    /// instructions and reading the state
+    pub overhead: u64,
+    /// How many recent samples to estimate from
+    pub window: usize,
+    samples: VecDeque<(u32, u64)>,
+}
+
+impl Estimator {
+    pub fn new(overhead: u64, window: usize) -> Self {
+        Estimator {
+            overhead,
+            window: window.max(1),
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Records a simulated drain of `items` entries using `units`
+    pub fn record(&mut self, items: u32, units: u64) {
+        if items == 0 {
+            return;
+        }
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((items, units));
+    }
+
+    /// Units per entry, the most of any recent sample so drains sized by it don't run out.
+    /// `None` before the first sample.
+    pub fn per_item(&self) -> Option<u64> {
+        self.samples
+            .iter()
+            .map(|&(items, units)| units.saturating_sub(self.overhead).div_ceil(items.into()))
+            .max()
+            .map(|units| units.max(1))
+    }
+
+    /// How many entries fit in `budget` compute units, at least one. `None` before the
+    /// first sample.
+    pub fn max_items(&self, budget: u32) -> Option<u32> {
+        let per_item = self.per_item()?;
+        let items = u64::from(budget).saturating_sub(self.overhead) / per_item;
+        Some(items.clamp(1, u32::MAX.into()) as u32)
+    }
+}
+
+/// Splits `due` entries into drains of at most `per_drain` each, at most `max_drains` of
+/// them. Whatever doesn't fit is left for after they land.
+pub fn plan(due: usize, per_drain: u32, max_drains: usize) -> Vec<u32> {
+    let per_drain = per_drain.max(1) as usize;
+    let mut drains = Vec::new();
+    let mut left = due;
+    while left > 0 && drains.len() < max_drains {
+        let items = left.min(per_drain);
+        drains.push(items as u32);
+        left -= items;
+    }
+    drains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimator() {
+        let mut estimator = Estimator::new(10_000, 2);
+        assert_eq!(estimator.max_items(200_000), None);
+
+        estimator.record(10, 60_000);
+        assert_eq!(estimator.per_item(), Some(5_000));
+        assert_eq!(estimator.max_items(200_000), Some(38));
+
+        // The costliest recent sample wins until it leaves the window
+        estimator.record(10, 110_000);
+        estimator.record(0, 1);
+        assert_eq!(estimator.per_item(), Some(10_000));
+        estimator.record(20, 50_000);
+        assert_eq!(estimator.per_item(), Some(10_000));
+        estimator.record(20, 50_000);
+        assert_eq!(estimator.per_item(), Some(2_000));
+
+        // Always at least one entry
+        assert_eq!(estimator.max_items(0), Some(1));
+    }
+
+    #[test]
+    fn test_plan() {
+        assert_eq!(plan(0, 10, 4), Vec::<u32>::new());
+        assert_eq!(plan(7, 10, 4), [7]);
+        assert_eq!(plan(25, 10, 4), [10, 10, 5]);
+        assert_eq!(plan(100, 10, 2), [10, 10]);
+        assert_eq!(plan(3, 0, 4), [1, 1, 1]);
+    }
+}
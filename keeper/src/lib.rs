@@ -0,0 +1,464 @@
+//! Off-chain keeper that drains a state account's queue whenever something in it is due
+//!
+//! The keeper follows the state account and new slots over a websocket subscription and
+//! checks the decoded state the way [`AsyncState::has_pending_async`] does by default, with
+//! the extra delay and drain window of the state's config account if it has one. When
+//! the head of the queue is due, it sends a drain with a compute budget and priority fee,
+//! pinned to the [`DrainCursor`](apq_core::cursor::DrainCursor) nonce it read so it never
+//! races another keeper, and retries with exponential backoff. Drains are sized to the
+//! compute budget from recent simulations, and a deep backlog is split over several drains
+//! sent at once, see [`batch`].
+//!
+//! Drains passing more accounts than a legacy transaction holds are sent as v0 transactions
+//! against a lookup table, see [`lookup`].
+
+use std::{
+    marker::PhantomData,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use apq_client::{instructions::InstructionBuilder, TryDecode};
+use apq_core::{
+    admin::pause,
+    config,
+    cursor::DrainArgs,
+    header::StateHeader,
+    ordering::{self, OrderingKey, Schedule},
+    window, AsyncState,
+};
+use solana_compute_budget_interface::ComputeBudgetInstruction;
+use solana_hash::Hash;
+use solana_keypair::Keypair;
+use solana_pubkey::Pubkey;
+use solana_signature::Signature;
+use solana_signer::Signer;
+use solana_transaction::Transaction;
+
+pub mod batch;
+pub mod lookup;
+pub mod rpc;
+
+use batch::{Estimator, MAX_COMPUTE_UNITS};
+use rpc::{Notification, Rpc, Subscription};
+
+/// Entries a drain is sized to before any simulation
+const CALIBRATION_ITEMS: u32 = 8;
+
+/// How many entries, up to `limit`, a drain at `now` would process, zero if draining is
+/// paused. Like the default [`AsyncState::has_pending_async`], a drain stops at the first
+/// entry in queue order that isn't due, and never takes entries queued at `now`. With a
+/// `config`, entries are due its extra delay later, and those whose drain window closed are
+/// expired rather than processed, see [`expiring`]. `now` is on the state's
+/// [`AsyncState::SCHEDULE`].
+pub fn pending<S: AsyncState>(
+    header: &StateHeader,
+    config: Option<&config::Config>,
+    state: &S,
+    now: u64,
+    limit: usize,
+) -> usize {
+    if is_paused(header, config) {
+        return 0;
+    }
+    let (due, closed_at, window) = cutoffs::<S>(config, now);
+    state
+        .entries()
+        .skip_while(|(key, _)| {
+            window::is_closed(key.slot(), closed_at, S::ASYNC_DELAY_SLOTS, window)
+        })
+        .take(limit)
+        .take_while(|(key, _)| {
+            OrderingKey::<S::AsyncIx, S::QueueArgs>::is_due(*key, due, S::ASYNC_DELAY_SLOTS)
+        })
+        .count()
+}
+
+/// Whether a drain at `now` would expire entries whose drain window closed, which only
+/// states with a `config` setting one have
+pub fn expiring<S: AsyncState>(
+    header: &StateHeader,
+    config: Option<&config::Config>,
+    state: &S,
+    now: u64,
+) -> bool {
+    let (_, closed_at, window) = cutoffs::<S>(config, now);
+    !is_paused(header, config) && window::is_head_closed(state, closed_at, window)
+}
+
+/// Whether a drain at `now` would process or expire anything, see [`pending`]
+pub fn has_pending<S: AsyncState>(
+    header: &StateHeader,
+    config: Option<&config::Config>,
+    state: &S,
+    now: u64,
+) -> bool {
+    pending(header, config, state, now, 1) != 0 || expiring(header, config, state, now)
+}
+
+fn is_paused(header: &StateHeader, config: Option<&config::Config>) -> bool {
+    header.admin.check_running(pause::DRAIN).is_err()
+        || config.is_some_and(|config| config.check_running(pause::DRAIN).is_err())
+}
+
+/// What a drain at `now` processes entries by, when drain windows close, and how long they
+/// are, the way the dispatch computes them
+fn cutoffs<S: AsyncState>(config: Option<&config::Config>, now: u64) -> (u64, u64, u64) {
+    let params = config.map(|config| config.params).unwrap_or_default();
+    let closed_at = now.saturating_sub(params.delay_slots);
+    let due = ordering::drain_cutoff(closed_at, now, S::ASYNC_DELAY_SLOTS, S::MIN_DRAIN_AGE_SLOTS);
+    (due, closed_at, params.drain_window as u64)
+}
+
+/// Exponential backoff between retries
+#[derive(Copy, Clone, Debug)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// How long to wait before retry number `attempt`, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base.saturating_mul(1 << attempt.min(16)).min(self.max)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            base: Duration::from_millis(200),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    /// Priority fee in micro-lamports per compute unit
+    pub priority_fee: u64,
+    /// Compute units each drain is sized to
+    pub compute_units: u32,
+    /// Most entries one drain processes regardless of the estimate, 0 for no limit
+    pub max_items: u32,
+    /// Most drains sent at once for a deep backlog
+    pub max_drains: usize,
+    /// Retries of a failed drain before waiting for the next notification
+    pub retries: u32,
+    pub backoff: Backoff,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            priority_fee: 0,
+            compute_units: 1_400_000,
+            max_items: 0,
+            max_drains: 4,
+            retries: 3,
+            backoff: Backoff::default(),
+        }
+    }
+}
+
+/// Builds a drain transaction with `compute_units` at `priority_fee` micro-lamports each
+pub fn drain_transaction(
+    builder: &InstructionBuilder,
+    keeper: &Keypair,
+    compute_units: u32,
+    priority_fee: u64,
+    args: DrainArgs,
+    blockhash: Hash,
+) -> Transaction {
+    let instructions = [
+        ComputeBudgetInstruction::set_compute_unit_limit(compute_units),
+        ComputeBudgetInstruction::set_compute_unit_price(priority_fee),
+        builder.drain_with(&keeper.pubkey(), args),
+    ];
+    Transaction::new_signed_with_payer(&instructions, Some(&keeper.pubkey()), &[keeper], blockhash)
+}
+
+/// Drains one state account of a program using the default dispatch
+pub struct Keeper<S> {
+    pub rpc: Rpc,
+    pub builder: InstructionBuilder,
+    pub keypair: Keypair,
+    pub config: Config,
+    pub estimator: Estimator,
+    /// The state's config account as of when the keeper started, see
+    /// [`Keeper::with_config`]
+    state_config: Option<config::Config>,
+    /// Account data as of the last notification
+    data: Vec<u8>,
+    slot: u64,
+    state: PhantomData<S>,
+}
+
+impl<S: AsyncState + TryDecode> Keeper<S> {
+    pub fn new(
+        rpc: Rpc,
+        program_id: Pubkey,
+        state: Pubkey,
+        keypair: Keypair,
+        config: Config,
+    ) -> Self {
+        Keeper {
+            rpc,
+            builder: InstructionBuilder::new(program_id, state),
+            keypair,
+            config,
+            estimator: Estimator::new(20_000, 16),
+            state_config: None,
+            data: Vec::new(),
+            slot: 0,
+            state: PhantomData,
+        }
+    }
+
+    /// Drains a state with the config account `config`, whose delay and drain window are read
+    /// when the keeper starts
+    pub fn with_config(mut self, config: Pubkey) -> Self {
+        self.builder = self.builder.with_config(config);
+        self
+    }
+
+    /// Follows the state account over the websocket at `ws_url` and drains it whenever
+    /// something is due. Only returns on a connection error.
+    pub fn run(&mut self, ws_url: &str) -> Result<(), String> {
+        if let Some(config) = self.builder.config {
+            let (_, data) = self.rpc.account(&config)?;
+            let state_config = config::Config::read(&data, &self.builder.state.to_bytes())
+                .map_err(|err| format!("config {config}: {err:?}"))?;
+            self.state_config = Some(state_config);
+        }
+        let mut subscription = Subscription::connect(ws_url, &self.builder.state)?;
+        (self.slot, self.data) = self.rpc.account(&self.builder.state)?;
+        self.crank()?;
+        loop {
+            match subscription.recv()? {
+                Notification::Account { slot, data } => {
+                    self.slot = self.slot.max(slot);
+                    self.data = data;
+                }
+                Notification::Slot(slot) => self.slot = self.slot.max(slot),
+            }
+            self.crank()?;
+        }
+    }
+
+    /// Sends drains if anything is due, retrying with backoff. Drains that still fail are
+    /// logged and left for the next notification, only failing to decode the state is an error.
+    pub fn crank(&mut self) -> Result<Vec<Signature>, String> {
+        let state = S::try_decode(&self.data).map_err(|err| format!("{err:?}"))?;
+        let header = StateHeader::read(&self.data).map_err(|err| format!("{err:?}"))?;
+        let config = self.state_config.as_ref();
+        let now = self.now();
+        let due = pending(&header, config, &*state, now, usize::MAX);
+        if due == 0 && !expiring(&header, config, &*state, now) {
+            return Ok(Vec::new());
+        }
+        // Expiring closed entries takes a drain even if it processes nothing
+        let due = due.max(1);
+
+        for attempt in 0..=self.config.retries {
+            if attempt > 0 {
+                thread::sleep(self.config.backoff.delay(attempt - 1));
+            }
+            match self.send_drains(header.cursor.nonce, due) {
+                Ok(signatures) => {
+                    for signature in &signatures {
+                        println!("drained at slot {}: {signature}", self.slot);
+                    }
+                    return Ok(signatures);
+                }
+                Err(err) => eprintln!("drain attempt {attempt} failed: {err}"),
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// Entries one drain is sized to
+    pub fn per_drain(&self) -> u32 {
+        let items = self
+            .estimator
+            .max_items(self.config.compute_units)
+            .unwrap_or(CALIBRATION_ITEMS);
+        match self.config.max_items {
+            0 => items,
+            max_items => items.min(max_items),
+        }
+    }
+
+    /// Simulates the first drain to refine the estimate, then sends the `due` entries over as
+    /// many drains as needed, pinned to consecutive nonces from `nonce`. Only the first is
+    /// preflighted, since the others expect a nonce the first hasn't advanced to yet. If one
+    /// lands out of order it fails on the nonce and the rest is picked up by the next crank.
+    fn send_drains(&mut self, nonce: u64, due: usize) -> Result<Vec<Signature>, String> {
+        let blockhash = self.rpc.latest_blockhash()?;
+        let transaction = |keeper: &Self, compute_units, max_items, nonce| {
+            let args = DrainArgs {
+                max_items,
+                nonce: Some(nonce),
+            };
+            drain_transaction(
+                &keeper.builder,
+                &keeper.keypair,
+                compute_units,
+                keeper.config.priority_fee,
+                args,
+                blockhash,
+            )
+        };
+
+        let items = batch::plan(due, self.per_drain(), 1)[0];
+        let units =
+            self.rpc
+                .simulate_transaction(&transaction(self, MAX_COMPUTE_UNITS, items, nonce))?;
+        self.estimator.record(items, units);
+
+        let drains = batch::plan(due, self.per_drain(), self.config.max_drains.max(1));
+        let mut signatures = Vec::with_capacity(drains.len());
+        for (n, items) in (0..).zip(drains) {
+            let drain = transaction(self, self.config.compute_units, items, nonce + n);
+            match self.rpc.send_transaction(&drain, n == 0) {
+                Ok(signature) => signatures.push(signature),
+                Err(err) if n == 0 => return Err(err),
+                Err(err) => {
+                    eprintln!("drain {n} of a batch failed: {err}");
+                    break;
+                }
+            }
+        }
+        Ok(signatures)
+    }
+
+    /// The current time on the state's schedule
+    fn now(&self) -> u64 {
+        match S::SCHEDULE {
+            Schedule::Slot => self.slot,
+            Schedule::UnixTimestamp => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use counter::{AsyncIxKey, CounterPayload, CounterState};
+    use sokoban::NodeAllocatorMap;
+
+    use super::*;
+
+    #[test]
+    fn test_has_pending() {
+        let mut state: Box<CounterState> = bytemuck::zeroed_box();
+        state.initialize();
+        let mut header = state.header;
+        assert!(!has_pending(&header, None, &*state, 100));
+
+        let key = AsyncIxKey {
+            slot: 5,
+            ..Default::default()
+        };
+        let payload = CounterPayload::new([1; 32], 1).unwrap();
+        state.async_queue.insert(key, payload);
+        let due = 5 + CounterState::ASYNC_DELAY_SLOTS;
+        assert!(!has_pending(&header, None, &*state, due - 1));
+        assert!(has_pending(&header, None, &*state, due));
+
+        // A drain stops at the first entry not due
+        for slot in [6, 9] {
+            let key = AsyncIxKey {
+                slot,
+                ..Default::default()
+            };
+            state.async_queue.insert(key, payload);
+        }
+        assert_eq!(pending(&header, None, &*state, due, usize::MAX), 1);
+        assert_eq!(pending(&header, None, &*state, due + 1, usize::MAX), 2);
+        assert_eq!(pending(&header, None, &*state, u64::MAX, 2), 2);
+
+        header.admin.paused = pause::DRAIN;
+        assert!(!has_pending(&header, None, &*state, due));
+    }
+
+    #[test]
+    fn test_pending_with_config() {
+        let mut state: Box<CounterState> = bytemuck::zeroed_box();
+        state.initialize();
+        let header = state.header;
+        let payload = CounterPayload::new([1; 32], 1).unwrap();
+        for slot in [5, 10] {
+            let key = AsyncIxKey {
+                slot,
+                ..Default::default()
+            };
+            state.async_queue.insert(key, payload);
+        }
+        let due = 10 + CounterState::ASYNC_DELAY_SLOTS;
+        let mut config = config::Config::default();
+        assert_eq!(pending(&header, Some(&config), &*state, due, usize::MAX), 2);
+
+        // Due the config's delay later
+        config.params.delay_slots = 3;
+        assert_eq!(pending(&header, Some(&config), &*state, due, usize::MAX), 1);
+        assert_eq!(
+            pending(&header, Some(&config), &*state, due + 3, usize::MAX),
+            2
+        );
+
+        // Entries whose window closed are expired, not processed
+        config.params.drain_window = 4;
+        assert_eq!(
+            pending(&header, Some(&config), &*state, due + 3, usize::MAX),
+            1
+        );
+        assert!(expiring(&header, Some(&config), &*state, due + 3));
+        state.async_queue.remove(&AsyncIxKey {
+            slot: 10,
+            ..Default::default()
+        });
+        assert!(has_pending(&header, Some(&config), &*state, due + 3));
+        assert_eq!(
+            pending(&header, Some(&config), &*state, due + 3, usize::MAX),
+            0
+        );
+
+        config.params.paused = pause::DRAIN;
+        assert!(!has_pending(&header, Some(&config), &*state, due + 3));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let delays: Vec<u128> = (0..6).map(|n| backoff.delay(n).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff.delay(u32::MAX), backoff.max);
+    }
+
+    #[test]
+    fn test_drain_transaction() {
+        let builder = InstructionBuilder::new(Pubkey::new_unique(), Pubkey::new_unique());
+        let keeper = Keypair::new();
+        let args = DrainArgs {
+            max_items: 4,
+            nonce: Some(7),
+        };
+        let transaction = drain_transaction(&builder, &keeper, 200_000, 10, args, Hash::default());
+
+        let message = &transaction.message;
+        assert_eq!(message.account_keys[0], keeper.pubkey());
+        assert_eq!(message.instructions.len(), 3);
+        let drain = &message.instructions[2];
+        assert_eq!(
+            message.account_keys[drain.program_id_index as usize],
+            builder.program_id
+        );
+        assert_eq!(drain.data[1..], args.encode());
+        assert!(transaction.verify().is_ok());
+    }
+}
@@ -0,0 +1,194 @@
+//! Address lookup tables for drains that pass more accounts than a legacy transaction holds
+//!
+//! A legacy transaction lists every account in full, which leaves room for a few dozen. Drains
+//! of programs whose entries reference accounts through an
+//! [`AccountRegistry`](apq_core::registry::AccountRegistry) can need more, so the keeper keeps
+//! a lookup table per state holding the [accounts any drain of it passes](table_addresses),
+//! creates it with [`create_table`] and [extends](extend_instructions) it with whatever the
+//! registry gained since, see [`missing_addresses`]. Drains are then built as v0 transactions
+//! with [`drain_transaction_v0`], which reference the table's accounts by a one byte index.
+//!
+//! A table only serves transactions from the slot after it was extended, so extend it before
+//! entries referencing the new keys come due.
+
+use apq_client::instructions::InstructionBuilder;
+use apq_core::{cursor::DrainArgs, registry::AccountRegistry};
+use solana_address_lookup_table_interface::{instruction, state::AddressLookupTable};
+use solana_compute_budget_interface::ComputeBudgetInstruction;
+use solana_hash::Hash;
+use solana_instruction::{AccountMeta, Instruction};
+use solana_keypair::Keypair;
+use solana_message::{v0, AddressLookupTableAccount, VersionedMessage};
+use solana_pubkey::Pubkey;
+use solana_signer::Signer;
+use solana_transaction::versioned::VersionedTransaction;
+
+/// Addresses one extend instruction adds, few enough for it to fit a legacy transaction
+pub const MAX_EXTEND: usize = 20;
+
+/// The instruction creating a lookup table owned by `authority`, and the table's address.
+/// `recent_slot` must be a recent finalized slot, which the address is derived from.
+pub fn create_table(authority: &Pubkey, payer: &Pubkey, recent_slot: u64) -> (Instruction, Pubkey) {
+    instruction::create_lookup_table(*authority, *payer, recent_slot)
+}
+
+/// Instructions adding `addresses` to `table`, [`MAX_EXTEND`] at a time, each to be sent in
+/// its own transaction
+pub fn extend_instructions(
+    table: &Pubkey,
+    authority: &Pubkey,
+    payer: &Pubkey,
+    addresses: &[Pubkey],
+) -> Vec<Instruction> {
+    addresses
+        .chunks(MAX_EXTEND)
+        .map(|chunk| {
+            instruction::extend_lookup_table(*table, *authority, Some(*payer), chunk.to_vec())
+        })
+        .collect()
+}
+
+/// Every account a drain of `builder`'s state can pass: the program, the state, the registry
+/// at `registry_address` and each key registered in it
+pub fn table_addresses(
+    builder: &InstructionBuilder,
+    registry_address: &Pubkey,
+    registry: &AccountRegistry,
+) -> Vec<Pubkey> {
+    [builder.program_id, builder.state, *registry_address]
+        .into_iter()
+        .chain(
+            registry
+                .keys()
+                .iter()
+                .map(|key| Pubkey::new_from_array(*key)),
+        )
+        .collect()
+}
+
+/// The `addresses` not in `table` yet, in order, for [`extend_instructions`]
+pub fn missing_addresses(table: &AddressLookupTableAccount, addresses: &[Pubkey]) -> Vec<Pubkey> {
+    addresses
+        .iter()
+        .filter(|address| !table.addresses.contains(address))
+        .copied()
+        .collect()
+}
+
+/// Decodes the lookup table at `address` from its account data
+pub fn read_table(address: Pubkey, data: &[u8]) -> Result<AddressLookupTableAccount, String> {
+    let table = AddressLookupTable::deserialize(data).map_err(|err| err.to_string())?;
+    Ok(AddressLookupTableAccount {
+        key: address,
+        addresses: table.addresses.to_vec(),
+    })
+}
+
+/// The remaining accounts of a drain whose entries reference `keys` of the registry at
+/// `registry_address`, as [`split_registry`](apq_core::registry::split_registry) takes them
+pub fn registry_accounts(registry_address: &Pubkey, keys: &[Pubkey]) -> Vec<AccountMeta> {
+    [AccountMeta::new_readonly(*registry_address, false)]
+        .into_iter()
+        .chain(keys.iter().map(|key| AccountMeta::new(*key, false)))
+        .collect()
+}
+
+/// [`drain_transaction`](crate::drain_transaction) as a v0 transaction passing `remaining`
+/// accounts after the drain's own, loading whichever of its accounts are in `tables` from them
+#[allow(clippy::too_many_arguments)]
+pub fn drain_transaction_v0(
+    builder: &InstructionBuilder,
+    keeper: &Keypair,
+    compute_units: u32,
+    priority_fee: u64,
+    args: DrainArgs,
+    remaining: &[AccountMeta],
+    tables: &[AddressLookupTableAccount],
+    blockhash: Hash,
+) -> Result<VersionedTransaction, String> {
+    let mut drain = builder.drain_with(&keeper.pubkey(), args);
+    drain.accounts.extend_from_slice(remaining);
+    let instructions = [
+        ComputeBudgetInstruction::set_compute_unit_limit(compute_units),
+        ComputeBudgetInstruction::set_compute_unit_price(priority_fee),
+        drain,
+    ];
+    let message = v0::Message::try_compile(&keeper.pubkey(), &instructions, tables, blockhash)
+        .map_err(|err| err.to_string())?;
+    VersionedTransaction::try_new(VersionedMessage::V0(message), &[keeper])
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_address_lookup_table_interface::state::LookupTableMeta;
+
+    use super::*;
+
+    #[test]
+    fn test_drain_transaction_v0() {
+        let builder = InstructionBuilder::new(Pubkey::new_unique(), Pubkey::new_unique());
+        let keeper = Keypair::new();
+        let registry_address = Pubkey::new_unique();
+        let mut registry = AccountRegistry::new(builder.state.to_bytes());
+        let keys: Vec<Pubkey> = (0..40).map(|_| Pubkey::new_unique()).collect();
+        for key in &keys {
+            registry.register(&key.to_bytes()).unwrap();
+        }
+
+        let addresses = table_addresses(&builder, &registry_address, &registry);
+        assert_eq!(addresses.len(), 43);
+        let extends = extend_instructions(
+            &Pubkey::new_unique(),
+            &keeper.pubkey(),
+            &keeper.pubkey(),
+            &addresses,
+        );
+        assert_eq!(extends.len(), 3);
+
+        // The table as the program stores it, missing the last key
+        let table = AddressLookupTable {
+            meta: LookupTableMeta::new(keeper.pubkey()),
+            addresses: addresses[..42].into(),
+        };
+        let table =
+            read_table(Pubkey::new_unique(), &table.serialize_for_tests().unwrap()).unwrap();
+        assert_eq!(missing_addresses(&table, &addresses), [keys[39]]);
+
+        let args = DrainArgs {
+            max_items: 4,
+            nonce: Some(7),
+        };
+        let remaining = registry_accounts(&registry_address, &keys);
+        let transaction = drain_transaction_v0(
+            &builder,
+            &keeper,
+            200_000,
+            10,
+            args,
+            &remaining,
+            &[table],
+            Hash::default(),
+        )
+        .unwrap();
+
+        // Signers, invoked programs and the key the table misses are listed in full, the
+        // state, the registry and every other key are loaded from the table
+        let message = &transaction.message;
+        assert_eq!(
+            message.static_account_keys(),
+            [
+                keeper.pubkey(),
+                keys[39],
+                builder.program_id,
+                solana_compute_budget_interface::id(),
+            ]
+        );
+        let lookups = message.address_table_lookups().unwrap();
+        assert_eq!(lookups.len(), 1);
+        assert_eq!(lookups[0].writable_indexes.len(), 40);
+        assert_eq!(lookups[0].readonly_indexes.len(), 1);
+        assert!(bincode::serialize(&transaction).unwrap().len() <= 1232);
+        assert!(transaction.verify_with_results().iter().all(|ok| *ok));
+    }
+}
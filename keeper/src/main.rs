@@ -0,0 +1,146 @@
+//! Keeps an example program's queue drained
+//!
+//! ```text
+//! cargo run -p apq-keeper --release -- --state <STATE> --keypair ~/.config/solana/id.json \
+//!     --rpc http://127.0.0.1:8899 --ws ws://127.0.0.1:8900 --priority-fee 1000
+//! ```
+//!
+//! Follows the state account and drains it whenever the head of its queue is due, paying the
+//! transaction fees from the keypair and collecting the crank bounties into it.
+//! `--state-type counter|orderbook|vesting` picks how the state is decoded, the counter by
+//! default, and `--program` is required for the others. States with a config account pass it
+//! with `--config`, so that its delay and drain window are taken into account.
+//! `--compute-units` is the budget each drain is sized to, and up to `--max-drains` are sent at
+//! once when the backlog is deeper than one fits.
+
+use std::{process::ExitCode, str::FromStr};
+
+use apq_client::TryDecode;
+use apq_core::AsyncState;
+use apq_keeper::{rpc::Rpc, Config, Keeper};
+use solana_keypair::Keypair;
+use solana_pubkey::Pubkey;
+
+const COUNTER_PROGRAM_ID: Pubkey =
+    solana_pubkey::pubkey!("CounterProgram111111111111111111111111111111");
+
+/// Which example program's state to decode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum StateType {
+    Counter,
+    Orderbook,
+    Vesting,
+}
+
+struct Args {
+    rpc: String,
+    ws: String,
+    state_type: StateType,
+    program: Option<Pubkey>,
+    state: Option<Pubkey>,
+    state_config: Option<Pubkey>,
+    keypair: String,
+    config: Config,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            rpc: "http://127.0.0.1:8899".into(),
+            ws: "ws://127.0.0.1:8900".into(),
+            state_type: StateType::Counter,
+            program: None,
+            state: None,
+            state_config: None,
+            keypair: "keeper.json".into(),
+            config: Config::default(),
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(flag) = argv.next() {
+            let value = argv.next().ok_or(format!("{flag} needs a value"))?;
+            match flag.as_str() {
+                "--rpc" => args.rpc = value,
+                "--ws" => args.ws = value,
+                "--state-type" => {
+                    args.state_type = match value.as_str() {
+                        "counter" => StateType::Counter,
+                        "orderbook" => StateType::Orderbook,
+                        "vesting" => StateType::Vesting,
+                        _ => return Err(format!("unknown state type {value}")),
+                    }
+                }
+                "--program" => args.program = Some(parse(&value)?),
+                "--state" => args.state = Some(parse(&value)?),
+                "--config" => args.state_config = Some(parse(&value)?),
+                "--keypair" => args.keypair = value,
+                "--priority-fee" => args.config.priority_fee = parse(&value)?,
+                "--compute-units" => args.config.compute_units = parse(&value)?,
+                "--max-items" => args.config.max_items = parse(&value)?,
+                "--max-drains" => args.config.max_drains = parse(&value)?,
+                "--retries" => args.config.retries = parse(&value)?,
+                _ => return Err(format!("unknown flag {flag}")),
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("`{value}` is invalid"))
+}
+
+/// Drains `state` of `program` as an `S` until the connection fails
+fn run<S: AsyncState + TryDecode>(
+    args: Args,
+    program: Pubkey,
+    state: Pubkey,
+    keypair: Keypair,
+) -> Result<(), String> {
+    let rpc = Rpc::new(&args.rpc);
+    let mut keeper = Keeper::<S>::new(rpc, program, state, keypair, args.config);
+    if let Some(config) = args.state_config {
+        keeper = keeper.with_config(config);
+    }
+    keeper.run(&args.ws)
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(state) = args.state else {
+        eprintln!("--state is required");
+        return ExitCode::FAILURE;
+    };
+    let keypair = match solana_keypair::read_keypair_file(&args.keypair) {
+        Ok(keypair) => keypair,
+        Err(err) => {
+            eprintln!("{}: {err}", args.keypair);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let program = match (args.program, args.state_type) {
+        (Some(program), _) => program,
+        (None, StateType::Counter) => COUNTER_PROGRAM_ID,
+        (None, _) => {
+            eprintln!("--program is required");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match args.state_type {
+        StateType::Counter => run::<counter::CounterState>(args, program, state, keypair),
+        StateType::Orderbook => run::<orderbook::OrderbookState>(args, program, state, keypair),
+        StateType::Vesting => run::<vesting::VestingState>(args, program, state, keypair),
+    };
+    if let Err(err) = result {
+        eprintln!("{err}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,227 @@
+//! Just enough Solana JSON-RPC for the keeper: requests over HTTP and subscriptions over the
+//! websocket
+
+use std::net::TcpStream;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use serde_json::{json, Value};
+use solana_hash::Hash;
+use solana_message::AddressLookupTableAccount;
+use solana_pubkey::Pubkey;
+use solana_signature::Signature;
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+/// Commitment everything is read at, so a drain isn't built from a state that gets rolled back
+const COMMITMENT: &str = "confirmed";
+
+pub struct Rpc {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Rpc {
+    pub fn new(url: &str) -> Self {
+        Rpc {
+            url: url.to_owned(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// The `result` of calling `method`, or the error the node answered with
+    fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let mut response: Value = self
+            .agent
+            .post(&self.url)
+            .send_json(request)
+            .map_err(|err| format!("{method}: {err}"))?
+            .into_json()
+            .map_err(|err| format!("{method}: {err}"))?;
+        if let Some(err) = response.get("error") {
+            return Err(format!("{method}: {err}"));
+        }
+        Ok(response["result"].take())
+    }
+
+    /// The account's data and the slot it was read at
+    pub fn account(&self, address: &Pubkey) -> Result<(u64, Vec<u8>), String> {
+        let result = self.call(
+            "getAccountInfo",
+            json!([address.to_string(), { "encoding": "base64", "commitment": COMMITMENT }]),
+        )?;
+        let slot = result["context"]["slot"]
+            .as_u64()
+            .ok_or("getAccountInfo: no slot")?;
+        Ok((slot, account_data(&result["value"])?))
+    }
+
+    /// The lookup table at `address`, see [`crate::lookup`]
+    pub fn lookup_table(&self, address: &Pubkey) -> Result<AddressLookupTableAccount, String> {
+        let (_slot, data) = self.account(address)?;
+        crate::lookup::read_table(*address, &data)
+    }
+
+    /// The latest finalized slot, recent enough to derive a new lookup table from
+    pub fn finalized_slot(&self) -> Result<u64, String> {
+        let result = self.call("getSlot", json!([{ "commitment": "finalized" }]))?;
+        result.as_u64().ok_or_else(|| "getSlot: no slot".to_owned())
+    }
+
+    pub fn latest_blockhash(&self) -> Result<Hash, String> {
+        let result = self.call("getLatestBlockhash", json!([{ "commitment": COMMITMENT }]))?;
+        result["value"]["blockhash"]
+            .as_str()
+            .and_then(|hash| hash.parse().ok())
+            .ok_or_else(|| "getLatestBlockhash: no blockhash".to_owned())
+    }
+
+    /// Sends `transaction`, with `preflight` after simulating it so drains that would fail
+    /// cost nothing
+    pub fn send_transaction(
+        &self,
+        transaction: &impl Serialize,
+        preflight: bool,
+    ) -> Result<Signature, String> {
+        let result = self.call(
+            "sendTransaction",
+            json!([encode(transaction)?, {
+                "encoding": "base64",
+                "skipPreflight": !preflight,
+                "preflightCommitment": COMMITMENT,
+            }]),
+        )?;
+        result
+            .as_str()
+            .and_then(|signature| signature.parse().ok())
+            .ok_or_else(|| "sendTransaction: no signature".to_owned())
+    }
+
+    /// Compute units `transaction` uses when simulated against the latest state
+    pub fn simulate_transaction(&self, transaction: &impl Serialize) -> Result<u64, String> {
+        let result = self.call(
+            "simulateTransaction",
+            json!([encode(transaction)?, {
+                "encoding": "base64",
+                "commitment": COMMITMENT,
+                "replaceRecentBlockhash": true,
+            }]),
+        )?;
+        let value = &result["value"];
+        if !value["err"].is_null() {
+            return Err(format!("simulateTransaction: {}", value["err"]));
+        }
+        value["unitsConsumed"]
+            .as_u64()
+            .ok_or_else(|| "simulateTransaction: no units consumed".to_owned())
+    }
+}
+
+/// A legacy or versioned transaction as base64 encoded wire bytes
+fn encode(transaction: &impl Serialize) -> Result<String, String> {
+    let bytes = bincode::serialize(transaction).map_err(|err| err.to_string())?;
+    Ok(STANDARD.encode(bytes))
+}
+
+/// Something the keeper was notified of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notification {
+    /// The state account changed at `slot`
+    Account { slot: u64, data: Vec<u8> },
+    /// A new slot, when entries may have become due without the state changing
+    Slot(u64),
+}
+
+/// Account and slot notifications on one websocket
+pub struct Subscription {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+}
+
+impl Subscription {
+    /// Subscribes to changes of `account` and to new slots
+    pub fn connect(url: &str, account: &Pubkey) -> Result<Self, String> {
+        let (mut socket, _response) = tungstenite::connect(url).map_err(|err| err.to_string())?;
+        let requests = [
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "accountSubscribe",
+                "params": [account.to_string(), { "encoding": "base64", "commitment": COMMITMENT }],
+            }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "slotSubscribe" }),
+        ];
+        for request in requests {
+            socket
+                .send(Message::text(request.to_string()))
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(Subscription { socket })
+    }
+
+    /// Blocks until the next notification
+    pub fn recv(&mut self) -> Result<Notification, String> {
+        loop {
+            // Pings are answered by tungstenite while reading
+            let message = self.socket.read().map_err(|err| err.to_string())?;
+            if let Message::Text(text) = message {
+                if let Some(notification) = parse_notification(&text)? {
+                    return Ok(notification);
+                }
+            }
+        }
+    }
+}
+
+/// Parses a websocket message, `None` for anything but a notification, e.g. the subscription
+/// confirmations
+pub fn parse_notification(text: &str) -> Result<Option<Notification>, String> {
+    let message: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    let result = &message["params"]["result"];
+    match message["method"].as_str() {
+        Some("accountNotification") => Ok(Some(Notification::Account {
+            slot: result["context"]["slot"]
+                .as_u64()
+                .ok_or("accountNotification: no slot")?,
+            data: account_data(&result["value"])?,
+        })),
+        Some("slotNotification") => Ok(Some(Notification::Slot(
+            result["slot"].as_u64().ok_or("slotNotification: no slot")?,
+        ))),
+        _ => Ok(None),
+    }
+}
+
+/// Decodes the data of an account encoded as base64
+fn account_data(account: &Value) -> Result<Vec<u8>, String> {
+    let data = account["data"][0]
+        .as_str()
+        .ok_or("account doesn't exist or isn't base64 encoded")?;
+    STANDARD.decode(data).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_notification() {
+        let account = r#"{"jsonrpc":"2.0","method":"accountNotification","params":{"result":{
+            "context":{"slot":7},"value":{"data":["AQID","base64"],"lamports":1}},
+            "subscription":3}}"#;
+        assert_eq!(
+            parse_notification(account),
+            Ok(Some(Notification::Account {
+                slot: 7,
+                data: vec![1, 2, 3],
+            }))
+        );
+
+        let slot = r#"{"jsonrpc":"2.0","method":"slotNotification","params":{"result":{
+            "parent":8,"root":1,"slot":9},"subscription":4}}"#;
+        assert_eq!(parse_notification(slot), Ok(Some(Notification::Slot(9))));
+
+        let confirmation = r#"{"jsonrpc":"2.0","result":3,"id":1}"#;
+        assert_eq!(parse_notification(confirmation), Ok(None));
+        assert!(parse_notification("not json").is_err());
+    }
+}
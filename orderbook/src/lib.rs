@@ -0,0 +1,1349 @@
+#![allow(unexpected_cfgs)]
+
+//! An order book on the async queue
+//!
+//! Makers post limit orders synchronously, which rest in the book stored in the state account.
+//! Takes and cancels go through the queue instead, so nobody can react to a resting order
+//! within the slot it was queued in. Each slot's entries are settled together as a batch:
+//! cancels first, so makers can always pull their quotes before being taken, then takes in
+//! price-time priority with [`PriceTimeKey`].
+//!
+//! Balances are per user and internal to the state. Deposits are paid into the base and quote
+//! [`Vault`]s, free until the admin sets them, and withdrawals are paid out of them. Makers can
+//! also pull their orders at once, but only while nothing is queued that could fill them.
+//!
+//! The admin tunes the book in a [`Config`](apq_core::config::Config) account, which can set
+//! a drain window: takes and cancels left undrained once their batch's window closed expire,
+//! takes unlocking what they locked, instead of landing in a later batch.
+
+use apq_core::{
+    balances::{self, Amount, Balances},
+    context::{AccountsCtx, StateAccounts},
+    deser_containers::{OwnedOrBorrowed, OwnedOrBorrowedMut},
+    dispatch, emit,
+    events::{CancelledEvent, ProcessedEvent, QueuedEvent},
+    header::StateHeader,
+    log_debug, log_error, log_info,
+    migrate::{self, Migratable},
+    ordering::{self, OrderingKey, PriceTimeArgs, PriceTimeKey, Side},
+    pod::read_pod,
+    queue::{self, peek_min, pop_min, ArgsSlot, CancelAll, Entry, UserPayload},
+    strict::{self, Strictness},
+    vault::Vault,
+    AsyncIx, AsyncState, FromBytes, Program, SyncIx,
+};
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum OrderbookSyncIx {
+    /// Pays into an asset's vault and credits the signer. Data is `[asset, amount]`.
+    Deposit = 0,
+    /// Rests a limit order, locking its funds. Data is `[side, price, size]`, and an order
+    /// that would cross the book is rejected: taking goes through the queue.
+    PostOrder = 1,
+    /// Sets an asset's vault, signed by the admin. Data is the asset and the new [`Vault`].
+    SetVault = 2,
+    /// Debits the signer and pays out of an asset's vault into the account that comes first
+    /// after the user, followed by the vault's, see [`Vault::pay_out`]. Data is
+    /// `[asset, amount]`.
+    Withdraw = 3,
+    /// Pulls the signer's resting order, unlocking it. Data is the order's `[side, price, seq]`.
+    /// Fails with [`QUEUE_NOT_EMPTY`] while anything is queued, since a queued take may be
+    /// about to fill the order: makers cancel through the queue then.
+    CancelOrder = 4,
+}
+
+impl OrderbookSyncIx {
+    /// Owned decoding for clients, accepting exactly what the [`FromBytes`] impl does
+    pub fn decode_owned(bytes: &[u8]) -> Result<Self, ProgramError> {
+        match read_pod::<u64>(bytes)? {
+            0 => Ok(OrderbookSyncIx::Deposit),
+            1 => Ok(OrderbookSyncIx::PostOrder),
+            2 => Ok(OrderbookSyncIx::SetVault),
+            3 => Ok(OrderbookSyncIx::Withdraw),
+            4 => Ok(OrderbookSyncIx::CancelOrder),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+}
+
+impl FromBytes for OrderbookSyncIx {
+    type Target<'a> = OwnedOrBorrowed<'a, Self>;
+    type TargetMut<'a> = OwnedOrBorrowedMut<'a, Self>;
+    fn from_bytes<'a>(bytes: &'a [u8]) -> Result<OwnedOrBorrowed<'a, Self>, ProgramError> {
+        Ok(OwnedOrBorrowed::Owned(Self::decode_owned(bytes)?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u64)]
+pub enum OrderbookAsyncIx {
+    /// Pulls a resting order. Data is the order's `[side, price, seq]`.
+    Cancel = 0, // Cancels settle before takes
+    /// Fills against the book up to a limit price, immediate or cancel. Data is
+    /// `[side, limit price, size]`.
+    Take = 1,
+}
+
+impl OrderbookAsyncIx {
+    /// `None` if `a` isn't a variant
+    pub fn from_u64(a: u64) -> Option<OrderbookAsyncIx> {
+        match a {
+            0 => Some(OrderbookAsyncIx::Cancel),
+            1 => Some(OrderbookAsyncIx::Take),
+            _ => None,
+        }
+    }
+}
+
+impl FromBytes for OrderbookAsyncIx {
+    type Target<'a> = OwnedOrBorrowed<'a, Self>;
+    type TargetMut<'a> = OwnedOrBorrowedMut<'a, Self>;
+    fn from_bytes<'a>(bytes: &'a [u8]) -> Result<OwnedOrBorrowed<'a, Self>, ProgramError> {
+        let variant = read_pod::<u64>(bytes)?;
+        let Some(ix) = OrderbookAsyncIx::from_u64(variant) else {
+            log_error!("got ix variant {}", variant);
+            return Err(ProgramError::InvalidInstructionData);
+        };
+
+        Ok(OwnedOrBorrowed::Owned(ix))
+    }
+}
+
+/// What a deposit pays in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Asset {
+    Base = 0,
+    Quote = 1,
+}
+
+impl Asset {
+    pub fn from_u64(a: u64) -> Result<Asset, ProgramError> {
+        match a {
+            0 => Ok(Asset::Base),
+            1 => Ok(Asset::Quote),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+}
+
+fn side_from_u64(side: u64) -> Result<Side, ProgramError> {
+    match side {
+        0 => Ok(Side::Bid),
+        1 => Ok(Side::Ask),
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+/// We first sort by auction (slot), then cancels before takes, then by price-time priority
+#[derive(Copy, Clone, Zeroable, Pod, PartialEq, Eq, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct OrderKey {
+    pub ixn_value: u64,
+    /// The order's side and price, the resting order's for cancels
+    pub price_time: PriceTimeKey,
+}
+
+apq_core::impl_layout_hash!(OrderKey {
+    ixn_value: u64,
+    price_time: PriceTimeKey
+});
+
+impl Ord for OrderKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.price_time
+            .slot
+            .cmp(&other.price_time.slot)
+            .then(self.ixn_value.cmp(&other.ixn_value))
+            .then(self.price_time.cmp(&other.price_time))
+    }
+}
+
+impl PartialOrd for OrderKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl OrderingKey<OrderbookAsyncIx, QueueOrderArgs> for OrderKey {
+    fn key(slot: u64, seq: u64, ixn: &OrderbookAsyncIx, args: &QueueOrderArgs) -> Self {
+        OrderKey {
+            ixn_value: *ixn as u64,
+            price_time: PriceTimeKey::key(slot, seq, ixn, args),
+        }
+    }
+
+    fn slot(&self) -> u64 {
+        self.price_time.slot
+    }
+}
+
+/// Arguments of both async instructions, `seq` only for cancels and `size` only for takes
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct OrderAsyncIxArgs {
+    /// A [`Side`] as its `u64` value
+    pub side: u64,
+    pub price: u64,
+    pub size: u64,
+    /// Sequence number of the resting order to cancel
+    pub seq: u64,
+}
+
+impl OrderAsyncIxArgs {
+    pub fn side(&self) -> Result<Side, ProgramError> {
+        side_from_u64(self.side)
+    }
+}
+
+/// What gets queued with each instruction
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct OrderPayload {
+    pub user: Pubkey,
+    /// The encoded [`OrderAsyncIxArgs`]
+    pub args: ArgsSlot,
+}
+
+apq_core::impl_layout_hash!(OrderPayload {
+    user: Pubkey,
+    args: ArgsSlot
+});
+
+impl UserPayload for OrderPayload {
+    fn user(&self) -> &Pubkey {
+        &self.user
+    }
+}
+
+impl OrderPayload {
+    pub fn args(&self) -> Result<OrderAsyncIxArgs, ProgramError> {
+        OrderbookAsyncIx::decode_args(&self.args)
+    }
+}
+
+/// An order resting in the book
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct RestingOrder {
+    pub owner: Pubkey,
+    /// Base units left to fill
+    pub size: u64,
+}
+
+apq_core::impl_layout_hash!(RestingOrder {
+    owner: Pubkey,
+    size: u64
+});
+
+/// A user's funds, not counting what their resting orders and queued takes lock
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Balance {
+    pub base: u64,
+    pub quote: u64,
+}
+
+apq_core::impl_layout_hash!(Balance {
+    base: u64,
+    quote: u64
+});
+
+impl Amount for Balance {
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Some(Balance {
+            base: self.base.checked_add(other.base)?,
+            quote: self.quote.checked_add(other.quote)?,
+        })
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        Some(Balance {
+            base: self.base.checked_sub(other.base)?,
+            quote: self.quote.checked_sub(other.quote)?,
+        })
+    }
+}
+
+/// Totals of the last settled batch
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct BatchSummary {
+    /// Auction the batch was queued in
+    pub slot: u64,
+    pub fills: u64,
+    pub base_volume: u64,
+    pub quote_volume: u64,
+    /// Price of the batch's last fill, zero if nothing filled
+    pub last_price: u64,
+}
+
+apq_core::impl_layout_hash!(BatchSummary {
+    slot: u64,
+    fills: u64,
+    base_volume: u64,
+    quote_volume: u64,
+    last_price: u64
+});
+
+/// Custom program error for a take priced outside the band around the last batch's price,
+/// spelling `APQ` followed by 11
+pub const OUTSIDE_PRICE_BAND: u32 = 0x4150_5111;
+
+/// Custom program error for cancelling an order synchronously while entries are queued,
+/// spelling `APQ` followed by 12
+pub const QUEUE_NOT_EMPTY: u32 = 0x4150_5112;
+
+/// How far from the last batch's price takes can be limited, in percent
+pub const PRICE_BAND_PERCENT: u64 = 50;
+
+/// Resting orders per side
+pub const MAX_ORDERS: usize = 1024;
+
+/// Users with a balance at once
+pub const MAX_USERS: usize = 1024;
+
+/// One side of the book, best price first then oldest
+///
+/// Keys are [`PriceTimeKey`]s with the slot zeroed, so orders rank on price and time alone
+/// however long they have been resting.
+pub type Book = RedBlackTree<PriceTimeKey, RestingOrder, MAX_ORDERS>;
+
+#[derive(Copy, Clone, Zeroable, Pod)]
+#[repr(C)]
+pub struct OrderbookState {
+    /// Layout version, see [`Migratable`]
+    pub header: StateHeader,
+
+    /// Sequence number to assign to the next order or queued instruction, starting at 1
+    pub seq: u64,
+
+    /// What the last batch settled
+    pub last_batch: BatchSummary,
+
+    /// Where base and quote deposits are paid, free until the admin sets them
+    pub base_vault: Vault,
+    pub quote_vault: Vault,
+
+    pub bids: Book,
+    pub asks: Book,
+
+    /// The asynchronous queue for cancels and takes
+    pub async_queue: RedBlackTree<OrderKey, OrderPayload, 4096>,
+
+    pub balances: Balances<Balance, MAX_USERS>,
+}
+
+// Changing the layout needs a new version, see `Migratable`
+apq_core::const_assert_state_layout!(OrderbookState, size = 738_160, align = 8);
+
+impl OrderbookState {
+    /// Boxed since the state is far too large for the stack
+    #[cfg(test)]
+    fn new() -> Box<Self> {
+        let mut state: Box<Self> = bytemuck::zeroed_box();
+        state.initialize();
+        state
+    }
+
+    pub fn balance(&self, user: &Pubkey) -> Balance {
+        balances::balance(&self.balances, user)
+    }
+
+    pub fn credit(&mut self, user: &Pubkey, base: u64, quote: u64) -> ProgramResult {
+        balances::credit(&mut self.balances, user, Balance { base, quote })?;
+        Ok(())
+    }
+
+    pub fn debit(&mut self, user: &Pubkey, base: u64, quote: u64) -> ProgramResult {
+        let amount = Balance { base, quote };
+        balances::debit(
+            &mut self.balances,
+            user,
+            amount,
+            ProgramError::InsufficientFunds,
+        )?;
+        Ok(())
+    }
+
+    pub fn book(&self, side: Side) -> &Book {
+        match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        }
+    }
+
+    fn book_mut(&mut self, side: Side) -> &mut Book {
+        match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        }
+    }
+
+    /// The best resting price on `side`
+    pub fn best(&self, side: Side) -> Option<u64> {
+        peek_min(self.book(side)).map(|(_addr, node)| node.key.price)
+    }
+
+    /// Rests an order for `owner`, locking `size` base for an ask or `price * size` quote for
+    /// a bid. Returns the order's key.
+    pub fn post_order(
+        &mut self,
+        owner: &Pubkey,
+        side: Side,
+        price: u64,
+        size: u64,
+    ) -> Result<PriceTimeKey, ProgramError> {
+        if price == 0 || size == 0 {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let crosses = match side {
+            Side::Bid => self.best(Side::Ask).is_some_and(|ask| price >= ask),
+            Side::Ask => self.best(Side::Bid).is_some_and(|bid| price <= bid),
+        };
+        if crosses {
+            log_error!("Order at {} would cross the book", price);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let (base, quote) = lock(side, price, size)?;
+        self.debit(owner, base, quote)?;
+        let key = PriceTimeKey::new(price, side, 0, ordering::next_seq(&mut self.seq)?);
+        let order = RestingOrder {
+            owner: *owner,
+            size,
+        };
+        self.book_mut(side)
+            .insert(key, order)
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        Ok(key)
+    }
+
+    /// Pulls `owner`'s resting order, unlocking what is left of it. Does nothing if it has
+    /// already filled, which a cancel queued in the same slot as its last take can't know.
+    pub fn cancel_order(&mut self, owner: &Pubkey, key: &PriceTimeKey) -> ProgramResult {
+        let side = key.side();
+        let Some(order) = self.book(side).get(key).copied() else {
+            log_debug!("Order {} already filled", key.seq);
+            return Ok(());
+        };
+        if order.owner != *owner {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        self.book_mut(side).remove(key);
+        let (base, quote) = lock(side, key.price, order.size)?;
+        self.credit(owner, base, quote)
+    }
+
+    /// Fills `taker`'s `side` order against the other side of the book, best price first, up
+    /// to `limit` and `size`. What a take locked when it was queued and didn't spend is
+    /// returned to the taker.
+    pub fn take(&mut self, taker: &Pubkey, side: Side, limit: u64, size: u64) -> ProgramResult {
+        let makers = match side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        let mut remaining = size;
+        let mut spent = 0;
+        while remaining > 0 {
+            let Some((_addr, best)) = peek_min(self.book(makers)) else {
+                break;
+            };
+            let (key, order) = (best.key, best.value);
+            let crosses = match side {
+                Side::Bid => key.price <= limit,
+                Side::Ask => key.price >= limit,
+            };
+            if !crosses {
+                break;
+            }
+
+            let fill = remaining.min(order.size);
+            let quote = fill
+                .checked_mul(key.price)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            match side {
+                Side::Bid => {
+                    self.credit(&order.owner, 0, quote)?;
+                    self.credit(taker, fill, 0)?;
+                    spent += quote;
+                }
+                Side::Ask => {
+                    self.credit(&order.owner, fill, 0)?;
+                    self.credit(taker, 0, quote)?;
+                    spent += fill;
+                }
+            }
+            if fill == order.size {
+                self.book_mut(makers).remove(&key);
+            } else if let Some(order) = self.book_mut(makers).get_mut(&key) {
+                order.size -= fill;
+            }
+            remaining -= fill;
+
+            self.last_batch.fills += 1;
+            self.last_batch.base_volume = self.last_batch.base_volume.saturating_add(fill);
+            self.last_batch.quote_volume = self.last_batch.quote_volume.saturating_add(quote);
+            self.last_batch.last_price = key.price;
+            log_debug!("Filled {} at {}", fill, key.price);
+        }
+
+        let (base, quote) = lock(side, limit, size)?;
+        match side {
+            Side::Bid => self.credit(taker, 0, quote - spent),
+            Side::Ask => self.credit(taker, base - spent, 0),
+        }
+    }
+}
+
+/// Base and quote an order of `size` at `price` locks
+fn lock(side: Side, price: u64, size: u64) -> Result<(u64, u64), ProgramError> {
+    match side {
+        Side::Bid => Ok((
+            0,
+            price
+                .checked_mul(size)
+                .ok_or(ProgramError::ArithmeticOverflow)?,
+        )),
+        Side::Ask => Ok((size, 0)),
+    }
+}
+
+impl FromBytes for OrderbookState {
+    type Target<'a> = &'a Self;
+    type TargetMut<'a> = &'a mut Self;
+    fn from_bytes<'a>(bytes: &'a [u8]) -> Result<Self::Target<'a>, ProgramError> {
+        bytemuck::try_from_bytes(bytes).map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    fn from_bytes_mut<'a>(bytes: &'a mut [u8]) -> Result<Self::TargetMut<'a>, ProgramError> {
+        bytemuck::try_from_bytes_mut(bytes).map_err(|_| ProgramError::InvalidAccountData)
+    }
+}
+
+impl Migratable for OrderbookState {
+    const VERSION: u32 = 2;
+    const LAYOUT_HASH: u64 = apq_core::layout_hash!(OrderbookState {
+        header: StateHeader,
+        seq: u64,
+        last_batch: BatchSummary,
+        base_vault: Vault,
+        quote_vault: Vault,
+        bids: Book,
+        asks: Book,
+        async_queue: RedBlackTree<OrderKey, OrderPayload, 4096>,
+        balances: Balances<Balance, MAX_USERS>,
+    });
+
+    fn migrate(from_version: u32, data: &mut [u8]) -> ProgramResult {
+        match from_version {
+            // v1 headers predate the layout hash, grow the account by its 8 bytes first
+            1 => migrate::insert_layout_hash(data, 738_152),
+            _ => {
+                log_error!("Unknown state version {}", from_version);
+                Err(ProgramError::InvalidAccountData)
+            }
+        }
+    }
+}
+
+impl SyncIx for OrderbookSyncIx {
+    fn process<S: AsyncState>(
+        &self,
+        data: &[u8],
+        accounts: &[AccountInfo],
+        state: &mut S,
+    ) -> ProgramResult {
+        let StateAccounts { user, rem, .. } = StateAccounts::parse(accounts)?;
+        // Same hack as the counter to access the concrete state
+        let book = unsafe { &mut *(state as *mut S as *mut OrderbookState) };
+        let strictness = OrderbookState::STRICTNESS;
+
+        match self {
+            OrderbookSyncIx::Deposit => {
+                let [asset, amount] = read_pod::<[u64; 2]>(data.get(8..).unwrap_or_default())?;
+                strict::check_consumed(data, 24, strictness)?;
+                if !user.is_signer() {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                match Asset::from_u64(asset)? {
+                    Asset::Base => {
+                        book.base_vault.charge(user, rem, amount)?;
+                        book.credit(user.key(), amount, 0)
+                    }
+                    Asset::Quote => {
+                        book.quote_vault.charge(user, rem, amount)?;
+                        book.credit(user.key(), 0, amount)
+                    }
+                }
+            }
+            OrderbookSyncIx::PostOrder => {
+                let [side, price, size] = read_pod::<[u64; 3]>(data.get(8..).unwrap_or_default())?;
+                strict::check_consumed(data, 32, strictness)?;
+                if !user.is_signer() {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                let key = book.post_order(user.key(), side_from_u64(side)?, price, size)?;
+                log_info!("Posted order {} at {}", key.seq, price);
+                Ok(())
+            }
+            OrderbookSyncIx::SetVault => {
+                book.header.admin.check_authority(user)?;
+                let asset = Asset::from_u64(read_pod(data.get(8..).unwrap_or_default())?)?;
+                let vault = read_pod::<Vault>(data.get(16..).unwrap_or_default())?;
+                strict::check_consumed(data, 16 + size_of::<Vault>(), strictness)?;
+                match asset {
+                    Asset::Base => book.base_vault = vault,
+                    Asset::Quote => book.quote_vault = vault,
+                }
+                Ok(())
+            }
+            OrderbookSyncIx::Withdraw => {
+                let [asset, amount] = read_pod::<[u64; 2]>(data.get(8..).unwrap_or_default())?;
+                strict::check_consumed(data, 24, strictness)?;
+                if !user.is_signer() {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                let (recipient, vault) = rem
+                    .split_first()
+                    .ok_or(ProgramError::NotEnoughAccountKeys)?;
+                match Asset::from_u64(asset)? {
+                    Asset::Base => {
+                        book.debit(user.key(), amount, 0)?;
+                        book.base_vault.pay_out(vault, recipient, amount)
+                    }
+                    Asset::Quote => {
+                        book.debit(user.key(), 0, amount)?;
+                        book.quote_vault.pay_out(vault, recipient, amount)
+                    }
+                }
+            }
+            OrderbookSyncIx::CancelOrder => {
+                let [side, price, seq] = read_pod::<[u64; 3]>(data.get(8..).unwrap_or_default())?;
+                strict::check_consumed(data, 32, strictness)?;
+                if !user.is_signer() {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if !book.async_queue.is_empty() {
+                    return Err(ProgramError::Custom(QUEUE_NOT_EMPTY));
+                }
+                let key = PriceTimeKey::new(price, side_from_u64(side)?, 0, seq);
+                if book.book(key.side()).get(&key).is_none() {
+                    return Err(ProgramError::InvalidArgument);
+                }
+                book.cancel_order(user.key(), &key)?;
+                log_info!("Cancelled order {}", seq);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl AsyncIx for OrderbookAsyncIx {
+    type Args = OrderAsyncIxArgs;
+
+    /// Orders are settled with the user who queued them by
+    /// [`OrderbookState::process_batch`], which these args don't carry
+    fn process<S: AsyncState>(&self, _args: &Self::Args, _state: &mut S) -> ProgramResult {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    fn encode_args(args: &OrderAsyncIxArgs) -> Result<ArgsSlot, ProgramError> {
+        queue::encode_pod_args(args)
+    }
+
+    fn decode_args(slot: &ArgsSlot) -> Result<OrderAsyncIxArgs, ProgramError> {
+        queue::decode_pod_args(slot)
+    }
+}
+
+pub struct QueueOrderArgs {
+    payload: OrderPayload,
+    args: OrderAsyncIxArgs,
+}
+
+impl QueueOrderArgs {
+    /// Parses `[side, price, size or seq]` following the async variant
+    fn parse(user: &Pubkey, ixn: OrderbookAsyncIx, data: &[u8]) -> Result<Self, ProgramError> {
+        let [side, price, last] = read_pod::<[u64; 3]>(data)?;
+        strict::check_consumed(data, 24, OrderbookState::STRICTNESS)?;
+        side_from_u64(side)?;
+        let args = match ixn {
+            OrderbookAsyncIx::Take => OrderAsyncIxArgs {
+                side,
+                price,
+                size: last,
+                seq: 0,
+            },
+            OrderbookAsyncIx::Cancel => OrderAsyncIxArgs {
+                side,
+                price,
+                size: 0,
+                seq: last,
+            },
+        };
+
+        Ok(QueueOrderArgs {
+            payload: OrderPayload {
+                user: *user,
+                args: OrderbookAsyncIx::encode_args(&args)?,
+            },
+            args,
+        })
+    }
+}
+
+impl PriceTimeArgs for QueueOrderArgs {
+    fn price(&self) -> u64 {
+        self.args.price
+    }
+
+    fn side(&self) -> Side {
+        // Checked when parsed
+        self.args.side().unwrap_or(Side::Ask)
+    }
+}
+
+impl AsyncState for OrderbookState {
+    type SyncIx = OrderbookSyncIx;
+    type AsyncIx = OrderbookAsyncIx;
+    type Payload = OrderPayload;
+
+    type QueueArgs = QueueOrderArgs;
+    type Key = OrderKey;
+
+    const STRICTNESS: Strictness = Strictness::Strict;
+
+    /// Every slot's cancels and takes settle together
+    const BATCH_MODE: bool = true;
+
+    /// Orders stop being accepted once the crank is about a minute behind
+    const MAX_QUEUE_AGE_SLOTS: u64 = 150;
+
+    const CONFIG_ACCOUNT: bool = true;
+
+    /// Batches that miss their window expire, see [`apq_core::window`]
+    const DRAIN_WINDOW: bool = true;
+
+    fn initialize(&mut self) {
+        let OrderbookState {
+            ref mut seq,
+            ref mut bids,
+            ref mut asks,
+            ref mut async_queue,
+            ref mut balances,
+            // zero initialized
+            header: _,
+            last_batch: _,
+            base_vault: _,
+            quote_vault: _,
+        } = self;
+        *seq = 1;
+        bids.initialize();
+        asks.initialize();
+        async_queue.initialize();
+        balances.initialize();
+    }
+
+    /// Rejects takes limited further than [`PRICE_BAND_PERCENT`] from the last batch's price,
+    /// likely mistyped, once a batch has filled
+    fn validate_enqueue(
+        &self,
+        ixn: &OrderbookAsyncIx,
+        args: &QueueOrderArgs,
+        _accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let last = self.last_batch.last_price;
+        if *ixn != OrderbookAsyncIx::Take || last == 0 {
+            return Ok(());
+        }
+        let band = last.saturating_mul(PRICE_BAND_PERCENT) / 100;
+        if !(last - band..=last.saturating_add(band)).contains(&args.args.price) {
+            return Err(ProgramError::Custom(OUTSIDE_PRICE_BAND));
+        }
+        Ok(())
+    }
+
+    fn queue_args(user: &AccountInfo, data: &[u8]) -> Result<QueueOrderArgs, ProgramError> {
+        let ixn = *OrderbookAsyncIx::from_bytes(data)?;
+        QueueOrderArgs::parse(user.key(), ixn, data.get(8..).unwrap_or_default())
+    }
+
+    fn queue_async(
+        &mut self,
+        ixn: &OrderbookAsyncIx,
+        args: &QueueOrderArgs,
+        now: u64,
+    ) -> Result<(), ProgramError> {
+        let user = args.payload.user;
+        let side = args.args.side()?;
+        match ixn {
+            OrderbookAsyncIx::Take => {
+                let (base, quote) = lock(side, args.args.price, args.args.size)?;
+                self.debit(&user, base, quote)?;
+            }
+            OrderbookAsyncIx::Cancel => {
+                let key = PriceTimeKey::new(args.args.price, side, 0, args.args.seq);
+                let order = self
+                    .book(side)
+                    .get(&key)
+                    .ok_or(ProgramError::InvalidArgument)?;
+                if order.owner != user {
+                    return Err(ProgramError::IncorrectAuthority);
+                }
+            }
+        }
+
+        let key = OrderKey::key(now, ordering::next_seq(&mut self.seq)?, ixn, args);
+        self.async_queue
+            .insert(key, args.payload)
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        emit!(
+            QueuedEvent {
+                user,
+                ixn: key.ixn_value,
+                priority_bid: 0,
+            },
+            key
+        );
+        Ok(())
+    }
+
+    /// Unqueues a take, returning what it locked to the user's balance, which they withdraw
+    /// with [`OrderbookSyncIx::Withdraw`]. Nothing is escrowed in lamports, so nothing is
+    /// refunded in them.
+    fn cancel_async(&mut self, user: &Pubkey, key: &OrderKey) -> Result<u64, ProgramError> {
+        let payload = *self
+            .async_queue
+            .get(key)
+            .ok_or(ProgramError::InvalidArgument)?;
+        if payload.user != *user {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        self.async_queue.remove(key);
+        if key.ixn_value == OrderbookAsyncIx::Take as u64 {
+            let args = payload.args()?;
+            let (base, quote) = lock(args.side()?, args.price, args.size)?;
+            self.credit(user, base, quote)?;
+        }
+
+        emit!(
+            CancelledEvent {
+                user: *user,
+                ixn: key.ixn_value,
+                refund: 0,
+            },
+            *key
+        );
+        Ok(0)
+    }
+
+    fn cancel_all_async(&mut self, user: &Pubkey, max: u32) -> Result<CancelAll, ProgramError> {
+        queue::cancel_all(self, user, max)
+    }
+
+    /// Cancels the entry for whoever queued it, so an expired take returns what it locked
+    fn expire_entry(&mut self, key: &OrderKey) -> ProgramResult {
+        let user = self
+            .async_queue
+            .get(key)
+            .ok_or(ProgramError::InvalidArgument)?
+            .user;
+        self.cancel_async(&user, key)?;
+        Ok(())
+    }
+
+    /// Cancels the next entry for whoever queued it, so a flushed take returns what it locked
+    fn flush_entry(&mut self) -> Result<bool, ProgramError> {
+        let Some((key, user)) = self.peek_entry().map(|entry| (entry.key, entry.value.user)) else {
+            return Ok(false);
+        };
+        self.cancel_async(&user, &key)?;
+        Ok(true)
+    }
+
+    fn next_seq(&mut self) -> Result<u64, ProgramError> {
+        ordering::next_seq(&mut self.seq)
+    }
+
+    /// Resting orders lock what their makers deposited, so they have to be cancelled too
+    fn is_settled(&self) -> bool {
+        self.async_queue.is_empty()
+            && self.bids.is_empty()
+            && self.asks.is_empty()
+            && self.balances.is_empty()
+    }
+
+    fn process_next_async(&mut self) -> ProgramResult {
+        if let Some(next) = pop_min(&mut self.async_queue) {
+            self.process_entry(&next)?;
+        }
+        Ok(())
+    }
+
+    fn process_entry(&mut self, entry: &Entry<OrderKey, OrderPayload>) -> ProgramResult {
+        let ixn = OrderbookAsyncIx::from_u64(entry.key.ixn_value)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let user = entry.value.user;
+        let args = entry.value.args()?;
+        let side = args.side()?;
+        let result = match ixn {
+            OrderbookAsyncIx::Cancel => {
+                self.cancel_order(&user, &PriceTimeKey::new(args.price, side, 0, args.seq))
+            }
+            OrderbookAsyncIx::Take => self.take(&user, side, args.price, args.size),
+        };
+        emit!(
+            ProcessedEvent::new(user, entry.key.ixn_value, &result),
+            entry.key
+        );
+        result
+    }
+
+    /// Settles the batch in key order: cancels, then takes in price-time priority
+    fn process_batch(&mut self, batch: &[Entry<OrderKey, OrderPayload>]) -> ProgramResult {
+        self.last_batch = BatchSummary {
+            slot: batch.first().map_or(0, |entry| entry.key.slot()),
+            ..Default::default()
+        };
+        for entry in batch {
+            self.process_entry(entry)?;
+        }
+        log_debug!(
+            "Settled {} entries, {} fills",
+            batch.len(),
+            self.last_batch.fills
+        );
+        Ok(())
+    }
+
+    fn has_pending_async(&self, slot: u64) -> bool {
+        self.peek_entry()
+            .is_some_and(|entry| entry.key.is_due(slot, Self::ASYNC_DELAY_SLOTS))
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&OrderKey, &OrderPayload)> {
+        self.async_queue.iter()
+    }
+
+    fn peek_entry(&self) -> Option<&Entry<OrderKey, OrderPayload>> {
+        peek_min(&self.async_queue).map(|(_addr, node)| node)
+    }
+
+    fn pop_entry(&mut self) -> Option<Entry<OrderKey, OrderPayload>> {
+        pop_min(&mut self.async_queue)
+    }
+}
+
+pub struct OrderbookProgram;
+
+impl Program for OrderbookProgram {
+    type Sync = OrderbookSyncIx;
+    type Async = OrderbookAsyncIx;
+    type State = OrderbookState;
+
+    fn process(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        dispatch::process::<Self>(program_id, accounts, instruction_data)
+    }
+}
+
+// The macro expands to an unqualified `entrypoint!`
+#[cfg(not(feature = "no-entrypoint"))]
+use pinocchio::entrypoint;
+#[cfg(not(feature = "no-entrypoint"))]
+entrypoint!(process_instruction);
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    OrderbookProgram::process(program_id, accounts, instruction_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apq_client::instructions::InstructionBuilder;
+    use apq_core::{
+        breaker, close,
+        config::{Config, ConfigParams},
+        discriminator::Tag,
+    };
+    use apq_testkit::host::Host;
+    use solana_instruction::AccountMeta;
+
+    const MAKER: Pubkey = [1; 32];
+    const TAKER: Pubkey = [2; 32];
+
+    fn queue(state: &mut OrderbookState, ixn: OrderbookAsyncIx, data: [u64; 3], slot: u64) {
+        let args = QueueOrderArgs::parse(&TAKER, ixn, bytemuck::bytes_of(&data)).unwrap();
+        state.queue_async(&ixn, &args, slot).unwrap();
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = apq_core::state_layout!(OrderbookState {
+            header,
+            seq,
+            last_batch,
+            base_vault,
+            quote_vault,
+            bids,
+            asks,
+            async_queue,
+            balances
+        });
+        let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/golden/OrderbookState.txt");
+        apq_core::layout::assert_golden(&layout, golden);
+    }
+
+    #[test]
+    fn test_take_fills_in_price_time_priority() {
+        let mut state = OrderbookState::new();
+        state.credit(&MAKER, 30, 0).unwrap();
+        state.credit(&[3; 32], 10, 0).unwrap();
+        state.credit(&TAKER, 0, 1_000).unwrap();
+
+        // Same price, the maker posted first. The better price fills before both.
+        state.post_order(&MAKER, Side::Ask, 11, 10).unwrap();
+        state.post_order(&[3; 32], Side::Ask, 11, 10).unwrap();
+        state.post_order(&MAKER, Side::Ask, 10, 10).unwrap();
+
+        queue(&mut state, OrderbookAsyncIx::Take, [0, 11, 25], 5);
+        assert_eq!(state.balance(&TAKER).quote, 1_000 - 11 * 25);
+        assert_eq!(state.process_next_batch(6), Ok(1));
+
+        assert_eq!(
+            state.balance(&TAKER),
+            Balance {
+                base: 25,
+                quote: 735
+            }
+        );
+        assert_eq!(state.balance(&MAKER).quote, 10 * 10 + 11 * 10);
+        assert_eq!(state.balance(&[3; 32]).quote, 11 * 5);
+        let (_addr, rest) = peek_min(&state.asks).unwrap();
+        assert_eq!((rest.value.owner, rest.value.size), ([3; 32], 5));
+        assert_eq!(
+            state.last_batch,
+            BatchSummary {
+                slot: 5,
+                fills: 3,
+                base_volume: 25,
+                quote_volume: 265,
+                last_price: 11,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cancels_settle_before_takes() {
+        let mut state = OrderbookState::new();
+        state.credit(&TAKER, 10, 0).unwrap();
+        let key = state.post_order(&TAKER, Side::Ask, 10, 10).unwrap();
+        state.credit(&TAKER, 0, 100).unwrap();
+
+        // The take is queued first in the same auction, and misses the pulled order
+        queue(&mut state, OrderbookAsyncIx::Take, [0, 10, 10], 5);
+        queue(&mut state, OrderbookAsyncIx::Cancel, [1, 10, key.seq], 5);
+        assert_eq!(state.process_next_batch(6), Ok(2));
+
+        assert!(state.asks.is_empty());
+        assert_eq!(state.last_batch.fills, 0);
+        assert_eq!(
+            state.balance(&TAKER),
+            Balance {
+                base: 10,
+                quote: 100
+            }
+        );
+    }
+
+    #[test]
+    fn test_post_order_rejects_crossing() {
+        let mut state = OrderbookState::new();
+        state.credit(&MAKER, 10, 100).unwrap();
+        state.post_order(&MAKER, Side::Bid, 9, 5).unwrap();
+        assert_eq!(state.balance(&MAKER).quote, 55);
+
+        assert_eq!(
+            state.post_order(&MAKER, Side::Ask, 9, 5),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            state.post_order(&MAKER, Side::Bid, 9, 100),
+            Err(ProgramError::InsufficientFunds)
+        );
+        state.post_order(&MAKER, Side::Ask, 10, 5).unwrap();
+        assert_eq!(
+            (state.best(Side::Bid), state.best(Side::Ask)),
+            (Some(9), Some(10))
+        );
+    }
+
+    #[test]
+    fn test_breaker_trips_on_stalled_crank() {
+        let mut state = OrderbookState::new();
+        state.credit(&TAKER, 0, 100).unwrap();
+        let max_age = OrderbookState::MAX_QUEUE_AGE_SLOTS;
+        queue(&mut state, OrderbookAsyncIx::Take, [0, 10, 1], 5);
+        assert_eq!(breaker::check(&*state, 5 + max_age), Ok(()));
+        assert_eq!(
+            breaker::check(&*state, 6 + max_age),
+            Err(ProgramError::Custom(breaker::STALLED))
+        );
+
+        // Draining the backlog resets it
+        state.process_next_batch(6 + max_age).unwrap();
+        assert_eq!(breaker::check(&*state, 6 + max_age), Ok(()));
+    }
+
+    #[test]
+    fn test_takes_within_price_band() {
+        let mut state = OrderbookState::new();
+        let take = |price: u64| {
+            let data = [0, price, 1];
+            QueueOrderArgs::parse(&TAKER, OrderbookAsyncIx::Take, bytemuck::bytes_of(&data))
+                .unwrap()
+        };
+        let validate = |state: &OrderbookState, price: u64| {
+            state.validate_enqueue(&OrderbookAsyncIx::Take, &take(price), &[])
+        };
+        // Nothing filled yet to band around
+        assert_eq!(validate(&state, 1_000), Ok(()));
+
+        state.last_batch.last_price = 10;
+        for price in [5, 10, 15] {
+            assert_eq!(validate(&state, price), Ok(()));
+        }
+        for price in [4, 16] {
+            assert_eq!(
+                validate(&state, price),
+                Err(ProgramError::Custom(OUTSIDE_PRICE_BAND))
+            );
+        }
+    }
+
+    #[test]
+    fn test_cancel_async_unlocks_take() {
+        let mut state = OrderbookState::new();
+        state.credit(&TAKER, 3, 0).unwrap();
+        queue(&mut state, OrderbookAsyncIx::Take, [1, 10, 3], 5);
+        assert_eq!(state.balance(&TAKER).base, 0);
+
+        let key = *state.async_queue.iter().next().unwrap().0;
+        assert_eq!(
+            state.cancel_async(&MAKER, &key),
+            Err(ProgramError::IncorrectAuthority)
+        );
+        assert_eq!(state.cancel_async(&TAKER, &key), Ok(0));
+        assert_eq!(state.balance(&TAKER).base, 3);
+    }
+
+    /// The order book run through the dispatch on a [`Host`], with a builder for its state
+    /// and config, and the admin who initialized them
+    fn host() -> (Host, InstructionBuilder, solana_pubkey::Pubkey) {
+        let program_id = solana_pubkey::Pubkey::new_unique();
+        let mut host = Host::new(program_id, dispatch::process_with::<OrderbookProgram, Tag>);
+        let state = host.create_state::<OrderbookState>();
+        let config = host.create_account(Config::LEN, &program_id, 0);
+        let builder = InstructionBuilder::new(program_id, state).with_config(config);
+        // The first signer becomes the admin
+        let admin = host.user(0);
+        host.send(&builder.init_config(&admin, &ConfigParams::default()))
+            .unwrap();
+        (host, builder, admin)
+    }
+
+    /// Instruction data of a variant followed by its `u64` arguments
+    fn data(variant: u64, args: &[u64]) -> Vec<u8> {
+        [&[variant][..], args]
+            .concat()
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_withdraw_and_cancel_order() {
+        let (mut host, builder, admin) = host();
+        let state = builder.state;
+        let (maker, cranker) = (host.user(0), host.user(0));
+        // A lamport vault the program owns, so that it can pay out of it
+        let owner = host.program_id;
+        let vault = host.create_account(0, &owner, 0);
+        let set_vault = [
+            data(OrderbookSyncIx::SetVault as u64, &[Asset::Quote as u64]),
+            bytemuck::bytes_of(&Vault::lamports(vault.to_bytes(), 1)).to_vec(),
+        ]
+        .concat();
+        host.send(&builder.sync(&admin, &set_vault, &[])).unwrap();
+
+        let deposit = data(
+            OrderbookSyncIx::Deposit as u64,
+            &[Asset::Quote as u64, 1_000],
+        );
+        let system_program = AccountMeta::new_readonly(Default::default(), false);
+        let accounts = [AccountMeta::new(vault, false), system_program];
+        host.send(&builder.sync(&maker, &deposit, &accounts))
+            .unwrap();
+        // What the deposit's transfer would have paid, CPIs don't run on the host
+        host.airdrop(&vault, 1_000);
+        for price in [10, 9] {
+            let post = data(OrderbookSyncIx::PostOrder as u64, &[0, price, 50]);
+            host.send(&builder.sync(&maker, &post, &[])).unwrap();
+        }
+        let book = host.state::<OrderbookState>(&state);
+        let keys: Vec<PriceTimeKey> = book.bids.iter().map(|(key, _)| *key).collect();
+        assert_eq!(book.balance(&maker.to_bytes()).quote, 1_000 - 950);
+        // Not closable while the orders and deposits are owed to the maker
+        let close_state = builder.close_state(&admin, &admin);
+        let not_settled = Err(ProgramError::Custom(close::NOT_SETTLED));
+        assert_eq!(host.send(&close_state), not_settled);
+
+        // Only what orders don't lock can be withdrawn
+        let withdraw = |amount| {
+            data(
+                OrderbookSyncIx::Withdraw as u64,
+                &[Asset::Quote as u64, amount],
+            )
+        };
+        let accounts = [
+            AccountMeta::new(maker, false),
+            AccountMeta::new(vault, false),
+        ];
+        assert_eq!(
+            host.send(&builder.sync(&maker, &withdraw(51), &accounts)),
+            Err(ProgramError::InsufficientFunds)
+        );
+
+        // While anything is queued, orders are cancelled through the queue
+        let cancel = |key: &PriceTimeKey| [0, key.price, key.seq];
+        let queued = data(OrderbookAsyncIx::Cancel as u64, &cancel(&keys[0]));
+        host.send(&builder.queue_async(&maker, &queued)).unwrap();
+        let cancel_order = data(OrderbookSyncIx::CancelOrder as u64, &cancel(&keys[1]));
+        assert_eq!(
+            host.send(&builder.sync(&maker, &cancel_order, &[])),
+            Err(ProgramError::Custom(QUEUE_NOT_EMPTY))
+        );
+        host.slot = 1;
+        host.send(&builder.drain(&cranker)).unwrap();
+        host.send(&builder.sync(&maker, &cancel_order, &[]))
+            .unwrap();
+        // Only once
+        assert_eq!(
+            host.send(&builder.sync(&maker, &cancel_order, &[])),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        let book = host.state::<OrderbookState>(&state);
+        assert!(book.bids.is_empty());
+        assert_eq!(book.balance(&maker.to_bytes()).quote, 1_000);
+        assert_eq!(host.send(&close_state), not_settled);
+        host.send(&builder.sync(&maker, &withdraw(1_000), &accounts))
+            .unwrap();
+        assert_eq!((host.lamports(&maker), host.lamports(&vault)), (1_000, 0));
+        assert_eq!(
+            host.state::<OrderbookState>(&state)
+                .balance(&maker.to_bytes()),
+            Balance::default()
+        );
+        host.send(&close_state).unwrap();
+    }
+
+    #[test]
+    fn test_flush_unlocks_takes() {
+        let (mut host, builder, admin) = host();
+        let state = builder.state;
+        let taker = host.user(0);
+        // Free vaults
+        let deposit = data(OrderbookSyncIx::Deposit as u64, &[Asset::Quote as u64, 100]);
+        host.send(&builder.sync(&admin, &deposit, &[])).unwrap();
+        host.send(&builder.sync(&taker, &deposit, &[])).unwrap();
+        let take = data(OrderbookAsyncIx::Take as u64, &[0, 10, 5]);
+        host.send(&builder.queue_async(&taker, &take)).unwrap();
+        let book = host.state::<OrderbookState>(&state);
+        assert_eq!(book.balance(&taker.to_bytes()).quote, 50);
+
+        host.send(&builder.flush(&admin, 8)).unwrap();
+        let book = host.state::<OrderbookState>(&state);
+        assert!(book.async_queue.is_empty());
+        assert_eq!(book.balance(&taker.to_bytes()).quote, 100);
+    }
+
+    #[test]
+    fn test_drain_window_expires_missed_batches() {
+        let (mut host, builder, admin) = host();
+        let state = builder.state;
+        let (taker, cranker) = (host.user(0), host.user(0));
+        let mut params = ConfigParams::default();
+        params.drain_window = 2;
+        host.send(&builder.update_config(&admin, &params)).unwrap();
+
+        let deposit = data(OrderbookSyncIx::Deposit as u64, &[Asset::Quote as u64, 100]);
+        host.send(&builder.sync(&taker, &deposit, &[])).unwrap();
+        let take = data(OrderbookAsyncIx::Take as u64, &[0, 10, 5]);
+        host.send(&builder.queue_async(&taker, &take)).unwrap();
+        host.slot = 1;
+        host.send(&builder.queue_async(&taker, &take)).unwrap();
+        assert_eq!(
+            host.state::<OrderbookState>(&state)
+                .balance(&taker.to_bytes())
+                .quote,
+            0
+        );
+
+        // Slot 0's batch was drainable in slots 1 and 2, slot 1's is still open
+        host.slot = 3;
+        host.send(&builder.drain(&cranker)).unwrap();
+        let book = host.state::<OrderbookState>(&state);
+        assert!(book.async_queue.is_empty());
+        let stats = book.header.stats;
+        assert_eq!((stats.total_cancelled, stats.total_processed), (1, 1));
+        assert_eq!(book.last_batch.slot, 1);
+        // The expired take unlocked, the drained one found nothing to fill
+        assert_eq!(book.balance(&taker.to_bytes()).quote, 100);
+    }
+
+    #[test]
+    fn test_queueing_needs_the_users_signature() {
+        let (mut host, builder, _admin) = host();
+        let (taker, attacker) = (host.user(0), host.user(0));
+        let deposit = data(OrderbookSyncIx::Deposit as u64, &[Asset::Quote as u64, 100]);
+        host.send(&builder.sync(&taker, &deposit, &[])).unwrap();
+
+        // Nothing is escrowed, so only the signature stops a take debiting someone else
+        let take = data(OrderbookAsyncIx::Take as u64, &[0, 10, 5]);
+        let mut forged = builder.queue_async(&taker, &take);
+        for meta in &mut forged.accounts {
+            meta.is_signer = false;
+        }
+        forged
+            .accounts
+            .push(AccountMeta::new_readonly(attacker, true));
+        assert_eq!(
+            host.send(&forged),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        let book = host.state::<OrderbookState>(&builder.state);
+        assert!(book.async_queue.is_empty());
+        assert_eq!(book.balance(&taker.to_bytes()).quote, 100);
+    }
+}
@@ -0,0 +1,113 @@
+//! Differential testing of instruction and state decoders
+//!
+//! Programs often decode the same type two ways, e.g. zero-copy for the hot path and owned for
+//! clients or tests. [`assert_same_decoding`] runs both over the [`inputs`] and fails on the
+//! first input they disagree on, whether one accepts what the other rejects or both accept it
+//! as different values.
+
+use std::{fmt::Debug, ops::Deref};
+
+use apq_core::FromBytes;
+
+/// Edge values tried in every 8 byte word of the generated inputs
+const EDGES: [u64; 6] = [0, 1, 2, 0xff, i64::MAX as u64, u64::MAX];
+
+/// Inputs of up to `max_len` bytes: every length zeroed, each [`EDGES`] value in each aligned
+/// word, then `random` inputs of random length and content drawn from `seed`
+pub fn inputs(max_len: usize, random: usize, seed: u64) -> Vec<Vec<u8>> {
+    let mut inputs: Vec<Vec<u8>> = (0..=max_len).map(|len| vec![0; len]).collect();
+    for offset in (0..max_len.saturating_sub(7)).step_by(8) {
+        for edge in EDGES {
+            let mut input = vec![0; max_len];
+            input[offset..offset + 8].copy_from_slice(&edge.to_le_bytes());
+            inputs.push(input);
+        }
+    }
+
+    let mut state = seed;
+    let mut next = move || {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    for _ in 0..random {
+        let len = next() as usize % (max_len + 1);
+        // Mostly small words so that enum variants and lengths come up
+        let input = (0..len)
+            .map(|_| match next() % 4 {
+                0 => next() as u8,
+                _ => (next() % 3) as u8,
+            })
+            .collect();
+        inputs.push(input);
+    }
+    inputs
+}
+
+/// Decodes with `T`'s [`FromBytes`] impl, cloning the value out of whatever it borrows
+pub fn decode<T>(bytes: &[u8]) -> Option<T>
+where
+    T: FromBytes + Clone,
+    for<'a> T::Target<'a>: Deref<Target = T>,
+{
+    T::from_bytes(bytes)
+        .ok()
+        .map(|target| target.deref().clone())
+}
+
+/// Asserts that `reference` and `candidate` accept the same inputs as the same values
+///
+/// Each input is passed 8 byte aligned, so zero-copy decoders are tested on what they decode
+/// rather than where the bytes happen to be.
+#[track_caller]
+pub fn assert_same_decoding<T: PartialEq + Debug>(
+    inputs: &[Vec<u8>],
+    reference: impl Fn(&[u8]) -> Option<T>,
+    candidate: impl Fn(&[u8]) -> Option<T>,
+) {
+    for input in inputs {
+        let mut aligned = vec![0u64; input.len().div_ceil(8)];
+        let bytes = &mut bytemuck::cast_slice_mut(&mut aligned)[..input.len()];
+        bytes.copy_from_slice(input);
+
+        let expected = reference(bytes);
+        let actual = candidate(bytes);
+        assert_eq!(
+            expected,
+            actual,
+            "decoders disagree on {} bytes {:02x?}",
+            input.len(),
+            input
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(bytes: &[u8]) -> Option<u64> {
+        bytes
+            .first_chunk()
+            .map(|word| u64::from_le_bytes(*word))
+            .filter(|&variant| variant < 2)
+    }
+
+    #[test]
+    fn test_disagreement_found() {
+        let inputs = inputs(16, 64, 1);
+        assert!(inputs
+            .iter()
+            .any(|input| input.len() == 16 && input[..8] == [1, 0, 0, 0, 0, 0, 0, 0]));
+        assert_same_decoding(&inputs, variant, variant);
+
+        // Only looking at the first byte accepts variants the full word doesn't
+        let first_byte = |bytes: &[u8]| bytes.first().map(|&b| b as u64).filter(|&v| v < 2);
+        let result =
+            std::panic::catch_unwind(|| assert_same_decoding(&inputs, variant, first_byte));
+        assert!(result.is_err());
+    }
+}
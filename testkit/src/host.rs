@@ -0,0 +1,685 @@
+//! Running a program's dispatch natively, without building it for SBF
+//!
+//! [`Host`] keeps accounts in memory, serializes the ones an instruction names into the
+//! runtime's input format and runs the program's entrypoint on them in the test process,
+//! keeping what it wrote only if it succeeds, like the runtime. Tests then cover the dispatch
+//! end to end with instructions from the client, e.g.
+//!
+//! ```ignore
+//! let mut host = Host::new(program_id, dispatch::process_with::<MyProgram, Tag>);
+//! let state = host.create_state::<MyState>();
+//! host.send(&client.drain(&cranker))?;
+//! ```
+//!
+//! Off-chain, pinocchio turns CPIs into no-ops and can't read sysvars. So system program
+//! transfers don't move lamports, e.g. the escrow a queued instruction pays, which tests pay
+//! with [`Host::airdrop`] where it matters, and the clock is the host's [`Host::slot`].
+//! Lamports a program moves itself, e.g. crank bounties paid out of the state, do move. The
+//! instructions sysvar is an account like any other, which [`Host::send_transaction`] fills.
+
+use std::{collections::HashMap, mem::MaybeUninit};
+
+use apq_client::{DecodeError, TryDecode};
+use apq_core::{ordering::FixedClock, AsyncState};
+use pinocchio::{
+    account_info::{AccountInfo, MAX_PERMITTED_DATA_INCREASE},
+    program_error::ProgramError,
+    ProgramResult,
+};
+use solana_instruction::{BorrowedAccountMeta, BorrowedInstruction, Instruction};
+use solana_program::sysvar::{self, instructions};
+use solana_pubkey::Pubkey;
+
+/// A program entrypoint the host runs, e.g. `dispatch::process_with::<MyProgram, Tag>`
+pub type Entrypoint =
+    fn(&pinocchio::pubkey::Pubkey, &[AccountInfo], &[u8], &FixedClock) -> ProgramResult;
+
+/// Most accounts one instruction can name
+const MAX_ACCOUNTS: usize = 64;
+
+/// Stack the program runs on
+const STACK_SIZE: usize = 64 << 20;
+
+/// Marks an account serialized in full rather than as a duplicate of an earlier one
+const NON_DUP_MARKER: u8 = u8::MAX;
+
+/// Bytes of the runtime's header of a serialized account, up to its data
+const ACCOUNT_HEADER_LEN: usize = 88;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostAccount {
+    pub owner: Pubkey,
+    pub lamports: u64,
+    pub data: Vec<u8>,
+}
+
+pub struct Host {
+    pub program_id: Pubkey,
+    entrypoint: Entrypoint,
+    /// Accounts not in here are empty and owned by the system program, as on-chain
+    pub accounts: HashMap<Pubkey, HostAccount>,
+    /// Time the program reads, on every schedule
+    pub slot: u64,
+}
+
+impl Host {
+    pub fn new(program_id: Pubkey, entrypoint: Entrypoint) -> Self {
+        Host {
+            program_id,
+            entrypoint,
+            accounts: HashMap::new(),
+            slot: 0,
+        }
+    }
+
+    /// A new user holding `lamports`
+    pub fn user(&mut self, lamports: u64) -> Pubkey {
+        let user = Pubkey::new_unique();
+        self.airdrop(&user, lamports);
+        user
+    }
+
+    pub fn airdrop(&mut self, pubkey: &Pubkey, lamports: u64) {
+        self.accounts.entry(*pubkey).or_default().lamports += lamports;
+    }
+
+    pub fn lamports(&self, pubkey: &Pubkey) -> u64 {
+        self.accounts
+            .get(pubkey)
+            .map_or(0, |account| account.lamports)
+    }
+
+    /// Creates a zeroed account of `len` bytes owned by `owner`, holding `lamports`
+    pub fn create_account(&mut self, len: usize, owner: &Pubkey, lamports: u64) -> Pubkey {
+        let account = Pubkey::new_unique();
+        self.accounts.insert(
+            account,
+            HostAccount {
+                owner: *owner,
+                lamports,
+                data: vec![0; len],
+            },
+        );
+        account
+    }
+
+    /// Creates a state account of [`AsyncState::LEN`] for the program, initialized by its
+    /// first instruction. It holds no lamports, so those it pays out are what it was paid.
+    pub fn create_state<S: AsyncState>(&mut self) -> Pubkey {
+        let owner = self.program_id;
+        self.create_account(S::LEN, &owner, 0)
+    }
+
+    pub fn try_state<S: TryDecode>(&self, state: &Pubkey) -> Result<Box<S>, DecodeError> {
+        let data = self
+            .accounts
+            .get(state)
+            .map_or(&[][..], |account| &account.data);
+        S::try_decode(data)
+    }
+
+    /// The decoded state, panicking if it doesn't decode
+    #[track_caller]
+    pub fn state<S: TryDecode>(&self, state: &Pubkey) -> Box<S> {
+        match self.try_state(state) {
+            Ok(state) => state,
+            Err(err) => panic!("invalid state: {err}"),
+        }
+    }
+
+    /// Decodes the state, lets `modify` change it and writes it back
+    #[track_caller]
+    pub fn modify_state<S: TryDecode>(&mut self, state: &Pubkey, modify: impl FnOnce(&mut S)) {
+        let mut decoded = self.state::<S>(state);
+        modify(&mut decoded);
+        let account = self.accounts.get_mut(state).unwrap();
+        account.data[..size_of::<S>()].copy_from_slice(bytemuck::bytes_of(&*decoded));
+    }
+
+    /// Runs `instruction`, keeping the accounts it wrote if it succeeds. Signatures aren't
+    /// checked, so it can name any account as a signer.
+    pub fn send(&mut self, instruction: &Instruction) -> ProgramResult {
+        if instruction.program_id != self.program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if instruction.accounts.len() > MAX_ACCOUNTS {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+
+        let (mut input, offsets) = self.serialize(instruction);
+        let (entrypoint, clock) = (self.entrypoint, FixedClock(self.slot));
+        let run = || {
+            let mut infos = [const { MaybeUninit::<AccountInfo>::uninit() }; MAX_ACCOUNTS];
+            // SAFETY: `input` is laid out as the runtime serializes it, 8-byte aligned, and
+            // outlives the account infos
+            let (program_id, count, data) = unsafe {
+                pinocchio::entrypoint::deserialize::<MAX_ACCOUNTS>(
+                    input.as_mut_ptr() as *mut u8,
+                    &mut infos,
+                )
+            };
+            let infos =
+                unsafe { std::slice::from_raw_parts(infos.as_ptr() as *const AccountInfo, count) };
+            entrypoint(program_id, infos, data, &clock)
+        };
+        // Unoptimized dispatches need more stack than test threads get
+        std::thread::scope(|scope| {
+            let program = std::thread::Builder::new()
+                .stack_size(STACK_SIZE)
+                .spawn_scoped(scope, run)
+                .expect("spawning the program's thread");
+            program
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })?;
+
+        let bytes: &[u8] = bytemuck::cast_slice(&input);
+        for (key, offset) in offsets {
+            let header = &bytes[offset..offset + ACCOUNT_HEADER_LEN];
+            let owner = Pubkey::new_from_array(header[40..72].try_into().unwrap());
+            let lamports = u64::from_le_bytes(header[72..80].try_into().unwrap());
+            let len = u64::from_le_bytes(header[80..88].try_into().unwrap()) as usize;
+            let data = offset + ACCOUNT_HEADER_LEN;
+            self.accounts.insert(
+                key,
+                HostAccount {
+                    owner,
+                    lamports,
+                    data: bytes[data..data + len].to_vec(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Runs the program's `instructions` in order, like [`Host::send`] but with the instructions
+    /// sysvar holding all of them, keeping the accounts they wrote only if they all succeed.
+    /// Instructions to other programs, e.g. ed25519 signature checks, are skipped, so their
+    /// signatures aren't verified either.
+    pub fn send_transaction(&mut self, instructions: &[Instruction]) -> ProgramResult {
+        let borrowed: Vec<BorrowedInstruction> = instructions
+            .iter()
+            .map(|instruction| BorrowedInstruction {
+                program_id: &instruction.program_id,
+                accounts: instruction
+                    .accounts
+                    .iter()
+                    .map(|meta| BorrowedAccountMeta {
+                        pubkey: &meta.pubkey,
+                        is_signer: meta.is_signer,
+                        is_writable: meta.is_writable,
+                    })
+                    .collect(),
+                data: &instruction.data,
+            })
+            .collect();
+        let mut data = instructions::construct_instructions_data(&borrowed);
+
+        let before = self.accounts.clone();
+        for (index, instruction) in instructions.iter().enumerate() {
+            if instruction.program_id != self.program_id {
+                continue;
+            }
+            // The sysvar ends with the index of the instruction running
+            let current = data.len() - 2;
+            data[current..].copy_from_slice(&(index as u16).to_le_bytes());
+            let sysvar = HostAccount {
+                owner: sysvar::ID,
+                lamports: 1,
+                data: data.clone(),
+            };
+            self.accounts.insert(instructions::ID, sysvar);
+            if let Err(err) = self.send(instruction) {
+                self.accounts = before;
+                return Err(err);
+            }
+        }
+        self.accounts.remove(&instructions::ID);
+        Ok(())
+    }
+
+    /// Serializes the instruction as the runtime's input, 8-byte aligned, with where each
+    /// distinct account's header starts
+    fn serialize(&self, instruction: &Instruction) -> (Vec<u64>, Vec<(Pubkey, usize)>) {
+        let metas = &instruction.accounts;
+        let mut bytes = (metas.len() as u64).to_le_bytes().to_vec();
+        let mut offsets = Vec::new();
+        for (i, meta) in metas.iter().enumerate() {
+            if let Some(first) = metas[..i].iter().position(|m| m.pubkey == meta.pubkey) {
+                bytes.extend([first as u8, 0, 0, 0, 0, 0, 0, 0]);
+                continue;
+            }
+            // The runtime grants an account what any of its metas asks for
+            let same = || metas.iter().filter(|m| m.pubkey == meta.pubkey);
+            let is_signer = same().any(|m| m.is_signer);
+            let is_writable = same().any(|m| m.is_writable);
+
+            let account = self.accounts.get(&meta.pubkey).cloned().unwrap_or_default();
+            offsets.push((meta.pubkey, bytes.len()));
+            bytes.extend([NON_DUP_MARKER, is_signer as u8, is_writable as u8, 0]);
+            bytes.extend(0_u32.to_le_bytes());
+            bytes.extend(meta.pubkey.to_bytes());
+            bytes.extend(account.owner.to_bytes());
+            bytes.extend(account.lamports.to_le_bytes());
+            bytes.extend((account.data.len() as u64).to_le_bytes());
+            bytes.extend(&account.data);
+            bytes.resize(
+                (bytes.len() + MAX_PERMITTED_DATA_INCREASE).next_multiple_of(8),
+                0,
+            );
+            // Rent epoch
+            bytes.extend(0_u64.to_le_bytes());
+        }
+        bytes.extend((instruction.data.len() as u64).to_le_bytes());
+        bytes.extend(&instruction.data);
+        bytes.extend(instruction.program_id.to_bytes());
+
+        let mut input = vec![0_u64; bytes.len().div_ceil(8)];
+        bytemuck::cast_slice_mut::<u64, u8>(&mut input)[..bytes.len()].copy_from_slice(&bytes);
+        (input, offsets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_instruction::AccountMeta;
+
+    use super::*;
+
+    /// Moves the instruction's lamports from the first account to the second, then fails if
+    /// asked to
+    fn transfer(
+        _program_id: &pinocchio::pubkey::Pubkey,
+        accounts: &[AccountInfo],
+        data: &[u8],
+        clock: &FixedClock,
+    ) -> ProgramResult {
+        let [from, to] = accounts else {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        };
+        if !from.is_signer() || !to.is_writable() {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let lamports = u64::from_le_bytes(data[..8].try_into().unwrap());
+        *from.try_borrow_mut_lamports()? -= lamports;
+        *to.try_borrow_mut_lamports()? += lamports;
+        to.try_borrow_mut_data()?[0] = clock.0 as u8;
+        match data[8] {
+            0 => Ok(()),
+            _ => Err(ProgramError::Custom(7)),
+        }
+    }
+
+    /// States run through the dispatch
+    mod mock {
+        use apq_core::{
+            dispatch,
+            header::StateHeader,
+            layout_hash,
+            migrate::Migratable,
+            ordering::{FifoKey, OrderingKey},
+            paged_queue::{Pages, QueuePage},
+            queue::{peek_min, pop_min, Entry},
+            AsyncIx, AsyncState, FromBytes, Program, SyncIx,
+        };
+        use bytemuck::{Pod, Zeroable};
+        use pinocchio::{account_info::AccountInfo, program_error::ProgramError, ProgramResult};
+
+        pub type Page = QueuePage<FifoKey, u64, 2>;
+
+        #[derive(PartialEq, Eq, PartialOrd, Ord)]
+        pub struct Add;
+
+        impl FromBytes for Add {
+            type Target<'a> = &'a Add;
+            type TargetMut<'a> = &'a Add;
+            fn from_bytes(_bytes: &[u8]) -> Result<&Add, ProgramError> {
+                Ok(&Add)
+            }
+        }
+
+        impl SyncIx for Add {
+            fn process<S: AsyncState>(
+                &self,
+                _: &[u8],
+                _: &[AccountInfo],
+                _: &mut S,
+            ) -> ProgramResult {
+                Err(ProgramError::InvalidInstructionData)
+            }
+        }
+
+        impl AsyncIx for Add {
+            type Args = ();
+            fn process<S: AsyncState>(&self, _args: &(), _state: &mut S) -> ProgramResult {
+                Ok(())
+            }
+        }
+
+        /// Adds up the amounts it queues, in a queue of two entries per page
+        #[derive(Copy, Clone, Zeroable, Pod)]
+        #[repr(C)]
+        pub struct Paged {
+            pub header: StateHeader,
+            pub seq: u64,
+            pub total: u64,
+            pub queue: Page,
+        }
+
+        impl Migratable for Paged {
+            const VERSION: u32 = 1;
+            fn migrate(_from_version: u32, _data: &mut [u8]) -> ProgramResult {
+                Ok(())
+            }
+        }
+
+        impl FromBytes for Paged {
+            type Target<'a> = &'a Paged;
+            type TargetMut<'a> = &'a mut Paged;
+            fn from_bytes(bytes: &[u8]) -> Result<&Paged, ProgramError> {
+                bytemuck::try_from_bytes(bytes).map_err(|_| ProgramError::InvalidAccountData)
+            }
+            fn from_bytes_mut(bytes: &mut [u8]) -> Result<&mut Paged, ProgramError> {
+                bytemuck::try_from_bytes_mut(bytes).map_err(|_| ProgramError::InvalidAccountData)
+            }
+        }
+
+        impl AsyncState for Paged {
+            type SyncIx = Add;
+            type AsyncIx = Add;
+            type Payload = u64;
+            type QueueArgs = u64;
+            type Key = FifoKey;
+
+            const OVERFLOW_PAGES: bool = true;
+
+            fn initialize(&mut self) {
+                self.queue.initialize();
+            }
+
+            fn queue_args(_user: &AccountInfo, data: &[u8]) -> Result<u64, ProgramError> {
+                apq_core::pod::read_pod(data)
+            }
+
+            fn queue_async(&mut self, _ix: &Add, _args: &u64, _now: u64) -> ProgramResult {
+                Err(ProgramError::InvalidInstructionData)
+            }
+
+            fn queue_async_paged(
+                &mut self,
+                ix: &Add,
+                amount: &u64,
+                now: u64,
+                pages: &Pages,
+            ) -> ProgramResult {
+                self.seq += 1;
+                let key = FifoKey::key(now, self.seq, ix, amount);
+                pages.load(&mut self.queue)?.insert(key, *amount)
+            }
+
+            fn refill_from_pages(&mut self, pages: &Pages) -> Result<bool, ProgramError> {
+                pages.refill(&mut self.queue)
+            }
+
+            fn process_next_async(&mut self) -> ProgramResult {
+                if let Some(entry) = self.pop_entry() {
+                    self.total += entry.value;
+                }
+                Ok(())
+            }
+
+            fn has_pending_async(&self, now: u64) -> bool {
+                self.peek_entry()
+                    .is_some_and(|entry| entry.key.slot + Self::ASYNC_DELAY_SLOTS <= now)
+            }
+
+            fn entries(&self) -> impl Iterator<Item = (&FifoKey, &u64)> {
+                apq_client::queue::entries(&self.queue.queue).map_while(Result::ok)
+            }
+
+            fn peek_entry(&self) -> Option<&Entry<FifoKey, u64>> {
+                peek_min(&self.queue.queue).map(|(_addr, node)| node)
+            }
+
+            fn pop_entry(&mut self) -> Option<Entry<FifoKey, u64>> {
+                pop_min(&mut self.queue.queue)
+            }
+        }
+
+        pub struct PagedProgram;
+
+        impl Program for PagedProgram {
+            type Sync = Add;
+            type Async = Add;
+            type State = Paged;
+
+            fn process(
+                program_id: &pinocchio::pubkey::Pubkey,
+                accounts: &[AccountInfo],
+                data: &[u8],
+            ) -> ProgramResult {
+                dispatch::process::<Self>(program_id, accounts, data)
+            }
+        }
+
+        /// Counts what it queues without keeping it, as an instance of a registry
+        #[derive(Copy, Clone, Zeroable, Pod)]
+        #[repr(C)]
+        pub struct Tally {
+            pub header: StateHeader,
+            pub queued: u64,
+        }
+
+        impl Migratable for Tally {
+            const VERSION: u32 = 1;
+            const LAYOUT_HASH: u64 = layout_hash!(Tally {
+                header: StateHeader,
+                queued: u64,
+            });
+            fn migrate(_from_version: u32, _data: &mut [u8]) -> ProgramResult {
+                Ok(())
+            }
+        }
+
+        impl FromBytes for Tally {
+            type Target<'a> = &'a Tally;
+            type TargetMut<'a> = &'a mut Tally;
+            fn from_bytes(bytes: &[u8]) -> Result<&Tally, ProgramError> {
+                bytemuck::try_from_bytes(bytes).map_err(|_| ProgramError::InvalidAccountData)
+            }
+            fn from_bytes_mut(bytes: &mut [u8]) -> Result<&mut Tally, ProgramError> {
+                bytemuck::try_from_bytes_mut(bytes).map_err(|_| ProgramError::InvalidAccountData)
+            }
+        }
+
+        impl AsyncState for Tally {
+            type SyncIx = Add;
+            type AsyncIx = Add;
+            type Payload = u64;
+            type QueueArgs = ();
+            type Key = FifoKey;
+
+            const INSTANCES: bool = true;
+
+            fn initialize(&mut self) {}
+
+            fn queue_args(_user: &AccountInfo, _data: &[u8]) -> Result<(), ProgramError> {
+                Ok(())
+            }
+
+            fn queue_async(&mut self, _ix: &Add, _args: &(), _now: u64) -> ProgramResult {
+                self.queued += 1;
+                Ok(())
+            }
+
+            fn process_next_async(&mut self) -> ProgramResult {
+                Ok(())
+            }
+
+            fn has_pending_async(&self, _now: u64) -> bool {
+                false
+            }
+
+            fn entries(&self) -> impl Iterator<Item = (&FifoKey, &u64)> {
+                std::iter::empty()
+            }
+
+            fn peek_entry(&self) -> Option<&Entry<FifoKey, u64>> {
+                None
+            }
+
+            fn pop_entry(&mut self) -> Option<Entry<FifoKey, u64>> {
+                None
+            }
+        }
+
+        pub struct TallyProgram;
+
+        impl Program for TallyProgram {
+            type Sync = Add;
+            type Async = Add;
+            type State = Tally;
+
+            fn process(
+                program_id: &pinocchio::pubkey::Pubkey,
+                accounts: &[AccountInfo],
+                data: &[u8],
+            ) -> ProgramResult {
+                dispatch::process::<Self>(program_id, accounts, data)
+            }
+        }
+    }
+
+    #[test]
+    fn test_paged_queue() {
+        use apq_client::{instructions::InstructionBuilder, queue};
+        use apq_core::{discriminator::Tag, dispatch, paged_queue::NO_PAGE};
+        use mock::{Page, Paged, PagedProgram};
+
+        let program_id = Pubkey::new_unique();
+        let mut host = Host::new(program_id, dispatch::process_with::<PagedProgram, Tag>);
+        let state = host.create_state::<Paged>();
+        let builder = InstructionBuilder::new(program_id, state);
+        let user = host.user(0);
+        let pages = [0; 2].map(|_| host.create_account(size_of::<Page>(), &program_id, 0));
+        let with_pages = |mut ix: Instruction, pages: &[Pubkey]| {
+            let pages = pages.iter().map(|page| AccountMeta::new(*page, false));
+            ix.accounts.extend(pages);
+            ix
+        };
+        let add = |amount: u64| builder.queue_async(&user, &amount.to_le_bytes());
+        let page = |host: &Host, page: &Pubkey| {
+            bytemuck::pod_read_unaligned::<Page>(&host.accounts[page].data)
+        };
+
+        // The primary page fills up, then spills into the pages given
+        for amount in [1, 2] {
+            host.send(&add(amount)).unwrap();
+        }
+        assert_eq!(host.send(&add(3)), Err(ProgramError::NotEnoughAccountKeys));
+        for amount in [3, 4, 5, 6] {
+            host.send(&with_pages(add(amount), &pages)).unwrap();
+        }
+        let primary = host.state::<Paged>(&state).queue;
+        assert_eq!(primary.header.next_page, pages[0].to_bytes());
+        assert_eq!(page(&host, &pages[0]).header.next_page, pages[1].to_bytes());
+        assert_eq!(page(&host, &pages[1]).header.queue, state.to_bytes());
+
+        // Each drain processes the primary page and refills it from the next one
+        host.slot += 2;
+        let cranker = host.user(0);
+        host.send(&with_pages(builder.drain(&cranker), &pages))
+            .unwrap();
+        let drained = host.state::<Paged>(&state);
+        assert_eq!(drained.total, 1 + 2);
+        assert_eq!(drained.queue.header.next_page, pages[1].to_bytes());
+        assert_eq!(page(&host, &pages[0]).header.queue, NO_PAGE);
+
+        // Refilling needs the next page
+        assert_eq!(
+            host.send(&builder.drain(&cranker)),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+        for _ in 0..2 {
+            host.send(&with_pages(builder.drain(&cranker), &pages[1..]))
+                .unwrap();
+        }
+        let drained = host.state::<Paged>(&state);
+        assert_eq!(drained.total, (1..=6).sum::<u64>());
+        assert_eq!(queue::entries(&drained.queue.queue).count(), 0);
+        assert_eq!(drained.queue.header.next_page, NO_PAGE);
+        assert_eq!(page(&host, &pages[1]).header.queue, NO_PAGE);
+    }
+
+    #[test]
+    fn test_queue_into_stale_instance() {
+        use apq_client::instructions::InstructionBuilder;
+        use apq_core::{
+            discriminator::Tag, dispatch, header::StateHeader, instance::Registry,
+            layout::LAYOUT_MISMATCH,
+        };
+        use mock::{Tally, TallyProgram};
+
+        let program_id = Pubkey::new_unique();
+        let mut host = Host::new(program_id, dispatch::process_with::<TallyProgram, Tag>);
+        let registry = host.create_account(Registry::LEN, &program_id, 0);
+        let authority = host.user(0);
+        let [first, second] = [1, 2].map(|id| {
+            let state = host.create_state::<Tally>();
+            let builder = InstructionBuilder::new(program_id, state);
+            host.send(&builder.create_instance(&authority, &registry, id))
+                .unwrap();
+            builder
+        });
+        let user = host.user(0);
+        let queue = first.queue_many(&user, &[second.state], &[]);
+        host.send(&queue).unwrap();
+        let queued = |host: &Host| [&first, &second].map(|b| host.state::<Tally>(&b.state).queued);
+        assert_eq!(queued(&host), [1, 1]);
+
+        // An instance left on an older layout isn't written to until migrated
+        let data = &mut host.accounts.get_mut(&second.state).unwrap().data;
+        let mut header = StateHeader::read(data).unwrap();
+        header.layout_hash ^= 1;
+        header.write(data).unwrap();
+        assert_eq!(
+            host.send(&queue),
+            Err(ProgramError::Custom(LAYOUT_MISMATCH))
+        );
+        let data = &host.accounts[&second.state].data;
+        assert_eq!(bytemuck::pod_read_unaligned::<Tally>(data).queued, 1);
+        assert_eq!(host.state::<Tally>(&first.state).queued, 1);
+    }
+
+    #[test]
+    fn test_send() {
+        let program_id = Pubkey::new_unique();
+        let mut host = Host::new(program_id, transfer);
+        host.slot = 9;
+        let from = host.user(100);
+        let to = host.create_account(4, &program_id, 1);
+        let ix = |lamports: u64, fail: bool| {
+            let data = [&lamports.to_le_bytes()[..], &[fail as u8]].concat();
+            Instruction::new_with_bytes(
+                program_id,
+                &data,
+                vec![AccountMeta::new(from, true), AccountMeta::new(to, false)],
+            )
+        };
+
+        host.send(&ix(30, false)).unwrap();
+        assert_eq!((host.lamports(&from), host.lamports(&to)), (70, 31));
+        assert_eq!(host.accounts[&to].data, [9, 0, 0, 0]);
+
+        // Failing instructions leave the accounts as they were
+        assert_eq!(host.send(&ix(30, true)), Err(ProgramError::Custom(7)));
+        assert_eq!((host.lamports(&from), host.lamports(&to)), (70, 31));
+
+        let mut unsigned = ix(1, false);
+        unsigned.accounts[0].is_signer = false;
+        assert_eq!(
+            host.send(&unsigned),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! LiteSVM harness for integration tests of programs built on apq_core
+//!
+//! [`TestKit`] loads a program into a fresh LiteSVM with a funded payer, creates state accounts
+//! sized from the state type, sends instructions and decodes the state back for assertions.
+//! Signatures aren't verified, so instructions can name any account as a signer.
+//!
+//! [`assert_cu!`] bounds the compute units a transaction used, so cost regressions fail tests.
+//!
+//! [`host`] runs a program's dispatch natively on in-memory accounts, for dispatch tests that
+//! don't need the program built for SBF.
+//!
+//! [`differential`] checks that two decoders of the same type agree.
+//! [`snapshot`] saves accounts and the clock to a file and restores them, for replaying bugs
+//! seen on live deployments.
+
+use std::path::{Path, PathBuf};
+
+pub mod differential;
+pub mod host;
+pub mod snapshot;
+
+use apq_client::{DecodeError, TryDecode};
+use apq_core::{events::Event, queue::QueueKey, AsyncState};
+pub use litesvm::{
+    types::{FailedTransactionMetadata, TransactionMetadata, TransactionResult},
+    LiteSVM,
+};
+use solana_instruction::Instruction;
+use solana_keypair::Keypair;
+use solana_message::Message;
+use solana_program::{clock::Clock, system_instruction};
+use solana_pubkey::Pubkey;
+use solana_signer::Signer;
+use solana_transaction::Transaction;
+
+/// Where `cargo build-sbf` put program `name`: `$SBF_OUT_DIR` if set, otherwise the nearest
+/// `target/deploy` up from the working directory
+pub fn deploy_path(name: &str) -> PathBuf {
+    let file = format!("{name}.so");
+    if let Some(dir) = std::env::var_os("SBF_OUT_DIR") {
+        return Path::new(&dir).join(file);
+    }
+    let cwd = std::env::current_dir().unwrap_or_default();
+    cwd.ancestors()
+        .map(|dir| dir.join("target/deploy").join(&file))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| Path::new("target/deploy").join(file))
+}
+
+pub struct TestKit {
+    pub svm: LiteSVM,
+    pub program_id: Pubkey,
+    /// Pays for every transaction
+    pub payer: Pubkey,
+}
+
+impl TestKit {
+    /// A fresh LiteSVM without the program loaded, see [`TestKit::load_program`]
+    pub fn empty(program_id: Pubkey) -> Self {
+        // History is off so the same instruction can be sent again
+        let mut svm = LiteSVM::new()
+            .with_blockhash_check(false)
+            .with_sigverify(false)
+            .with_transaction_history(0);
+        let payer = Pubkey::new_unique();
+        svm.airdrop(&payer, 1_000_000_000_000).unwrap();
+        TestKit {
+            svm,
+            program_id,
+            payer,
+        }
+    }
+
+    /// Loads the program built at `path`, see [`deploy_path`]
+    pub fn new(program_id: Pubkey, path: &Path) -> Result<Self, String> {
+        let mut kit = Self::empty(program_id);
+        kit.load_program(path)?;
+        Ok(kit)
+    }
+
+    pub fn load_program(&mut self, path: &Path) -> Result<(), String> {
+        self.svm
+            .add_program_from_file(self.program_id, path)
+            .map_err(|err| format!("loading {}: {err}", path.display()))
+    }
+
+    /// A new user holding `lamports`
+    pub fn user(&mut self, lamports: u64) -> Pubkey {
+        let user = Pubkey::new_unique();
+        self.svm.airdrop(&user, lamports).unwrap();
+        user
+    }
+
+    /// Creates a zeroed, rent exempt account of `len` bytes owned by `owner`
+    #[track_caller]
+    pub fn create_account(&mut self, len: usize, owner: &Pubkey) -> Pubkey {
+        let account = Keypair::new().pubkey();
+        let create = system_instruction::create_account(
+            &self.payer,
+            &account,
+            self.svm.minimum_balance_for_rent_exemption(len),
+            len as u64,
+            owner,
+        );
+        self.send_ok(&[create]);
+        account
+    }
+
+    /// Creates a state account of [`AsyncState::LEN`] for the program, initialized by its
+    /// first instruction
+    #[track_caller]
+    pub fn create_state<S: AsyncState>(&mut self) -> Pubkey {
+        let owner = self.program_id;
+        self.create_account(S::LEN, &owner)
+    }
+
+    /// Sends `instructions` in one transaction
+    // LiteSVM's own result, passed through
+    #[allow(clippy::result_large_err)]
+    pub fn send(&mut self, instructions: &[Instruction]) -> TransactionResult {
+        let message = Message::new(instructions, Some(&self.payer));
+        self.svm
+            .send_transaction(Transaction::new_unsigned(message))
+    }
+
+    /// Sends `instructions` in one transaction, panicking with its logs if it fails
+    #[track_caller]
+    pub fn send_ok(&mut self, instructions: &[Instruction]) -> TransactionMetadata {
+        match self.send(instructions) {
+            Ok(meta) => meta,
+            Err(failed) => panic!(
+                "transaction failed: {:?}\n{}",
+                failed.err,
+                failed.meta.logs.join("\n")
+            ),
+        }
+    }
+
+    pub fn slot(&self) -> u64 {
+        self.svm.get_sysvar::<Clock>().slot
+    }
+
+    pub fn warp_slots(&mut self, slots: u64) {
+        let slot = self.slot() + slots;
+        self.svm.warp_to_slot(slot);
+    }
+
+    pub fn try_state<S: TryDecode>(&self, state: &Pubkey) -> Result<Box<S>, DecodeError> {
+        let account = self.svm.get_account(state).unwrap_or_default();
+        S::try_decode(&account.data)
+    }
+
+    /// The decoded state, panicking if it doesn't decode
+    #[track_caller]
+    pub fn state<S: TryDecode>(&self, state: &Pubkey) -> Box<S> {
+        match self.try_state(state) {
+            Ok(state) => state,
+            Err(err) => panic!("invalid state: {err}"),
+        }
+    }
+
+    /// Asserts that `check` holds for the decoded state
+    #[track_caller]
+    pub fn assert_state<S: TryDecode>(&self, state: &Pubkey, check: impl FnOnce(&S) -> bool) {
+        assert!(check(&self.state::<S>(state)), "state check failed");
+    }
+
+    /// Decodes the state, lets `modify` change it and writes it back, e.g. to set up a full
+    /// queue without sending thousands of transactions
+    #[track_caller]
+    pub fn modify_state<S: TryDecode>(&mut self, state: &Pubkey, modify: impl FnOnce(&mut S)) {
+        let mut decoded = self.state::<S>(state);
+        modify(&mut decoded);
+        let mut account = self.svm.get_account(state).unwrap();
+        account.data[..size_of::<S>()].copy_from_slice(bytemuck::bytes_of(&*decoded));
+        self.svm.set_account(*state, account).unwrap();
+    }
+}
+
+/// Asserts that the transaction whose [`TransactionMetadata`] is bound to `name` used at most
+/// `max` compute units, e.g. `assert_cu!(enqueue <= 12_000)`. Transactions run deterministically,
+/// so a bound with a small margin over a measured run catches regressions.
+#[macro_export]
+macro_rules! assert_cu {
+    ($name:ident <= $max:expr) => {
+        $crate::check_cu(stringify!($name), &$name, $max)
+    };
+}
+
+/// Panics with the transaction's logs if it used more than `max` compute units, see
+/// [`assert_cu!`]
+#[track_caller]
+pub fn check_cu(name: &str, meta: &TransactionMetadata, max: u64) {
+    let used = meta.compute_units_consumed;
+    assert!(
+        used <= max,
+        "{name} used {used} compute units, over the bound of {max}\n{}",
+        meta.logs.join("\n")
+    );
+}
+
+/// Every `E` emitted in `logs`, see [`apq_core::events`]
+pub fn events<E: Event>(logs: &[String]) -> Vec<E> {
+    logs.iter()
+        .filter_map(|line| apq_core::events::decode(&apq_core::events::decode_log(line)?))
+        .collect()
+}
+
+/// Every `E` emitted in `logs` with the queue key it is about
+pub fn keyed_events<E: Event, K: QueueKey>(logs: &[String]) -> Vec<(E, K)> {
+    logs.iter()
+        .filter_map(|line| apq_core::events::decode_keyed(&apq_core::events::decode_log(line)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_core::{events::DrainedEvent, header::StateHeader, migrate::Migratable};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use bytemuck::{Pod, Zeroable};
+
+    use super::*;
+
+    #[derive(Copy, Clone, Zeroable, Pod)]
+    #[repr(C)]
+    struct Tiny {
+        header: StateHeader,
+        value: u64,
+    }
+
+    impl Migratable for Tiny {
+        const VERSION: u32 = 1;
+
+        fn migrate(_from_version: u32, _data: &mut [u8]) -> pinocchio::ProgramResult {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut kit = TestKit::empty(Pubkey::new_unique());
+        let owner = kit.program_id;
+        let state = kit.create_account(size_of::<Tiny>(), &owner);
+        assert_eq!(
+            kit.try_state::<Tiny>(&state).err(),
+            Some(DecodeError::Uninitialized)
+        );
+
+        // What the program's first instruction would do
+        let mut account = kit.svm.get_account(&state).unwrap();
+        StateHeader::new(Tiny::VERSION)
+            .write(&mut account.data)
+            .unwrap();
+        kit.svm.set_account(state, account).unwrap();
+
+        kit.modify_state::<Tiny>(&state, |tiny| tiny.value = 5);
+        kit.assert_state::<Tiny>(&state, |tiny| tiny.value == 5);
+
+        let slot = kit.slot();
+        kit.warp_slots(3);
+        assert_eq!(kit.slot(), slot + 3);
+    }
+
+    #[test]
+    fn test_assert_cu() {
+        let enqueue = TransactionMetadata {
+            compute_units_consumed: 12_000,
+            logs: vec!["Program log: Queueing".to_owned()],
+            ..Default::default()
+        };
+        assert_cu!(enqueue <= 12_000);
+        let over = std::panic::catch_unwind(|| assert_cu!(enqueue <= 11_999)).unwrap_err();
+        let message = over.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("enqueue used 12000 compute units"));
+        assert!(message.ends_with("Program log: Queueing"));
+    }
+
+    #[test]
+    fn test_events() {
+        let drained = DrainedEvent {
+            cranker: [1; 32],
+            drained: 2,
+            bounty: 3,
+        };
+        let encoded: Vec<String> = [
+            &DrainedEvent::DISCRIMINATOR[..],
+            bytemuck::bytes_of(&drained),
+        ]
+        .iter()
+        .map(|segment| STANDARD.encode(segment))
+        .collect();
+        let line = format!("Program data: {}", encoded.join(" "));
+        let logs = ["Program log: Draining".to_owned(), line];
+        assert_eq!(events::<DrainedEvent>(&logs), [drained]);
+        assert!(keyed_events::<DrainedEvent, u64>(&logs).is_empty());
+    }
+}
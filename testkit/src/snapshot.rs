@@ -0,0 +1,184 @@
+//! Snapshots of accounts and the clock, for replaying bugs seen on live deployments
+//!
+//! A [`Snapshot`] holds the accounts a bug involves, e.g. a program's state and its users, and
+//! the clock they were read at. [`TestKit::restore`] writes it into a kit, after which the
+//! drains that misbehaved can be sent one at a time and the state checked between them.
+//!
+//! Snapshots are saved as JSON. Each account has the shape `solana account <ADDRESS> --output
+//! json` prints, so accounts dumped from a cluster can be pasted in as they are:
+//!
+//! ```json
+//! {
+//!   "clock": { "slot": 100, "epochStartTimestamp": 0, "epoch": 0, "leaderScheduleEpoch": 1, "unixTimestamp": 1700000000 },
+//!   "accounts": [
+//!     { "pubkey": "..", "account": { "lamports": 1, "data": ["..", "base64"], "owner": "..", "executable": false, "rentEpoch": 0 } }
+//!   ]
+//! }
+//! ```
+
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use solana_account::Account;
+use solana_program::clock::Clock;
+use solana_pubkey::Pubkey;
+
+use crate::TestKit;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub clock: Clock,
+    pub accounts: Vec<(Pubkey, Account)>,
+}
+
+/// `value[field]` as a `T`, or which field is missing
+fn field<'a, T>(
+    value: &'a Value,
+    field: &str,
+    read: impl FnOnce(&'a Value) -> Option<T>,
+) -> Result<T, String> {
+    read(&value[field]).ok_or(format!("missing or invalid `{field}`"))
+}
+
+fn pubkey(value: &Value, name: &str) -> Result<Pubkey, String> {
+    field(value, name, |value| value.as_str()?.parse().ok())
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> Value {
+        let accounts: Vec<Value> = self
+            .accounts
+            .iter()
+            .map(|(pubkey, account)| {
+                json!({
+                    "pubkey": pubkey.to_string(),
+                    "account": {
+                        "lamports": account.lamports,
+                        "data": [STANDARD.encode(&account.data), "base64"],
+                        "owner": account.owner.to_string(),
+                        "executable": account.executable,
+                        "rentEpoch": account.rent_epoch,
+                    },
+                })
+            })
+            .collect();
+        json!({
+            "clock": {
+                "slot": self.clock.slot,
+                "epochStartTimestamp": self.clock.epoch_start_timestamp,
+                "epoch": self.clock.epoch,
+                "leaderScheduleEpoch": self.clock.leader_schedule_epoch,
+                "unixTimestamp": self.clock.unix_timestamp,
+            },
+            "accounts": accounts,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let clock = &value["clock"];
+        let clock = Clock {
+            slot: field(clock, "slot", Value::as_u64)?,
+            epoch_start_timestamp: field(clock, "epochStartTimestamp", Value::as_i64)?,
+            epoch: field(clock, "epoch", Value::as_u64)?,
+            leader_schedule_epoch: field(clock, "leaderScheduleEpoch", Value::as_u64)?,
+            unix_timestamp: field(clock, "unixTimestamp", Value::as_i64)?,
+        };
+
+        let accounts = field(value, "accounts", Value::as_array)?;
+        let accounts = accounts
+            .iter()
+            .map(|entry| {
+                let address = pubkey(entry, "pubkey")?;
+                let account = &entry["account"];
+                let data = field(account, "data", |data| data[0].as_str())?;
+                let account = Account {
+                    lamports: field(account, "lamports", Value::as_u64)?,
+                    data: STANDARD
+                        .decode(data)
+                        .map_err(|err| format!("data of {address}: {err}"))?,
+                    owner: pubkey(account, "owner")?,
+                    executable: field(account, "executable", Value::as_bool)?,
+                    rent_epoch: field(account, "rentEpoch", Value::as_u64)?,
+                };
+                Ok((address, account))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Snapshot { clock, accounts })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(&self.to_json()).expect("JSON values serialize");
+        std::fs::write(path, json).map_err(|err| format!("writing {}: {err}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|err| format!("reading {}: {err}", path.display()))?;
+        let value = serde_json::from_str(&json)
+            .map_err(|err| format!("parsing {}: {err}", path.display()))?;
+        Self::from_json(&value).map_err(|err| format!("{}: {err}", path.display()))
+    }
+}
+
+impl TestKit {
+    /// The clock and `accounts` as they are now. Accounts that don't exist are left out.
+    pub fn snapshot(&self, accounts: &[Pubkey]) -> Snapshot {
+        Snapshot {
+            clock: self.svm.get_sysvar(),
+            accounts: accounts
+                .iter()
+                .filter_map(|pubkey| Some((*pubkey, self.svm.get_account(pubkey)?)))
+                .collect(),
+        }
+    }
+
+    /// Writes the snapshot's accounts over whatever the kit holds and sets its clock
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        self.svm.set_sysvar(&snapshot.clock);
+        for (pubkey, account) in &snapshot.accounts {
+            self.svm
+                .set_account(*pubkey, account.clone())
+                .map_err(|err| format!("restoring {pubkey}: {err}"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut kit = TestKit::empty(Pubkey::new_unique());
+        let owner = kit.program_id;
+        let state = kit.create_account(64, &owner);
+        let mut account = kit.svm.get_account(&state).unwrap();
+        account.data[..8].copy_from_slice(&7u64.to_le_bytes());
+        kit.svm.set_account(state, account).unwrap();
+        kit.warp_slots(40);
+        let mut clock: Clock = kit.svm.get_sysvar();
+        clock.unix_timestamp = 1_700_000_000;
+        kit.svm.set_sysvar(&clock);
+
+        let snapshot = kit.snapshot(&[state, Pubkey::new_unique()]);
+        assert_eq!(snapshot.accounts.len(), 1);
+        let path = std::env::temp_dir().join(format!("apq-snapshot-{state}.json"));
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, snapshot);
+
+        let mut fresh = TestKit::empty(owner);
+        fresh.restore(&loaded).unwrap();
+        assert_eq!(fresh.slot(), clock.slot);
+        assert_eq!(fresh.svm.get_sysvar::<Clock>(), clock);
+        assert_eq!(fresh.svm.get_account(&state), kit.svm.get_account(&state));
+
+        assert_eq!(
+            Snapshot::from_json(&json!({ "clock": {} })),
+            Err("missing or invalid `slot`".to_owned())
+        );
+    }
+}
@@ -0,0 +1,825 @@
+#![allow(unexpected_cfgs)]
+
+//! Token vesting on a wall-clock schedule
+//!
+//! The admin grants a beneficiary an amount that vests linearly between a start and an end
+//! time, with nothing vested before a cliff. Beneficiaries queue claims for a unix timestamp
+//! of their choosing, and a claim becomes processable once that time has passed: the state is
+//! on [`Schedule::UnixTimestamp`] and keys its queue with [`TimestampKey`]. Processing
+//! releases whatever has vested as of the claim's time, and a claim leaving some of the grant
+//! unvested queues a follow-up claim for the grant's end, see [`apq_core::chain`], so the
+//! rest is released without the beneficiary claiming again. Claims escrow a deposit on top
+//! of the crank bounty to pay for it.
+//!
+//! Grants are paid into the [`Vault`], free until the admin sets it. Released amounts are
+//! tracked in the state; a token program would pay them out when processing, with the
+//! accounts the claim references, see [`apq_core::accounts`].
+
+use apq_core::{
+    chain::{Chain, Link},
+    context::{AccountsCtx, StateAccounts},
+    deser_containers::{OwnedOrBorrowed, OwnedOrBorrowedMut},
+    dispatch, emit,
+    error::ApqError,
+    events::{CancelledEvent, ProcessedEvent, QueuedEvent},
+    header::StateHeader,
+    log_debug, log_error, log_info,
+    migrate::{self, Migratable},
+    ordering::{self, ExecuteAt, OrderingKey, Schedule, TimestampKey},
+    pod::read_pod,
+    queue::{self, peek_min, pop_min, ArgsSlot, CancelAll, Entry, UserPayload},
+    strict::{self, Strictness},
+    vault::Vault,
+    AsyncIx, AsyncState, FromBytes, Program, SyncIx,
+};
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey, ProgramResult,
+};
+use sokoban::{NodeAllocatorMap, RedBlackTree};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum VestingSyncIx {
+    /// Grants a beneficiary an amount, paid into the vault by the admin who signs. Data is a
+    /// [`CreateGrantArgs`].
+    CreateGrant = 0,
+    /// Sets the vault, signed by the admin. Data is the new [`Vault`].
+    SetVault = 1,
+}
+
+impl VestingSyncIx {
+    /// Owned decoding for clients, accepting exactly what the [`FromBytes`] impl does
+    pub fn decode_owned(bytes: &[u8]) -> Result<Self, ProgramError> {
+        match read_pod::<u64>(bytes)? {
+            0 => Ok(VestingSyncIx::CreateGrant),
+            1 => Ok(VestingSyncIx::SetVault),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+}
+
+impl FromBytes for VestingSyncIx {
+    type Target<'a> = OwnedOrBorrowed<'a, Self>;
+    type TargetMut<'a> = OwnedOrBorrowedMut<'a, Self>;
+    fn from_bytes<'a>(bytes: &'a [u8]) -> Result<OwnedOrBorrowed<'a, Self>, ProgramError> {
+        Ok(OwnedOrBorrowed::Owned(Self::decode_owned(bytes)?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u64)]
+pub enum VestingAsyncIx {
+    /// Releases what has vested by the claim's time, following up with a claim for the rest
+    /// at the grant's end. Data is the `unlock_at` unix timestamp the claim waits for.
+    Claim = 0,
+}
+
+impl FromBytes for VestingAsyncIx {
+    type Target<'a> = OwnedOrBorrowed<'a, Self>;
+    type TargetMut<'a> = OwnedOrBorrowedMut<'a, Self>;
+    fn from_bytes<'a>(bytes: &'a [u8]) -> Result<OwnedOrBorrowed<'a, Self>, ProgramError> {
+        match read_pod::<u64>(bytes)? {
+            0 => Ok(OwnedOrBorrowed::Owned(VestingAsyncIx::Claim)),
+            variant => {
+                log_error!("got ix variant {}", variant);
+                Err(ProgramError::InvalidInstructionData)
+            }
+        }
+    }
+}
+
+/// Data of [`VestingSyncIx::CreateGrant`], times in unix seconds
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct CreateGrantArgs {
+    pub beneficiary: Pubkey,
+    pub total: u64,
+    pub start: u64,
+    pub cliff: u64,
+    pub end: u64,
+}
+
+/// What a beneficiary was granted and has been released so far
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct Grant {
+    pub total: u64,
+    pub released: u64,
+    /// Vesting starts here, in unix seconds
+    pub start: u64,
+    /// Nothing vests before this
+    pub cliff: u64,
+    /// Everything has vested from here on
+    pub end: u64,
+}
+
+apq_core::impl_layout_hash!(Grant {
+    total: u64,
+    released: u64,
+    start: u64,
+    cliff: u64,
+    end: u64
+});
+
+impl Grant {
+    pub fn new(args: &CreateGrantArgs) -> Result<Self, ProgramError> {
+        let ordered = args.start <= args.cliff && args.cliff <= args.end && args.start < args.end;
+        if !ordered || args.total == 0 {
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(Grant {
+            total: args.total,
+            released: 0,
+            start: args.start,
+            cliff: args.cliff,
+            end: args.end,
+        })
+    }
+
+    /// Amount vested at `timestamp`
+    pub fn vested(&self, timestamp: u64) -> u64 {
+        if timestamp < self.cliff {
+            return 0;
+        }
+        if timestamp >= self.end {
+            return self.total;
+        }
+        let elapsed = u128::from(timestamp - self.start);
+        let duration = u128::from(self.end - self.start);
+        // Less than the total, since elapsed < duration
+        (u128::from(self.total) * elapsed / duration) as u64
+    }
+}
+
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct ClaimArgs {
+    /// Unix timestamp the claim waits for
+    pub unlock_at: u64,
+    /// Where the claim is in its chain, set by the program, see [`Link`]
+    pub link: Link,
+}
+
+/// What gets queued with each claim
+#[derive(Copy, Clone, Zeroable, Pod, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct ClaimPayload {
+    pub beneficiary: Pubkey,
+    /// The encoded [`ClaimArgs`]
+    pub args: ArgsSlot,
+}
+
+apq_core::impl_layout_hash!(ClaimPayload {
+    beneficiary: Pubkey,
+    args: ArgsSlot
+});
+
+impl UserPayload for ClaimPayload {
+    fn user(&self) -> &Pubkey {
+        &self.beneficiary
+    }
+}
+
+/// Beneficiaries with a grant at once
+pub const MAX_GRANTS: usize = 1024;
+
+#[derive(Copy, Clone, Zeroable, Pod)]
+#[repr(C)]
+pub struct VestingState {
+    /// Layout version, see [`Migratable`]
+    pub header: StateHeader,
+
+    /// Sequence number to assign to the next claim, starting at 1
+    pub seq: u64,
+
+    /// Where grants are paid, free until the admin sets it
+    pub vault: Vault,
+
+    pub grants: RedBlackTree<Pubkey, Grant, MAX_GRANTS>,
+
+    /// Claims in the order of the time they wait for
+    pub async_queue: RedBlackTree<TimestampKey, ClaimPayload, 4096>,
+}
+
+// Changing the layout needs a new version, see `Migratable`
+apq_core::const_assert_state_layout!(VestingState, size = 484_032, align = 8);
+
+impl VestingState {
+    /// Boxed since the state is far too large for the stack
+    #[cfg(test)]
+    fn new() -> Box<Self> {
+        let mut state: Box<Self> = bytemuck::zeroed_box();
+        state.initialize();
+        state
+    }
+
+    pub fn create_grant(&mut self, args: &CreateGrantArgs) -> ProgramResult {
+        if self.grants.get(&args.beneficiary).is_some() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        self.grants
+            .insert(args.beneficiary, Grant::new(args)?)
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        Ok(())
+    }
+
+    /// Releases what `beneficiary` has vested by `timestamp` and not been released yet.
+    /// Returns the amount.
+    pub fn release(&mut self, beneficiary: &Pubkey, timestamp: u64) -> Result<u64, ProgramError> {
+        let grant = self
+            .grants
+            .get_mut(beneficiary)
+            .ok_or(ProgramError::UninitializedAccount)?;
+        let amount = grant.vested(timestamp).saturating_sub(grant.released);
+        grant.released += amount;
+        Ok(amount)
+    }
+}
+
+impl FromBytes for VestingState {
+    type Target<'a> = &'a Self;
+    type TargetMut<'a> = &'a mut Self;
+    fn from_bytes<'a>(bytes: &'a [u8]) -> Result<Self::Target<'a>, ProgramError> {
+        bytemuck::try_from_bytes(bytes).map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    fn from_bytes_mut<'a>(bytes: &'a mut [u8]) -> Result<Self::TargetMut<'a>, ProgramError> {
+        bytemuck::try_from_bytes_mut(bytes).map_err(|_| ProgramError::InvalidAccountData)
+    }
+}
+
+impl Migratable for VestingState {
+    const VERSION: u32 = 2;
+    const LAYOUT_HASH: u64 = apq_core::layout_hash!(VestingState {
+        header: StateHeader,
+        seq: u64,
+        vault: Vault,
+        grants: RedBlackTree<Pubkey, Grant, MAX_GRANTS>,
+        async_queue: RedBlackTree<TimestampKey, ClaimPayload, 4096>,
+    });
+
+    fn migrate(from_version: u32, data: &mut [u8]) -> ProgramResult {
+        match from_version {
+            // v1 headers predate the layout hash, grow the account by its 8 bytes first
+            1 => migrate::insert_layout_hash(data, 484_024),
+            _ => {
+                log_error!("Unknown state version {}", from_version);
+                Err(ProgramError::InvalidAccountData)
+            }
+        }
+    }
+}
+
+impl SyncIx for VestingSyncIx {
+    fn process<S: AsyncState>(
+        &self,
+        data: &[u8],
+        accounts: &[AccountInfo],
+        state: &mut S,
+    ) -> ProgramResult {
+        let StateAccounts { user, rem, .. } = StateAccounts::parse(accounts)?;
+        // Same hack as the counter to access the concrete state
+        let vesting = unsafe { &mut *(state as *mut S as *mut VestingState) };
+        vesting.header.admin.check_authority(user)?;
+        let data_len = 8 + match self {
+            VestingSyncIx::CreateGrant => size_of::<CreateGrantArgs>(),
+            VestingSyncIx::SetVault => size_of::<Vault>(),
+        };
+        strict::check_consumed(data, data_len, VestingState::STRICTNESS)?;
+        let data = data.get(8..).unwrap_or_default();
+
+        match self {
+            VestingSyncIx::CreateGrant => {
+                let args = read_pod::<CreateGrantArgs>(data)?;
+                vesting.create_grant(&args)?;
+                vesting.vault.charge(user, rem, args.total)?;
+                log_info!("Granted {}", args.total);
+                Ok(())
+            }
+            VestingSyncIx::SetVault => {
+                vesting.vault = read_pod(data)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl AsyncIx for VestingAsyncIx {
+    type Args = ClaimArgs;
+
+    /// Claims are released as of their key's time by [`VestingState::process_entry`], which
+    /// these args don't carry
+    fn process<S: AsyncState>(&self, _args: &Self::Args, _state: &mut S) -> ProgramResult {
+        Err(ProgramError::InvalidInstructionData)
+    }
+
+    fn encode_args(args: &ClaimArgs) -> Result<ArgsSlot, ProgramError> {
+        queue::encode_pod_args(args)
+    }
+
+    fn decode_args(slot: &ArgsSlot) -> Result<ClaimArgs, ProgramError> {
+        queue::decode_pod_args(slot)
+    }
+}
+
+pub struct QueueClaimArgs {
+    payload: ClaimPayload,
+    unlock_at: u64,
+}
+
+impl QueueClaimArgs {
+    /// A claim by `beneficiary` waiting for `unlock_at`, at `link` in its chain
+    fn new(beneficiary: &Pubkey, unlock_at: u64, link: Link) -> Result<Self, ProgramError> {
+        Ok(QueueClaimArgs {
+            payload: ClaimPayload {
+                beneficiary: *beneficiary,
+                args: VestingAsyncIx::encode_args(&ClaimArgs { unlock_at, link })?,
+            },
+            unlock_at,
+        })
+    }
+
+    /// Parses the `unlock_at` timestamp following the async variant, for a claim at the root
+    /// of its chain
+    fn parse(beneficiary: &Pubkey, data: &[u8]) -> Result<Self, ProgramError> {
+        let unlock_at = read_pod::<u64>(data)?;
+        strict::check_consumed(data, 8, VestingState::STRICTNESS)?;
+        Self::new(beneficiary, unlock_at, Link::root::<VestingState>())
+    }
+}
+
+impl ExecuteAt for QueueClaimArgs {
+    fn execute_at(&self) -> u64 {
+        self.unlock_at
+    }
+}
+
+impl AsyncState for VestingState {
+    type SyncIx = VestingSyncIx;
+    type AsyncIx = VestingAsyncIx;
+    type Payload = ClaimPayload;
+
+    type QueueArgs = QueueClaimArgs;
+    type Key = TimestampKey;
+
+    /// Pays keepers to process claims as they unlock
+    const CRANK_BOUNTY: u64 = 5_000;
+
+    /// Pays for the follow-up claim at the grant's end
+    const DEPOSIT: u64 = Self::CRANK_BOUNTY;
+
+    /// Claims are processable from their unlock time on
+    const ASYNC_DELAY_SLOTS: u64 = 0;
+
+    const SCHEDULE: Schedule = Schedule::UnixTimestamp;
+
+    const STRICTNESS: Strictness = Strictness::Strict;
+
+    const CHAINED: bool = true;
+
+    fn initialize(&mut self) {
+        let VestingState {
+            ref mut seq,
+            ref mut grants,
+            ref mut async_queue,
+            // zero initialized
+            header: _,
+            vault: _,
+        } = self;
+        *seq = 1;
+        grants.initialize();
+        async_queue.initialize();
+    }
+
+    fn queue_args(user: &AccountInfo, data: &[u8]) -> Result<QueueClaimArgs, ProgramError> {
+        QueueClaimArgs::parse(user.key(), data.get(8..).unwrap_or_default())
+    }
+
+    fn queue_async(
+        &mut self,
+        ixn: &VestingAsyncIx,
+        args: &QueueClaimArgs,
+        now: u64,
+    ) -> Result<(), ProgramError> {
+        let beneficiary = args.payload.beneficiary;
+        if self.grants.get(&beneficiary).is_none() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        let key = TimestampKey::key(now, self.next_seq()?, ixn, args);
+        self.async_queue
+            .insert(key, args.payload)
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        emit!(
+            QueuedEvent {
+                user: beneficiary,
+                ixn: *ixn as u64,
+                priority_bid: 0,
+            },
+            key
+        );
+        log_debug!("Claim queued for {}", key.timestamp);
+        Ok(())
+    }
+
+    fn queue_follow_up(
+        &mut self,
+        ixn: &VestingAsyncIx,
+        args: &QueueClaimArgs,
+        link: Link,
+        now: u64,
+    ) -> ProgramResult {
+        let args = QueueClaimArgs::new(&args.payload.beneficiary, args.unlock_at, link)?;
+        self.queue_async(ixn, &args, now)
+    }
+
+    /// Refunds what is left of the deposit with the bounty, as a follow-up's share of it
+    fn cancel_async(&mut self, user: &Pubkey, key: &TimestampKey) -> Result<u64, ProgramError> {
+        let payload = *self
+            .async_queue
+            .get(key)
+            .ok_or(ProgramError::InvalidArgument)?;
+        if payload.beneficiary != *user {
+            return Err(ProgramError::IncorrectAuthority);
+        }
+        let link = VestingAsyncIx::decode_args(&payload.args)?.link;
+        self.async_queue.remove(key);
+
+        let refund = Self::CRANK_BOUNTY + link.budget;
+        emit!(
+            CancelledEvent {
+                user: *user,
+                ixn: VestingAsyncIx::Claim as u64,
+                refund,
+            },
+            *key
+        );
+        Ok(refund)
+    }
+
+    fn cancel_all_async(&mut self, user: &Pubkey, max: u32) -> Result<CancelAll, ProgramError> {
+        queue::cancel_all(self, user, max)
+    }
+
+    /// Claims escrow their crank bounty and deposit, which nothing would account for once
+    /// flushed, so only a refunding flush clears them, see [`apq_core::flush`]
+    fn flush_entry(&mut self) -> Result<bool, ProgramError> {
+        match self.peek_entry() {
+            Some(_) => Err(ApqError::Unsupported.into()),
+            None => Ok(false),
+        }
+    }
+
+    fn next_seq(&mut self) -> Result<u64, ProgramError> {
+        ordering::next_seq(&mut self.seq)
+    }
+
+    /// Grants are owed to their beneficiaries until fully released
+    fn is_settled(&self) -> bool {
+        self.async_queue.is_empty()
+            && self
+                .grants
+                .iter()
+                .all(|(_, grant)| grant.released == grant.total)
+    }
+
+    fn process_next_async(&mut self) -> ProgramResult {
+        if let Some(next) = self.pop_entry() {
+            self.process_entry(&next)?;
+        }
+        Ok(())
+    }
+
+    fn process_entry(&mut self, entry: &Entry<TimestampKey, ClaimPayload>) -> ProgramResult {
+        let beneficiary = entry.value.beneficiary;
+        let result = self
+            .release(&beneficiary, entry.key.timestamp)
+            .map(|amount| log_debug!("Released {}", amount));
+        emit!(
+            ProcessedEvent::new(beneficiary, VestingAsyncIx::Claim as u64, &result),
+            entry.key
+        );
+        result
+    }
+
+    fn chain_link(
+        &self,
+        entry: &Entry<TimestampKey, ClaimPayload>,
+    ) -> Result<(Pubkey, Link), ProgramError> {
+        let args = VestingAsyncIx::decode_args(&entry.value.args)?;
+        Ok((entry.value.beneficiary, args.link))
+    }
+
+    fn process_chained_entry(
+        &mut self,
+        entry: &Entry<TimestampKey, ClaimPayload>,
+        chain: &mut Chain<VestingAsyncIx, QueueClaimArgs>,
+    ) -> ProgramResult {
+        self.process_entry(entry)?;
+        let beneficiary = entry.value.beneficiary;
+        let grant = self
+            .grants
+            .get(&beneficiary)
+            .ok_or(ProgramError::UninitializedAccount)?;
+        if grant.released == grant.total {
+            return Ok(());
+        }
+        let args = QueueClaimArgs::new(&beneficiary, grant.end, Link::default())?;
+        // Claims from before chains have no budget to follow up with
+        if chain.enqueue(VestingAsyncIx::Claim, args).is_err() {
+            log_debug!("No follow-up claim");
+        }
+        Ok(())
+    }
+
+    fn has_pending_async(&self, now: u64) -> bool {
+        self.peek_entry().is_some_and(|entry| {
+            OrderingKey::<VestingAsyncIx, QueueClaimArgs>::is_due(
+                &entry.key,
+                now,
+                Self::ASYNC_DELAY_SLOTS,
+            )
+        })
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&TimestampKey, &ClaimPayload)> {
+        self.async_queue.iter()
+    }
+
+    fn peek_entry(&self) -> Option<&Entry<TimestampKey, ClaimPayload>> {
+        peek_min(&self.async_queue).map(|(_addr, node)| node)
+    }
+
+    fn pop_entry(&mut self) -> Option<Entry<TimestampKey, ClaimPayload>> {
+        pop_min(&mut self.async_queue)
+    }
+}
+
+pub struct VestingProgram;
+
+impl Program for VestingProgram {
+    type Sync = VestingSyncIx;
+    type Async = VestingAsyncIx;
+    type State = VestingState;
+
+    fn process(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        dispatch::process::<Self>(program_id, accounts, instruction_data)
+    }
+}
+
+// The macro expands to an unqualified `entrypoint!`
+#[cfg(not(feature = "no-entrypoint"))]
+use pinocchio::entrypoint;
+#[cfg(not(feature = "no-entrypoint"))]
+entrypoint!(process_instruction);
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    VestingProgram::process(program_id, accounts, instruction_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use apq_client::instructions::InstructionBuilder;
+    use apq_core::{
+        close,
+        dead_letter::{self, FailurePolicy},
+        discriminator::Tag,
+        error, flush,
+    };
+    use apq_testkit::host::Host;
+
+    use super::*;
+
+    const ALICE: Pubkey = [1; 32];
+
+    fn granted() -> Box<VestingState> {
+        let mut state = VestingState::new();
+        state
+            .create_grant(&CreateGrantArgs {
+                beneficiary: ALICE,
+                total: 1_000,
+                start: 100,
+                cliff: 150,
+                end: 200,
+            })
+            .unwrap();
+        state
+    }
+
+    fn claim(state: &mut VestingState, unlock_at: u64, now: u64) {
+        let args = QueueClaimArgs::parse(&ALICE, &unlock_at.to_le_bytes()).unwrap();
+        state
+            .queue_async(&VestingAsyncIx::Claim, &args, now)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = apq_core::state_layout!(VestingState {
+            header,
+            seq,
+            vault,
+            grants,
+            async_queue
+        });
+        let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/golden/VestingState.txt");
+        apq_core::layout::assert_golden(&layout, golden);
+    }
+
+    #[test]
+    fn test_vesting_schedule() {
+        let state = granted();
+        let grant = state.grants.get(&ALICE).unwrap();
+        assert_eq!(grant.vested(149), 0);
+        assert_eq!(grant.vested(150), 500);
+        assert_eq!(grant.vested(175), 750);
+        assert_eq!(grant.vested(u64::MAX), 1_000);
+
+        let backwards = CreateGrantArgs {
+            cliff: 90,
+            ..Default::default()
+        };
+        assert_eq!(Grant::new(&backwards), Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_claims_wait_for_unlock() {
+        let mut state = granted();
+        claim(&mut state, 175, 120);
+        // Claims for the past are due at the time they were queued, which a drain only gets
+        // to from the next second on, see `test_claims_never_drain_when_queued`
+        claim(&mut state, 0, 160);
+
+        // Scheduled claims aren't skewed entries
+        assert_eq!(
+            dead_letter::skip_future(&mut *state, 120, FailurePolicy::Abort),
+            Ok(0)
+        );
+        assert!(!state.has_pending_async(159));
+        assert!(state.has_pending_async(160));
+        state.process_next_async().unwrap();
+        assert_eq!(state.grants.get(&ALICE).unwrap().released, 600);
+
+        assert!(!state.has_pending_async(174));
+        state.process_next_async().unwrap();
+        assert_eq!(state.grants.get(&ALICE).unwrap().released, 750);
+        assert!(state.async_queue.is_empty());
+    }
+
+    #[test]
+    fn test_flush_refunds_claims() {
+        let mut state = granted();
+        claim(&mut state, 175, 120);
+        assert_eq!(
+            flush::flush(&mut *state, 8),
+            Err(ProgramError::Custom(error::UNSUPPORTED))
+        );
+        assert_eq!(state.async_queue.len(), 1);
+        // Cancelling it refunds the bounty and deposit, as a refunding flush does
+        let cancelled = state.cancel_all_async(&ALICE, 8).unwrap();
+        let escrow = VestingState::CRANK_BOUNTY + VestingState::DEPOSIT;
+        assert_eq!(cancelled.refund, escrow);
+        assert_eq!(flush::flush(&mut *state, 8), Ok(Default::default()));
+    }
+
+    #[test]
+    fn test_close_needs_grants_released() {
+        let mut state = granted();
+        let header = StateHeader::new(VestingState::VERSION);
+        let not_settled = Err(ProgramError::Custom(close::NOT_SETTLED));
+        assert_eq!(close::check_settled(&mut *state, &header), not_settled);
+
+        claim(&mut state, 200, 120);
+        state.process_next_async().unwrap();
+        assert_eq!(state.grants.get(&ALICE).unwrap().released, 1_000);
+        assert_eq!(close::check_settled(&mut *state, &header), Ok(()));
+    }
+
+    #[test]
+    fn test_claims_need_a_grant() {
+        let mut state = VestingState::new();
+        let args = QueueClaimArgs::parse(&ALICE, &0_u64.to_le_bytes()).unwrap();
+        assert_eq!(
+            state.queue_async(&VestingAsyncIx::Claim, &args, 0),
+            Err(ProgramError::UninitializedAccount)
+        );
+        assert_eq!(
+            QueueClaimArgs::parse(&ALICE, &[0; 9]).map(|args| args.unlock_at),
+            Err(ProgramError::Custom(strict::TRAILING_BYTES))
+        );
+    }
+
+    #[test]
+    fn test_claims_follow_up_at_grant_end() {
+        let program_id = solana_pubkey::Pubkey::new_unique();
+        let mut host = Host::new(program_id, dispatch::process_with::<VestingProgram, Tag>);
+        let state = host.create_state::<VestingState>();
+        let builder = InstructionBuilder::new(program_id, state);
+        let (admin, beneficiary, cranker) = (host.user(0), host.user(0), host.user(0));
+        let grant = CreateGrantArgs {
+            beneficiary: beneficiary.to_bytes(),
+            total: 1_000,
+            start: 100,
+            cliff: 150,
+            end: 200,
+        };
+        let create_grant = [&0_u64.to_le_bytes()[..], bytemuck::bytes_of(&grant)].concat();
+        // The first signer becomes the admin
+        host.send(&builder.sync(&admin, &create_grant, &[]))
+            .unwrap();
+
+        // Users can't queue anywhere in a chain but at its root
+        host.slot = 120;
+        let claim = |unlock_at: u64| [0, unlock_at].map(u64::to_le_bytes).concat();
+        let linked = [claim(175), vec![0; size_of::<Link>()]].concat();
+        assert_eq!(
+            host.send(&builder.queue_async(&beneficiary, &linked)),
+            Err(ProgramError::Custom(strict::TRAILING_BYTES))
+        );
+        host.send(&builder.queue_async(&beneficiary, &claim(175)))
+            .unwrap();
+        // Escrow transfers are no-ops off-chain
+        host.airdrop(&state, VestingState::CRANK_BOUNTY + VestingState::DEPOSIT);
+
+        host.slot = 180;
+        host.send(&builder.drain(&cranker)).unwrap();
+        let vesting = host.state::<VestingState>(&state);
+        assert_eq!(
+            vesting.grants.get(&grant.beneficiary).unwrap().released,
+            750
+        );
+        // The deposit paid for the follow-up's bounty, with nothing left over
+        let (key, follow_up) = vesting.async_queue.iter().next().unwrap();
+        assert_eq!(key.timestamp, 200);
+        let args = VestingAsyncIx::decode_args(&follow_up.args).unwrap();
+        assert_eq!((args.link.depth, args.link.budget), (1, 0));
+        assert_eq!(host.lamports(&cranker), VestingState::CRANK_BOUNTY);
+
+        // Releasing everything, without following up
+        host.slot = 201;
+        host.send(&builder.drain(&cranker)).unwrap();
+        let vesting = host.state::<VestingState>(&state);
+        assert_eq!(
+            vesting.grants.get(&grant.beneficiary).unwrap().released,
+            1_000
+        );
+        assert!(vesting.async_queue.is_empty());
+        assert_eq!(host.lamports(&cranker), 2 * VestingState::CRANK_BOUNTY);
+    }
+
+    #[test]
+    fn test_claims_never_drain_when_queued() {
+        let program_id = solana_pubkey::Pubkey::new_unique();
+        let mut host = Host::new(program_id, dispatch::process_with::<VestingProgram, Tag>);
+        let state = host.create_state::<VestingState>();
+        let builder = InstructionBuilder::new(program_id, state);
+        let (admin, beneficiary, cranker) = (host.user(0), host.user(0), host.user(0));
+        let grant = CreateGrantArgs {
+            beneficiary: beneficiary.to_bytes(),
+            total: 1_000,
+            start: 100,
+            cliff: 150,
+            end: 200,
+        };
+        let create_grant = [&0_u64.to_le_bytes()[..], bytemuck::bytes_of(&grant)].concat();
+        host.send(&builder.sync(&admin, &create_grant, &[]))
+            .unwrap();
+        let claim = |unlock_at: u64| [0, unlock_at].map(u64::to_le_bytes).concat();
+        let released = |host: &Host| {
+            let vesting = host.state::<VestingState>(&state);
+            vesting.grants.get(&grant.beneficiary).unwrap().released
+        };
+
+        // Unlocked already, but queued this second
+        host.slot = 160;
+        host.send(&builder.queue_async(&beneficiary, &claim(0)))
+            .unwrap();
+        host.airdrop(&state, VestingState::CRANK_BOUNTY + VestingState::DEPOSIT);
+        host.send(&builder.drain(&cranker)).unwrap();
+        assert_eq!(released(&host), 0);
+        host.slot = 161;
+        host.send(&builder.drain(&cranker)).unwrap();
+        assert_eq!(released(&host), 600);
+
+        // Unlocking later, drained from the second after
+        host.send(&builder.queue_async(&beneficiary, &claim(175)))
+            .unwrap();
+        host.airdrop(&state, VestingState::CRANK_BOUNTY + VestingState::DEPOSIT);
+        host.slot = 175;
+        host.send(&builder.drain(&cranker)).unwrap();
+        assert_eq!(released(&host), 600);
+        host.slot = 176;
+        host.send(&builder.drain(&cranker)).unwrap();
+        assert_eq!(released(&host), 750);
+    }
+}